@@ -0,0 +1,1118 @@
+// Copyright 2016 David Li
+// This file is part of rustv.
+
+// rustv is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// rustv is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with rustv.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use isa::{self, encoding, funct3, Instruction, Register};
+use memory::{IdentityMmu, Memory};
+use symbols::SymbolTable;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AssemblyError {
+    /// The (1-based) source line the error occurred on
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AssemblyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+pub type Result<T> = ::std::result::Result<T, AssemblyError>;
+
+type ParseResult<T> = ::std::result::Result<T, String>;
+
+/// An assembled memory image.
+pub struct Program {
+    pub text_base: isa::Address,
+    pub text: Vec<u8>,
+    pub data_base: isa::Address,
+    pub data: Vec<u8>,
+    pub symbols: SymbolTable,
+    labels: HashMap<String, u32>,
+}
+
+impl Program {
+    /// The address of `_start` if defined, else the start of `.text`.
+    pub fn entry(&self) -> isa::Address {
+        self.symbol("_start").unwrap_or(self.text_base)
+    }
+
+    /// The value of a label or `.equ` constant.
+    pub fn symbol(&self, name: &str) -> Option<isa::Address> {
+        self.labels.get(name).map(|value| isa::Word(*value))
+    }
+
+    /// Copy the text and data segments into memory.
+    pub fn load(&self, memory: &mut Memory) {
+        let mmu = IdentityMmu::new();
+        memory.write_segment(&mmu, &self.text, self.text_base.0 as usize);
+        memory.write_segment(&mmu, &self.data, self.data_base.0 as usize);
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Section {
+    Text,
+    Data,
+}
+
+enum Body {
+    Instruction {
+        mnemonic: String,
+        operands: Vec<String>,
+        size: u32,
+    },
+    /// `.word`, `.half` or `.byte` with the given width in bytes
+    Values(usize, Vec<String>),
+    Bytes(Vec<u8>),
+}
+
+struct Statement {
+    line: usize,
+    section: Section,
+    address: u32,
+    /// Index among all statements, used to resolve numeric labels
+    position: usize,
+    body: Body,
+}
+
+/// Assembles RISC-V assembly source into a `Program`. Supports labels
+/// (including numeric local labels), the common data directives, the
+/// standard pseudo-instructions and the `%hi`, `%lo`, `%pcrel_hi` and
+/// `%pcrel_lo` relocations.
+pub struct Assembler {
+    text_base: u32,
+    data_base: u32,
+}
+
+impl Assembler {
+    pub fn new(text_base: isa::Address, data_base: isa::Address) -> Assembler {
+        Assembler {
+            text_base: text_base.0,
+            data_base: data_base.0,
+        }
+    }
+
+    pub fn assemble(&self, source: &str) -> Result<Program> {
+        let mut labels = HashMap::new();
+        let mut numeric = Vec::new();
+        let mut address_labels = Vec::new();
+        let mut statements = Vec::new();
+        let mut section = Section::Text;
+        let mut text_pc = self.text_base;
+        let mut data_pc = self.data_base;
+
+        // First pass: lay out statements and assign label addresses
+        for (number, line) in source.lines().enumerate() {
+            let number = number + 1;
+            let error = |message: String| AssemblyError {
+                line: number,
+                message: message,
+            };
+
+            let mut rest = strip_comment(line).trim();
+
+            while let Some((label, remainder)) = split_label(rest) {
+                let pc = if section == Section::Text { text_pc } else { data_pc };
+                if label.chars().all(|c| c.is_digit(10)) {
+                    numeric.push((label.to_owned(), statements.len(), pc));
+                }
+                else if labels.insert(label.to_owned(), pc).is_some() {
+                    return Err(error(format!("duplicate label {}", label)));
+                }
+                else {
+                    address_labels.push((label.to_owned(), pc));
+                }
+                rest = remainder.trim();
+            }
+
+            if rest.len() == 0 {
+                continue;
+            }
+
+            let (head, tail) = match rest.find(char::is_whitespace) {
+                Some(index) => (&rest[..index], rest[index..].trim()),
+                None => (rest, ""),
+            };
+            let head = head.to_lowercase();
+            let operands = try!(split_operands(tail).map_err(&error));
+            let pc = if section == Section::Text { text_pc } else { data_pc };
+
+            let body = if head.starts_with(".") {
+                match &head[..] {
+                    ".text" => { section = Section::Text; None },
+                    ".data" | ".rodata" | ".bss" => {
+                        section = Section::Data;
+                        None
+                    },
+                    ".section" => {
+                        section = match operands.get(0).map(|s| &s[..]) {
+                            Some(name) if name.starts_with(".text") => Section::Text,
+                            Some(_) => Section::Data,
+                            None => return Err(error(
+                                ".section requires a name".to_owned())),
+                        };
+                        None
+                    },
+                    ".word" | ".4byte" => Some(Body::Values(4, operands)),
+                    ".half" | ".2byte" | ".short" => Some(Body::Values(2, operands)),
+                    ".byte" => Some(Body::Values(1, operands)),
+                    ".string" | ".asciz" | ".ascii" => {
+                        let mut bytes = Vec::new();
+                        for operand in operands.iter() {
+                            bytes.extend(try!(parse_string(operand).map_err(&error)));
+                            if head != ".ascii" {
+                                bytes.push(0);
+                            }
+                        }
+                        Some(Body::Bytes(bytes))
+                    },
+                    ".zero" | ".space" | ".skip" => {
+                        let context = Context::new(&labels, &numeric, 0, pc);
+                        let count = try!(operands.get(0)
+                                         .ok_or("missing size".to_owned())
+                                         .and_then(|size| context.eval(size))
+                                         .map_err(&error));
+                        if count < 0 || count > (u32::max_value() - pc) as i64 {
+                            return Err(error(format!("size {} out of range", count)));
+                        }
+                        Some(Body::Bytes(vec![0; count as usize]))
+                    },
+                    ".align" | ".p2align" | ".balign" => {
+                        let context = Context::new(&labels, &numeric, 0, pc);
+                        let amount = try!(operands.get(0)
+                                          .ok_or("missing alignment".to_owned())
+                                          .and_then(|size| context.eval(size))
+                                          .map_err(&error));
+                        let in_range = if head == ".balign" {
+                            amount > 0 && amount <= 1 << 31
+                        }
+                        else {
+                            amount >= 0 && amount < 32
+                        };
+                        if !in_range {
+                            return Err(error(format!("alignment {} out of range", amount)));
+                        }
+                        let alignment = if head == ".balign" {
+                            amount as u32
+                        }
+                        else {
+                            1 << amount
+                        };
+                        if alignment == 0 || alignment & (alignment - 1) != 0 {
+                            return Err(error(format!(
+                                "alignment {} is not a power of two", alignment)));
+                        }
+                        let padding = (alignment - pc % alignment) % alignment;
+                        let mut bytes = vec![0; padding as usize];
+                        if section == Section::Text && padding % 4 == 0 {
+                            // Pad code with nops
+                            for chunk in bytes.chunks_mut(4) {
+                                chunk[0] = 0x13;
+                            }
+                        }
+                        Some(Body::Bytes(bytes))
+                    },
+                    ".equ" | ".set" => {
+                        if operands.len() != 2 {
+                            return Err(error(format!("{} takes a name and a value", head)));
+                        }
+                        let value = {
+                            let context = Context::new(&labels, &numeric, 0, pc);
+                            try!(context.eval(&operands[1]).map_err(&error))
+                        };
+                        labels.insert(operands[0].clone(), value as u32);
+                        None
+                    },
+                    ".globl" | ".global" | ".local" | ".type" | ".size" |
+                    ".option" | ".file" | ".ident" | ".attribute" => None,
+                    _ => return Err(error(format!("unknown directive {}", head))),
+                }
+            }
+            else {
+                let context = Context::new(&labels, &numeric, 0, pc);
+                let size = instruction_size(&head, &operands, &context);
+                Some(Body::Instruction {
+                    mnemonic: head,
+                    operands: operands,
+                    size: size,
+                })
+            };
+
+            if let Some(body) = body {
+                let size = match body {
+                    Body::Instruction { size, .. } => size,
+                    Body::Values(width, ref values) => (width * values.len()) as u32,
+                    Body::Bytes(ref bytes) => bytes.len() as u32,
+                };
+                if section == Section::Text {
+                    text_pc += size;
+                }
+                else {
+                    data_pc += size;
+                }
+
+                statements.push(Statement {
+                    line: number,
+                    section: section,
+                    address: pc,
+                    position: statements.len(),
+                    body: body,
+                });
+            }
+        }
+
+        if self.text_base < self.data_base && text_pc > self.data_base {
+            return Err(AssemblyError {
+                line: source.lines().count(),
+                message: format!("text segment overlaps data at 0x{:x}",
+                                 self.data_base),
+            });
+        }
+
+        // Find the targets of %pcrel_hi relocations, so %pcrel_lo can
+        // refer to them by the label of the auipc
+        let mut pcrel_targets = HashMap::new();
+        for statement in statements.iter() {
+            if let Body::Instruction { ref mnemonic, ref operands, .. } = statement.body {
+                if mnemonic != "auipc" || operands.len() != 2 {
+                    continue;
+                }
+                if let Some(inner) = relocation(&operands[1], "%pcrel_hi") {
+                    let context = Context::new(&labels, &numeric,
+                                               statement.position,
+                                               statement.address);
+                    let target = try!(context.eval(inner).map_err(|message| AssemblyError {
+                        line: statement.line,
+                        message: message,
+                    }));
+                    pcrel_targets.insert(statement.address, target);
+                }
+            }
+        }
+
+        // Second pass: encode
+        let mut text = Vec::new();
+        let mut data = Vec::new();
+        for statement in statements.iter() {
+            let mut context = Context::new(&labels, &numeric,
+                                           statement.position, statement.address);
+            context.pcrel_targets = Some(&pcrel_targets);
+            let error = |message: String| AssemblyError {
+                line: statement.line,
+                message: message,
+            };
+
+            let bytes = match statement.body {
+                Body::Instruction { ref mnemonic, ref operands, size } => {
+                    let instructions = try!(context.encode(mnemonic, operands, size)
+                                            .map_err(&error));
+                    debug_assert_eq!(4 * instructions.len() as u32, size);
+                    let mut bytes = Vec::new();
+                    for instruction in instructions {
+                        for offset in 0..4 {
+                            bytes.push((instruction.word().0 >> (8 * offset)) as u8);
+                        }
+                    }
+                    bytes
+                },
+                Body::Values(width, ref values) => {
+                    let mut bytes = Vec::new();
+                    for value in values.iter() {
+                        let value = try!(context.eval_operand(value).map_err(&error));
+                        for offset in 0..width {
+                            bytes.push((value >> (8 * offset)) as u8);
+                        }
+                    }
+                    bytes
+                },
+                Body::Bytes(ref bytes) => bytes.clone(),
+            };
+
+            if statement.section == Section::Text {
+                text.extend(bytes);
+            }
+            else {
+                data.extend(bytes);
+            }
+        }
+
+        let mut symbols = SymbolTable::new();
+        for &(ref name, address) in address_labels.iter() {
+            symbols.insert(isa::Word(address), name);
+        }
+
+        Ok(Program {
+            text_base: isa::Word(self.text_base),
+            text: text,
+            data_base: isa::Word(self.data_base),
+            data: data,
+            symbols: symbols,
+            labels: labels,
+        })
+    }
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (index, c) in line.char_indices() {
+        if in_string {
+            if escaped {
+                escaped = false;
+            }
+            else if c == '\\' {
+                escaped = true;
+            }
+            else if c == '"' {
+                in_string = false;
+            }
+        }
+        else if c == '"' {
+            in_string = true;
+        }
+        else if c == '#' {
+            return &line[..index];
+        }
+    }
+    line
+}
+
+fn is_symbol_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '.' || c == '$'
+}
+
+fn split_label(line: &str) -> Option<(&str, &str)> {
+    let end = match line.find(|c| !is_symbol_char(c)) {
+        Some(end) => end,
+        None => return None,
+    };
+    if end > 0 && line[end..].starts_with(":") {
+        Some((&line[..end], &line[end + 1..]))
+    }
+    else {
+        None
+    }
+}
+
+fn split_operands(operands: &str) -> ParseResult<Vec<String>> {
+    let mut result = Vec::new();
+    let mut current = String::new();
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for c in operands.chars() {
+        if in_string {
+            current.push(c);
+            if escaped {
+                escaped = false;
+            }
+            else if c == '\\' {
+                escaped = true;
+            }
+            else if c == '"' {
+                in_string = false;
+            }
+            continue;
+        }
+
+        match c {
+            '"' => { in_string = true; current.push(c); },
+            '(' => { depth += 1; current.push(c); },
+            ')' => { depth -= 1; current.push(c); },
+            ',' if depth == 0 => {
+                result.push(current.trim().to_owned());
+                current.clear();
+            },
+            _ => current.push(c),
+        }
+    }
+
+    if in_string {
+        return Err("unterminated string".to_owned());
+    }
+    if depth != 0 {
+        return Err("unbalanced parentheses".to_owned());
+    }
+    if current.trim().len() > 0 || result.len() > 0 {
+        result.push(current.trim().to_owned());
+    }
+
+    Ok(result)
+}
+
+fn parse_string(operand: &str) -> ParseResult<Vec<u8>> {
+    if operand.len() < 2 || !operand.starts_with("\"") || !operand.ends_with("\"") {
+        return Err(format!("expected a string, found {}", operand));
+    }
+
+    let mut bytes = Vec::new();
+    let mut chars = operand[1..operand.len() - 1].chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buffer = [0; 4];
+            bytes.extend(c.encode_utf8(&mut buffer).as_bytes());
+            continue;
+        }
+        match chars.next() {
+            Some('n') => bytes.push(b'\n'),
+            Some('t') => bytes.push(b'\t'),
+            Some('r') => bytes.push(b'\r'),
+            Some('0') => bytes.push(0),
+            Some('\\') => bytes.push(b'\\'),
+            Some('"') => bytes.push(b'"'),
+            Some(c) => return Err(format!("unknown escape \\{}", c)),
+            None => return Err("unterminated escape".to_owned()),
+        }
+    }
+
+    Ok(bytes)
+}
+
+fn parse_register(name: &str) -> ParseResult<Register> {
+    let name = name.trim();
+    if name == "fp" {
+        return Ok(Register::X8);
+    }
+    if name.starts_with("x") {
+        if let Ok(num) = name[1..].parse::<u32>() {
+            if num < 32 {
+                return Ok(Register::from_num(num));
+            }
+        }
+    }
+    for num in 0..32 {
+        let register = Register::from_num(num);
+        if register.abi_name() == name {
+            return Ok(register);
+        }
+    }
+    Err(format!("invalid register {}", name))
+}
+
+fn parse_number(term: &str) -> Option<i64> {
+    let (digits, radix) = if term.starts_with("0x") || term.starts_with("0X") {
+        (&term[2..], 16)
+    }
+    else if term.starts_with("0b") || term.starts_with("0B") {
+        (&term[2..], 2)
+    }
+    else {
+        (term, 10)
+    };
+    i64::from_str_radix(digits, radix).ok()
+}
+
+/// If `operand` is `name(inner)`, return `inner`.
+fn relocation<'a>(operand: &'a str, name: &str) -> Option<&'a str> {
+    let operand = operand.trim();
+    if operand.starts_with(name) && operand.ends_with(")") {
+        let rest = operand[name.len()..].trim_left();
+        if rest.starts_with("(") {
+            return Some(&rest[1..rest.len() - 1]);
+        }
+    }
+    None
+}
+
+/// Split `offset(register)` into its parts.
+fn parse_memory_operand(operand: &str) -> Option<(&str, Register)> {
+    let operand = operand.trim();
+    if !operand.ends_with(")") {
+        return None;
+    }
+    let open = match operand.rfind('(') {
+        Some(open) => open,
+        None => return None,
+    };
+    match parse_register(&operand[open + 1..operand.len() - 1]) {
+        Ok(register) => Some((operand[..open].trim(), register)),
+        Err(_) => None,
+    }
+}
+
+fn fits_signed(value: i64, bits: u32) -> bool {
+    let limit = 1i64 << (bits - 1);
+    value >= -limit && value < limit
+}
+
+/// Split a 32-bit value into the upper immediate of a `lui`/`auipc` and
+/// the sign-extended lower immediate of the following instruction.
+fn split_immediate(value: i64) -> (i64, i64) {
+    let value = value & 0xFFFFFFFF;
+    let low = ((value & 0xFFF) ^ 0x800) - 0x800;
+    let high = ((value - low) >> 12) & 0xFFFFF;
+    (high, low)
+}
+
+fn instruction_size(mnemonic: &str, operands: &[String], context: &Context) -> u32 {
+    match mnemonic {
+        "la" | "lla" | "call" | "tail" => 8,
+        "li" => match operands.get(1).map(|value| context.eval(value)) {
+            Some(Ok(value)) => {
+                if fits_signed(value, 12) || value & 0xFFF == 0 { 4 } else { 8 }
+            },
+            _ => 8,
+        },
+        "lb" | "lh" | "lw" | "lbu" | "lhu" => {
+            match operands.get(1) {
+                Some(operand) if parse_memory_operand(operand).is_none() &&
+                    !operand.starts_with("%") => 8,
+                _ => 4,
+            }
+        },
+        "sb" | "sh" | "sw" if operands.len() == 3 => 8,
+        _ => 4,
+    }
+}
+
+/// Narrow an evaluated immediate; the encoder does the range checks.
+fn immediate(value: i64) -> ParseResult<i32> {
+    if value < ::std::i32::MIN as i64 || value > ::std::i32::MAX as i64 {
+        Err(format!("immediate {} out of range", value))
+    }
+    else {
+        Ok(value as i32)
+    }
+}
+
+/// Convert the 20-bit field written in `lui`/`auipc` to the value loaded.
+fn upper_immediate(field: i64) -> ParseResult<i32> {
+    if field < -0x80000 || field > 0xFFFFF {
+        Err(format!("upper immediate {} out of range", field))
+    }
+    else {
+        Ok(((field as u32) << 12) as i32)
+    }
+}
+
+fn encoded(result: encoding::Result<Instruction>) -> ParseResult<Instruction> {
+    result.map_err(|error| error.to_string())
+}
+
+fn csr_number(name: &str) -> Option<u32> {
+    match name {
+        "fflags" => Some(0x001),
+        "frm" => Some(0x002),
+        "fcsr" => Some(0x003),
+        "mstatus" => Some(0x300),
+        "misa" => Some(0x301),
+        "mie" => Some(0x304),
+        "mtvec" => Some(0x305),
+        "mscratch" => Some(0x340),
+        "mepc" => Some(0x341),
+        "mcause" => Some(0x342),
+        "mtval" => Some(0x343),
+        "mip" => Some(0x344),
+        "mcycle" => Some(0xB00),
+        "minstret" => Some(0xB02),
+        "cycle" => Some(0xC00),
+        "time" => Some(0xC01),
+        "instret" => Some(0xC02),
+        "cycleh" => Some(0xC80),
+        "timeh" => Some(0xC81),
+        "instreth" => Some(0xC82),
+        "mhartid" => Some(0xF14),
+        _ => None,
+    }
+}
+
+fn fence_bits(set: &str) -> ParseResult<u32> {
+    let mut bits = 0;
+    for c in set.chars() {
+        bits |= match c {
+            'i' => 8,
+            'o' => 4,
+            'r' => 2,
+            'w' => 1,
+            _ => return Err(format!("invalid fence set {}", set)),
+        };
+    }
+    Ok(bits)
+}
+
+struct Context<'a> {
+    labels: &'a HashMap<String, u32>,
+    numeric: &'a [(String, usize, u32)],
+    pcrel_targets: Option<&'a HashMap<u32, i64>>,
+    position: usize,
+    pc: u32,
+}
+
+impl<'a> Context<'a> {
+    fn new(labels: &'a HashMap<String, u32>, numeric: &'a [(String, usize, u32)],
+           position: usize, pc: u32) -> Context<'a> {
+        Context {
+            labels: labels,
+            numeric: numeric,
+            pcrel_targets: None,
+            position: position,
+            pc: pc,
+        }
+    }
+
+    fn eval_term(&self, term: &str) -> ParseResult<i64> {
+        let term = term.trim();
+        if term.len() == 0 {
+            return Err("missing operand".to_owned());
+        }
+        if term.starts_with("(") && term.ends_with(")") {
+            return self.eval(&term[1..term.len() - 1]);
+        }
+        if term == "." {
+            return Ok(self.pc as i64);
+        }
+        if term.len() == 3 && term.starts_with("'") && term.ends_with("'") {
+            return Ok(term.as_bytes()[1] as i64);
+        }
+
+        // The term may end in a multibyte character
+        let last = term.char_indices().last().unwrap().0;
+        let (body, suffix) = term.split_at(last);
+        if (suffix == "f" || suffix == "b") && body.len() > 0 &&
+            body.chars().all(|c| c.is_digit(10)) {
+            let found = if suffix == "f" {
+                self.numeric.iter()
+                    .filter(|&&(ref name, position, _)|
+                            name == body && position > self.position)
+                    .next()
+            }
+            else {
+                self.numeric.iter()
+                    .filter(|&&(ref name, position, _)|
+                            name == body && position <= self.position)
+                    .last()
+            };
+            return match found {
+                Some(&(_, _, address)) => Ok(address as i64),
+                None => Err(format!("undefined local label {}", term)),
+            };
+        }
+
+        if term.chars().next().map_or(false, |c| c.is_digit(10)) {
+            return parse_number(term).ok_or(format!("invalid number {}", term));
+        }
+
+        match self.labels.get(term) {
+            Some(value) => Ok(*value as i64),
+            None => Err(format!("undefined symbol {}", term)),
+        }
+    }
+
+    /// Evaluate a sum of terms.
+    fn eval(&self, expr: &str) -> ParseResult<i64> {
+        let mut total = 0i64;
+        let mut sign = 1;
+        let mut depth = 0;
+        let mut start = 0;
+
+        for (index, c) in expr.char_indices() {
+            match c {
+                '(' => depth += 1,
+                ')' => depth -= 1,
+                '+' | '-' if depth == 0 => {
+                    let term = expr[start..index].trim();
+                    if term.len() > 0 {
+                        total += sign * try!(self.eval_term(term));
+                        sign = 1;
+                    }
+                    if c == '-' {
+                        sign = -sign;
+                    }
+                    start = index + 1;
+                },
+                _ => {},
+            }
+        }
+
+        total += sign * try!(self.eval_term(&expr[start..]));
+        Ok(total)
+    }
+
+    /// Evaluate an immediate, applying any relocation function.
+    fn eval_operand(&self, operand: &str) -> ParseResult<i64> {
+        if let Some(inner) = relocation(operand, "%hi") {
+            Ok(split_immediate(try!(self.eval(inner))).0)
+        }
+        else if let Some(inner) = relocation(operand, "%lo") {
+            Ok(split_immediate(try!(self.eval(inner))).1)
+        }
+        else if let Some(inner) = relocation(operand, "%pcrel_hi") {
+            Ok(split_immediate(try!(self.eval(inner)) - self.pc as i64).0)
+        }
+        else if let Some(inner) = relocation(operand, "%pcrel_lo") {
+            let auipc = try!(self.eval(inner)) as u32;
+            let target = match self.pcrel_targets.and_then(|t| t.get(&auipc)) {
+                Some(target) => *target,
+                None => return Err(format!(
+                    "{} does not label an auipc with %pcrel_hi", inner)),
+            };
+            Ok(split_immediate(target - auipc as i64).1)
+        }
+        else if operand.trim().starts_with("%") {
+            Err(format!("unknown relocation {}", operand))
+        }
+        else {
+            self.eval(operand)
+        }
+    }
+
+    fn offset(&self, target: &str) -> ParseResult<i64> {
+        Ok(try!(self.eval(target)) - self.pc as i64)
+    }
+
+    fn csr(&self, operand: &str) -> ParseResult<i64> {
+        match csr_number(operand.trim()) {
+            Some(csr) => Ok(csr as i64),
+            None => self.eval(operand),
+        }
+    }
+
+    fn encode(&self, mnemonic: &str, ops: &[String], size: u32)
+              -> ParseResult<Vec<Instruction>> {
+        let count = |n: usize| -> ParseResult<()> {
+            if ops.len() == n {
+                Ok(())
+            }
+            else {
+                Err(format!("{} expects {} operands, found {}",
+                            mnemonic, n, ops.len()))
+            }
+        };
+        let reg = |index: usize| parse_register(&ops[index]);
+        let imm = |operand: &str| -> ParseResult<i32> {
+            immediate(try!(self.eval_operand(operand)))
+        };
+        let offset = |target: &str| -> ParseResult<i32> {
+            immediate(try!(self.offset(target)))
+        };
+        // The two halves of a PC-relative address, for auipc pairs
+        let pcrel = |target: &str| -> ParseResult<(i32, i32)> {
+            let (high, low) = split_immediate(try!(self.offset(target)));
+            Ok((try!(upper_immediate(high)), low as i32))
+        };
+        let zero = Register::X0;
+        let ra = Register::X1;
+
+        type Register3 = fn(Register, Register, Register) -> Instruction;
+        type Immediate = fn(Register, Register, i32) -> encoding::Result<Instruction>;
+
+        let integer_register = |encode: Register3| -> ParseResult<Vec<Instruction>> {
+            try!(count(3));
+            Ok(vec![encode(try!(reg(0)), try!(reg(1)), try!(reg(2)))])
+        };
+        let integer_immediate = |encode: Immediate| -> ParseResult<Vec<Instruction>> {
+            try!(count(3));
+            Ok(vec![try!(encoded(encode(try!(reg(0)), try!(reg(1)),
+                                        try!(imm(&ops[2])))))])
+        };
+        let branch = |encode: Immediate, swap: bool| -> ParseResult<Vec<Instruction>> {
+            try!(count(3));
+            let (rs1, rs2) = if swap { (1, 0) } else { (0, 1) };
+            Ok(vec![try!(encoded(encode(try!(reg(rs1)), try!(reg(rs2)),
+                                        try!(offset(&ops[2])))))])
+        };
+        let branch_zero = |encode: Immediate, swap: bool| -> ParseResult<Vec<Instruction>> {
+            try!(count(2));
+            let (rs1, rs2) = if swap {
+                (zero, try!(reg(0)))
+            }
+            else {
+                (try!(reg(0)), zero)
+            };
+            Ok(vec![try!(encoded(encode(rs1, rs2, try!(offset(&ops[1])))))])
+        };
+        let load = |encode: Immediate| -> ParseResult<Vec<Instruction>> {
+            try!(count(2));
+            let rd = try!(reg(0));
+            if size == 8 {
+                // Load from a symbol
+                let (high, low) = try!(pcrel(&ops[1]));
+                return Ok(vec![try!(encoded(Instruction::auipc(rd, high))),
+                               try!(encoded(encode(rd, rd, low)))]);
+            }
+            let (offset, base) = match parse_memory_operand(&ops[1]) {
+                Some((offset, base)) => (offset, base),
+                None => (&ops[1][..], zero),
+            };
+            let offset = if offset.len() == 0 { 0 } else { try!(imm(offset)) };
+            Ok(vec![try!(encoded(encode(rd, base, offset)))])
+        };
+        let store = |encode: Immediate| -> ParseResult<Vec<Instruction>> {
+            let rs2 = try!(reg(0));
+            if size == 8 {
+                // Store to a symbol, using a temporary register
+                try!(count(3));
+                let temp = try!(reg(2));
+                let (high, low) = try!(pcrel(&ops[1]));
+                return Ok(vec![try!(encoded(Instruction::auipc(temp, high))),
+                               try!(encoded(encode(temp, rs2, low)))]);
+            }
+            try!(count(2));
+            let (offset, base) = match parse_memory_operand(&ops[1]) {
+                Some((offset, base)) => (offset, base),
+                None => return Err(format!("invalid memory operand {}", ops[1])),
+            };
+            let offset = if offset.len() == 0 { 0 } else { try!(imm(offset)) };
+            Ok(vec![try!(encoded(encode(base, rs2, offset)))])
+        };
+        let csr = |funct3: u32, rd: Option<Register>, immediate: bool|
+                   -> ParseResult<Vec<Instruction>> {
+            // Full form is rd, csr, rs1; the short forms omit rd
+            let (rd, first) = match rd {
+                Some(rd) => { try!(count(2)); (rd, 0) },
+                None => { try!(count(3)); (try!(reg(0)), 1) },
+            };
+            let csr = try!(self.csr(&ops[first]));
+            let source = if immediate {
+                let value = try!(self.eval(&ops[first + 1]));
+                if value < 0 || value > 31 {
+                    return Err(format!("CSR immediate {} out of range", value));
+                }
+                Register::from_num(value as u32)
+            }
+            else {
+                try!(reg(first + 1))
+            };
+            Ok(vec![try!(encoded(Instruction::csr(funct3, rd, csr as u32, source)))])
+        };
+        let csr_read = |csr: u32| -> ParseResult<Vec<Instruction>> {
+            try!(count(1));
+            Ok(vec![try!(encoded(Instruction::csr(funct3::CSRRS, try!(reg(0)),
+                                                  csr, zero)))])
+        };
+        let single = |result: encoding::Result<Instruction>|
+                      -> ParseResult<Vec<Instruction>> {
+            Ok(vec![try!(encoded(result))])
+        };
+
+        match mnemonic {
+            "lui" => {
+                try!(count(2));
+                let value = try!(upper_immediate(try!(self.eval_operand(&ops[1]))));
+                single(Instruction::lui(try!(reg(0)), value))
+            },
+            "auipc" => {
+                try!(count(2));
+                let value = try!(upper_immediate(try!(self.eval_operand(&ops[1]))));
+                single(Instruction::auipc(try!(reg(0)), value))
+            },
+            "jal" => {
+                if ops.len() == 1 {
+                    single(Instruction::jal(ra, try!(offset(&ops[0]))))
+                }
+                else {
+                    try!(count(2));
+                    single(Instruction::jal(try!(reg(0)), try!(offset(&ops[1]))))
+                }
+            },
+            "jalr" => {
+                if ops.len() == 1 {
+                    return single(Instruction::jalr(ra, try!(reg(0)), 0));
+                }
+                let rd = try!(reg(0));
+                let (rs1, offset) = if ops.len() == 2 {
+                    match parse_memory_operand(&ops[1]) {
+                        Some((offset, base)) => (base, offset),
+                        None => (try!(reg(1)), ""),
+                    }
+                }
+                else {
+                    try!(count(3));
+                    (try!(reg(1)), &ops[2][..])
+                };
+                let offset = if offset.len() == 0 { 0 } else { try!(imm(offset)) };
+                single(Instruction::jalr(rd, rs1, offset))
+            },
+            "beq" => branch(Instruction::beq, false),
+            "bne" => branch(Instruction::bne, false),
+            "blt" => branch(Instruction::blt, false),
+            "bge" => branch(Instruction::bge, false),
+            "bltu" => branch(Instruction::bltu, false),
+            "bgeu" => branch(Instruction::bgeu, false),
+            "bgt" => branch(Instruction::blt, true),
+            "ble" => branch(Instruction::bge, true),
+            "bgtu" => branch(Instruction::bltu, true),
+            "bleu" => branch(Instruction::bgeu, true),
+            "beqz" => branch_zero(Instruction::beq, false),
+            "bnez" => branch_zero(Instruction::bne, false),
+            "bltz" => branch_zero(Instruction::blt, false),
+            "bgez" => branch_zero(Instruction::bge, false),
+            "blez" => branch_zero(Instruction::bge, true),
+            "bgtz" => branch_zero(Instruction::blt, true),
+            "lb" => load(Instruction::lb),
+            "lh" => load(Instruction::lh),
+            "lw" => load(Instruction::lw),
+            "lbu" => load(Instruction::lbu),
+            "lhu" => load(Instruction::lhu),
+            "sb" => store(Instruction::sb),
+            "sh" => store(Instruction::sh),
+            "sw" => store(Instruction::sw),
+            "addi" => integer_immediate(Instruction::addi),
+            "slti" => integer_immediate(Instruction::slti),
+            "sltiu" => integer_immediate(Instruction::sltiu),
+            "xori" => integer_immediate(Instruction::xori),
+            "ori" => integer_immediate(Instruction::ori),
+            "andi" => integer_immediate(Instruction::andi),
+            "slli" => integer_immediate(Instruction::slli),
+            "srli" => integer_immediate(Instruction::srli),
+            "srai" => integer_immediate(Instruction::srai),
+            "add" => integer_register(Instruction::add),
+            "sub" => integer_register(Instruction::sub),
+            "sll" => integer_register(Instruction::sll),
+            "slt" => integer_register(Instruction::slt),
+            "sltu" => integer_register(Instruction::sltu),
+            "xor" => integer_register(Instruction::xor),
+            "srl" => integer_register(Instruction::srl),
+            "sra" => integer_register(Instruction::sra),
+            "or" => integer_register(Instruction::or),
+            "and" => integer_register(Instruction::and),
+            "mul" => integer_register(Instruction::mul),
+            "mulh" => integer_register(Instruction::mulh),
+            "mulhsu" => integer_register(Instruction::mulhsu),
+            "mulhu" => integer_register(Instruction::mulhu),
+            "div" => integer_register(Instruction::div),
+            "divu" => integer_register(Instruction::divu),
+            "rem" => integer_register(Instruction::rem),
+            "remu" => integer_register(Instruction::remu),
+            "fence" => {
+                if ops.len() == 0 {
+                    return Ok(vec![Instruction::fence(0xF, 0xF)]);
+                }
+                try!(count(2));
+                Ok(vec![Instruction::fence(try!(fence_bits(&ops[0])),
+                                           try!(fence_bits(&ops[1])))])
+            },
+            "fence.i" => { try!(count(0)); Ok(vec![Instruction::fence_i()]) },
+            "ecall" => { try!(count(0)); Ok(vec![Instruction::ecall()]) },
+            "ebreak" => { try!(count(0)); Ok(vec![Instruction::ebreak()]) },
+            "csrrw" => csr(funct3::CSRRW, None, false),
+            "csrrs" => csr(funct3::CSRRS, None, false),
+            "csrrc" => csr(funct3::CSRRC, None, false),
+            "csrrwi" => csr(funct3::CSRRWI, None, true),
+            "csrrsi" => csr(funct3::CSRRSI, None, true),
+            "csrrci" => csr(funct3::CSRRCI, None, true),
+            "csrw" => csr(funct3::CSRRW, Some(zero), false),
+            "csrs" => csr(funct3::CSRRS, Some(zero), false),
+            "csrc" => csr(funct3::CSRRC, Some(zero), false),
+            "csrwi" => csr(funct3::CSRRWI, Some(zero), true),
+            "csrsi" => csr(funct3::CSRRSI, Some(zero), true),
+            "csrci" => csr(funct3::CSRRCI, Some(zero), true),
+            "csrr" => {
+                try!(count(2));
+                let csr = try!(self.csr(&ops[1])) as u32;
+                single(Instruction::csr(funct3::CSRRS, try!(reg(0)), csr, zero))
+            },
+            "rdcycle" => csr_read(0xC00),
+            "rdtime" => csr_read(0xC01),
+            "rdinstret" => csr_read(0xC02),
+            "rdcycleh" => csr_read(0xC80),
+            "rdtimeh" => csr_read(0xC81),
+            "rdinstreth" => csr_read(0xC82),
+
+            // Pseudo-instructions
+            "nop" => {
+                try!(count(0));
+                single(Instruction::addi(zero, zero, 0))
+            },
+            "li" => {
+                try!(count(2));
+                let rd = try!(reg(0));
+                let value = try!(self.eval(&ops[1]));
+                if !fits_signed(value, 32) && (value < 0 || value > 0xFFFFFFFF) {
+                    return Err(format!("immediate {} out of range", value));
+                }
+                let (high, low) = split_immediate(value);
+                let high = try!(upper_immediate(high));
+                if size == 4 && fits_signed(value, 12) {
+                    single(Instruction::addi(rd, zero, value as i32))
+                }
+                else if size == 4 {
+                    single(Instruction::lui(rd, high))
+                }
+                else {
+                    Ok(vec![try!(encoded(Instruction::lui(rd, high))),
+                            try!(encoded(Instruction::addi(rd, rd, low as i32)))])
+                }
+            },
+            "la" | "lla" => {
+                try!(count(2));
+                let rd = try!(reg(0));
+                let (high, low) = try!(pcrel(&ops[1]));
+                Ok(vec![try!(encoded(Instruction::auipc(rd, high))),
+                        try!(encoded(Instruction::addi(rd, rd, low)))])
+            },
+            "mv" => {
+                try!(count(2));
+                single(Instruction::addi(try!(reg(0)), try!(reg(1)), 0))
+            },
+            "not" => {
+                try!(count(2));
+                single(Instruction::xori(try!(reg(0)), try!(reg(1)), -1))
+            },
+            "neg" => {
+                try!(count(2));
+                Ok(vec![Instruction::sub(try!(reg(0)), zero, try!(reg(1)))])
+            },
+            "seqz" => {
+                try!(count(2));
+                single(Instruction::sltiu(try!(reg(0)), try!(reg(1)), 1))
+            },
+            "snez" => {
+                try!(count(2));
+                Ok(vec![Instruction::sltu(try!(reg(0)), zero, try!(reg(1)))])
+            },
+            "sltz" => {
+                try!(count(2));
+                Ok(vec![Instruction::slt(try!(reg(0)), try!(reg(1)), zero)])
+            },
+            "sgtz" => {
+                try!(count(2));
+                Ok(vec![Instruction::slt(try!(reg(0)), zero, try!(reg(1)))])
+            },
+            "j" => {
+                try!(count(1));
+                single(Instruction::jal(zero, try!(offset(&ops[0]))))
+            },
+            "jr" => {
+                try!(count(1));
+                single(Instruction::jalr(zero, try!(reg(0)), 0))
+            },
+            "ret" => {
+                try!(count(0));
+                single(Instruction::jalr(zero, ra, 0))
+            },
+            "call" | "tail" => {
+                try!(count(1));
+                let (link, temp) = if mnemonic == "call" {
+                    (ra, ra)
+                }
+                else {
+                    (zero, Register::X6)
+                };
+                let (high, low) = try!(pcrel(&ops[0]));
+                Ok(vec![try!(encoded(Instruction::auipc(temp, high))),
+                        try!(encoded(Instruction::jalr(link, temp, low)))])
+            },
+            _ => Err(format!("unknown instruction {}", mnemonic)),
+        }
+    }
+}
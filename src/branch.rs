@@ -0,0 +1,564 @@
+// Copyright 2016 David Li
+// This file is part of rustv.
+
+// rustv is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// rustv is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with rustv.  If not, see <http://www.gnu.org/licenses/>.
+
+use isa::{self, IsaType};
+use isa::opcodes;
+
+/// Predicts the direction of conditional branches.
+pub trait BranchPredictor {
+    /// Predict whether the branch at `pc`, which goes to `target` if
+    /// taken, is taken.
+    fn predict(&mut self, pc: isa::Address, target: isa::Address) -> bool;
+
+    /// Learn which way the branch went. This follows each `predict`.
+    fn update(&mut self, pc: isa::Address, target: isa::Address, taken: bool);
+}
+
+/// Which predictor to use, for configuring cores. Table sizes are in
+/// entries, and must be powers of two.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Predictor {
+    AlwaysNotTaken,
+    /// Backward taken, forward not taken.
+    Btfn,
+    /// Two-bit counters indexed by the PC.
+    Bimodal { entries: usize },
+    /// Two-bit counters indexed by the PC xor the global history.
+    Gshare { entries: usize, history: u32 },
+    /// Bimodal and gshare, with two-bit counters choosing between them.
+    Tournament { entries: usize, history: u32 },
+    /// A bimodal base predictor and `tables` tagged tables with
+    /// geometrically increasing history lengths.
+    Tage { tables: usize, entries: usize },
+}
+
+impl Predictor {
+    pub fn build<'a>(self) -> Box<BranchPredictor + 'a> {
+        match self {
+            Predictor::AlwaysNotTaken => Box::new(AlwaysNotTaken),
+            Predictor::Btfn => Box::new(Btfn),
+            Predictor::Bimodal { entries } => Box::new(Bimodal::new(entries)),
+            Predictor::Gshare { entries, history } => {
+                Box::new(Gshare::new(entries, history))
+            },
+            Predictor::Tournament { entries, history } => {
+                Box::new(Tournament::new(entries, history))
+            },
+            Predictor::Tage { tables, entries } => {
+                Box::new(TageLite::new(tables, entries))
+            },
+        }
+    }
+}
+
+/// Index a table by the PC of a branch.
+fn pc_index(pc: isa::Address, entries: usize) -> usize {
+    (pc.0 as usize >> 2) & (entries - 1)
+}
+
+/// Move a two-bit saturating counter towards an outcome.
+fn train(counter: &mut u8, taken: bool) {
+    if taken {
+        if *counter < 3 {
+            *counter += 1;
+        }
+    }
+    else if *counter > 0 {
+        *counter -= 1;
+    }
+}
+
+pub struct AlwaysNotTaken;
+
+impl BranchPredictor for AlwaysNotTaken {
+    fn predict(&mut self, _: isa::Address, _: isa::Address) -> bool {
+        false
+    }
+
+    fn update(&mut self, _: isa::Address, _: isa::Address, _: bool) {}
+}
+
+/// Predicts loops: backward branches are taken.
+pub struct Btfn;
+
+impl BranchPredictor for Btfn {
+    fn predict(&mut self, pc: isa::Address, target: isa::Address) -> bool {
+        target < pc
+    }
+
+    fn update(&mut self, _: isa::Address, _: isa::Address, _: bool) {}
+}
+
+pub struct Bimodal {
+    counters: Vec<u8>,
+}
+
+impl Bimodal {
+    pub fn new(entries: usize) -> Bimodal {
+        assert!(entries.is_power_of_two());
+        Bimodal {
+            // Weakly not taken
+            counters: vec![1; entries],
+        }
+    }
+}
+
+impl BranchPredictor for Bimodal {
+    fn predict(&mut self, pc: isa::Address, _: isa::Address) -> bool {
+        self.counters[pc_index(pc, self.counters.len())] >= 2
+    }
+
+    fn update(&mut self, pc: isa::Address, _: isa::Address, taken: bool) {
+        let index = pc_index(pc, self.counters.len());
+        train(&mut self.counters[index], taken);
+    }
+}
+
+/// Counters indexed by the PC xor the outcomes of the last `history`
+/// branches (McFarling).
+pub struct Gshare {
+    counters: Vec<u8>,
+    history: u32,
+    history_bits: u32,
+}
+
+impl Gshare {
+    pub fn new(entries: usize, history: u32) -> Gshare {
+        assert!(entries.is_power_of_two());
+        assert!(history <= 32);
+        Gshare {
+            counters: vec![1; entries],
+            history: 0,
+            history_bits: history,
+        }
+    }
+
+    fn index(&self, pc: isa::Address) -> usize {
+        let mask = if self.history_bits == 32 {
+            !0
+        }
+        else {
+            (1 << self.history_bits) - 1
+        };
+        (((pc.0 >> 2) ^ (self.history & mask)) as usize) & (self.counters.len() - 1)
+    }
+}
+
+impl BranchPredictor for Gshare {
+    fn predict(&mut self, pc: isa::Address, _: isa::Address) -> bool {
+        self.counters[self.index(pc)] >= 2
+    }
+
+    fn update(&mut self, pc: isa::Address, _: isa::Address, taken: bool) {
+        let index = self.index(pc);
+        train(&mut self.counters[index], taken);
+        self.history = (self.history << 1) | taken as u32;
+    }
+}
+
+/// Chooses between a bimodal and a gshare predictor per branch, by
+/// which has been right more often.
+pub struct Tournament {
+    bimodal: Bimodal,
+    gshare: Gshare,
+    /// Two-bit counters, where high values prefer gshare
+    choosers: Vec<u8>,
+}
+
+impl Tournament {
+    pub fn new(entries: usize, history: u32) -> Tournament {
+        Tournament {
+            bimodal: Bimodal::new(entries),
+            gshare: Gshare::new(entries, history),
+            choosers: vec![1; entries],
+        }
+    }
+}
+
+impl BranchPredictor for Tournament {
+    fn predict(&mut self, pc: isa::Address, target: isa::Address) -> bool {
+        if self.choosers[pc_index(pc, self.choosers.len())] >= 2 {
+            self.gshare.predict(pc, target)
+        }
+        else {
+            self.bimodal.predict(pc, target)
+        }
+    }
+
+    fn update(&mut self, pc: isa::Address, target: isa::Address, taken: bool) {
+        let bimodal = self.bimodal.predict(pc, target) == taken;
+        let gshare = self.gshare.predict(pc, target) == taken;
+        if bimodal != gshare {
+            let index = pc_index(pc, self.choosers.len());
+            train(&mut self.choosers[index], gshare);
+        }
+        self.bimodal.update(pc, target, taken);
+        self.gshare.update(pc, target, taken);
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct TageEntry {
+    tag: u32,
+    /// A signed three-bit counter; taken if non-negative
+    counter: i8,
+    /// A two-bit usefulness counter, protecting the entry from
+    /// replacement
+    useful: u8,
+}
+
+const TAGE_TAG_BITS: u32 = 9;
+
+/// A simplified TAGE (Seznec): a bimodal base predictor, and tagged
+/// tables indexed by hashes of the PC and global histories of 4, 8, 16,
+/// ... branches. The table with the longest matching history provides
+/// the prediction. After a misprediction, an entry is allocated in a
+/// table with a longer history. There is no periodic reset of the
+/// usefulness counters.
+pub struct TageLite {
+    base: Bimodal,
+    tables: Vec<Vec<TageEntry>>,
+    lengths: Vec<u32>,
+    /// The most recent outcome in bit 0
+    history: u64,
+}
+
+impl TageLite {
+    pub fn new(tables: usize, entries: usize) -> TageLite {
+        assert!(tables > 0 && tables <= 5);
+        assert!(entries.is_power_of_two());
+        TageLite {
+            base: Bimodal::new(entries),
+            tables: vec![vec![TageEntry::default(); entries]; tables],
+            lengths: (0..tables as u32).map(|table| 4 << table).collect(),
+            history: 0,
+        }
+    }
+
+    /// Fold the last `length` outcomes into `bits` bits.
+    fn fold(&self, length: u32, bits: u32) -> u32 {
+        let mut history = if length >= 64 {
+            self.history
+        }
+        else {
+            self.history & ((1 << length) - 1)
+        };
+        let mut folded = 0;
+        while history != 0 {
+            folded ^= (history & ((1 << bits) - 1)) as u32;
+            history >>= bits;
+        }
+        folded
+    }
+
+    fn index(&self, table: usize, pc: isa::Address) -> usize {
+        let entries = self.tables[table].len();
+        let bits = entries.trailing_zeros();
+        let hash = (pc.0 >> 2) ^ (pc.0 >> (2 + bits)) ^
+            self.fold(self.lengths[table], ::std::cmp::max(bits, 1));
+        hash as usize & (entries - 1)
+    }
+
+    fn tag(&self, table: usize, pc: isa::Address) -> u32 {
+        let length = self.lengths[table];
+        let hash = (pc.0 >> 2) ^ self.fold(length, TAGE_TAG_BITS) ^
+            (self.fold(length, TAGE_TAG_BITS - 1) << 1);
+        hash & ((1 << TAGE_TAG_BITS) - 1)
+    }
+
+    /// The tables whose entry for this branch matches, longest history
+    /// first, with the entry's index.
+    fn matches(&self, pc: isa::Address) -> Vec<(usize, usize)> {
+        (0..self.tables.len()).rev()
+            .map(|table| (table, self.index(table, pc)))
+            .filter(|&(table, index)| self.tables[table][index].tag == self.tag(table, pc))
+            .collect()
+    }
+}
+
+impl BranchPredictor for TageLite {
+    fn predict(&mut self, pc: isa::Address, target: isa::Address) -> bool {
+        match self.matches(pc).first() {
+            Some(&(table, index)) => self.tables[table][index].counter >= 0,
+            None => self.base.predict(pc, target),
+        }
+    }
+
+    fn update(&mut self, pc: isa::Address, target: isa::Address, taken: bool) {
+        let matches = self.matches(pc);
+        let base = self.base.predict(pc, target);
+        let prediction = match matches.first() {
+            Some(&(table, index)) => self.tables[table][index].counter >= 0,
+            None => base,
+        };
+
+        match matches.first() {
+            Some(&(table, index)) => {
+                // The alternate prediction, had this entry not matched
+                let alternate = match matches.get(1) {
+                    Some(&(table, index)) => self.tables[table][index].counter >= 0,
+                    None => base,
+                };
+                let ref mut entry = self.tables[table][index];
+                if prediction != alternate {
+                    if prediction == taken {
+                        entry.useful = ::std::cmp::min(entry.useful + 1, 3);
+                    }
+                    else {
+                        entry.useful = entry.useful.saturating_sub(1);
+                    }
+                }
+                if taken {
+                    entry.counter = ::std::cmp::min(entry.counter + 1, 3);
+                }
+                else {
+                    entry.counter = ::std::cmp::max(entry.counter - 1, -4);
+                }
+            },
+            None => self.base.update(pc, target, taken),
+        }
+
+        if prediction != taken {
+            let provider = matches.first().map_or(0, |&(table, _)| table + 1);
+            let candidates: Vec<(usize, usize)> = (provider..self.tables.len())
+                .map(|table| (table, self.index(table, pc)))
+                .collect();
+            let free = candidates.iter()
+                .find(|&&(table, index)| self.tables[table][index].useful == 0)
+                .cloned();
+            match free {
+                Some((table, index)) => {
+                    let tag = self.tag(table, pc);
+                    self.tables[table][index] = TageEntry {
+                        tag: tag,
+                        counter: if taken { 0 } else { -1 },
+                        useful: 0,
+                    };
+                },
+                None => {
+                    for (table, index) in candidates {
+                        let ref mut entry = self.tables[table][index];
+                        entry.useful = entry.useful.saturating_sub(1);
+                    }
+                },
+            }
+        }
+
+        self.history = (self.history << 1) | taken as u64;
+    }
+}
+
+/// A direct-mapped, tagged cache of the targets of taken branches and
+/// jumps.
+pub struct BranchTargetBuffer {
+    entries: Vec<Option<(isa::Address, isa::Address)>>,
+}
+
+impl BranchTargetBuffer {
+    pub fn new(entries: usize) -> BranchTargetBuffer {
+        assert!(entries.is_power_of_two());
+        BranchTargetBuffer {
+            entries: vec![None; entries],
+        }
+    }
+
+    pub fn lookup(&self, pc: isa::Address) -> Option<isa::Address> {
+        match self.entries[pc_index(pc, self.entries.len())] {
+            Some((tag, target)) if tag == pc => Some(target),
+            _ => None,
+        }
+    }
+
+    pub fn insert(&mut self, pc: isa::Address, target: isa::Address) {
+        let index = pc_index(pc, self.entries.len());
+        self.entries[index] = Some((pc, target));
+    }
+}
+
+/// A circular stack of return addresses, pushed by calls and popped by
+/// returns. When it overflows, the oldest addresses are overwritten.
+pub struct ReturnAddressStack {
+    entries: Vec<isa::Address>,
+    top: usize,
+    depth: usize,
+}
+
+impl ReturnAddressStack {
+    pub fn new(depth: usize) -> ReturnAddressStack {
+        assert!(depth > 0);
+        ReturnAddressStack {
+            entries: vec![isa::Word(0); depth],
+            top: 0,
+            depth: 0,
+        }
+    }
+
+    pub fn push(&mut self, address: isa::Address) {
+        self.entries[self.top] = address;
+        self.top = (self.top + 1) % self.entries.len();
+        self.depth = ::std::cmp::min(self.depth + 1, self.entries.len());
+    }
+
+    pub fn pop(&mut self) -> Option<isa::Address> {
+        if self.depth == 0 {
+            return None;
+        }
+        self.depth -= 1;
+        self.top = (self.top + self.entries.len() - 1) % self.entries.len();
+        Some(self.entries[self.top])
+    }
+}
+
+/// Prediction statistics of one core.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct BranchStats {
+    /// Conditional branches
+    pub branches: u32,
+    /// Conditional branches whose direction was mispredicted
+    pub direction_mispredictions: u32,
+    /// Jumps, calls and returns
+    pub jumps: u32,
+    /// Control transfers whose next PC was mispredicted, including
+    /// branches with the right direction but an unknown target
+    pub mispredictions: u32,
+    pub btb_hits: u32,
+    pub btb_misses: u32,
+    /// Returns predicted by the return address stack
+    pub ras_hits: u32,
+    pub ras_misses: u32,
+    /// Cycles charged for mispredictions
+    pub penalty_cycles: u32,
+}
+
+impl BranchStats {
+    /// The fraction of conditional branches whose direction was
+    /// predicted correctly.
+    pub fn accuracy(&self) -> f64 {
+        if self.branches == 0 {
+            0.0
+        }
+        else {
+            (self.branches - self.direction_mispredictions) as f64 /
+                self.branches as f64
+        }
+    }
+}
+
+/// Predicts the next PC after each control transfer, the way a fetch
+/// stage would: the direction of conditional branches from a
+/// `BranchPredictor`, the targets of taken branches and jumps from a
+/// branch target buffer, and returns from a return address stack.
+/// Calls are JAL and JALR writing `ra`; returns are JALR through `ra`
+/// writing `x0`.
+///
+/// Each misprediction costs `penalty` cycles.
+pub struct BranchUnit<'a> {
+    predictor: Box<BranchPredictor + 'a>,
+    btb: BranchTargetBuffer,
+    ras: ReturnAddressStack,
+    penalty: u32,
+    stats: BranchStats,
+}
+
+impl<'a> BranchUnit<'a> {
+    pub fn new(predictor: Box<BranchPredictor + 'a>, btb_entries: usize,
+               ras_depth: usize, penalty: u32) -> BranchUnit<'a> {
+        BranchUnit {
+            predictor: predictor,
+            btb: BranchTargetBuffer::new(btb_entries),
+            ras: ReturnAddressStack::new(ras_depth),
+            penalty: penalty,
+            stats: BranchStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> BranchStats {
+        self.stats
+    }
+
+    /// Check the prediction for an instruction that has executed, and
+    /// train on its outcome. `next` is the PC it went to. Returns the
+    /// stall, which is the penalty if the next PC was mispredicted.
+    pub fn resolve(&mut self, pc: isa::Address, inst: isa::Instruction,
+                   next: isa::Address) -> u32 {
+        let fallthrough = pc + 4;
+        let ra = isa::Register::X1;
+        let predicted = match inst.opcode() {
+            opcodes::BRANCH => {
+                let target = ((pc.as_signed_word()) + inst.sb_imm()).as_address();
+                let taken = next != fallthrough;
+                let predicted_taken = self.predictor.predict(pc, target);
+                self.predictor.update(pc, target, taken);
+                self.stats.branches += 1;
+                if predicted_taken != taken {
+                    self.stats.direction_mispredictions += 1;
+                }
+                if predicted_taken {
+                    self.btb_target(pc).unwrap_or(fallthrough)
+                }
+                else {
+                    fallthrough
+                }
+            },
+            opcodes::JAL | opcodes::JALR => {
+                self.stats.jumps += 1;
+                let is_return = inst.opcode() == opcodes::JALR &&
+                    inst.rs1() == ra && inst.rd() == isa::Register::X0;
+                let predicted = if is_return {
+                    let predicted = self.ras.pop();
+                    if predicted == Some(next) {
+                        self.stats.ras_hits += 1;
+                    }
+                    else {
+                        self.stats.ras_misses += 1;
+                    }
+                    predicted.unwrap_or(fallthrough)
+                }
+                else {
+                    self.btb_target(pc).unwrap_or(fallthrough)
+                };
+                if inst.rd() == ra {
+                    self.ras.push(fallthrough);
+                }
+                predicted
+            },
+            _ => return 0,
+        };
+
+        if next != fallthrough {
+            self.btb.insert(pc, next);
+        }
+        if predicted == next {
+            0
+        }
+        else {
+            self.stats.mispredictions += 1;
+            self.stats.penalty_cycles += self.penalty;
+            self.penalty
+        }
+    }
+
+    fn btb_target(&mut self, pc: isa::Address) -> Option<isa::Address> {
+        let target = self.btb.lookup(pc);
+        if target.is_some() {
+            self.stats.btb_hits += 1;
+        }
+        else {
+            self.stats.btb_misses += 1;
+        }
+        target
+    }
+}
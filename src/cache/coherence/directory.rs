@@ -0,0 +1,316 @@
+// Copyright 2016 David Li
+// This file is part of rustv.
+
+// rustv is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// rustv is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with rustv.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::rc::{Rc, Weak};
+
+use isa;
+use cache::EventHandler;
+use super::{CoherenceState, CoherentCache, Interconnect, Protocol, Transaction};
+
+/// How a directory records which caches hold a block.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SharerTracking {
+    /// One bit per cache, so that only the caches holding a block are
+    /// sent invalidations.
+    FullMap,
+    /// Up to this many cache ids per block. Once they overflow, the
+    /// block may be held anywhere, and invalidations are broadcast.
+    LimitedPointer(usize),
+}
+
+/// Message latencies of a directory, in cycles.
+#[derive(Clone, Copy, Debug)]
+pub struct DirectoryLatencies {
+    /// From a cache to the directory, including the lookup
+    pub request: u32,
+    /// From the directory to the cache holding a block, and from there
+    /// to the requester with the data
+    pub forward: u32,
+    /// An invalidation and its acknowledgement
+    pub invalidate: u32,
+    /// From the directory back to the requester
+    pub reply: u32,
+}
+
+/// Messages sent by a directory.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DirectoryStats {
+    /// Requests received from caches
+    pub requests: u32,
+    /// Requests forwarded to the cache holding a block
+    pub forwards: u32,
+    /// Invalidations sent
+    pub invalidations: u32,
+    /// Invalidations that had to be broadcast, because the sharers
+    /// overflowed their pointers
+    pub broadcasts: u32,
+}
+
+#[derive(Default)]
+struct Entry {
+    /// The cache that may hold the block exclusively, or dirty
+    owner: Option<usize>,
+    /// Other caches that may hold the block
+    sharers: Vec<usize>,
+    /// Whether the sharers overflowed the pointers
+    overflowed: bool,
+}
+
+pub type SharedDirectory<'a, T> = Rc<RefCell<Directory<'a, T>>>;
+
+/// A directory at the shared level, which tracks the caches holding
+/// each block and sends messages only to them, instead of broadcasting
+/// every miss.
+///
+/// Clean blocks are evicted silently, so the directory may think a cache
+/// holds a block it has dropped; such a cache ignores the messages. A
+/// miss is supplied by the owner if there is one, and otherwise read
+/// from the next level.
+pub struct Directory<'a, T: EventHandler> {
+    protocol: Protocol,
+    tracking: SharerTracking,
+    latencies: DirectoryLatencies,
+    caches: Vec<Weak<RefCell<CoherentCache<'a, T>>>>,
+    entries: HashMap<isa::Address, Entry>,
+    /// Blocks with a transaction in progress
+    busy: HashSet<isa::Address>,
+    stats: DirectoryStats,
+}
+
+impl<'a, T: EventHandler> Directory<'a, T> {
+    pub fn new(protocol: Protocol, tracking: SharerTracking,
+               latencies: DirectoryLatencies) -> SharedDirectory<'a, T> {
+        Rc::new(RefCell::new(Directory {
+            protocol: protocol,
+            tracking: tracking,
+            latencies: latencies,
+            caches: Vec::new(),
+            entries: HashMap::new(),
+            busy: HashSet::new(),
+            stats: DirectoryStats::default(),
+        }))
+    }
+
+    pub fn stats(&self) -> DirectoryStats {
+        self.stats
+    }
+
+    /// The caches that may hold a block, given its first address, or
+    /// `None` if the sharers overflowed.
+    pub fn sharers(&self, block: isa::Address) -> Option<Vec<usize>> {
+        match self.entries.get(&block) {
+            Some(entry) if entry.overflowed => None,
+            Some(entry) => {
+                let mut sharers: Vec<usize> = entry.owner.into_iter()
+                    .chain(entry.sharers.iter().cloned())
+                    .collect();
+                sharers.sort();
+                Some(sharers)
+            },
+            None => Some(Vec::new()),
+        }
+    }
+
+    fn entry(&mut self, block: isa::Address) -> &mut Entry {
+        self.entries.entry(block).or_insert_with(Entry::default)
+    }
+
+    fn add_sharer(&mut self, block: isa::Address, id: usize) {
+        let limit = match self.tracking {
+            SharerTracking::FullMap => None,
+            SharerTracking::LimitedPointer(pointers) => Some(pointers),
+        };
+        let entry = self.entry(block);
+        if entry.overflowed || entry.owner == Some(id) ||
+            entry.sharers.contains(&id) {
+            return;
+        }
+
+        if limit.map_or(false, |limit| entry.sharers.len() >= limit) {
+            entry.overflowed = true;
+            entry.sharers.clear();
+        }
+        else {
+            entry.sharers.push(id);
+        }
+    }
+
+    /// Whether any cache but the requester may hold a block.
+    fn held_elsewhere(&self, block: isa::Address, requester: usize) -> bool {
+        match self.entries.get(&block) {
+            Some(entry) => {
+                entry.overflowed ||
+                    entry.owner.map_or(false, |owner| owner != requester) ||
+                    entry.sharers.iter().any(|&id| id != requester)
+            },
+            None => false,
+        }
+    }
+
+    /// Invalidate every copy of a block but the requester's, and make
+    /// the requester its owner. With `fetch`, the request is forwarded
+    /// to the owner for the data instead of invalidating it. Returns the
+    /// contents supplied by the owner, if any, and whether any
+    /// invalidations were sent.
+    fn invalidate_others(&mut self, block: isa::Address, requester: usize,
+                         fetch: bool) -> (Option<Vec<isa::Word>>, bool) {
+        let (owner, mut targets, overflowed) = match self.entries.get(&block) {
+            Some(entry) => {
+                let targets: Vec<usize> = if entry.overflowed {
+                    (0..self.caches.len()).collect()
+                }
+                else {
+                    entry.owner.into_iter().chain(entry.sharers.iter().cloned())
+                        .collect()
+                };
+                (entry.owner, targets, entry.overflowed)
+            },
+            None => (None, Vec::new(), false),
+        };
+        if overflowed {
+            self.stats.broadcasts += 1;
+        }
+
+        let mut data = None;
+        if let Some(owner) = owner {
+            if fetch && owner != requester {
+                self.stats.forwards += 1;
+                targets.retain(|&id| id != owner);
+                if let Some(cache) = self.caches[owner].upgrade() {
+                    let mut cache = cache.borrow_mut();
+                    data = cache.snoop_invalidate(block);
+                    if data.is_some() {
+                        cache.stats.interventions += 1;
+                    }
+                }
+            }
+        }
+
+        let mut invalidated = false;
+        for id in targets {
+            if id == requester {
+                continue;
+            }
+            self.stats.invalidations += 1;
+            invalidated = true;
+            if let Some(cache) = self.caches[id].upgrade() {
+                cache.borrow_mut().snoop_invalidate(block);
+            }
+        }
+
+        let entry = self.entry(block);
+        entry.owner = Some(requester);
+        entry.sharers.clear();
+        entry.overflowed = false;
+        (data, invalidated)
+    }
+}
+
+impl<'a, T: EventHandler> Interconnect<'a, T> for Directory<'a, T> {
+    fn connect(&mut self, cache: Weak<RefCell<CoherentCache<'a, T>>>) -> usize {
+        self.caches.push(cache);
+        self.caches.len() - 1
+    }
+
+    fn begin(&mut self, block: isa::Address) -> bool {
+        self.busy.insert(block)
+    }
+
+    fn end(&mut self, block: isa::Address) {
+        self.busy.remove(&block);
+    }
+
+    fn read(&mut self, requester: usize, block: isa::Address) -> Transaction {
+        self.stats.requests += 1;
+        let mut latency = self.latencies.request;
+        let mut data = None;
+
+        let owner = self.entries.get(&block).and_then(|entry| entry.owner);
+        match owner {
+            Some(owner) if owner != requester => {
+                self.stats.forwards += 1;
+                latency += self.latencies.forward;
+                let (held, still_owner) = match self.caches[owner].upgrade() {
+                    Some(cache) => {
+                        let mut cache = cache.borrow_mut();
+                        let (held, contents) = cache.snoop_read(block, self.protocol);
+                        if contents.is_some() {
+                            cache.stats.interventions += 1;
+                        }
+                        data = contents;
+                        (held, cache.state(block) == CoherenceState::Owned)
+                    },
+                    None => (false, false),
+                };
+                if !still_owner {
+                    self.entry(block).owner = None;
+                    if held {
+                        self.add_sharer(block, owner);
+                    }
+                }
+            },
+            // The requester evicted the block it owned
+            Some(_) => self.entry(block).owner = None,
+            None => (),
+        }
+
+        let shared = self.held_elsewhere(block, requester);
+        if shared {
+            self.add_sharer(block, requester);
+        }
+        else {
+            self.entry(block).owner = Some(requester);
+        }
+        if data.is_none() {
+            latency += self.latencies.reply;
+        }
+
+        Transaction {
+            shared: shared,
+            data: data,
+            latency: latency,
+        }
+    }
+
+    fn read_exclusive(&mut self, requester: usize, block: isa::Address)
+                      -> Transaction {
+        self.stats.requests += 1;
+        let (data, invalidated) = self.invalidate_others(block, requester, true);
+        let response = if data.is_some() {
+            self.latencies.forward
+        }
+        else {
+            self.latencies.reply
+        };
+        let acks = if invalidated { self.latencies.invalidate } else { 0 };
+
+        Transaction {
+            shared: false,
+            data: data,
+            latency: self.latencies.request + cmp::max(response, acks),
+        }
+    }
+
+    fn upgrade(&mut self, requester: usize, block: isa::Address) -> u32 {
+        self.stats.requests += 1;
+        let (_, invalidated) = self.invalidate_others(block, requester, false);
+        let acks = if invalidated { self.latencies.invalidate } else { 0 };
+        self.latencies.request + acks + self.latencies.reply
+    }
+}
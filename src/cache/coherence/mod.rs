@@ -0,0 +1,441 @@
+// Copyright 2016 David Li
+// This file is part of rustv.
+
+// rustv is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// rustv is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with rustv.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::ops::{Deref, DerefMut};
+use std::rc::{Rc, Weak};
+
+use isa;
+use memory::{MemoryError, MemoryInterface, Result};
+use super::{CacheInterface, CacheMetadata, CacheStats, EventHandler,
+            SetAssociativeCache, WriteMissPolicy, WritePolicy};
+
+pub mod directory;
+pub mod snooping;
+
+pub use self::directory::{Directory, DirectoryLatencies, DirectoryStats,
+                          SharerTracking};
+pub use self::snooping::SnoopingBus;
+
+/// The state of a block in a coherent cache.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum CoherenceState {
+    /// The only copy, and dirty.
+    Modified,
+    /// Dirty, but other caches may hold clean copies. MOESI only.
+    Owned,
+    /// The only copy, and clean.
+    Exclusive,
+    /// Other caches may hold copies.
+    Shared,
+    Invalid,
+}
+
+/// Which coherence protocol an interconnect runs.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Protocol {
+    /// A modified block is written back when another cache reads it.
+    Mesi,
+    /// A modified block stays dirty when another cache reads it, in the
+    /// Owned state, and is only written back when evicted.
+    Moesi,
+}
+
+/// Coherence traffic seen by one cache.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CoherenceStats {
+    /// Read misses sent to the interconnect
+    pub reads: u32,
+    /// Write misses sent to the interconnect, invalidating other copies
+    pub read_exclusives: u32,
+    /// Writes to shared blocks, invalidating other copies
+    pub upgrades: u32,
+    /// Blocks taken away by another cache's write
+    pub invalidations: u32,
+    /// Blocks supplied to another cache's miss
+    pub interventions: u32,
+    /// Misses to blocks that were taken away by an invalidation
+    pub coherence_misses: u32,
+}
+
+/// The stall while another cache's transaction on a block completes.
+fn busy() -> MemoryError {
+    MemoryError::CacheMiss {
+        stall_cycles: 1,
+        retry: true,
+    }
+}
+
+/// Report a stall after a write that has been done.
+fn stall_after(stall_cycles: u32) -> Result<()> {
+    if stall_cycles == 0 {
+        Ok(())
+    }
+    else {
+        Err(MemoryError::CacheMiss {
+            stall_cycles: stall_cycles,
+            retry: false,
+        })
+    }
+}
+
+/// The outcome of a miss sent to an interconnect.
+pub struct Transaction {
+    /// Whether other caches may hold the block
+    pub shared: bool,
+    /// The block's contents, if another cache supplied them; otherwise
+    /// the block is read from the next level
+    pub data: Option<Vec<isa::Word>>,
+    /// How many cycles the transaction takes, before the next level is
+    /// read if it has to be
+    pub latency: u32,
+}
+
+pub type SharedInterconnect<'a, T> = Rc<RefCell<Interconnect<'a, T> + 'a>>;
+
+/// Keeps the `CoherentCache`s connected to it coherent, by snooping or
+/// through a directory. Only one transaction per block can be in
+/// progress, so a miss holds the block until its fill completes, and
+/// other caches accessing the block wait for it.
+pub trait Interconnect<'a, T: EventHandler> {
+    /// Connect a cache, returning its id.
+    fn connect(&mut self, cache: Weak<RefCell<CoherentCache<'a, T>>>) -> usize;
+    /// Start a transaction on a block, unless one is already in
+    /// progress.
+    fn begin(&mut self, block: isa::Address) -> bool;
+    fn end(&mut self, block: isa::Address);
+    /// A read miss.
+    fn read(&mut self, requester: usize, block: isa::Address) -> Transaction;
+    /// A write miss, which invalidates every other copy.
+    fn read_exclusive(&mut self, requester: usize, block: isa::Address)
+                      -> Transaction;
+    /// A write to a shared block, which invalidates every other copy.
+    /// Returns how many cycles it takes.
+    fn upgrade(&mut self, requester: usize, block: isa::Address) -> u32;
+}
+
+/// A private cache kept coherent with the other caches on its
+/// interconnect. The cache is made write-back and write-allocate, and
+/// must not prefetch, since prefetches would bypass the interconnect.
+///
+/// Coherence states are tracked per block alongside the wrapped cache,
+/// and reported to its `EventHandler`. Clean blocks are evicted
+/// silently, and dirty blocks are written back as usual.
+pub struct CoherentCache<'a, T: EventHandler> {
+    id: usize,
+    cache: SetAssociativeCache<'a, T>,
+    interconnect: SharedInterconnect<'a, T>,
+    block_bytes: u32,
+    states: HashMap<isa::Address, CoherenceState>,
+    /// Blocks being fetched, and the state they will be filled in
+    pending: Vec<(isa::Address, CoherenceState)>,
+    /// Blocks lost to invalidations, to classify later misses
+    invalidated: HashSet<isa::Address>,
+    stats: CoherenceStats,
+}
+
+impl<'a, T: EventHandler> CoherentCache<'a, T> {
+    /// Wrap a cache and connect it to an interconnect.
+    pub fn new<I>(mut cache: SetAssociativeCache<'a, T>,
+                  interconnect: &Rc<RefCell<I>>)
+                  -> Rc<RefCell<CoherentCache<'a, T>>>
+        where I: Interconnect<'a, T> + 'a {
+        assert!(!cache.has_prefetcher(), "a coherent cache cannot prefetch");
+        cache.set_write_policy(WritePolicy::WriteBack);
+        cache.set_write_miss_policy(WriteMissPolicy::WriteAllocate);
+        cache.record_evictions();
+        let block_bytes = 4 * cache.cache_metadata().num_block_words as u32;
+        let shared: SharedInterconnect<'a, T> = interconnect.clone();
+
+        let cache = Rc::new(RefCell::new(CoherentCache {
+            id: 0,
+            cache: cache,
+            interconnect: shared,
+            block_bytes: block_bytes,
+            states: HashMap::new(),
+            pending: Vec::new(),
+            invalidated: HashSet::new(),
+            stats: CoherenceStats::default(),
+        }));
+        let id = interconnect.borrow_mut().connect(Rc::downgrade(&cache));
+        cache.borrow_mut().id = id;
+        cache
+    }
+
+    /// The cache's id on its interconnect.
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// The state of the block containing an address.
+    pub fn state(&self, address: isa::Address) -> CoherenceState {
+        let block = self.block(address);
+        if !self.cache.is_address_accessible(block) {
+            return CoherenceState::Invalid;
+        }
+        self.states.get(&block).cloned().unwrap_or(CoherenceState::Invalid)
+    }
+
+    pub fn coherence_stats(&self) -> CoherenceStats {
+        self.stats
+    }
+
+    fn block(&self, address: isa::Address) -> isa::Address {
+        address & !(self.block_bytes - 1)
+    }
+
+    fn set_state(&mut self, block: isa::Address, state: CoherenceState) {
+        let old = self.state(block);
+        if old != state {
+            self.cache.events().state_changed(block, old, state);
+        }
+        if state == CoherenceState::Invalid {
+            self.states.remove(&block);
+        }
+        else {
+            self.states.insert(block, state);
+        }
+    }
+
+    /// Claim a block for a miss.
+    fn begin_miss(&mut self, block: isa::Address) -> Result<()> {
+        if !self.cache.can_fetch(block) ||
+            !self.interconnect.borrow_mut().begin(block) {
+            return Err(busy());
+        }
+        if self.invalidated.remove(&block) {
+            self.stats.coherence_misses += 1;
+        }
+        Ok(())
+    }
+
+    /// Start filling a block, from another cache if it supplied the
+    /// data. The block is released once the fill completes.
+    fn fill(&mut self, block: isa::Address, state: CoherenceState,
+            transaction: Transaction) -> MemoryError {
+        // Forget the state of any earlier copy
+        self.states.remove(&block);
+        self.pending.push((block, state));
+        match transaction.data {
+            Some(data) => self.cache.fill_from(block, data, transaction.latency),
+            None => self.cache.fetch(block, transaction.latency),
+        }
+    }
+
+    /// Make a block readable, starting a read on a miss.
+    fn prepare_read(&mut self, address: isa::Address) -> Result<()> {
+        let block = self.block(address);
+        if self.state(block) != CoherenceState::Invalid ||
+            self.cache.is_fetching(block) {
+            return Ok(());
+        }
+
+        try!(self.begin_miss(block));
+        self.cache.demand_miss(address, false);
+        self.stats.reads += 1;
+        let transaction = self.interconnect.borrow_mut().read(self.id, block);
+        let state = if transaction.shared {
+            CoherenceState::Shared
+        }
+        else {
+            CoherenceState::Exclusive
+        };
+        Err(self.fill(block, state, transaction))
+    }
+
+    /// Make a block writable, invalidating other copies. Returns how
+    /// many cycles the write should stall for after it is done.
+    fn prepare_write(&mut self, address: isa::Address) -> Result<u32> {
+        let block = self.block(address);
+        match self.state(block) {
+            CoherenceState::Modified => Ok(0),
+            CoherenceState::Exclusive => {
+                self.set_state(block, CoherenceState::Modified);
+                Ok(0)
+            },
+            CoherenceState::Shared | CoherenceState::Owned => {
+                let latency = {
+                    let mut interconnect = self.interconnect.borrow_mut();
+                    if !interconnect.begin(block) {
+                        return Err(busy());
+                    }
+                    let latency = interconnect.upgrade(self.id, block);
+                    interconnect.end(block);
+                    latency
+                };
+                self.stats.upgrades += 1;
+                self.set_state(block, CoherenceState::Modified);
+                Ok(latency)
+            },
+            CoherenceState::Invalid => {
+                if self.cache.is_fetching(block) {
+                    // The write waits for the fill, then tries again
+                    return Ok(0);
+                }
+
+                try!(self.begin_miss(block));
+                self.cache.demand_miss(address, true);
+                self.stats.read_exclusives += 1;
+                let transaction = self.interconnect.borrow_mut()
+                    .read_exclusive(self.id, block);
+                Err(self.fill(block, CoherenceState::Modified, transaction))
+            },
+        }
+    }
+
+    /// Respond to another cache's read miss. Returns whether this cache
+    /// holds the block, and its contents if it can supply them.
+    fn snoop_read(&mut self, block: isa::Address, protocol: Protocol)
+                  -> (bool, Option<Vec<isa::Word>>) {
+        let data = self.cache.block_contents(block);
+        let state = self.state(block);
+        let next = match (state, protocol) {
+            (CoherenceState::Modified, Protocol::Mesi) => {
+                self.cache.clean(block);
+                CoherenceState::Shared
+            },
+            (CoherenceState::Modified, Protocol::Moesi) |
+            (CoherenceState::Owned, _) => CoherenceState::Owned,
+            (CoherenceState::Exclusive, _) |
+            (CoherenceState::Shared, _) => CoherenceState::Shared,
+            (CoherenceState::Invalid, _) => CoherenceState::Invalid,
+        };
+        self.set_state(block, next);
+        (state != CoherenceState::Invalid, data)
+    }
+
+    /// Respond to another cache taking ownership of a block. Returns the
+    /// block's contents if this cache can supply them.
+    fn snoop_invalidate(&mut self, block: isa::Address) -> Option<Vec<isa::Word>> {
+        let data = self.cache.block_contents(block);
+        if self.state(block) != CoherenceState::Invalid {
+            self.stats.invalidations += 1;
+            self.invalidated.insert(block);
+            self.set_state(block, CoherenceState::Invalid);
+        }
+        self.cache.surrender(block);
+        data
+    }
+}
+
+impl<'a, T: EventHandler> Deref for CoherentCache<'a, T> {
+    type Target = SetAssociativeCache<'a, T>;
+
+    fn deref(&self) -> &SetAssociativeCache<'a, T> {
+        &self.cache
+    }
+}
+
+impl<'a, T: EventHandler> DerefMut for CoherentCache<'a, T> {
+    fn deref_mut(&mut self) -> &mut SetAssociativeCache<'a, T> {
+        &mut self.cache
+    }
+}
+
+impl<'a, T: EventHandler> MemoryInterface for CoherentCache<'a, T> {
+    fn latency(&self) -> u32 {
+        self.cache.latency()
+    }
+
+    fn step(&mut self) {
+        self.cache.step();
+
+        for block in self.cache.take_evictions() {
+            if !self.cache.is_address_accessible(block) {
+                if let Some(state) = self.states.remove(&block) {
+                    self.cache.events().state_changed(
+                        block, state, CoherenceState::Invalid);
+                }
+            }
+        }
+
+        let mut i = 0;
+        while i < self.pending.len() {
+            let (block, state) = self.pending[i];
+            if self.cache.is_fetching(block) {
+                i += 1;
+                continue;
+            }
+
+            self.pending.remove(i);
+            if self.cache.is_address_accessible(block) {
+                self.set_state(block, state);
+            }
+            self.interconnect.borrow_mut().end(block);
+        }
+    }
+
+    fn is_address_accessible(&self, address: isa::Address) -> bool {
+        self.cache.is_address_accessible(address)
+    }
+
+    fn read_word(&mut self, address: isa::Address) -> Result<isa::Word> {
+        try!(self.prepare_read(address));
+        self.cache.read_word(address)
+    }
+
+    fn write_word(&mut self, address: isa::Address, value: isa::Word)
+                  -> Result<()> {
+        let stall = try!(self.prepare_write(address));
+        try!(self.cache.write_word(address, value));
+        stall_after(stall)
+    }
+
+    fn write_halfword(&mut self, address: isa::Address, value: isa::HalfWord)
+                      -> Result<()> {
+        let stall = try!(self.prepare_write(address));
+        try!(self.cache.write_halfword(address, value));
+        stall_after(stall)
+    }
+
+    fn write_byte(&mut self, address: isa::Address, value: isa::Byte)
+                  -> Result<()> {
+        let stall = try!(self.prepare_write(address));
+        try!(self.cache.write_byte(address, value));
+        stall_after(stall)
+    }
+
+    fn invalidate(&mut self, address: isa::Address) -> Result<()> {
+        self.cache.invalidate(address)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.cache.flush()
+    }
+
+    fn invalidate_all(&mut self) -> Result<()> {
+        self.cache.invalidate_all()
+    }
+
+    fn set_retry(&mut self, retry: bool) {
+        self.cache.set_retry(retry)
+    }
+}
+
+impl<'a, T: EventHandler> CacheInterface for CoherentCache<'a, T> {
+    fn cache_metadata(&self) -> CacheMetadata {
+        self.cache.cache_metadata()
+    }
+
+    fn cache_stats(&self) -> CacheStats {
+        self.cache.cache_stats()
+    }
+
+    fn set_pc(&mut self, pc: isa::Address) {
+        self.cache.set_pc(pc)
+    }
+}
@@ -0,0 +1,132 @@
+// Copyright 2016 David Li
+// This file is part of rustv.
+
+// rustv is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// rustv is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with rustv.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::rc::{Rc, Weak};
+
+use isa;
+use cache::EventHandler;
+use super::{CoherentCache, Interconnect, Protocol, Transaction};
+
+pub type SharedBus<'a, T> = Rc<RefCell<SnoopingBus<'a, T>>>;
+
+/// A bus connecting private caches, each of which snoops the misses and
+/// upgrades of the others. Every miss and upgrade takes the bus latency.
+/// A miss is then supplied by another cache holding the block if there
+/// is one; otherwise it is read from the next level.
+pub struct SnoopingBus<'a, T: EventHandler> {
+    protocol: Protocol,
+    latency: u32,
+    caches: Vec<Weak<RefCell<CoherentCache<'a, T>>>>,
+    /// Blocks with a transaction in progress
+    busy: HashSet<isa::Address>,
+}
+
+impl<'a, T: EventHandler> SnoopingBus<'a, T> {
+    pub fn new(protocol: Protocol, latency: u32) -> SharedBus<'a, T> {
+        Rc::new(RefCell::new(SnoopingBus {
+            protocol: protocol,
+            latency: latency,
+            caches: Vec::new(),
+            busy: HashSet::new(),
+        }))
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    pub fn latency(&self) -> u32 {
+        self.latency
+    }
+}
+
+impl<'a, T: EventHandler> Interconnect<'a, T> for SnoopingBus<'a, T> {
+    fn connect(&mut self, cache: Weak<RefCell<CoherentCache<'a, T>>>) -> usize {
+        self.caches.push(cache);
+        self.caches.len() - 1
+    }
+
+    fn begin(&mut self, block: isa::Address) -> bool {
+        self.busy.insert(block)
+    }
+
+    fn end(&mut self, block: isa::Address) {
+        self.busy.remove(&block);
+    }
+
+    fn read(&mut self, requester: usize, block: isa::Address) -> Transaction {
+        let mut shared = false;
+        let mut data = None;
+        for (id, cache) in self.caches.iter().enumerate() {
+            if id == requester {
+                continue;
+            }
+            if let Some(cache) = cache.upgrade() {
+                let mut cache = cache.borrow_mut();
+                let (held, contents) = cache.snoop_read(block, self.protocol);
+                shared = shared || held;
+                if data.is_none() && contents.is_some() {
+                    cache.stats.interventions += 1;
+                    data = contents;
+                }
+            }
+        }
+
+        Transaction {
+            shared: shared,
+            latency: self.latency,
+            data: data,
+        }
+    }
+
+    fn read_exclusive(&mut self, requester: usize, block: isa::Address)
+                      -> Transaction {
+        let mut data = None;
+        for (id, cache) in self.caches.iter().enumerate() {
+            if id == requester {
+                continue;
+            }
+            if let Some(cache) = cache.upgrade() {
+                let mut cache = cache.borrow_mut();
+                let contents = cache.snoop_invalidate(block);
+                if data.is_none() && contents.is_some() {
+                    cache.stats.interventions += 1;
+                    data = contents;
+                }
+            }
+        }
+
+        Transaction {
+            shared: false,
+            latency: self.latency,
+            data: data,
+        }
+    }
+
+    fn upgrade(&mut self, requester: usize, block: isa::Address) -> u32 {
+        for (id, cache) in self.caches.iter().enumerate() {
+            if id == requester {
+                continue;
+            }
+            if let Some(cache) = cache.upgrade() {
+                cache.borrow_mut().snoop_invalidate(block);
+            }
+        }
+        self.latency
+    }
+}
@@ -0,0 +1,269 @@
+// Copyright 2015-2016 David Li
+// This file is part of rustv.
+
+// rustv is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// rustv is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with rustv.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::ops::{Deref, DerefMut};
+use std::rc::Rc;
+use std::cell::RefCell;
+
+use isa;
+use memory::{MemoryError, MemoryInterface, Result, SharedMemory};
+
+/// Implement `Deref` to the wrapped `SetAssociativeCache`, and the
+/// cache traits by delegating to it.
+macro_rules! cache_wrapper {
+    ($name: ident) => {
+        impl<'a, T: EventHandler> Deref for $name<'a, T> {
+            type Target = SetAssociativeCache<'a, T>;
+
+            fn deref(&self) -> &SetAssociativeCache<'a, T> {
+                &self.cache
+            }
+        }
+
+        impl<'a, T: EventHandler> DerefMut for $name<'a, T> {
+            fn deref_mut(&mut self) -> &mut SetAssociativeCache<'a, T> {
+                &mut self.cache
+            }
+        }
+
+        impl<'a, T: EventHandler> MemoryInterface for $name<'a, T> {
+            fn latency(&self) -> u32 {
+                self.cache.latency()
+            }
+
+            fn step(&mut self) {
+                self.cache.step()
+            }
+
+            fn is_address_accessible(&self, address: isa::Address) -> bool {
+                self.cache.is_address_accessible(address)
+            }
+
+            fn read_word(&mut self, address: isa::Address)
+                         -> Result<isa::Word> {
+                self.cache.read_word(address)
+            }
+
+            fn write_word(&mut self, address: isa::Address,
+                          value: isa::Word) -> Result<()> {
+                self.cache.write_word(address, value)
+            }
+
+            fn write_halfword(&mut self, address: isa::Address,
+                              value: isa::HalfWord) -> Result<()> {
+                self.cache.write_halfword(address, value)
+            }
+
+            fn write_byte(&mut self, address: isa::Address,
+                          value: isa::Byte) -> Result<()> {
+                self.cache.write_byte(address, value)
+            }
+
+            fn invalidate(&mut self, address: isa::Address) -> Result<()> {
+                self.cache.invalidate(address)
+            }
+
+            fn flush(&mut self) -> Result<()> {
+                self.cache.flush()
+            }
+
+            fn invalidate_all(&mut self) -> Result<()> {
+                self.cache.invalidate_all()
+            }
+
+            fn prefetch(&mut self, address: isa::Address) {
+                self.cache.prefetch(address)
+            }
+
+            fn set_retry(&mut self, retry: bool) {
+                self.cache.set_retry(retry)
+            }
+        }
+
+        impl<'a, T: EventHandler> CacheInterface for $name<'a, T> {
+            fn cache_metadata(&self) -> CacheMetadata {
+                self.cache.cache_metadata()
+            }
+
+            fn cache_stats(&self) -> CacheStats {
+                self.cache.cache_stats()
+            }
+
+            fn set_pc(&mut self, pc: isa::Address) {
+                self.cache.set_pc(pc)
+            }
+        }
+    }
+}
+
+pub mod coherence;
+pub mod prefetch;
+pub mod replacement;
+mod set_associative;
+pub mod stats;
+pub mod victim;
+
+pub use self::coherence::{CoherenceState, CoherenceStats, CoherentCache,
+                          Directory, DirectoryLatencies, Interconnect,
+                          Protocol, SharerTracking, SnoopingBus};
+pub use self::prefetch::{AccessOutcome, Prefetch, Prefetcher, PrefetchStats};
+pub use self::replacement::{Replacement, ReplacementPolicy};
+pub use self::set_associative::{SetAssociativeCache, DEFAULT_MSHRS};
+pub use self::stats::{CacheStats, MissClass, MissClasses, MissClassifier,
+                      stats_table};
+pub use self::victim::{BufferEntry, BufferKind, BufferStats, VictimBuffer};
+
+pub struct CacheMetadata {
+    /// How many sets are in the cache
+    pub num_sets: usize,
+    /// How many ways are in a set
+    pub num_ways: usize,
+    /// How many words are in a block/line
+    pub num_block_words: usize,
+    /// The tags currently in the cache, in order of set, then way
+    pub tags: Vec<Option<isa::Address>>,
+}
+
+pub trait CacheInterface : MemoryInterface {
+    fn cache_metadata(&self) -> CacheMetadata;
+
+    fn cache_stats(&self) -> CacheStats;
+
+    /// Note the PC of the instruction making the next accesses, for
+    /// prefetchers that track instructions.
+    fn set_pc(&mut self, _pc: isa::Address) {}
+}
+
+pub type SharedCache<'a> = Rc<RefCell<CacheInterface + 'a>>;
+
+#[derive(Clone,Copy)]
+pub struct CacheLocation {
+    pub tag: u32,
+    pub index: u32,
+    pub offset: u32,
+    pub way: u32,
+}
+
+#[derive(Clone)]
+struct FetchRequest {
+    address: isa::Address,
+    prefetch: bool, // is this a prefetch
+    cycles_left: u32,
+    location: CacheLocation,
+    data: Vec<isa::Word>, // hold data temporarily while we wait for an entire line
+    error: Option<MemoryError>, // in case next level returns an error
+    waiting_on: u32, // which word of the block are we waiting on
+    retrying: bool, // whether the next level missed on that word
+}
+
+#[derive(Clone)]
+struct Block {
+    valid: bool,
+    dirty: bool,
+    /// Prefetched, and not yet used
+    prefetched: bool,
+    tag: u32,
+    contents: Vec<isa::Word>,
+}
+
+/// How the contents of a cache relate to the caches above it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Inclusion {
+    /// Every block held above is also held here. Evicting a block here
+    /// invalidates it above.
+    Inclusive,
+    /// A block is held either here or above, never both. Blocks move up
+    /// when they are read, and come back when they are evicted above.
+    Exclusive,
+    /// Non-inclusive, non-exclusive (NINE): nothing is enforced.
+    NonInclusive,
+}
+
+/// What a cache does when a store hits.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WritePolicy {
+    /// Write to the next level immediately.
+    WriteThrough,
+    /// Mark the block dirty, and write it to the next level when it
+    /// is evicted or flushed.
+    WriteBack,
+}
+
+/// What a cache does when a store misses.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WriteMissPolicy {
+    /// Fetch the block, then write to it.
+    WriteAllocate,
+    /// Write to the next level without fetching the block.
+    WriteNoAllocate,
+}
+
+pub trait EventHandler {
+    fn block_requested(&self, location: CacheLocation);
+    fn block_fetched(&self, location: CacheLocation);
+    /// A block of a coherent cache changed state.
+    fn state_changed(&self, _address: isa::Address, _from: CoherenceState,
+                     _to: CoherenceState) {}
+}
+
+pub struct EmptyEventHandler {}
+
+impl EventHandler for EmptyEventHandler {
+    fn block_fetched(&self, _: CacheLocation) {}
+    fn block_requested(&self, _: CacheLocation) {}
+}
+
+/// A set-associative cache with a single way per set.
+pub struct DirectMappedCache<'a, T: EventHandler> {
+    cache: SetAssociativeCache<'a, T>,
+}
+
+impl<'a, T: EventHandler> DirectMappedCache<'a, T> {
+    pub fn new(sets: u32, block_words: u32,
+               next_level: SharedMemory<'a>, events: T)
+               -> DirectMappedCache<'a, T> {
+        DirectMappedCache {
+            cache: SetAssociativeCache::new(
+                sets, 1, block_words, Replacement::Lru.build(sets, 1),
+                next_level, events),
+        }
+    }
+}
+
+cache_wrapper!(DirectMappedCache);
+
+/// A cache with a single set, so that any block can go in any way. Tags
+/// are looked up through a hash table rather than by scanning, so that
+/// it scales to large numbers of lines. It can serve as a small L0 cache
+/// in front of another cache. (For a victim cache beside another cache,
+/// see `VictimBuffer`.)
+pub struct FullyAssociativeCache<'a, T: EventHandler> {
+    cache: SetAssociativeCache<'a, T>,
+}
+
+impl<'a, T: EventHandler> FullyAssociativeCache<'a, T> {
+    pub fn new(lines: u32, block_words: u32, replacement: Replacement,
+               next_level: SharedMemory<'a>, events: T)
+               -> FullyAssociativeCache<'a, T> {
+        FullyAssociativeCache {
+            cache: SetAssociativeCache::new(
+                1, lines, block_words, replacement.build(1, lines),
+                next_level, events).with_tag_index(),
+        }
+    }
+}
+
+cache_wrapper!(FullyAssociativeCache);
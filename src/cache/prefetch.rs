@@ -0,0 +1,327 @@
+// Copyright 2016 David Li
+// This file is part of rustv.
+
+// rustv is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// rustv is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with rustv.  If not, see <http://www.gnu.org/licenses/>.
+
+use isa;
+
+/// How a demand access to a cache was served.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AccessOutcome {
+    Hit,
+    /// The first use of a prefetched block, which may still be in flight.
+    PrefetchHit,
+    Miss,
+}
+
+/// Predicts which blocks will be accessed soon, from the demand accesses
+/// to a cache. The cache prefetches the blocks it returns, unless they
+/// are already present or being fetched, or no MSHR is free.
+pub trait Prefetcher {
+    /// Observe a demand access. The PC is that of the instruction making
+    /// the access, if the cache knows it.
+    fn access(&mut self, pc: Option<isa::Address>, address: isa::Address,
+              outcome: AccessOutcome) -> Vec<isa::Address>;
+}
+
+/// Which prefetcher to use, for configuring caches.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Prefetch {
+    /// Prefetch the next `degree` blocks.
+    NextLine { degree: u32 },
+    /// A reference prediction table of `entries` entries, prefetching
+    /// `degree` strides ahead.
+    Stride { entries: usize, degree: u32 },
+    /// Track up to `streams` streams, running up to `distance` blocks
+    /// ahead, `degree` blocks at a time.
+    Stream { streams: usize, distance: u32, degree: u32 },
+}
+
+impl Prefetch {
+    pub fn build<'a>(self, block_words: u32) -> Box<Prefetcher + 'a> {
+        match self {
+            Prefetch::NextLine { degree } => {
+                Box::new(NextLinePrefetcher::new(block_words, degree))
+            },
+            Prefetch::Stride { entries, degree } => {
+                Box::new(StridePrefetcher::new(entries, degree))
+            },
+            Prefetch::Stream { streams, distance, degree } => {
+                Box::new(StreamPrefetcher::new(block_words, streams, distance, degree))
+            },
+        }
+    }
+}
+
+/// Prefetch statistics of one cache.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PrefetchStats {
+    /// Prefetches sent to the next level
+    pub issued: u32,
+    /// Prefetched blocks used by a demand access, including ones that
+    /// were still in flight
+    pub useful: u32,
+    /// Prefetched blocks evicted without being used
+    pub unused: u32,
+    /// Demand misses that no prefetch covered
+    pub misses: u32,
+}
+
+impl PrefetchStats {
+    /// The fraction of prefetches that were used.
+    pub fn accuracy(&self) -> f64 {
+        if self.issued == 0 {
+            0.0
+        }
+        else {
+            self.useful as f64 / self.issued as f64
+        }
+    }
+
+    /// The fraction of misses that prefetching removed.
+    pub fn coverage(&self) -> f64 {
+        let total = self.useful + self.misses;
+        if total == 0 {
+            0.0
+        }
+        else {
+            self.useful as f64 / total as f64
+        }
+    }
+}
+
+/// Prefetches the blocks following a miss, or the first use of a
+/// prefetched block, so that a sequential sweep stays ahead once it has
+/// started.
+pub struct NextLinePrefetcher {
+    block_bytes: u32,
+    degree: u32,
+}
+
+impl NextLinePrefetcher {
+    pub fn new(block_words: u32, degree: u32) -> NextLinePrefetcher {
+        NextLinePrefetcher {
+            block_bytes: 4 * block_words,
+            degree: degree,
+        }
+    }
+}
+
+impl Prefetcher for NextLinePrefetcher {
+    fn access(&mut self, _: Option<isa::Address>, address: isa::Address,
+              outcome: AccessOutcome) -> Vec<isa::Address> {
+        if outcome == AccessOutcome::Hit {
+            return Vec::new();
+        }
+
+        let block = address & !(self.block_bytes - 1);
+        (1..self.degree + 1)
+            .map(|ahead| block + ahead * self.block_bytes)
+            .collect()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum StrideState {
+    Initial,
+    Transient,
+    Steady,
+    NoPrediction,
+}
+
+#[derive(Clone, Copy)]
+struct StrideEntry {
+    pc: isa::Address,
+    last_address: isa::Address,
+    stride: i32,
+    state: StrideState,
+}
+
+/// A reference prediction table (Chen and Baer), indexed by the PC of
+/// loads and stores. Each entry learns the stride between successive
+/// accesses by its instruction, and prefetches ahead once the stride
+/// has repeated. Accesses without a PC are ignored.
+pub struct StridePrefetcher {
+    degree: u32,
+    table: Vec<Option<StrideEntry>>,
+}
+
+impl StridePrefetcher {
+    pub fn new(entries: usize, degree: u32) -> StridePrefetcher {
+        assert!(entries > 0);
+        StridePrefetcher {
+            degree: degree,
+            table: vec![None; entries],
+        }
+    }
+}
+
+impl Prefetcher for StridePrefetcher {
+    fn access(&mut self, pc: Option<isa::Address>, address: isa::Address,
+              _: AccessOutcome) -> Vec<isa::Address> {
+        let pc = match pc {
+            Some(pc) => pc,
+            None => return Vec::new(),
+        };
+        let slot = ((pc.0 / 4) as usize) % self.table.len();
+
+        let entry = match self.table[slot] {
+            Some(mut entry) if entry.pc == pc => {
+                let stride = address.0.wrapping_sub(entry.last_address.0) as i32;
+                let correct = stride == entry.stride;
+                entry.state = match (entry.state, correct) {
+                    (StrideState::NoPrediction, true) => StrideState::Transient,
+                    (_, true) => StrideState::Steady,
+                    (StrideState::Steady, false) => StrideState::Initial,
+                    (StrideState::Initial, false) => StrideState::Transient,
+                    (_, false) => StrideState::NoPrediction,
+                };
+                // A steady entry keeps its stride through one mistake
+                if !correct && entry.state != StrideState::Initial {
+                    entry.stride = stride;
+                }
+                entry.last_address = address;
+                entry
+            },
+            _ => StrideEntry {
+                pc: pc,
+                last_address: address,
+                stride: 0,
+                state: StrideState::Initial,
+            },
+        };
+        self.table[slot] = Some(entry);
+
+        if entry.state != StrideState::Steady || entry.stride == 0 {
+            return Vec::new();
+        }
+        (1..self.degree as i32 + 1)
+            .map(|ahead| {
+                isa::Word(address.0.wrapping_add(ahead.wrapping_mul(entry.stride) as u32))
+            })
+            .collect()
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Stream {
+    /// The last block accessed, as a block number
+    last_block: u32,
+    /// +1 for ascending, -1 for descending, 0 if not yet known
+    direction: i32,
+    /// Whether two accesses have moved in the same direction
+    confirmed: bool,
+    /// The furthest block prefetched so far
+    prefetched_to: u32,
+    last_used: u64,
+}
+
+/// Detects sequences of misses moving through memory in either
+/// direction, and once a direction is confirmed, prefetches up to
+/// `distance` blocks ahead of the stream, `degree` blocks per access.
+pub struct StreamPrefetcher {
+    block_bytes: u32,
+    distance: u32,
+    degree: u32,
+    streams: Vec<Stream>,
+    max_streams: usize,
+    clock: u64,
+}
+
+impl StreamPrefetcher {
+    pub fn new(block_words: u32, streams: usize, distance: u32, degree: u32)
+               -> StreamPrefetcher {
+        assert!(streams > 0);
+        StreamPrefetcher {
+            block_bytes: 4 * block_words,
+            distance: distance,
+            degree: degree,
+            streams: Vec::new(),
+            max_streams: streams,
+            clock: 0,
+        }
+    }
+}
+
+impl Prefetcher for StreamPrefetcher {
+    fn access(&mut self, _: Option<isa::Address>, address: isa::Address,
+              outcome: AccessOutcome) -> Vec<isa::Address> {
+        if outcome == AccessOutcome::Hit {
+            return Vec::new();
+        }
+
+        self.clock += 1;
+        let block = address.0 / self.block_bytes;
+        let distance = self.distance;
+        let found = self.streams.iter().position(|stream| {
+            (block as i64 - stream.last_block as i64).abs() <= distance as i64
+        });
+
+        let index = match found {
+            Some(index) => index,
+            None => {
+                let stream = Stream {
+                    last_block: block,
+                    direction: 0,
+                    confirmed: false,
+                    prefetched_to: block,
+                    last_used: self.clock,
+                };
+                if self.streams.len() < self.max_streams {
+                    self.streams.push(stream);
+                }
+                else {
+                    let oldest = (0..self.streams.len())
+                        .min_by_key(|&index| self.streams[index].last_used)
+                        .unwrap();
+                    self.streams[oldest] = stream;
+                }
+                return Vec::new();
+            },
+        };
+
+        let ref mut stream = self.streams[index];
+        stream.last_used = self.clock;
+        if block == stream.last_block {
+            return Vec::new();
+        }
+        let direction = if block > stream.last_block { 1 } else { -1 };
+        stream.confirmed = stream.direction == direction;
+        if !stream.confirmed {
+            stream.prefetched_to = block;
+        }
+        stream.direction = direction;
+        stream.last_block = block;
+        if !stream.confirmed {
+            return Vec::new();
+        }
+
+        let mut prefetches = Vec::new();
+        let limit = block as i64 + direction as i64 * distance as i64;
+        let mut next = stream.prefetched_to as i64;
+        if (next - block as i64) * direction as i64 <= 0 {
+            next = block as i64;
+        }
+        while (prefetches.len() as u32) < self.degree &&
+            (limit - next) * direction as i64 > 0 {
+            next += direction as i64;
+            if next < 0 || next > (u32::max_value() / self.block_bytes) as i64 {
+                break;
+            }
+            prefetches.push(isa::Word(next as u32 * self.block_bytes));
+        }
+        stream.prefetched_to = next as u32;
+        prefetches
+    }
+}
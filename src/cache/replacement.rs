@@ -0,0 +1,287 @@
+// Copyright 2016 David Li
+// This file is part of rustv.
+
+// rustv is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// rustv is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with rustv.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use rng::XorShiftRng;
+
+/// Chooses which way of a set to evict. Policies keep their own
+/// per-block state, and are told about every hit, fill and
+/// invalidation. Invalid ways are always filled before the policy is
+/// asked for a victim.
+pub trait ReplacementPolicy {
+    /// A block was accessed and hit.
+    fn touch(&mut self, set: u32, way: u32);
+    /// A block was filled with new data.
+    fn insert(&mut self, set: u32, way: u32);
+    /// A block was invalidated.
+    fn invalidate(&mut self, _set: u32, _way: u32) {}
+    /// Choose a way of a full set to evict.
+    fn victim(&mut self, set: u32) -> u32;
+}
+
+/// Which replacement policy to use, for configuring caches.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Replacement {
+    Lru,
+    TreePlru,
+    Fifo,
+    /// Random replacement, reproducible from the seed.
+    Random(u64),
+    Nru,
+}
+
+impl Replacement {
+    pub fn build<'a>(self, sets: u32, ways: u32) -> Box<ReplacementPolicy + 'a> {
+        match self {
+            Replacement::Lru => Box::new(LruPolicy::new(sets, ways)),
+            Replacement::TreePlru => Box::new(TreePlruPolicy::new(sets, ways)),
+            Replacement::Fifo => Box::new(FifoPolicy::new(sets, ways)),
+            Replacement::Random(seed) => Box::new(RandomPolicy::new(ways, seed)),
+            Replacement::Nru => Box::new(NruPolicy::new(sets, ways)),
+        }
+    }
+}
+
+/// Orders the ways of each set by a timestamp, so that the oldest way
+/// can be found without scanning the set.
+struct Timestamps {
+    ways: u32,
+    clock: u64,
+    stamps: Vec<Option<u64>>,
+    order: Vec<BTreeMap<u64, u32>>,
+}
+
+impl Timestamps {
+    fn new(sets: u32, ways: u32) -> Timestamps {
+        Timestamps {
+            ways: ways,
+            clock: 0,
+            stamps: vec![None; (sets * ways) as usize],
+            order: vec![BTreeMap::new(); sets as usize],
+        }
+    }
+
+    fn remove(&mut self, set: u32, way: u32) {
+        let block = (set * self.ways + way) as usize;
+        if let Some(stamp) = self.stamps[block].take() {
+            self.order[set as usize].remove(&stamp);
+        }
+    }
+
+    fn update(&mut self, set: u32, way: u32) {
+        self.remove(set, way);
+        let block = (set * self.ways + way) as usize;
+        self.clock += 1;
+        self.stamps[block] = Some(self.clock);
+        self.order[set as usize].insert(self.clock, way);
+    }
+
+    fn oldest(&self, set: u32) -> u32 {
+        self.order[set as usize].values().next().cloned().unwrap_or(0)
+    }
+}
+
+/// Evicts the least recently used way.
+pub struct LruPolicy {
+    stamps: Timestamps,
+}
+
+impl LruPolicy {
+    pub fn new(sets: u32, ways: u32) -> LruPolicy {
+        LruPolicy {
+            stamps: Timestamps::new(sets, ways),
+        }
+    }
+}
+
+impl ReplacementPolicy for LruPolicy {
+    fn touch(&mut self, set: u32, way: u32) {
+        self.stamps.update(set, way);
+    }
+
+    fn insert(&mut self, set: u32, way: u32) {
+        self.stamps.update(set, way);
+    }
+
+    fn invalidate(&mut self, set: u32, way: u32) {
+        self.stamps.remove(set, way);
+    }
+
+    fn victim(&mut self, set: u32) -> u32 {
+        self.stamps.oldest(set)
+    }
+}
+
+/// Evicts the way that was filled longest ago, ignoring hits.
+pub struct FifoPolicy {
+    stamps: Timestamps,
+}
+
+impl FifoPolicy {
+    pub fn new(sets: u32, ways: u32) -> FifoPolicy {
+        FifoPolicy {
+            stamps: Timestamps::new(sets, ways),
+        }
+    }
+}
+
+impl ReplacementPolicy for FifoPolicy {
+    fn touch(&mut self, _: u32, _: u32) {}
+
+    fn insert(&mut self, set: u32, way: u32) {
+        self.stamps.update(set, way);
+    }
+
+    fn invalidate(&mut self, set: u32, way: u32) {
+        self.stamps.remove(set, way);
+    }
+
+    fn victim(&mut self, set: u32) -> u32 {
+        self.stamps.oldest(set)
+    }
+}
+
+/// Approximates LRU with a binary tree of bits per set, each pointing
+/// towards the less recently used half. The number of ways must be a
+/// power of two.
+pub struct TreePlruPolicy {
+    ways: u32,
+    bits: Vec<bool>,
+}
+
+impl TreePlruPolicy {
+    pub fn new(sets: u32, ways: u32) -> TreePlruPolicy {
+        assert!(ways.is_power_of_two(), "tree-PLRU needs a power of two ways");
+        TreePlruPolicy {
+            ways: ways,
+            // A tree with `ways` leaves has `ways - 1` internal nodes;
+            // pad to `ways` so that each set's tree is indexed from 1
+            bits: vec![false; (sets * ways) as usize],
+        }
+    }
+
+    fn point_away(&mut self, set: u32, way: u32) {
+        let base = (set * self.ways) as usize;
+        let mut node = 1;
+        let mut width = self.ways;
+        while width > 1 {
+            width /= 2;
+            let upper = way & width != 0;
+            // Point at the half that was not just used
+            self.bits[base + node] = !upper;
+            node = 2 * node + upper as usize;
+        }
+    }
+}
+
+impl ReplacementPolicy for TreePlruPolicy {
+    fn touch(&mut self, set: u32, way: u32) {
+        self.point_away(set, way);
+    }
+
+    fn insert(&mut self, set: u32, way: u32) {
+        self.point_away(set, way);
+    }
+
+    fn victim(&mut self, set: u32) -> u32 {
+        let base = (set * self.ways) as usize;
+        let mut node = 1;
+        let mut way = 0;
+        let mut width = self.ways;
+        while width > 1 {
+            width /= 2;
+            let upper = self.bits[base + node];
+            if upper {
+                way |= width;
+            }
+            node = 2 * node + upper as usize;
+        }
+        way
+    }
+}
+
+/// Evicts a uniformly random way. The generator is seeded so that runs
+/// are reproducible.
+pub struct RandomPolicy {
+    ways: u32,
+    rng: XorShiftRng,
+}
+
+impl RandomPolicy {
+    pub fn new(ways: u32, seed: u64) -> RandomPolicy {
+        RandomPolicy {
+            ways: ways,
+            rng: XorShiftRng::new(seed),
+        }
+    }
+}
+
+impl ReplacementPolicy for RandomPolicy {
+    fn touch(&mut self, _: u32, _: u32) {}
+    fn insert(&mut self, _: u32, _: u32) {}
+
+    fn victim(&mut self, _: u32) -> u32 {
+        self.rng.range(0, self.ways)
+    }
+}
+
+/// Not-recently-used: each block has a reference bit, set on access.
+/// The victim is the first way whose bit is clear; once every bit in a
+/// set is set, they are all cleared.
+///
+/// The ways with clear bits are kept in order, so that the victim can
+/// be found without scanning the set.
+pub struct NruPolicy {
+    ways: u32,
+    unreferenced: Vec<BTreeSet<u32>>,
+}
+
+impl NruPolicy {
+    pub fn new(sets: u32, ways: u32) -> NruPolicy {
+        NruPolicy {
+            ways: ways,
+            unreferenced: vec![(0..ways).collect(); sets as usize],
+        }
+    }
+}
+
+impl ReplacementPolicy for NruPolicy {
+    fn touch(&mut self, set: u32, way: u32) {
+        self.unreferenced[set as usize].remove(&way);
+    }
+
+    fn insert(&mut self, set: u32, way: u32) {
+        self.touch(set, way);
+    }
+
+    fn invalidate(&mut self, set: u32, way: u32) {
+        self.unreferenced[set as usize].insert(way);
+    }
+
+    fn victim(&mut self, set: u32) -> u32 {
+        let ref mut unreferenced = self.unreferenced[set as usize];
+        match unreferenced.iter().next().cloned() {
+            Some(way) => way,
+            None => {
+                // Clearing the bits takes a pass over the set, but only
+                // after every way has been referenced since the last
+                *unreferenced = (0..self.ways).collect();
+                0
+            },
+        }
+    }
+}
@@ -0,0 +1,1248 @@
+// Copyright 2016 David Li
+// This file is part of rustv.
+
+// rustv is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// rustv is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with rustv.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::rc::{Rc, Weak};
+
+use isa::{self, IsaType};
+use memory::{MemoryError, MemoryInterface, Result, SharedMemory};
+use super::{AccessOutcome, Block, BufferEntry, BufferKind, BufferStats,
+            CacheInterface, CacheLocation, CacheMetadata, CacheStats,
+            EventHandler, FetchRequest, Inclusion, MissClasses,
+            MissClassifier, Prefetcher, PrefetchStats, ReplacementPolicy,
+            VictimBuffer, WriteMissPolicy, WritePolicy};
+
+/// A dirty block waiting to be written to the next level.
+struct Writeback {
+    address: isa::Address,
+    data: Vec<isa::Word>,
+    /// Which word of the block is written next
+    next_word: u32,
+    /// Whether the next level missed on that word
+    retrying: bool,
+}
+
+/// Default number of miss status holding registers.
+pub const DEFAULT_MSHRS: usize = 8;
+
+/// An N-way set-associative cache. The cache is non-blocking: each
+/// outstanding miss holds a miss status holding register (MSHR), misses
+/// to a line that is already being fetched are merged into its MSHR,
+/// and hits are served while misses are outstanding. An access only has
+/// to wait for another miss when every MSHR is in use, or every way of
+/// its set is already reserved.
+///
+/// The way to fill is chosen when the fetch is requested, so that it
+/// can be reported to the `EventHandler`.
+///
+/// Tags are found by scanning the set, unless a tag index is enabled,
+/// in which case lookups go through a hash table. The index is meant
+/// for highly associative caches.
+///
+/// By default the cache is write-through and write-allocate. With
+/// write-back, dirty blocks are moved to a writeback buffer when
+/// evicted; the buffer drains before any fetch reads the next level,
+/// and a miss that evicts a dirty block costs the next level's latency
+/// twice.
+///
+/// When part of a hierarchy, the cache can enforce an inclusion policy
+/// towards the caches above it; see `Inclusion`.
+///
+/// A `Prefetcher` can be attached, which observes each demand access
+/// once: retries of a missed access, which requesters flag with
+/// `set_retry`, are not observed again. The same goes for the cache's
+/// statistics.
+///
+/// A `VictimBuffer` can also be attached, as a victim cache or a miss
+/// cache. A victim cache takes every block the cache replaces, and dirty
+/// blocks are only written back once they leave the buffer.
+pub struct SetAssociativeCache<'a, T: EventHandler> {
+    num_sets: u32,
+    num_ways: u32,
+    block_words: u32,
+    /// Blocks in order of set, then way
+    blocks: Vec<Block>,
+    /// Outstanding misses, oldest first
+    mshrs: Vec<FetchRequest>,
+    num_mshrs: usize,
+    /// Invalid ways of each set, lowest way last
+    free_ways: Vec<Vec<u32>>,
+    /// Ways of each set that outstanding misses will fill
+    reserved_ways: Vec<BTreeSet<u32>>,
+    /// Maps (set, tag) to the way holding it
+    tag_index: Option<HashMap<(u32, u32), u32>>,
+    writebacks: VecDeque<Writeback>,
+    write_policy: WritePolicy,
+    write_miss_policy: WriteMissPolicy,
+    inclusion: Inclusion,
+    /// Write back clean blocks on eviction too, for an exclusive next level
+    evict_clean: bool,
+    upper_levels: Vec<Weak<RefCell<MemoryInterface + 'a>>>,
+    /// Evicted blocks still to be invalidated in the upper levels
+    back_invalidations: Vec<isa::Address>,
+    /// Blocks that have left the cache, if anyone is listening
+    evictions: Option<Vec<isa::Address>>,
+    policy: Box<ReplacementPolicy + 'a>,
+    prefetcher: Option<Box<Prefetcher + 'a>>,
+    prefetch_stats: PrefetchStats,
+    /// The PC of the instruction making accesses, if known
+    pc: Option<isa::Address>,
+    /// Whether the accesses being made retry ones that missed
+    retry: bool,
+    /// The address of the last access observed. A retry is only
+    /// observed if its first attempt never reached the cache.
+    last_access: Option<isa::Address>,
+    buffer: Option<VictimBuffer>,
+    stats: CacheStats,
+    classifier: Option<MissClassifier>,
+    next_level: SharedMemory<'a>,
+    events: T,
+}
+
+impl<'a, T: EventHandler> SetAssociativeCache<'a, T> {
+    pub fn new(sets: u32, ways: u32, block_words: u32,
+               policy: Box<ReplacementPolicy + 'a>,
+               next_level: SharedMemory<'a>, events: T)
+               -> SetAssociativeCache<'a, T> {
+        assert!(sets.is_power_of_two() && block_words.is_power_of_two());
+        assert!(ways > 0);
+        let block = Block {
+            valid: false,
+            dirty: false,
+            prefetched: false,
+            tag: 0,
+            contents: vec![isa::Word(0); block_words as usize],
+        };
+        SetAssociativeCache {
+            num_sets: sets,
+            num_ways: ways,
+            block_words: block_words,
+            blocks: vec![block; (sets * ways) as usize],
+            mshrs: Vec::new(),
+            num_mshrs: DEFAULT_MSHRS,
+            free_ways: vec![(0..ways).rev().collect(); sets as usize],
+            reserved_ways: vec![BTreeSet::new(); sets as usize],
+            tag_index: None,
+            writebacks: VecDeque::new(),
+            write_policy: WritePolicy::WriteThrough,
+            write_miss_policy: WriteMissPolicy::WriteAllocate,
+            inclusion: Inclusion::NonInclusive,
+            evict_clean: false,
+            upper_levels: Vec::new(),
+            back_invalidations: Vec::new(),
+            evictions: None,
+            policy: policy,
+            prefetcher: None,
+            prefetch_stats: PrefetchStats::default(),
+            pc: None,
+            retry: false,
+            last_access: None,
+            buffer: None,
+            stats: CacheStats::default(),
+            classifier: None,
+            next_level: next_level,
+            events: events,
+        }
+    }
+
+    /// Look up tags through a hash table instead of scanning sets.
+    pub fn with_tag_index(mut self) -> SetAssociativeCache<'a, T> {
+        let mut tag_index = HashMap::new();
+        for index in 0..self.num_sets {
+            for way in 0..self.num_ways {
+                let ref block = self.blocks[self.block_index(index, way)];
+                if block.valid {
+                    tag_index.insert((index, block.tag), way);
+                }
+            }
+        }
+        self.tag_index = Some(tag_index);
+        self
+    }
+
+    /// Set how many misses may be outstanding at once.
+    pub fn set_mshr_count(&mut self, mshrs: usize) {
+        assert!(mshrs > 0);
+        self.num_mshrs = mshrs;
+    }
+
+    /// The number of misses currently outstanding.
+    pub fn outstanding_misses(&self) -> usize {
+        self.mshrs.len()
+    }
+
+    pub fn set_write_policy(&mut self, policy: WritePolicy) {
+        self.write_policy = policy;
+    }
+
+    pub fn set_write_miss_policy(&mut self, policy: WriteMissPolicy) {
+        self.write_miss_policy = policy;
+    }
+
+    /// Set the relationship between this cache and the caches above it,
+    /// which must be registered with `add_upper_level`.
+    pub fn set_inclusion(&mut self, inclusion: Inclusion) {
+        self.inclusion = inclusion;
+    }
+
+    /// Write evicted blocks to the next level even when they are clean.
+    /// This is needed above an exclusive cache.
+    pub fn set_evict_clean(&mut self, evict_clean: bool) {
+        self.evict_clean = evict_clean;
+    }
+
+    /// Register a cache whose next level is this cache.
+    pub fn add_upper_level(&mut self, upper: &SharedMemory<'a>) {
+        self.upper_levels.push(Rc::downgrade(upper));
+    }
+
+    pub fn set_prefetcher(&mut self, prefetcher: Box<Prefetcher + 'a>) {
+        self.prefetcher = Some(prefetcher);
+    }
+
+    pub fn has_prefetcher(&self) -> bool {
+        self.prefetcher.is_some()
+    }
+
+    pub fn prefetch_stats(&self) -> PrefetchStats {
+        self.prefetch_stats
+    }
+
+    /// Attach a victim cache or miss cache, replacing any attached
+    /// before.
+    pub fn attach_buffer(&mut self, buffer: VictimBuffer) {
+        self.buffer = Some(buffer);
+    }
+
+    pub fn buffer(&self) -> Option<&VictimBuffer> {
+        self.buffer.as_ref()
+    }
+
+    /// Statistics of the attached buffer, if any.
+    pub fn buffer_stats(&self) -> Option<BufferStats> {
+        self.buffer.as_ref().map(|buffer| buffer.stats())
+    }
+
+    /// Classify misses as compulsory, capacity or conflict misses, from
+    /// now on.
+    pub fn classify_misses(&mut self) {
+        let lines = (self.num_sets * self.num_ways) as usize;
+        self.classifier = Some(MissClassifier::new(lines));
+        self.stats.classes = Some(MissClasses::default());
+    }
+
+    /// Count a demand miss found before the cache was searched, such as
+    /// a coherence miss or a store that bypasses the cache. Retries of
+    /// the access are then not counted again.
+    pub fn demand_miss(&mut self, address: isa::Address, write: bool) {
+        if let Some(outcome) = self.observe(address, write, None) {
+            self.train_prefetcher(address, outcome);
+        }
+    }
+
+    /// Start recording the address of every block that leaves the
+    /// cache, to be collected with `take_evictions`.
+    pub fn record_evictions(&mut self) {
+        self.evictions = Some(Vec::new());
+    }
+
+    /// The blocks that have left the cache since the last call.
+    pub fn take_evictions(&mut self) -> Vec<isa::Address> {
+        match self.evictions {
+            Some(ref mut evictions) => evictions.drain(..).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Whether a miss could be started now, rather than waiting for an
+    /// MSHR or a way of the set to become free.
+    pub fn can_fetch(&self, address: isa::Address) -> bool {
+        let (_, index, _) = self.parse_address(address);
+        self.mshrs.len() < self.num_mshrs &&
+            (!self.free_ways[index as usize].is_empty() ||
+             (self.reserved_ways[index as usize].len() as u32) < self.num_ways)
+    }
+
+    /// Whether the block containing an address is being fetched.
+    pub fn is_fetching(&self, address: isa::Address) -> bool {
+        let normalized = self.normalize_address(address);
+        self.mshrs.iter().any(|mshr| {
+            mshr.address == normalized && mshr.error.is_none()
+        })
+    }
+
+    /// Start fetching the block containing an address from the next
+    /// level, after `latency` extra cycles. Returns the stall, as for
+    /// any other miss.
+    pub fn fetch(&mut self, address: isa::Address, latency: u32) -> MemoryError {
+        self.miss(address, None, latency)
+    }
+
+    /// Start a miss whose data is supplied by another cache instead of
+    /// the next level, and arrives after `latency` cycles.
+    pub fn fill_from(&mut self, address: isa::Address, data: Vec<isa::Word>,
+                     latency: u32) -> MemoryError {
+        self.miss(address, Some(data), latency)
+    }
+
+    /// The contents of the block containing an address, if it is held
+    /// or waiting to be written back.
+    pub fn block_contents(&self, address: isa::Address) -> Option<Vec<isa::Word>> {
+        let (tag, index, _) = self.parse_address(address);
+        if let Some(way) = self.find_way(index, tag) {
+            return Some(self.blocks[self.block_index(index, way)].contents.clone());
+        }
+
+        let normalized = self.normalize_address(address);
+        if let Some(ref buffer) = self.buffer {
+            if let Some(data) = buffer.contents(normalized) {
+                return Some(data.clone());
+            }
+        }
+        self.writebacks.iter().rev()
+            .find(|writeback| writeback.address == normalized)
+            .map(|writeback| writeback.data.clone())
+    }
+
+    /// Write back the block containing an address if it is dirty,
+    /// keeping a clean copy.
+    pub fn clean(&mut self, address: isa::Address) {
+        let (tag, index, _) = self.parse_address(address);
+        if let Some(way) = self.find_way(index, tag) {
+            if self.blocks[self.block_index(index, way)].dirty {
+                self.write_back(index, way);
+            }
+        }
+    }
+
+    /// Give up the block containing an address to another cache: remove
+    /// it, and any queued writeback of it, without writing it back.
+    pub fn surrender(&mut self, address: isa::Address) {
+        let (tag, index, _) = self.parse_address(address);
+        if let Some(way) = self.find_way(index, tag) {
+            self.evicted(index, way);
+            self.discard(index, way);
+            if !self.is_reserved(index, way) {
+                self.free_ways[index as usize].push(way);
+            }
+        }
+
+        let normalized = self.normalize_address(address);
+        if let Some(ref mut buffer) = self.buffer {
+            buffer.remove(normalized);
+        }
+        self.writebacks.retain(|writeback| writeback.address != normalized);
+    }
+
+    /// Whether the block containing an address is dirty.
+    pub fn is_dirty(&self, address: isa::Address) -> bool {
+        let (tag, index, _) = self.parse_address(address);
+        match self.find_way(index, tag) {
+            Some(way) => self.blocks[self.block_index(index, way)].dirty,
+            None => false,
+        }
+    }
+
+    pub fn events(&self) -> &T {
+        &self.events
+    }
+
+    pub fn parse_address(&self, address: isa::Address) -> (u32, u32, u32) {
+        // TODO: use constant in ISA module for word->byte conversion
+        let offset_mask = (self.block_words * 4 - 1) as u32;
+        let offset = address & offset_mask;
+        let index_mask = (self.num_sets - 1) as u32;
+        let index_shift = 32 - (self.block_words * 4).leading_zeros() - 1;
+        let index = (address >> index_shift) & index_mask;
+        let tag_shift = index_shift + (32 - self.num_sets.leading_zeros()) - 1;
+        let tag = address >> tag_shift;
+
+        (tag.0, index.0, offset.0)
+    }
+
+    /// The address of the first word of a block.
+    fn block_address(&self, index: u32, tag: u32) -> isa::Address {
+        let index_shift = 32 - (self.block_words * 4).leading_zeros() - 1;
+        let tag_shift = index_shift + (32 - self.num_sets.leading_zeros()) - 1;
+        isa::Word((tag << tag_shift) | (index << index_shift))
+    }
+
+    fn normalize_address(&self, address: isa::Address) -> isa::Address {
+        let offset_mask = !(self.block_words * 4 - 1);
+        address & offset_mask
+    }
+
+    fn block_index(&self, index: u32, way: u32) -> usize {
+        (index * self.num_ways + way) as usize
+    }
+
+    /// Find the way holding a tag, if any.
+    fn find_way(&self, index: u32, tag: u32) -> Option<u32> {
+        if let Some(ref tag_index) = self.tag_index {
+            return tag_index.get(&(index, tag)).cloned();
+        }
+
+        let start = self.block_index(index, 0);
+        let set = &self.blocks[start..start + self.num_ways as usize];
+        set.iter()
+            .position(|block| block.valid && block.tag == tag)
+            .map(|way| way as u32)
+    }
+
+    /// Whether an outstanding miss will fill this way.
+    fn is_reserved(&self, index: u32, way: u32) -> bool {
+        self.reserved_ways[index as usize].contains(&way)
+    }
+
+    /// Choose the way to fill in a set, preferring invalid ways and
+    /// avoiding ways reserved by other misses. Returns `None` if every
+    /// way is reserved.
+    fn choose_way(&mut self, index: u32) -> Option<u32> {
+        if let Some(way) = self.free_ways[index as usize].pop() {
+            return Some(way);
+        }
+
+        let victim = self.policy.victim(index);
+        if !self.is_reserved(index, victim) {
+            return Some(victim);
+        }
+        // The lowest unreserved way is at the first gap in the reserved
+        // ways, of which there are at most as many as MSHRs
+        let mut way = 0;
+        for &reserved in self.reserved_ways[index as usize].iter() {
+            if reserved != way {
+                break;
+            }
+            way += 1;
+        }
+        if way < self.num_ways {
+            Some(way)
+        }
+        else {
+            None
+        }
+    }
+
+    /// The stall to report when a miss cannot be accepted yet.
+    fn structural_stall(&self) -> MemoryError {
+        let cycles_left = self.mshrs.iter()
+            .map(|mshr| mshr.cycles_left)
+            .min()
+            .unwrap_or(1);
+        MemoryError::CacheMiss {
+            stall_cycles: ::std::cmp::max(cycles_left, 1),
+            retry: true,
+        }
+    }
+
+    /// Queue a dirty block to be written to the next level, marking it
+    /// clean.
+    fn write_back(&mut self, index: u32, way: u32) {
+        let block = self.block_index(index, way);
+        let address = self.block_address(index, self.blocks[block].tag);
+        self.blocks[block].dirty = false;
+        let data = self.blocks[block].contents.clone();
+        self.queue_writeback(address, data);
+    }
+
+    fn queue_writeback(&mut self, address: isa::Address, data: Vec<isa::Word>) {
+        self.stats.writebacks += 1;
+        self.writebacks.push_back(Writeback {
+            address: address,
+            data: data,
+            next_word: 0,
+            retrying: false,
+        });
+    }
+
+    /// Whether replaced blocks go to an attached victim cache.
+    fn has_victim_cache(&self) -> bool {
+        self.buffer.as_ref().map_or(false, |buffer| buffer.kind() == BufferKind::Victim)
+    }
+
+    /// Move a replaced block into the victim cache, writing back the
+    /// block this pushes out if it is dirty.
+    fn move_to_victim_cache(&mut self, index: u32, way: u32) {
+        let block = self.block_index(index, way);
+        let entry = BufferEntry {
+            address: self.block_address(index, self.blocks[block].tag),
+            data: self.blocks[block].contents.clone(),
+            dirty: self.blocks[block].dirty,
+        };
+        self.evicted(index, way);
+        self.discard(index, way);
+
+        let replaced = match self.buffer {
+            Some(ref mut buffer) => buffer.insert(entry),
+            None => None,
+        };
+        if let Some(replaced) = replaced {
+            if replaced.dirty || self.evict_clean {
+                self.queue_writeback(replaced.address, replaced.data);
+            }
+        }
+    }
+
+    /// Note that a block is leaving the cache because of a replacement
+    /// or invalidation, so that inclusive caches can remove it from the
+    /// levels above.
+    fn evicted(&mut self, index: u32, way: u32) {
+        self.stats.evictions += 1;
+        let block = self.block_index(index, way);
+        if self.blocks[block].prefetched {
+            self.prefetch_stats.unused += 1;
+        }
+        let tag = self.blocks[block].tag;
+        let address = self.block_address(index, tag);
+        if self.inclusion == Inclusion::Inclusive {
+            self.back_invalidations.push(address);
+        }
+        if let Some(ref mut evictions) = self.evictions {
+            evictions.push(address);
+        }
+    }
+
+    /// Invalidate evicted blocks in the levels above. This happens in
+    /// `step`, when the upper levels are not borrowed.
+    fn invalidate_upper_levels(&mut self) {
+        for address in self.back_invalidations.drain(..) {
+            for upper in self.upper_levels.iter() {
+                if let Some(upper) = upper.upgrade() {
+                    for offset in 0..self.block_words {
+                        // Any writeback is charged to the upper level
+                        let _ = upper.borrow_mut().invalidate(address + 4 * offset);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Remove a block, without writing it back.
+    fn discard(&mut self, index: u32, way: u32) {
+        let block = self.block_index(index, way);
+        if let Some(ref mut tag_index) = self.tag_index {
+            tag_index.remove(&(index, self.blocks[block].tag));
+        }
+        self.blocks[block].valid = false;
+        self.blocks[block].dirty = false;
+        self.policy.invalidate(index, way);
+    }
+
+    /// Write queued dirty blocks to the next level, until it stalls.
+    fn drain_writebacks(&mut self) {
+        while let Some(mut writeback) = self.writebacks.pop_front() {
+            while writeback.next_word < self.block_words {
+                let offset = writeback.next_word;
+                let result = {
+                    let mut next_level = self.next_level.borrow_mut();
+                    next_level.set_retry(writeback.retrying);
+                    next_level.write_word(writeback.address + 4 * offset,
+                                          writeback.data[offset as usize])
+                };
+                match result {
+                    Ok(()) => {
+                        writeback.next_word += 1;
+                        writeback.retrying = false;
+                    },
+                    Err(MemoryError::CacheMiss { .. }) => {
+                        writeback.retrying = true;
+                        break;
+                    },
+                    // Nowhere to write the block, so drop it
+                    Err(MemoryError::InvalidAddress) => {
+                        writeback.next_word = self.block_words;
+                    },
+                }
+            }
+
+            if writeback.next_word < self.block_words {
+                self.writebacks.push_front(writeback);
+                return;
+            }
+        }
+    }
+
+    /// Stores that bypass the cache must wait for the writeback buffer
+    /// to drain, so that a writeback of the same block does not
+    /// overwrite them.
+    fn wait_for_writebacks(&self) -> Result<()> {
+        if self.writebacks.is_empty() {
+            Ok(())
+        }
+        else {
+            Err(MemoryError::CacheMiss {
+                stall_cycles: 1,
+                retry: true,
+            })
+        }
+    }
+
+    /// Read-modify-write part of a word, for sub-word stores.
+    fn write_masked(&mut self, address: isa::Address, value: isa::Word,
+                    mask: u32) -> Result<()> {
+        let way = try!(self.access(address, true));
+        let (_, index, offset) = self.parse_address(address);
+        let word = self.blocks[self.block_index(index, way)].contents[(offset / 4) as usize];
+        self.store(address, way, (word & !mask) | (value & mask))
+    }
+
+    /// Write a word to the way holding it.
+    fn store(&mut self, address: isa::Address, way: u32, value: isa::Word)
+             -> Result<()> {
+        let (_, index, offset) = self.parse_address(address);
+        let block = self.block_index(index, way);
+        self.blocks[block].contents[(offset / 4) as usize] = value;
+        // A miss cache's copy of the block is now stale
+        let normalized = self.normalize_address(address);
+        if let Some(ref mut buffer) = self.buffer {
+            if buffer.kind() == BufferKind::Miss {
+                buffer.remove(normalized);
+            }
+        }
+        match self.write_policy {
+            WritePolicy::WriteThrough => {
+                let mut next_level = self.next_level.borrow_mut();
+                next_level.set_retry(self.retry);
+                next_level.write_word(address, value)
+            },
+            WritePolicy::WriteBack => {
+                self.blocks[block].dirty = true;
+                Ok(())
+            },
+        }
+    }
+
+    /// Whether a store to this address bypasses the cache. A block in
+    /// the attached buffer counts as present: it is swapped in and
+    /// written there, so the buffer never holds stale data.
+    fn bypasses(&self, address: isa::Address) -> bool {
+        let buffered = match self.buffer {
+            Some(ref buffer) =>
+                buffer.contents(self.normalize_address(address)).is_some(),
+            None => false,
+        };
+        self.write_miss_policy == WriteMissPolicy::WriteNoAllocate &&
+            !self.is_address_accessible(address) && !buffered
+    }
+
+    /// Put a block into a way, replacing whatever was there. A clean
+    /// victim stays valid while its replacement is fetched, so a store
+    /// may have dirtied it since; it is written back here.
+    fn install(&mut self, index: u32, way: u32, tag: u32, data: Vec<isa::Word>) {
+        if self.blocks[self.block_index(index, way)].valid {
+            if self.blocks[self.block_index(index, way)].dirty {
+                self.write_back(index, way);
+            }
+            self.evicted(index, way);
+        }
+
+        let block = self.block_index(index, way);
+        let ref mut block = self.blocks[block];
+        if let Some(ref mut tag_index) = self.tag_index {
+            if block.valid {
+                tag_index.remove(&(index, block.tag));
+            }
+            tag_index.insert((index, tag), way);
+        }
+        block.valid = true;
+        block.dirty = false;
+        block.prefetched = false;
+        block.tag = tag;
+        block.contents = data;
+        self.policy.insert(index, way);
+    }
+
+    /// Find the way holding an address, starting a fetch on a miss.
+    fn access(&mut self, address: isa::Address, write: bool) -> Result<u32> {
+        let (tag, index, _) = self.parse_address(address);
+        let found = self.find_way(index, tag);
+        let outcome = self.observe(address, write, found);
+
+        let result = match found {
+            Some(way) => {
+                self.policy.touch(index, way);
+                Ok(way)
+            },
+            None => Err(self.miss(address, None, 0)),
+        };
+
+        if let Some(outcome) = outcome {
+            self.train_prefetcher(address, outcome);
+        }
+        result
+    }
+
+    /// Count a demand access, unless it is a retry of one already
+    /// counted, and classify it for the prefetcher.
+    fn observe(&mut self, address: isa::Address, write: bool,
+               found: Option<u32>) -> Option<AccessOutcome> {
+        if self.retry && self.last_access == Some(address) {
+            return None;
+        }
+        self.last_access = Some(address);
+
+        if write {
+            self.stats.writes += 1;
+        }
+        else {
+            self.stats.reads += 1;
+        }
+        let normalized = self.normalize_address(address);
+        let primary_miss = found.is_none() &&
+            !self.mshrs.iter().any(|mshr| mshr.address == normalized);
+        if found.is_some() {
+            self.stats.hits += 1;
+        }
+        else if primary_miss {
+            self.stats.misses += 1;
+        }
+        else {
+            self.stats.mshr_hits += 1;
+        }
+        if let Some(ref mut classifier) = self.classifier {
+            if let Some(class) = classifier.access(normalized, primary_miss) {
+                if let Some(ref mut classes) = self.stats.classes {
+                    classes.record(class);
+                }
+            }
+        }
+
+        Some(self.classify(address, found))
+    }
+
+    fn train_prefetcher(&mut self, address: isa::Address, outcome: AccessOutcome) {
+        let prefetches = match self.prefetcher {
+            Some(ref mut prefetcher) => prefetcher.access(self.pc, address, outcome),
+            None => Vec::new(),
+        };
+        for prefetch in prefetches {
+            self.prefetch(prefetch);
+        }
+    }
+
+    /// Classify a demand access, noting the first use of prefetched
+    /// blocks.
+    fn classify(&mut self, address: isa::Address, found: Option<u32>)
+                -> AccessOutcome {
+        let (_, index, _) = self.parse_address(address);
+        let normalized = self.normalize_address(address);
+        match found {
+            Some(way) => {
+                let block = self.block_index(index, way);
+                if self.blocks[block].prefetched {
+                    self.blocks[block].prefetched = false;
+                    self.prefetch_stats.useful += 1;
+                    AccessOutcome::PrefetchHit
+                }
+                else {
+                    AccessOutcome::Hit
+                }
+            },
+            None => {
+                match self.mshrs.iter().position(|mshr| mshr.address == normalized) {
+                    Some(mshr) if self.mshrs[mshr].prefetch => {
+                        // A late prefetch, which is still useful
+                        self.mshrs[mshr].prefetch = false;
+                        self.prefetch_stats.useful += 1;
+                        AccessOutcome::PrefetchHit
+                    },
+                    Some(_) => AccessOutcome::Miss,
+                    None => {
+                        self.prefetch_stats.misses += 1;
+                        AccessOutcome::Miss
+                    },
+                }
+            },
+        }
+    }
+
+    /// Handle a miss, by merging it into the outstanding fetch of its
+    /// line or starting a new fetch. The block is read from the next
+    /// level after `latency` extra cycles, unless `supplied` gives its
+    /// contents, in which case they arrive after `latency` cycles.
+    fn miss(&mut self, address: isa::Address, supplied: Option<Vec<isa::Word>>,
+            latency: u32) -> MemoryError {
+        let normalized = self.normalize_address(address);
+        let stall = self.next_level.borrow().latency();
+        let (tag, index, offset) = self.parse_address(address);
+
+        // Secondary miss: merge with the outstanding fetch of this line
+        if let Some(mshr) = self.mshrs.iter().position(|mshr| mshr.address == normalized) {
+            if self.mshrs[mshr].error.is_some() {
+                return self.drop_fetch(mshr);
+            }
+
+            return MemoryError::CacheMiss {
+                stall_cycles: self.mshrs[mshr].cycles_left,
+                retry: true,
+            };
+        }
+
+        // Never give a tag a second way, e.g. when a block was prefetched
+        // without a coherent cache knowing
+        if let Some(way) = self.find_way(index, tag) {
+            // Data supplied by another cache is newer than this copy
+            if let Some(data) = supplied {
+                let block = self.block_index(index, way);
+                self.blocks[block].contents = data;
+            }
+            return MemoryError::CacheMiss {
+                stall_cycles: ::std::cmp::max(latency, 1),
+                retry: true,
+            };
+        }
+
+        if self.mshrs.len() >= self.num_mshrs {
+            return self.structural_stall();
+        }
+        let way = match self.choose_way(index) {
+            Some(way) => way,
+            None => return self.structural_stall(),
+        };
+        // Data supplied by another cache is newer than any copy here
+        let buffered = match (self.buffer.as_mut(), supplied.is_some()) {
+            (Some(buffer), false) => buffer.lookup(normalized),
+            (Some(buffer), true) => {
+                buffer.remove(normalized);
+                None
+            },
+            (None, _) => None,
+        };
+
+        let victim = self.block_index(index, way);
+        let writeback = if self.blocks[victim].valid && self.has_victim_cache() {
+            self.move_to_victim_cache(index, way);
+            0
+        }
+        else if self.blocks[victim].valid &&
+            (self.blocks[victim].dirty || self.evict_clean) {
+            self.write_back(index, way);
+            self.evicted(index, way);
+            self.discard(index, way);
+            stall
+        }
+        else {
+            0
+        };
+
+        if let Some(entry) = buffered {
+            // Swap the block in now; the access is retried once the
+            // buffer's latency has passed
+            self.install(index, way, tag, entry.data);
+            let block = self.block_index(index, way);
+            self.blocks[block].dirty = entry.dirty;
+            let latency = self.buffer.as_ref().map_or(0, |buffer| buffer.latency());
+            return MemoryError::CacheMiss {
+                stall_cycles: ::std::cmp::max(latency + writeback, 1),
+                retry: true,
+            };
+        }
+
+        let (data, waiting_on, stall) = match supplied {
+            Some(data) => {
+                (data, self.block_words, ::std::cmp::max(latency + writeback, 1))
+            },
+            None => {
+                (vec![isa::Word(0); self.block_words as usize], 0,
+                 stall + writeback + latency)
+            },
+        };
+        let location = CacheLocation {
+            tag: tag,
+            index: index,
+            offset: offset,
+            way: way,
+        };
+        self.reserved_ways[index as usize].insert(way);
+        self.mshrs.push(FetchRequest {
+            address: normalized,
+            prefetch: false,
+            cycles_left: stall,
+            location: location,
+            data: data,
+            error: None,
+            waiting_on: waiting_on,
+            retrying: false,
+        });
+        self.events.block_requested(location);
+
+        MemoryError::CacheMiss {
+            stall_cycles: stall,
+            retry: true,
+        }
+    }
+
+    fn fill(&mut self, mshr: usize) {
+        let request = self.mshrs.remove(mshr);
+        let location = request.location;
+        self.reserved_ways[location.index as usize].remove(&location.way);
+        self.events.block_fetched(location);
+        if let Some(ref mut buffer) = self.buffer {
+            if buffer.kind() == BufferKind::Miss && !request.prefetch {
+                buffer.insert(BufferEntry {
+                    address: request.address,
+                    data: request.data.clone(),
+                    dirty: false,
+                });
+            }
+        }
+        self.install(location.index, location.way, location.tag, request.data);
+        let block = self.block_index(location.index, location.way);
+        self.blocks[block].prefetched = request.prefetch;
+    }
+
+    /// Drop a fetch that failed, freeing its way.
+    fn drop_fetch(&mut self, mshr: usize) -> MemoryError {
+        let failed = self.mshrs.remove(mshr);
+        let location = failed.location;
+        self.reserved_ways[location.index as usize].remove(&location.way);
+        if !self.blocks[self.block_index(location.index, location.way)].valid {
+            self.free_ways[location.index as usize].push(location.way);
+        }
+        failed.error.unwrap_or(MemoryError::InvalidAddress)
+    }
+
+    /// An exclusive cache holds blocks evicted from the levels above,
+    /// which are written back a word at a time starting from the first.
+    /// The first word allocates the block without fetching it.
+    fn allocate_for_writeback(&mut self, address: isa::Address) -> Result<()> {
+        let normalized = self.normalize_address(address);
+        if self.mshrs.iter().any(|mshr| mshr.address == normalized) {
+            return Err(self.structural_stall());
+        }
+
+        let (tag, index, _) = self.parse_address(address);
+        let way = match self.choose_way(index) {
+            Some(way) => way,
+            None => return Err(self.structural_stall()),
+        };
+        let victim = self.block_index(index, way);
+        if self.blocks[victim].valid && self.blocks[victim].dirty {
+            self.write_back(index, way);
+        }
+        self.install(index, way, tag, vec![isa::Word(0); self.block_words as usize]);
+        // The block may have been modified above
+        let block = self.block_index(index, way);
+        self.blocks[block].dirty = true;
+        Ok(())
+    }
+}
+
+impl<'a, T: EventHandler> MemoryInterface for SetAssociativeCache<'a, T> {
+    fn latency(&self) -> u32 {
+        // A level above this one must still wait a cycle for a hit
+        1
+    }
+
+    fn step(&mut self) {
+        self.invalidate_upper_levels();
+        self.drain_writebacks();
+
+        let mut mshr = 0;
+        while mshr < self.mshrs.len() {
+            if self.mshrs[mshr].prefetch && self.mshrs[mshr].error.is_some() {
+                // Nobody is waiting to collect the error
+                self.drop_fetch(mshr);
+                continue;
+            }
+
+            let complete = {
+                let ref mut fetch_request = self.mshrs[mshr];
+                if fetch_request.error.is_some() {
+                    // Wait for the requester to collect the error
+                    false
+                }
+                else if fetch_request.cycles_left > 1 {
+                    // Start filling the cache once the cycles_left would
+                    // have hit 0, so that the consumer never gets
+                    // stall_cycles = 0
+                    fetch_request.cycles_left -= 1;
+                    false
+                }
+                else if !self.writebacks.is_empty() {
+                    // Don't read data that is still being written back
+                    false
+                }
+                else {
+                    // read all the words in a line from the next
+                    // level, until we get a stall
+                    for offset in fetch_request.waiting_on..self.block_words {
+                        let result = {
+                            let mut next_level = self.next_level.borrow_mut();
+                            next_level.set_retry(fetch_request.retrying);
+                            next_level.read_word(fetch_request.address + (4 * offset))
+                        };
+                        match result {
+                            Ok(data) => {
+                                fetch_request.data[offset as usize] = data;
+                                fetch_request.waiting_on += 1;
+                                fetch_request.retrying = false;
+                            },
+                            Err(MemoryError::CacheMiss { stall_cycles, .. }) => {
+                                fetch_request.cycles_left = stall_cycles;
+                                fetch_request.retrying = true;
+                                break;
+                            },
+                            Err(MemoryError::InvalidAddress) => {
+                                fetch_request.error =
+                                    Some(MemoryError::InvalidAddress);
+                                break;
+                            }
+                        }
+                    }
+
+                    fetch_request.waiting_on == self.block_words
+                }
+            };
+
+            if complete {
+                // All words fetched, write to cache
+                self.fill(mshr);
+            }
+            else {
+                mshr += 1;
+            }
+        }
+    }
+
+    fn is_address_accessible(&self, address: isa::Address) -> bool {
+        let (tag, index, _) = self.parse_address(address);
+        self.find_way(index, tag).is_some()
+    }
+
+    fn read_word(&mut self, address: isa::Address) -> Result<isa::Word> {
+        let way = try!(self.access(address, false));
+        let (_, index, offset) = self.parse_address(address);
+        let block = self.block_index(index, way);
+        let value = self.blocks[block].contents[(offset / 4) as usize];
+
+        // In an exclusive cache, a block moves up once the level above
+        // has read all of it
+        if self.inclusion == Inclusion::Exclusive &&
+            offset / 4 == self.block_words - 1 {
+            self.discard(index, way);
+            if !self.is_reserved(index, way) {
+                self.free_ways[index as usize].push(way);
+            }
+        }
+
+        Ok(value)
+    }
+
+    fn write_word(&mut self, address: isa::Address, value: isa::Word)
+                  -> Result<()> {
+        if self.bypasses(address) {
+            self.demand_miss(address, true);
+            try!(self.wait_for_writebacks());
+            let mut next_level = self.next_level.borrow_mut();
+            next_level.set_retry(self.retry);
+            return next_level.write_word(address, value);
+        }
+
+        let (tag, index, offset) = self.parse_address(address);
+        if self.inclusion == Inclusion::Exclusive && offset == 0 &&
+            self.find_way(index, tag).is_none() {
+            try!(self.allocate_for_writeback(address));
+        }
+
+        // Write-allocate policy
+        let way = try!(self.access(address, true));
+        self.store(address, way, value)
+    }
+
+    fn write_halfword(&mut self, address: isa::Address, value: isa::HalfWord)
+                      -> Result<()> {
+        if self.bypasses(address) {
+            self.demand_miss(address, true);
+            try!(self.wait_for_writebacks());
+            let mut next_level = self.next_level.borrow_mut();
+            next_level.set_retry(self.retry);
+            return next_level.write_halfword(address, value);
+        }
+        let shift = 8 * (address & 0b10).0;
+        self.write_masked(address, value.as_word() << shift, 0xFFFF << shift)
+    }
+
+    fn write_byte(&mut self, address: isa::Address, value: isa::Byte)
+                  -> Result<()> {
+        if self.bypasses(address) {
+            self.demand_miss(address, true);
+            try!(self.wait_for_writebacks());
+            let mut next_level = self.next_level.borrow_mut();
+            next_level.set_retry(self.retry);
+            return next_level.write_byte(address, value);
+        }
+        let shift = 8 * (address % 4).0;
+        self.write_masked(address, value.as_word() << shift, 0xFF << shift)
+    }
+
+    fn invalidate(&mut self, address: isa::Address) -> Result<()> {
+        let (tag, index, _) = self.parse_address(address);
+        let normalized = self.normalize_address(address);
+        let buffered = match self.buffer {
+            Some(ref mut buffer) => buffer.remove(normalized),
+            None => None,
+        };
+        if let Some(entry) = buffered {
+            if entry.dirty {
+                self.queue_writeback(entry.address, entry.data);
+                return Err(MemoryError::CacheMiss {
+                    stall_cycles: self.next_level.borrow().latency(),
+                    retry: false,
+                });
+            }
+        }
+
+        match self.find_way(index, tag) {
+            Some(way) => {
+                let dirty = self.blocks[self.block_index(index, way)].dirty;
+                if dirty {
+                    self.write_back(index, way);
+                }
+                self.evicted(index, way);
+                self.discard(index, way);
+                // The way may already be reserved for an outstanding fetch
+                if !self.is_reserved(index, way) {
+                    self.free_ways[index as usize].push(way);
+                }
+                if dirty {
+                    return Err(MemoryError::CacheMiss {
+                        stall_cycles: self.next_level.borrow().latency(),
+                        retry: false,
+                    });
+                }
+                Ok(())
+            },
+            None => Ok(()),
+        }
+    }
+
+    fn invalidate_all(&mut self) -> Result<()> {
+        let mut addresses = match self.buffer {
+            Some(ref buffer) => buffer.blocks(),
+            None => Vec::new(),
+        };
+        for index in 0..self.num_sets {
+            for way in 0..self.num_ways {
+                let block = &self.blocks[self.block_index(index, way)];
+                if block.valid {
+                    addresses.push(self.block_address(index, block.tag));
+                }
+            }
+        }
+
+        let mut stall_cycles = 0;
+        for address in addresses {
+            if let Err(MemoryError::CacheMiss { stall_cycles: stall, .. }) =
+                self.invalidate(address) {
+                stall_cycles += stall;
+            }
+        }
+
+        if stall_cycles > 0 {
+            Err(MemoryError::CacheMiss {
+                stall_cycles: stall_cycles,
+                retry: false,
+            })
+        }
+        else {
+            Ok(())
+        }
+    }
+
+    fn prefetch(&mut self, address: isa::Address) {
+        let (tag, index, _) = self.parse_address(address);
+        let normalized = self.normalize_address(address);
+        if self.find_way(index, tag).is_some() ||
+            self.mshrs.iter().any(|mshr| mshr.address == normalized) ||
+            !self.can_fetch(address) {
+            return;
+        }
+
+        let outstanding = self.mshrs.len();
+        self.miss(address, None, 0);
+        // Nothing is fetched if the block was in an attached buffer
+        if self.mshrs.len() > outstanding {
+            if let Some(mshr) = self.mshrs.last_mut() {
+                mshr.prefetch = true;
+            }
+            self.prefetch_stats.issued += 1;
+        }
+    }
+
+    fn set_retry(&mut self, retry: bool) {
+        self.retry = retry;
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        let mut dirty = 0;
+        for index in 0..self.num_sets {
+            for way in 0..self.num_ways {
+                if self.blocks[self.block_index(index, way)].dirty {
+                    self.write_back(index, way);
+                    dirty += 1;
+                }
+            }
+        }
+        let buffered = match self.buffer {
+            Some(ref mut buffer) => buffer.take_dirty(),
+            None => Vec::new(),
+        };
+        for (address, data) in buffered {
+            self.queue_writeback(address, data);
+            dirty += 1;
+        }
+
+        if dirty > 0 {
+            Err(MemoryError::CacheMiss {
+                stall_cycles: dirty * self.next_level.borrow().latency(),
+                retry: false,
+            })
+        }
+        else {
+            Ok(())
+        }
+    }
+}
+
+impl<'a, T: EventHandler> CacheInterface for SetAssociativeCache<'a, T> {
+    fn cache_metadata(&self) -> CacheMetadata {
+        let tags = self.blocks.iter()
+            .map(|block| if block.valid {
+                Some(isa::Word(block.tag))
+            }
+            else {
+                None
+            })
+            .collect();
+
+        CacheMetadata {
+            num_sets: self.num_sets as usize,
+            num_ways: self.num_ways as usize,
+            num_block_words: self.block_words as usize,
+            tags: tags,
+        }
+    }
+
+    fn cache_stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    fn set_pc(&mut self, pc: isa::Address) {
+        self.pc = Some(pc);
+    }
+}
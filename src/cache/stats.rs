@@ -0,0 +1,210 @@
+// Copyright 2016 David Li
+// This file is part of rustv.
+
+// rustv is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// rustv is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with rustv.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+
+use isa;
+
+/// The cause of a miss, in Hill's 3C model.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MissClass {
+    /// The first reference to the block.
+    Compulsory,
+    /// A fully associative cache of the same size would also miss.
+    Capacity,
+    /// A fully associative cache of the same size would hit.
+    Conflict,
+}
+
+/// Misses counted by cause.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MissClasses {
+    pub compulsory: u32,
+    pub capacity: u32,
+    pub conflict: u32,
+}
+
+impl MissClasses {
+    pub fn record(&mut self, class: MissClass) {
+        match class {
+            MissClass::Compulsory => self.compulsory += 1,
+            MissClass::Capacity => self.capacity += 1,
+            MissClass::Conflict => self.conflict += 1,
+        }
+    }
+}
+
+/// Statistics of one cache. Each demand access is counted once, however
+/// many times it is retried; prefetches are not counted.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CacheStats {
+    pub reads: u32,
+    pub writes: u32,
+    pub hits: u32,
+    /// Misses that started a fetch
+    pub misses: u32,
+    /// Misses to a block already being fetched, which were merged into
+    /// its MSHR
+    pub mshr_hits: u32,
+    /// Blocks removed by replacement or invalidation
+    pub evictions: u32,
+    /// Blocks written to the next level
+    pub writebacks: u32,
+    /// The misses by cause, if classification is enabled
+    pub classes: Option<MissClasses>,
+}
+
+impl CacheStats {
+    pub fn accesses(&self) -> u32 {
+        self.reads + self.writes
+    }
+
+    /// The fraction of accesses that missed, including those merged
+    /// into an outstanding miss.
+    pub fn miss_rate(&self) -> f64 {
+        if self.accesses() == 0 {
+            0.0
+        }
+        else {
+            (self.misses + self.mshr_hits) as f64 / self.accesses() as f64
+        }
+    }
+}
+
+const COLUMNS: [&'static str; 11] = [
+    "reads", "writes", "hits", "misses", "mshr hits", "miss rate",
+    "evictions", "writebacks", "compulsory", "capacity", "conflict",
+];
+
+/// Format the statistics of several caches as a table, one row per
+/// cache. Miss classes are shown as `-` for caches not classifying
+/// misses.
+pub fn stats_table(caches: &[(&str, CacheStats)]) -> String {
+    let name_width = caches.iter()
+        .map(|&(name, _)| name.len())
+        .max()
+        .unwrap_or(0);
+    let mut rows = Vec::new();
+    for &(name, stats) in caches {
+        let classes = match stats.classes {
+            Some(classes) => vec![classes.compulsory.to_string(),
+                                  classes.capacity.to_string(),
+                                  classes.conflict.to_string()],
+            None => vec!["-".to_owned(); 3],
+        };
+        let mut row = vec![
+            stats.reads.to_string(),
+            stats.writes.to_string(),
+            stats.hits.to_string(),
+            stats.misses.to_string(),
+            stats.mshr_hits.to_string(),
+            format!("{:.2}%", 100.0 * stats.miss_rate()),
+            stats.evictions.to_string(),
+            stats.writebacks.to_string(),
+        ];
+        row.extend(classes);
+        rows.push((name, row));
+    }
+
+    let widths: Vec<usize> = (0..COLUMNS.len())
+        .map(|column| {
+            rows.iter()
+                .map(|&(_, ref row)| row[column].len())
+                .chain(Some(COLUMNS[column].len()))
+                .max()
+                .unwrap()
+        })
+        .collect();
+
+    let mut table = format!("{:1$}", "cache", name_width);
+    for (column, width) in widths.iter().enumerate() {
+        table.push_str(&format!("  {:>1$}", COLUMNS[column], width));
+    }
+    table.push('\n');
+    for (name, row) in rows {
+        table.push_str(&format!("{:1$}", name, name_width));
+        for (value, width) in row.iter().zip(widths.iter()) {
+            table.push_str(&format!("  {:>1$}", value, width));
+        }
+        table.push('\n');
+    }
+    table
+}
+
+impl fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", stats_table(&[("cache", *self)]))
+    }
+}
+
+/// Classifies misses by shadowing the cache with a fully associative
+/// LRU cache of as many lines, and remembering every block referenced.
+pub struct MissClassifier {
+    lines: usize,
+    seen: HashSet<isa::Address>,
+    /// The blocks in the shadow cache, with the time of their last use
+    last_use: HashMap<isa::Address, u64>,
+    by_use: BTreeMap<u64, isa::Address>,
+    clock: u64,
+}
+
+impl MissClassifier {
+    pub fn new(lines: usize) -> MissClassifier {
+        assert!(lines > 0);
+        MissClassifier {
+            lines: lines,
+            seen: HashSet::new(),
+            last_use: HashMap::new(),
+            by_use: BTreeMap::new(),
+            clock: 0,
+        }
+    }
+
+    /// Observe a demand access to a block, given its first address.
+    /// Returns the class of the miss if the real cache missed.
+    pub fn access(&mut self, block: isa::Address, missed: bool)
+                  -> Option<MissClass> {
+        self.clock += 1;
+        let shadow_hit = match self.last_use.insert(block, self.clock) {
+            Some(last_use) => {
+                self.by_use.remove(&last_use);
+                true
+            },
+            None => false,
+        };
+        self.by_use.insert(self.clock, block);
+        if self.by_use.len() > self.lines {
+            let oldest = *self.by_use.keys().next().unwrap();
+            let evicted = self.by_use.remove(&oldest).unwrap();
+            self.last_use.remove(&evicted);
+        }
+
+        let first = self.seen.insert(block);
+        if !missed {
+            None
+        }
+        else if first {
+            Some(MissClass::Compulsory)
+        }
+        else if shadow_hit {
+            Some(MissClass::Conflict)
+        }
+        else {
+            Some(MissClass::Capacity)
+        }
+    }
+}
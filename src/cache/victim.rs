@@ -0,0 +1,185 @@
+// Copyright 2016 David Li
+// This file is part of rustv.
+
+// rustv is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// rustv is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with rustv.  If not, see <http://www.gnu.org/licenses/>.
+
+use isa;
+
+/// What a `VictimBuffer` holds.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BufferKind {
+    /// Blocks evicted from the cache. A block found in the buffer is
+    /// swapped with the block it replaces, so each block is held either
+    /// in the cache or in the buffer.
+    Victim,
+    /// Copies of blocks fetched on misses. A block found in the buffer
+    /// is copied back into the cache.
+    Miss,
+}
+
+/// Statistics of a victim or miss cache.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct BufferStats {
+    /// Misses in the cache that looked in the buffer
+    pub lookups: u32,
+    /// Lookups that found the block, sparing a fetch from the next level
+    pub hits: u32,
+    /// Blocks the buffer replaced to make room
+    pub evictions: u32,
+}
+
+impl BufferStats {
+    /// The fraction of the cache's misses served by the buffer.
+    pub fn hit_rate(&self) -> f64 {
+        if self.lookups == 0 {
+            0.0
+        }
+        else {
+            self.hits as f64 / self.lookups as f64
+        }
+    }
+}
+
+/// A block held in a buffer.
+pub struct BufferEntry {
+    pub address: isa::Address,
+    pub data: Vec<isa::Word>,
+    pub dirty: bool,
+}
+
+/// A small fully associative buffer beside a cache (Jouppi, 1990), for
+/// removing conflict misses from direct-mapped caches. A miss in the
+/// cache looks in the buffer before going to the next level; if the
+/// block is there, it moves into the cache after the buffer's latency.
+/// Entries are replaced in LRU order.
+///
+/// The buffer is attached to a cache with `attach_buffer`.
+pub struct VictimBuffer {
+    kind: BufferKind,
+    lines: usize,
+    latency: u32,
+    /// Least recently used first
+    entries: Vec<BufferEntry>,
+    stats: BufferStats,
+}
+
+impl VictimBuffer {
+    pub fn new(kind: BufferKind, lines: usize, latency: u32) -> VictimBuffer {
+        assert!(lines > 0);
+        VictimBuffer {
+            kind: kind,
+            lines: lines,
+            latency: latency,
+            entries: Vec::new(),
+            stats: BufferStats::default(),
+        }
+    }
+
+    /// A victim cache of `lines` blocks.
+    pub fn victim_cache(lines: usize, latency: u32) -> VictimBuffer {
+        VictimBuffer::new(BufferKind::Victim, lines, latency)
+    }
+
+    /// A miss cache of `lines` blocks.
+    pub fn miss_cache(lines: usize, latency: u32) -> VictimBuffer {
+        VictimBuffer::new(BufferKind::Miss, lines, latency)
+    }
+
+    pub fn kind(&self) -> BufferKind {
+        self.kind
+    }
+
+    /// Cycles to move a block from the buffer into the cache.
+    pub fn latency(&self) -> u32 {
+        self.latency
+    }
+
+    pub fn stats(&self) -> BufferStats {
+        self.stats
+    }
+
+    /// The first addresses of the blocks held, least recently used first.
+    pub fn blocks(&self) -> Vec<isa::Address> {
+        self.entries.iter().map(|entry| entry.address).collect()
+    }
+
+    /// Look for a block after a miss in the cache, given its first
+    /// address. A victim cache gives up the block; a miss cache keeps
+    /// its copy.
+    pub fn lookup(&mut self, block: isa::Address) -> Option<BufferEntry> {
+        self.stats.lookups += 1;
+        let found = self.entries.iter().position(|entry| entry.address == block);
+        let index = match found {
+            Some(index) => index,
+            None => return None,
+        };
+
+        self.stats.hits += 1;
+        let entry = self.entries.remove(index);
+        match self.kind {
+            BufferKind::Victim => Some(entry),
+            BufferKind::Miss => {
+                let copy = BufferEntry {
+                    address: entry.address,
+                    data: entry.data.clone(),
+                    dirty: false,
+                };
+                self.entries.push(entry);
+                Some(copy)
+            },
+        }
+    }
+
+    /// Add a block as the most recently used. Returns the block
+    /// replaced to make room, if any.
+    pub fn insert(&mut self, entry: BufferEntry) -> Option<BufferEntry> {
+        self.remove(entry.address);
+        self.entries.push(entry);
+        if self.entries.len() > self.lines {
+            self.stats.evictions += 1;
+            Some(self.entries.remove(0))
+        }
+        else {
+            None
+        }
+    }
+
+    /// Remove a block without counting a lookup.
+    pub fn remove(&mut self, block: isa::Address) -> Option<BufferEntry> {
+        let found = self.entries.iter().position(|entry| entry.address == block);
+        match found {
+            Some(index) => Some(self.entries.remove(index)),
+            None => None,
+        }
+    }
+
+    /// The contents of a block, if held.
+    pub fn contents(&self, block: isa::Address) -> Option<&Vec<isa::Word>> {
+        self.entries.iter()
+            .find(|entry| entry.address == block)
+            .map(|entry| &entry.data)
+    }
+
+    /// Mark every block clean, returning the ones that were dirty.
+    pub fn take_dirty(&mut self) -> Vec<(isa::Address, Vec<isa::Word>)> {
+        let mut dirty = Vec::new();
+        for entry in self.entries.iter_mut() {
+            if entry.dirty {
+                entry.dirty = false;
+                dirty.push((entry.address, entry.data.clone()));
+            }
+        }
+        dirty
+    }
+}
@@ -0,0 +1,230 @@
+// Copyright 2016 David Li
+// This file is part of rustv.
+
+// rustv is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// rustv is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with rustv.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use isa;
+use isa::opcodes;
+use symbols::SymbolTable;
+
+/// The cycles attributed to one guest function.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FunctionStats {
+    /// Times the function was called
+    pub calls: u64,
+    /// Cycles spent in the function itself
+    pub self_cycles: u64,
+    /// Cycles spent in the function and everything it called
+    pub total_cycles: u64,
+}
+
+/// The name of the function at an address, or the address itself if
+/// there is no symbol for it.
+fn function_name(symbols: &SymbolTable, address: u32) -> String {
+    match symbols.lookup(isa::Word(address)) {
+        Some((name, 0)) => name.to_owned(),
+        Some((name, offset)) => format!("{}+0x{:x}", name, offset),
+        None => format!("0x{:x}", address),
+    }
+}
+
+/// Follows calls and returns to attribute cycles to guest functions.
+/// A call is a JAL or JALR that links into `ra`, and a return is a JALR
+/// through `ra` that discards the link. Functions are identified by the
+/// address they were called at; names come from a symbol table when
+/// reporting.
+///
+/// Each cycle is charged to the call stack as of the end of the cycle,
+/// so a call's cycle belongs to the callee, and a return's to the
+/// caller.
+pub struct CallGraph {
+    /// The entry points of the active functions, outermost first
+    stack: Vec<u32>,
+    /// Cycles by the call stack they were spent in
+    stacks: BTreeMap<Vec<u32>, u64>,
+    /// Call counts by caller and callee
+    edges: BTreeMap<(u32, u32), u64>,
+}
+
+impl CallGraph {
+    /// Start with the function containing the entry point.
+    pub fn new(entry: isa::Address) -> CallGraph {
+        CallGraph {
+            stack: vec![entry.0],
+            stacks: BTreeMap::new(),
+            edges: BTreeMap::new(),
+        }
+    }
+
+    /// Follow an instruction that retired, given the PC after it.
+    pub fn retire(&mut self, inst: isa::Instruction, next_pc: isa::Address) {
+        let link = inst.rd() == isa::Register::X1;
+        match inst.opcode() {
+            opcodes::JAL | opcodes::JALR if link => {
+                let caller = *self.stack.last().unwrap();
+                *self.edges.entry((caller, next_pc.0)).or_insert(0) += 1;
+                self.stack.push(next_pc.0);
+            },
+            opcodes::JALR if inst.rd() == isa::Register::X0 &&
+                inst.rs1() == isa::Register::X1 => {
+                // Returning from the outermost function halts the core
+                if self.stack.len() > 1 {
+                    self.stack.pop();
+                }
+            },
+            _ => {},
+        }
+    }
+
+    /// Charge a cycle to the current call stack.
+    pub fn charge(&mut self) {
+        let found = match self.stacks.get_mut(&self.stack) {
+            Some(cycles) => {
+                *cycles += 1;
+                true
+            },
+            None => false,
+        };
+        if !found {
+            self.stacks.insert(self.stack.clone(), 1);
+        }
+    }
+
+    /// The active functions, outermost first.
+    pub fn stack(&self) -> Vec<isa::Address> {
+        self.stack.iter().map(|&address| isa::Word(address)).collect()
+    }
+
+    /// Every function seen, by the cycles spent in it, most first.
+    pub fn functions(&self) -> Vec<(isa::Address, FunctionStats)> {
+        let mut functions = BTreeMap::new();
+        for (&(caller, callee), &count) in self.edges.iter() {
+            // A caller may not have been charged since it was entered
+            functions.entry(caller).or_insert_with(FunctionStats::default);
+            functions.entry(callee).or_insert_with(FunctionStats::default).calls += count;
+        }
+        for (stack, &cycles) in self.stacks.iter() {
+            // Recursive functions are only charged once per cycle
+            let active = stack.iter().cloned().collect::<BTreeSet<_>>();
+            for address in active {
+                functions.entry(address).or_insert_with(FunctionStats::default)
+                    .total_cycles += cycles;
+            }
+            functions.get_mut(stack.last().unwrap()).unwrap().self_cycles += cycles;
+        }
+
+        let mut functions = functions.into_iter()
+            .map(|(address, stats)| (isa::Word(address), stats))
+            .collect::<Vec<_>>();
+        functions.sort_by(|a, b| {
+            (b.1.self_cycles, b.1.total_cycles, a.0)
+                .cmp(&(a.1.self_cycles, a.1.total_cycles, b.0))
+        });
+        functions
+    }
+
+    /// The cycles as folded stacks, one `outer;inner cycles` line per
+    /// call stack, for `flamegraph.pl` and similar tools.
+    pub fn folded(&self, symbols: &SymbolTable) -> String {
+        let mut folded = String::new();
+        for (stack, &cycles) in self.stacks.iter() {
+            let names = stack.iter()
+                .map(|&address| function_name(symbols, address))
+                .collect::<Vec<_>>();
+            folded.push_str(&format!("{} {}\n", names.join(";"), cycles));
+        }
+        folded
+    }
+
+    /// A flat profile and call graph, laid out as `gprof` does, in
+    /// cycles rather than seconds.
+    pub fn gprof(&self, symbols: &SymbolTable) -> String {
+        let functions = self.functions();
+        let cycles = self.stacks.values().fold(0, |total, &cycles| total + cycles);
+        let percent = |part: u64| {
+            if cycles == 0 {
+                0.0
+            }
+            else {
+                100.0 * part as f64 / cycles as f64
+            }
+        };
+        let per_call = |part: u64, calls: u64| {
+            if calls == 0 {
+                String::new()
+            }
+            else {
+                format!("{:.2}", part as f64 / calls as f64)
+            }
+        };
+
+        let mut report = String::new();
+        report.push_str("Flat profile:\n\n");
+        report.push_str("  %   cumulative     self               self     total\n");
+        report.push_str(" time     cycles   cycles     calls  cyc/call  cyc/call  name\n");
+        let mut cumulative = 0;
+        for &(address, stats) in functions.iter() {
+            cumulative += stats.self_cycles;
+            report.push_str(&format!(
+                "{:6.2} {:>10} {:>8} {:>9} {:>9} {:>9}  {}\n",
+                percent(stats.self_cycles), cumulative, stats.self_cycles,
+                if stats.calls == 0 { String::new() } else { stats.calls.to_string() },
+                per_call(stats.self_cycles, stats.calls),
+                per_call(stats.total_cycles, stats.calls),
+                function_name(symbols, address.0)));
+        }
+
+        // Number functions in the order of the flat profile
+        let index = functions.iter().enumerate()
+            .map(|(i, &(address, _))| (address.0, i + 1))
+            .collect::<BTreeMap<_, _>>();
+        let called = functions.iter()
+            .map(|&(address, stats)| (address.0, stats.calls))
+            .collect::<BTreeMap<_, _>>();
+
+        report.push_str("\nCall graph:\n\n");
+        report.push_str("index  % time     self  children    called  name\n");
+        for &(address, stats) in functions.iter() {
+            for (&(caller, callee), &count) in self.edges.iter() {
+                if callee == address.0 {
+                    report.push_str(&format!(
+                        "{:>42}      {} [{}]\n",
+                        format!("{}/{}", count, stats.calls),
+                        function_name(symbols, caller), index[&caller]));
+                }
+            }
+            let name = function_name(symbols, address.0);
+            let primary = format!("[{}]", index[&address.0]);
+            report.push_str(&format!(
+                "{:<6} {:6.1} {:>8} {:>9} {:>9}  {} {}\n",
+                primary, percent(stats.total_cycles), stats.self_cycles,
+                stats.total_cycles - stats.self_cycles,
+                if stats.calls == 0 { String::new() } else { stats.calls.to_string() },
+                name, primary));
+            for (&(caller, callee), &count) in self.edges.iter() {
+                if caller == address.0 {
+                    report.push_str(&format!(
+                        "{:>42}      {} [{}]\n",
+                        format!("{}/{}", count, called[&callee]),
+                        function_name(symbols, callee), index[&callee]));
+                }
+            }
+            report.push_str("-----------------------------------------------\n");
+        }
+
+        report
+    }
+}
@@ -0,0 +1,358 @@
+// Copyright 2016 David Li
+// This file is part of rustv.
+
+// rustv is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// rustv is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with rustv.  If not, see <http://www.gnu.org/licenses/>.
+
+use isa::{self, Instruction, IsaType, Register};
+use isa::{funct3, funct7, opcodes};
+use memory::MemoryInterface;
+use symbols::SymbolTable;
+
+/// The mnemonic of an instruction, ignoring pseudo-instruction aliases,
+/// or `None` if the instruction cannot be decoded.
+pub fn mnemonic(inst: Instruction) -> Option<&'static str> {
+    match inst.opcode() {
+        opcodes::LUI => Some("lui"),
+        opcodes::AUIPC => Some("auipc"),
+        opcodes::JAL => Some("jal"),
+        opcodes::JALR if inst.funct3() == 0 => Some("jalr"),
+        opcodes::BRANCH => match inst.funct3() {
+            funct3::BEQ => Some("beq"),
+            funct3::BNE => Some("bne"),
+            funct3::BLT => Some("blt"),
+            funct3::BGE => Some("bge"),
+            funct3::BLTU => Some("bltu"),
+            funct3::BGEU => Some("bgeu"),
+            _ => None,
+        },
+        opcodes::LOAD => match inst.funct3() {
+            funct3::LB => Some("lb"),
+            funct3::LH => Some("lh"),
+            funct3::LW => Some("lw"),
+            funct3::LBU => Some("lbu"),
+            funct3::LHU => Some("lhu"),
+            _ => None,
+        },
+        opcodes::STORE => match inst.funct3() {
+            funct3::SB => Some("sb"),
+            funct3::SH => Some("sh"),
+            funct3::SW => Some("sw"),
+            _ => None,
+        },
+        opcodes::INTEGER_IMMEDIATE => match inst.funct3() {
+            funct3::ADDI => Some("addi"),
+            funct3::SLLI if inst.funct7() == 0 => Some("slli"),
+            funct3::SLTI => Some("slti"),
+            funct3::SLTIU => Some("sltiu"),
+            funct3::XORI => Some("xori"),
+            funct3::SRLI_SRAI => match inst.funct7() {
+                funct7::SRLI => Some("srli"),
+                funct7::SRAI => Some("srai"),
+                _ => None,
+            },
+            funct3::ORI => Some("ori"),
+            funct3::ANDI => Some("andi"),
+            _ => None,
+        },
+        opcodes::INTEGER_REGISTER => match (inst.funct7(), inst.funct3()) {
+            (funct7::ADD_SRL, funct3::ADD_SUB) => Some("add"),
+            (funct7::SUB_SRA, funct3::ADD_SUB) => Some("sub"),
+            (0, funct3::SLL) => Some("sll"),
+            (0, funct3::SLT) => Some("slt"),
+            (0, funct3::SLTU) => Some("sltu"),
+            (0, funct3::XOR) => Some("xor"),
+            (funct7::ADD_SRL, funct3::SRL_SRA) => Some("srl"),
+            (funct7::SUB_SRA, funct3::SRL_SRA) => Some("sra"),
+            (0, funct3::OR) => Some("or"),
+            (0, funct3::AND) => Some("and"),
+            (funct7::MULDIV, funct3::MUL) => Some("mul"),
+            (funct7::MULDIV, funct3::MULH) => Some("mulh"),
+            (funct7::MULDIV, funct3::MULHSU) => Some("mulhsu"),
+            (funct7::MULDIV, funct3::MULHU) => Some("mulhu"),
+            (funct7::MULDIV, funct3::DIV) => Some("div"),
+            (funct7::MULDIV, funct3::DIVU) => Some("divu"),
+            (funct7::MULDIV, funct3::REM) => Some("rem"),
+            (funct7::MULDIV, funct3::REMU) => Some("remu"),
+            _ => None,
+        },
+        opcodes::MISC_MEM => match inst.funct3() {
+            funct3::FENCE => Some("fence"),
+            funct3::FENCE_I => Some("fence.i"),
+            _ => None,
+        },
+        opcodes::SYSTEM => match inst.funct3() {
+            funct3::PRIV => match inst.word().0 {
+                0x00000073 => Some("ecall"),
+                0x00100073 => Some("ebreak"),
+                _ => None,
+            },
+            funct3::CSRRW => Some("csrrw"),
+            funct3::CSRRS => Some("csrrs"),
+            funct3::CSRRC => Some("csrrc"),
+            funct3::CSRRWI => Some("csrrwi"),
+            funct3::CSRRSI => Some("csrrsi"),
+            funct3::CSRRCI => Some("csrrci"),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn csr_name(csr: u32) -> Option<&'static str> {
+    match csr {
+        0x001 => Some("fflags"),
+        0x002 => Some("frm"),
+        0x003 => Some("fcsr"),
+        0x300 => Some("mstatus"),
+        0x301 => Some("misa"),
+        0x304 => Some("mie"),
+        0x305 => Some("mtvec"),
+        0x340 => Some("mscratch"),
+        0x341 => Some("mepc"),
+        0x342 => Some("mcause"),
+        0x343 => Some("mtval"),
+        0x344 => Some("mip"),
+        0xB00 => Some("mcycle"),
+        0xB02 => Some("minstret"),
+        0xC00 => Some("cycle"),
+        0xC01 => Some("time"),
+        0xC02 => Some("instret"),
+        0xC80 => Some("cycleh"),
+        0xC81 => Some("timeh"),
+        0xC82 => Some("instreth"),
+        0xF14 => Some("mhartid"),
+        _ => None,
+    }
+}
+
+fn fence_set(bits: u32) -> String {
+    let mut set = String::new();
+    for &(bit, name) in [(8, 'i'), (4, 'o'), (2, 'r'), (1, 'w')].iter() {
+        if bits & bit != 0 {
+            set.push(name);
+        }
+    }
+    set
+}
+
+/// Renders instructions the way `objdump -d` does: ABI register names,
+/// pseudo-instruction aliases, and branch targets annotated with the
+/// nearest symbol.
+pub struct Disassembler<'s> {
+    symbols: Option<&'s SymbolTable>,
+}
+
+impl<'s> Disassembler<'s> {
+    pub fn new() -> Disassembler<'s> {
+        Disassembler {
+            symbols: None,
+        }
+    }
+
+    pub fn with_symbols(symbols: &'s SymbolTable) -> Disassembler<'s> {
+        Disassembler {
+            symbols: Some(symbols),
+        }
+    }
+
+    pub fn symbols(&self) -> Option<&'s SymbolTable> {
+        self.symbols
+    }
+
+    fn target(&self, target: isa::Address) -> String {
+        let symbol = self.symbols.and_then(|symbols| symbols.lookup(target));
+        match symbol {
+            Some((name, 0)) => format!("{:x} <{}>", target, name),
+            Some((name, offset)) =>
+                format!("{:x} <{}+0x{:x}>", target, name, offset),
+            None => format!("{:x}", target),
+        }
+    }
+
+    fn csr(&self, inst: Instruction) -> String {
+        let csr = (inst.word() >> 20).0;
+        match csr_name(csr) {
+            Some(name) => name.to_owned(),
+            None => format!("0x{:x}", csr),
+        }
+    }
+
+    /// The mnemonic and operands of an instruction at the given PC,
+    /// separated by a tab.
+    pub fn disassemble(&self, pc: isa::Address, inst: Instruction) -> String {
+        let name = match mnemonic(inst) {
+            Some(name) => name,
+            None => return format!(".4byte\t0x{:x}", inst.word()),
+        };
+
+        let rd = inst.rd();
+        let rs1 = inst.rs1();
+        let rs2 = inst.rs2();
+        let (d, s1, s2) = (rd.abi_name(), rs1.abi_name(), rs2.abi_name());
+        let zero = Register::X0;
+
+        match inst.opcode() {
+            opcodes::LUI | opcodes::AUIPC => {
+                format!("{}\t{},0x{:x}", name, d, inst.u_imm().as_word() >> 12)
+            },
+            opcodes::JAL => {
+                let target = pc.as_signed_word().wrapping_add(inst.uj_imm()).as_address();
+                if rd == zero {
+                    format!("j\t{}", self.target(target))
+                }
+                else {
+                    format!("jal\t{},{}", d, self.target(target))
+                }
+            },
+            opcodes::JALR => {
+                let imm = inst.i_imm().0;
+                if rd == zero && rs1 == Register::X1 && imm == 0 {
+                    "ret".to_owned()
+                }
+                else if rd == zero && imm == 0 {
+                    format!("jr\t{}", s1)
+                }
+                else if rd == Register::X1 && imm == 0 {
+                    format!("jalr\t{}", s1)
+                }
+                else {
+                    format!("jalr\t{},{}({})", d, imm, s1)
+                }
+            },
+            opcodes::BRANCH => {
+                let target = pc.as_signed_word().wrapping_add(inst.sb_imm()).as_address();
+                let target = self.target(target);
+                match inst.funct3() {
+                    funct3::BEQ if rs2 == zero =>
+                        format!("beqz\t{},{}", s1, target),
+                    funct3::BNE if rs2 == zero =>
+                        format!("bnez\t{},{}", s1, target),
+                    funct3::BGE if rs1 == zero =>
+                        format!("blez\t{},{}", s2, target),
+                    funct3::BGE if rs2 == zero =>
+                        format!("bgez\t{},{}", s1, target),
+                    funct3::BLT if rs2 == zero =>
+                        format!("bltz\t{},{}", s1, target),
+                    funct3::BLT if rs1 == zero =>
+                        format!("bgtz\t{},{}", s2, target),
+                    _ => format!("{}\t{},{},{}", name, s1, s2, target),
+                }
+            },
+            opcodes::LOAD => {
+                format!("{}\t{},{}({})", name, d, inst.i_imm().0, s1)
+            },
+            opcodes::STORE => {
+                format!("{}\t{},{}({})", name, s2, inst.s_imm().0, s1)
+            },
+            opcodes::INTEGER_IMMEDIATE => {
+                let imm = inst.i_imm().0;
+                match inst.funct3() {
+                    funct3::ADDI if rd == zero && rs1 == zero && imm == 0 =>
+                        "nop".to_owned(),
+                    funct3::ADDI if rs1 == zero =>
+                        format!("li\t{},{}", d, imm),
+                    funct3::ADDI if imm == 0 =>
+                        format!("mv\t{},{}", d, s1),
+                    funct3::XORI if imm == -1 =>
+                        format!("not\t{},{}", d, s1),
+                    funct3::SLTIU if imm == 1 =>
+                        format!("seqz\t{},{}", d, s1),
+                    funct3::SLLI | funct3::SRLI_SRAI =>
+                        format!("{}\t{},{},0x{:x}", name, d, s1, inst.shamt()),
+                    _ => format!("{}\t{},{},{}", name, d, s1, imm),
+                }
+            },
+            opcodes::INTEGER_REGISTER => match name {
+                "sub" if rs1 == zero => format!("neg\t{},{}", d, s2),
+                "sltu" if rs1 == zero => format!("snez\t{},{}", d, s2),
+                "slt" if rs2 == zero => format!("sltz\t{},{}", d, s1),
+                "slt" if rs1 == zero => format!("sgtz\t{},{}", d, s2),
+                _ => format!("{}\t{},{},{}", name, d, s1, s2),
+            },
+            opcodes::MISC_MEM => {
+                let pred = (inst.word() >> 24).0 & 0xF;
+                let succ = (inst.word() >> 20).0 & 0xF;
+                if inst.funct3() == funct3::FENCE_I || (pred == 0xF && succ == 0xF) {
+                    name.to_owned()
+                }
+                else {
+                    format!("{}\t{},{}", name, fence_set(pred), fence_set(succ))
+                }
+            },
+            opcodes::SYSTEM => {
+                let csr = self.csr(inst);
+                let zimm = rs1.as_num();
+                match inst.funct3() {
+                    funct3::PRIV => name.to_owned(),
+                    funct3::CSRRS if rs1 == zero => match &csr[..] {
+                        "cycle" | "time" | "instret" |
+                        "cycleh" | "timeh" | "instreth" =>
+                            format!("rd{}\t{}", csr, d),
+                        _ => format!("csrr\t{},{}", d, csr),
+                    },
+                    funct3::CSRRW if rd == zero =>
+                        format!("csrw\t{},{}", csr, s1),
+                    funct3::CSRRS if rd == zero =>
+                        format!("csrs\t{},{}", csr, s1),
+                    funct3::CSRRC if rd == zero =>
+                        format!("csrc\t{},{}", csr, s1),
+                    funct3::CSRRWI if rd == zero =>
+                        format!("csrwi\t{},{}", csr, zimm),
+                    funct3::CSRRSI if rd == zero =>
+                        format!("csrsi\t{},{}", csr, zimm),
+                    funct3::CSRRCI if rd == zero =>
+                        format!("csrci\t{},{}", csr, zimm),
+                    funct3::CSRRW | funct3::CSRRS | funct3::CSRRC =>
+                        format!("{}\t{},{},{}", name, d, csr, s1),
+                    _ => format!("{}\t{},{},{}", name, d, csr, zimm),
+                }
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    /// A single line of `objdump -d` output.
+    pub fn format_line(&self, pc: isa::Address, inst: Instruction) -> String {
+        format!("{:8x}:\t{:08x}          \t{}",
+                pc.0, inst.word().0, self.disassemble(pc, inst))
+    }
+
+    /// Disassemble the words in `[start, end)`, with a header before
+    /// each symbol as `objdump -d` prints it. The memory should be the
+    /// backing store, not a cache, since misses are treated as the end
+    /// of the listing.
+    pub fn disassemble_range(&self, memory: &mut MemoryInterface,
+                             start: isa::Address, end: isa::Address) -> String {
+        let mut listing = String::new();
+        let mut pc = start;
+
+        while pc < end {
+            if let Some(name) = self.symbols.and_then(|s| s.get(pc)) {
+                listing.push_str(&format!("\n{:08x} <{}>:\n", pc.0, name));
+            }
+
+            match memory.read_instruction(pc) {
+                Some(inst) => {
+                    listing.push_str(&self.format_line(pc, inst));
+                    listing.push('\n');
+                },
+                None => break,
+            }
+
+            pc += 4;
+        }
+
+        listing
+    }
+}
@@ -0,0 +1,294 @@
+// Copyright 2016 David Li
+// This file is part of rustv.
+
+// rustv is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// rustv is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with rustv.  If not, see <http://www.gnu.org/licenses/>.
+
+use isa::{self, Instruction, IsaType};
+use isa::{funct3, funct7, opcodes};
+use memory::{MemoryError, MemoryInterface, Mmu};
+use register_file::RegisterFile;
+use retirement::Retirement;
+use syscall::SyscallHandler;
+use trap::Trap;
+
+/// Compute the result of an `INTEGER_REGISTER` or `INTEGER_IMMEDIATE`
+/// instruction. For the latter, `src2` is the immediate. Returns `None`
+/// for illegal encodings.
+pub fn integer_result(inst: Instruction, src1: isa::Word, src2: isa::Word)
+                      -> Option<isa::Word> {
+    let immediate = inst.opcode() == opcodes::INTEGER_IMMEDIATE;
+    let funct7 = inst.funct7();
+    let (a, b) = (src1.0, src2.0);
+    let (signed_a, signed_b) = (a as i32, b as i32);
+    let shamt = b & 0x1F;
+
+    let value = if !immediate && funct7 == funct7::MULDIV {
+        match inst.funct3() {
+            funct3::MUL => a.wrapping_mul(b),
+            funct3::MULH => (((signed_a as i64) * (signed_b as i64)) >> 32) as u32,
+            funct3::MULHSU => (((signed_a as i64) * (b as i64)) >> 32) as u32,
+            funct3::MULHU => (((a as u64) * (b as u64)) >> 32) as u32,
+            funct3::DIV => match signed_b {
+                0 => 0xFFFFFFFF,
+                -1 if signed_a == ::std::i32::MIN => a,
+                _ => (signed_a / signed_b) as u32,
+            },
+            funct3::DIVU => if b == 0 { 0xFFFFFFFF } else { a / b },
+            funct3::REM => match signed_b {
+                0 => a,
+                -1 => 0,
+                _ => (signed_a % signed_b) as u32,
+            },
+            funct3::REMU => if b == 0 { a } else { a % b },
+            _ => return None,
+        }
+    }
+    else {
+        // For immediates, funct7 is part of the immediate except in shifts
+        let base = immediate || funct7 == 0;
+        match inst.funct3() {
+            funct3::ADD_SUB if base => a.wrapping_add(b),
+            funct3::ADD_SUB if funct7 == funct7::SUB_SRA => a.wrapping_sub(b),
+            funct3::SLL if funct7 == 0 => a << shamt,
+            funct3::SLT if base => (signed_a < signed_b) as u32,
+            funct3::SLTU if base => (a < b) as u32,
+            funct3::XOR if base => a ^ b,
+            funct3::SRL_SRA if funct7 == funct7::ADD_SRL => a >> shamt,
+            funct3::SRL_SRA if funct7 == funct7::SUB_SRA => (signed_a >> shamt) as u32,
+            funct3::OR if base => a | b,
+            funct3::AND if base => a & b,
+            _ => return None,
+        }
+    };
+
+    Some(isa::Word(value))
+}
+
+/// Whether a branch is taken, or `None` for illegal encodings.
+pub fn branch_taken(inst: Instruction, src1: isa::Word, src2: isa::Word) -> Option<bool> {
+    match inst.funct3() {
+        funct3::BEQ => Some(src1 == src2),
+        funct3::BNE => Some(src1 != src2),
+        funct3::BLT => Some(src1.as_signed_word() < src2.as_signed_word()),
+        funct3::BGE => Some(src1.as_signed_word() >= src2.as_signed_word()),
+        funct3::BLTU => Some(src1 < src2),
+        funct3::BGEU => Some(src1 >= src2),
+        _ => None,
+    }
+}
+
+/// A timing-free instruction set simulator that retires one instruction
+/// per step against flat memory. It is written independently of
+/// `Core` so that it can serve as a reference model, but follows the
+/// same conventions: jumping to address 0 halts the core, and the
+/// `SYSTEM` instructions other than `ecall` are no-ops.
+pub struct FunctionalCore<'a> {
+    id: usize,
+    pc: isa::Address,
+    registers: RegisterFile,
+    running: bool,
+    mmu: Box<Mmu + 'a>,
+    instructions_retired: u64,
+    retired: Option<Retirement>,
+}
+
+impl<'a> FunctionalCore<'a> {
+    pub fn new(id: usize, entry: isa::Address, sp: isa::Address,
+               mmu: Box<Mmu + 'a>) -> FunctionalCore<'a> {
+        let mut registers = RegisterFile::new();
+        registers.write_word(isa::Register::X2, sp);
+        FunctionalCore {
+            id: id,
+            pc: entry,
+            registers: registers,
+            running: true,
+            mmu: mmu,
+            instructions_retired: 0,
+            retired: None,
+        }
+    }
+
+    pub fn registers(&mut self) -> &mut RegisterFile {
+        &mut self.registers
+    }
+
+    pub fn pc(&self) -> isa::Address {
+        self.pc
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    pub fn instructions_retired(&self) -> u64 {
+        self.instructions_retired
+    }
+
+    /// Take the record of the instruction retired by the last step.
+    pub fn take_retired(&mut self) -> Option<Retirement> {
+        self.retired.take()
+    }
+
+    fn memory_result<T>(result: Result<T, MemoryError>) -> Option<T> {
+        match result {
+            Ok(value) => Some(value),
+            Err(MemoryError::InvalidAddress) => None,
+            Err(MemoryError::CacheMiss { .. }) =>
+                panic!("FunctionalCore must be given flat memory, not a cache"),
+        }
+    }
+
+    /// Execute one instruction, returning the trap it raised, if any.
+    pub fn step(&mut self, memory: &mut MemoryInterface,
+                system: &mut SyscallHandler) -> Option<Trap> {
+        if !self.running {
+            return None;
+        }
+
+        self.retired = None;
+        let pc = self.pc;
+        let inst = match memory.read_instruction(self.mmu.translate(pc)) {
+            Some(inst) => inst,
+            None => {
+                self.running = false;
+                return Some(Trap::IllegalFetch {
+                    address: pc,
+                });
+            },
+        };
+        let illegal = Trap::IllegalInstruction {
+            address: pc,
+            instruction: inst,
+        };
+
+        let mut retirement = Retirement::new(pc, inst, &mut self.registers);
+        let rs1 = self.registers.read_word(inst.rs1());
+        let rs2 = self.registers.read_word(inst.rs2());
+        let mut next_pc = pc.wrapping_add(isa::Word(4));
+        let mut trap = None;
+
+        match inst.opcode() {
+            opcodes::LUI => {
+                self.registers.write_word(inst.rd(), inst.u_imm().as_word());
+            },
+            opcodes::AUIPC => {
+                let value = pc.wrapping_add(inst.u_imm().as_word());
+                self.registers.write_word(inst.rd(), value);
+            },
+            opcodes::JAL => {
+                self.registers.write_word(inst.rd(), next_pc);
+                next_pc = pc.wrapping_add(inst.uj_imm().as_word());
+            },
+            opcodes::JALR if inst.funct3() == 0 => {
+                let target = rs1.wrapping_add(inst.i_imm().as_word());
+                if target == isa::Word(0) {
+                    self.running = false;
+                }
+                else {
+                    self.registers.write_word(inst.rd(), next_pc);
+                    next_pc = target;
+                }
+            },
+            opcodes::BRANCH => match branch_taken(inst, rs1, rs2) {
+                Some(true) => next_pc = pc.wrapping_add(inst.sb_imm().as_word()),
+                Some(false) => {},
+                None => trap = Some(illegal),
+            },
+            opcodes::INTEGER_IMMEDIATE => {
+                match integer_result(inst, rs1, inst.i_imm().as_word()) {
+                    Some(value) => self.registers.write_word(inst.rd(), value),
+                    None => trap = Some(illegal),
+                }
+            },
+            opcodes::INTEGER_REGISTER => {
+                match integer_result(inst, rs1, rs2) {
+                    Some(value) => self.registers.write_word(inst.rd(), value),
+                    None => trap = Some(illegal),
+                }
+            },
+            opcodes::LOAD => {
+                let address = rs1.wrapping_add(inst.i_imm().as_word());
+                let physical = self.mmu.translate(address);
+                let value = match inst.funct3() {
+                    funct3::LB => FunctionalCore::memory_result(
+                        memory.read_byte(physical).map(|b| b.as_signed_word().as_word())),
+                    funct3::LH => FunctionalCore::memory_result(
+                        memory.read_halfword(physical).map(|h| h.as_signed_word().as_word())),
+                    funct3::LW => FunctionalCore::memory_result(
+                        memory.read_word(physical)),
+                    funct3::LBU => FunctionalCore::memory_result(
+                        memory.read_byte(physical).map(|b| b.as_word())),
+                    funct3::LHU => FunctionalCore::memory_result(
+                        memory.read_halfword(physical).map(|h| h.as_word())),
+                    _ => {
+                        trap = Some(illegal);
+                        None
+                    },
+                };
+                match value {
+                    Some(value) => self.registers.write_word(inst.rd(), value),
+                    None if trap.is_none() => {
+                        trap = Some(Trap::IllegalRead {
+                            address: pc,
+                            instruction: inst,
+                            memory_address: physical,
+                        });
+                    },
+                    None => {},
+                }
+            },
+            opcodes::STORE => {
+                let address = rs1.wrapping_add(inst.s_imm().as_word());
+                let physical = self.mmu.translate(address);
+                let result = match inst.funct3() {
+                    funct3::SB => FunctionalCore::memory_result(
+                        memory.write_byte(physical, rs2.as_byte())),
+                    funct3::SH => FunctionalCore::memory_result(
+                        memory.write_halfword(physical, rs2.as_half_word())),
+                    funct3::SW => FunctionalCore::memory_result(
+                        memory.write_word(physical, rs2)),
+                    _ => {
+                        trap = Some(illegal);
+                        Some(())
+                    },
+                };
+                if result.is_none() {
+                    trap = Some(Trap::IllegalWrite {
+                        address: pc,
+                        instruction: inst,
+                        memory_address: physical,
+                        memory_value: rs2,
+                    });
+                }
+            },
+            opcodes::MISC_MEM => {},
+            opcodes::SYSTEM => {
+                if inst.word() == isa::Word(0x00000073) {
+                    trap = system.syscall(self.id, &mut self.registers, &*self.mmu);
+                }
+            },
+            _ => trap = Some(illegal),
+        }
+
+        if trap.is_some() {
+            self.running = false;
+        }
+        self.pc = next_pc;
+        self.instructions_retired += 1;
+        retirement.complete(&mut self.registers, self.running);
+        self.retired = Some(retirement);
+
+        trap
+    }
+}
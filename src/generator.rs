@@ -0,0 +1,327 @@
+// Copyright 2016 David Li
+// This file is part of rustv.
+
+// rustv is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// rustv is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with rustv.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::rc::Rc;
+use std::cell::RefCell;
+
+use cache::{DirectMappedCache, EmptyEventHandler};
+use functional::FunctionalCore;
+use isa::{self, Instruction, Register};
+use memory::{IdentityMmu, Memory, MemoryInterface};
+use rng::XorShiftRng;
+use simulator::{Core, HaltReason, Simulator};
+use syscall::NoSyscalls;
+
+/// Register holding the base of the data region. Generated code never
+/// writes it, so every memory access stays in bounds.
+pub const DATA_REGISTER: Register = Register::X31;
+
+/// How far forward a branch or jump may skip, in instructions.
+const MAX_SKIP: u32 = 16;
+
+/// A randomly generated program and its initial data.
+pub struct GeneratedProgram {
+    pub text_base: isa::Address,
+    pub instructions: Vec<Instruction>,
+    pub data_base: isa::Address,
+    pub data: Vec<isa::Word>,
+}
+
+impl GeneratedProgram {
+    /// The size of memory needed to hold the program, in words.
+    pub fn memory_words(&self) -> usize {
+        (self.data_base.0 as usize) / 4 + self.data.len()
+    }
+
+    pub fn load(&self, memory: &mut Memory) {
+        let mmu = IdentityMmu::new();
+        let mut text = Vec::new();
+        for inst in self.instructions.iter() {
+            for offset in 0..4 {
+                text.push((inst.word().0 >> (8 * offset)) as u8);
+            }
+        }
+        let mut data = Vec::new();
+        for word in self.data.iter() {
+            for offset in 0..4 {
+                data.push((word.0 >> (8 * offset)) as u8);
+            }
+        }
+        memory.write_segment(&mmu, &text, self.text_base.0 as usize);
+        memory.write_segment(&mmu, &data, self.data_base.0 as usize);
+    }
+}
+
+/// Generates random RV32I programs, optionally with the M extension,
+/// that always terminate: all control flow goes forward, and the
+/// program ends by jumping to address 0. Loads and stores are relative
+/// to `DATA_REGISTER` with in-bounds, aligned offsets.
+pub struct ProgramGenerator {
+    rng: XorShiftRng,
+    multiply: bool,
+    data_words: u32,
+}
+
+impl ProgramGenerator {
+    pub fn new(seed: u64, multiply: bool) -> ProgramGenerator {
+        ProgramGenerator {
+            rng: XorShiftRng::new(seed),
+            multiply: multiply,
+            data_words: 256,
+        }
+    }
+
+    fn source(&mut self) -> Register {
+        Register::from_num(self.rng.range(0, 32))
+    }
+
+    fn destination(&mut self) -> Register {
+        // Occasionally write x0, which must be discarded
+        if self.rng.chance(1, 32) {
+            Register::X0
+        }
+        else {
+            Register::from_num(self.rng.range(1, DATA_REGISTER.as_num() as u32))
+        }
+    }
+
+    fn immediate(&mut self) -> i32 {
+        self.rng.range(0, 4096) as i32 - 2048
+    }
+
+    fn upper_immediate(&mut self) -> i32 {
+        (self.rng.next_u32() & 0xFFFFF000) as i32
+    }
+
+    fn load_constant(&mut self, rd: Register, value: u32, program: &mut Vec<Instruction>) {
+        let low = ((value & 0xFFF) as i32 ^ 0x800) - 0x800;
+        let high = value.wrapping_sub(low as u32) as i32;
+        program.push(Instruction::lui(rd, high).unwrap());
+        program.push(Instruction::addi(rd, rd, low).unwrap());
+    }
+
+    /// Byte offset into the data region for an access of `width` bytes.
+    fn data_offset(&mut self, width: u32) -> i32 {
+        let slots = self.data_words * 4 / width;
+        (self.rng.range(0, slots) * width) as i32
+    }
+
+    /// Generate one instruction that may skip forward by at most
+    /// `remaining` instructions.
+    fn instruction(&mut self, remaining: u32) -> Instruction {
+        let skip = 4 * self.rng.range(1, remaining.min(MAX_SKIP) + 1) as i32;
+        let choice = self.rng.range(0, if self.multiply { 11 } else { 10 });
+        let (rd, rs1, rs2) = (self.destination(), self.source(), self.source());
+
+        match choice {
+            0 | 1 => {
+                let ops: [fn(Register, Register, Register) -> Instruction; 10] = [
+                    Instruction::add, Instruction::sub, Instruction::sll,
+                    Instruction::slt, Instruction::sltu, Instruction::xor,
+                    Instruction::srl, Instruction::sra, Instruction::or,
+                    Instruction::and,
+                ];
+                ops[self.rng.range(0, 10) as usize](rd, rs1, rs2)
+            },
+            2 | 3 => {
+                let ops: [fn(Register, Register, i32) -> isa::encoding::Result<Instruction>; 6] = [
+                    Instruction::addi, Instruction::slti, Instruction::sltiu,
+                    Instruction::xori, Instruction::ori, Instruction::andi,
+                ];
+                let imm = self.immediate();
+                ops[self.rng.range(0, 6) as usize](rd, rs1, imm).unwrap()
+            },
+            4 => {
+                let ops: [fn(Register, Register, i32) -> isa::encoding::Result<Instruction>; 3] = [
+                    Instruction::slli, Instruction::srli, Instruction::srai,
+                ];
+                let shamt = self.rng.range(0, 32) as i32;
+                ops[self.rng.range(0, 3) as usize](rd, rs1, shamt).unwrap()
+            },
+            5 => {
+                let imm = self.upper_immediate();
+                if self.rng.chance(1, 2) {
+                    Instruction::lui(rd, imm).unwrap()
+                }
+                else {
+                    Instruction::auipc(rd, imm).unwrap()
+                }
+            },
+            6 => {
+                let (width, load): (u32, fn(Register, Register, i32)
+                                        -> isa::encoding::Result<Instruction>) =
+                    match self.rng.range(0, 5) {
+                        0 => (1, Instruction::lb),
+                        1 => (1, Instruction::lbu),
+                        2 => (2, Instruction::lh),
+                        3 => (2, Instruction::lhu),
+                        _ => (4, Instruction::lw),
+                    };
+                let offset = self.data_offset(width);
+                load(rd, DATA_REGISTER, offset).unwrap()
+            },
+            7 => {
+                let (width, store): (u32, fn(Register, Register, i32)
+                                         -> isa::encoding::Result<Instruction>) =
+                    match self.rng.range(0, 3) {
+                        0 => (1, Instruction::sb),
+                        1 => (2, Instruction::sh),
+                        _ => (4, Instruction::sw),
+                    };
+                let offset = self.data_offset(width);
+                store(DATA_REGISTER, rs2, offset).unwrap()
+            },
+            8 => {
+                let ops: [fn(Register, Register, i32) -> isa::encoding::Result<Instruction>; 6] = [
+                    Instruction::beq, Instruction::bne, Instruction::blt,
+                    Instruction::bge, Instruction::bltu, Instruction::bgeu,
+                ];
+                ops[self.rng.range(0, 6) as usize](rs1, rs2, skip).unwrap()
+            },
+            9 => Instruction::jal(rd, skip).unwrap(),
+            _ => {
+                let ops: [fn(Register, Register, Register) -> Instruction; 8] = [
+                    Instruction::mul, Instruction::mulh, Instruction::mulhsu,
+                    Instruction::mulhu, Instruction::div, Instruction::divu,
+                    Instruction::rem, Instruction::remu,
+                ];
+                ops[self.rng.range(0, 8) as usize](rd, rs1, rs2)
+            },
+        }
+    }
+
+    /// Generate a program with `length` random instructions, after a
+    /// prologue that gives every register a random value.
+    pub fn generate(&mut self, length: usize) -> GeneratedProgram {
+        let text_base = isa::Word(0x1000);
+        let mut instructions = Vec::new();
+
+        for num in 1..DATA_REGISTER.as_num() {
+            let value = self.rng.next_u32();
+            self.load_constant(Register::from_num(num as u32), value,
+                               &mut instructions);
+        }
+
+        let text_end = text_base.0 + 4 * (instructions.len() + length + 3) as u32;
+        let data_base = isa::Word((text_end + 0xFFF) & !0xFFF);
+        self.load_constant(DATA_REGISTER, data_base.0, &mut instructions);
+
+        for index in 0..length {
+            // The final jump to 0 is the furthest target
+            let remaining = (length - index) as u32;
+            instructions.push(self.instruction(remaining));
+        }
+        instructions.push(Instruction::jalr(Register::X0, Register::X0, 0).unwrap());
+
+        let data = (0..self.data_words).map(|_| isa::Word(self.rng.next_u32())).collect();
+
+        GeneratedProgram {
+            text_base: text_base,
+            instructions: instructions,
+            data_base: data_base,
+            data: data,
+        }
+    }
+}
+
+/// The first difference found between the simulator and the reference.
+#[derive(Debug, PartialEq)]
+pub enum Mismatch {
+    DidNotHalt,
+    Pc {
+        expected: isa::Address,
+        actual: isa::Address,
+    },
+    Register {
+        register: Register,
+        expected: isa::Word,
+        actual: isa::Word,
+    },
+    Memory {
+        address: isa::Address,
+        expected: isa::Word,
+        actual: isa::Word,
+    },
+}
+
+/// Run a program on a `Simulator` with a single cached `Core` and on a
+/// `FunctionalCore`, then compare the final PC, registers and data.
+pub fn check_against_reference(program: &GeneratedProgram, max_cycles: usize)
+                               -> Result<(), Mismatch> {
+    let sp = isa::Word(0);
+
+    let mut reference_memory = Memory::new(program.memory_words());
+    program.load(&mut reference_memory);
+    let mut reference = FunctionalCore::new(0, program.text_base, sp,
+                                            Box::new(IdentityMmu::new()));
+    let mut steps = 0;
+    while reference.is_running() {
+        if steps == max_cycles {
+            return Err(Mismatch::DidNotHalt);
+        }
+        reference.step(&mut reference_memory, &mut NoSyscalls {});
+        steps += 1;
+    }
+
+    let mut memory = Memory::new(program.memory_words());
+    program.load(&mut memory);
+    let memory_ref = Rc::new(RefCell::new(memory));
+    let cache = Rc::new(RefCell::new(DirectMappedCache::new(
+        16, 4, memory_ref.clone(), EmptyEventHandler {})));
+    let core = Core::new(0, program.text_base, sp, cache.clone(),
+                         Box::new(IdentityMmu::new()));
+    let mut simulator = Simulator::new(vec![core], memory_ref.clone(),
+                                       vec![cache], NoSyscalls {});
+    if let HaltReason::OutOfCycles = simulator.run_max(max_cycles) {
+        return Err(Mismatch::DidNotHalt);
+    }
+
+    let core = &mut simulator.cores()[0];
+    if core.pc() != reference.pc() {
+        return Err(Mismatch::Pc {
+            expected: reference.pc(),
+            actual: core.pc(),
+        });
+    }
+
+    for num in 1..32 {
+        let register = Register::from_num(num);
+        let expected = reference.registers().read_word(register);
+        let actual = core.registers().read_word(register);
+        if expected != actual {
+            return Err(Mismatch::Register {
+                register: register,
+                expected: expected,
+                actual: actual,
+            });
+        }
+    }
+
+    for offset in 0..program.data.len() {
+        let address = program.data_base + 4 * offset as u32;
+        let expected = reference_memory.read_word(address);
+        let actual = memory_ref.borrow_mut().read_word(address);
+        if expected != actual {
+            return Err(Mismatch::Memory {
+                address: address,
+                expected: expected.unwrap_or(isa::Word(0)),
+                actual: actual.unwrap_or(isa::Word(0)),
+            });
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,235 @@
+// Copyright 2016 David Li
+// This file is part of rustv.
+
+// rustv is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// rustv is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with rustv.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::rc::Rc;
+use std::cell::RefCell;
+
+use cache::{DEFAULT_MSHRS, CacheInterface, EmptyEventHandler, Inclusion,
+            Prefetch, Replacement, SetAssociativeCache, SharedCache,
+            WriteMissPolicy, WritePolicy, stats_table};
+use isa;
+use memory::{Mmu, SharedMemory};
+use simulator::Core;
+
+pub type HierarchyCache<'a> = SetAssociativeCache<'a, EmptyEventHandler>;
+pub type SharedHierarchyCache<'a> = Rc<RefCell<HierarchyCache<'a>>>;
+
+/// The shape and policies of one cache in a hierarchy.
+#[derive(Clone, Copy, Debug)]
+pub struct CacheConfig {
+    pub sets: u32,
+    pub ways: u32,
+    pub block_words: u32,
+    pub replacement: Replacement,
+    pub write_policy: WritePolicy,
+    pub write_miss_policy: WriteMissPolicy,
+    pub mshrs: usize,
+    pub prefetch: Option<Prefetch>,
+    /// Classify misses as compulsory, capacity or conflict misses
+    pub classify_misses: bool,
+}
+
+impl CacheConfig {
+    /// A write-back, write-allocate LRU cache, without a prefetcher.
+    pub fn new(sets: u32, ways: u32, block_words: u32) -> CacheConfig {
+        CacheConfig {
+            sets: sets,
+            ways: ways,
+            block_words: block_words,
+            replacement: Replacement::Lru,
+            write_policy: WritePolicy::WriteBack,
+            write_miss_policy: WriteMissPolicy::WriteAllocate,
+            mshrs: DEFAULT_MSHRS,
+            prefetch: None,
+            classify_misses: false,
+        }
+    }
+
+    fn build<'a>(&self, next_level: SharedMemory<'a>) -> SharedHierarchyCache<'a> {
+        let mut cache = SetAssociativeCache::new(
+            self.sets, self.ways, self.block_words,
+            self.replacement.build(self.sets, self.ways),
+            next_level, EmptyEventHandler {});
+        cache.set_write_policy(self.write_policy);
+        cache.set_write_miss_policy(self.write_miss_policy);
+        cache.set_mshr_count(self.mshrs);
+        if let Some(prefetch) = self.prefetch {
+            cache.set_prefetcher(prefetch.build(self.block_words));
+        }
+        if self.classify_misses {
+            cache.classify_misses();
+        }
+        Rc::new(RefCell::new(cache))
+    }
+}
+
+/// Builds split L1 instruction and data caches for each core, a shared
+/// L2, and optionally a shared L3 behind it.
+pub struct HierarchyBuilder {
+    cores: usize,
+    l1i: CacheConfig,
+    l1d: CacheConfig,
+    l2: CacheConfig,
+    l3: Option<CacheConfig>,
+    inclusion: Inclusion,
+}
+
+impl HierarchyBuilder {
+    pub fn new(cores: usize, l1i: CacheConfig, l1d: CacheConfig,
+               l2: CacheConfig) -> HierarchyBuilder {
+        HierarchyBuilder {
+            cores: cores,
+            l1i: l1i,
+            l1d: l1d,
+            l2: l2,
+            l3: None,
+            inclusion: Inclusion::NonInclusive,
+        }
+    }
+
+    pub fn l3(mut self, l3: CacheConfig) -> HierarchyBuilder {
+        self.l3 = Some(l3);
+        self
+    }
+
+    /// The inclusion policy of each shared level towards the levels
+    /// above it.
+    pub fn inclusion(mut self, inclusion: Inclusion) -> HierarchyBuilder {
+        self.inclusion = inclusion;
+        self
+    }
+
+    /// Build the hierarchy in front of main memory.
+    ///
+    /// Exclusive caches are filled by evictions from above a word at a
+    /// time, so every level above them must be write-back and
+    /// write-allocate, with the same block size.
+    pub fn build<'a>(&self, memory: SharedMemory<'a>) -> CacheHierarchy<'a> {
+        if self.inclusion == Inclusion::Exclusive {
+            let mut uppers = vec![&self.l1i, &self.l1d];
+            if self.l3.is_some() {
+                uppers.push(&self.l2);
+            }
+            for upper in uppers {
+                assert_eq!(upper.write_policy, WritePolicy::WriteBack);
+                assert_eq!(upper.write_miss_policy, WriteMissPolicy::WriteAllocate);
+            }
+            assert_eq!(self.l1i.block_words, self.l2.block_words);
+            assert_eq!(self.l1d.block_words, self.l2.block_words);
+            if let Some(ref l3) = self.l3 {
+                assert_eq!(self.l2.block_words, l3.block_words);
+            }
+        }
+
+        let l3 = self.l3.map(|l3| l3.build(memory.clone()));
+        let l2 = match l3 {
+            Some(ref l3) => self.l2.build(l3.clone()),
+            None => self.l2.build(memory.clone()),
+        };
+        if let Some(ref l3) = l3 {
+            self.connect(&l2, l3);
+        }
+
+        let mut l1i = Vec::new();
+        let mut l1d = Vec::new();
+        for _ in 0..self.cores {
+            let icache = self.l1i.build(l2.clone());
+            let dcache = self.l1d.build(l2.clone());
+            self.connect(&icache, &l2);
+            self.connect(&dcache, &l2);
+            l1i.push(icache);
+            l1d.push(dcache);
+        }
+
+        CacheHierarchy {
+            l1i: l1i,
+            l1d: l1d,
+            l2: l2,
+            l3: l3,
+        }
+    }
+
+    fn connect<'a>(&self, upper: &SharedHierarchyCache<'a>,
+                   lower: &SharedHierarchyCache<'a>) {
+        let shared: SharedMemory<'a> = upper.clone();
+        let mut lower = lower.borrow_mut();
+        lower.add_upper_level(&shared);
+        lower.set_inclusion(self.inclusion);
+        if self.inclusion == Inclusion::Exclusive {
+            upper.borrow_mut().set_evict_clean(true);
+        }
+    }
+}
+
+/// The caches created by a `HierarchyBuilder`.
+pub struct CacheHierarchy<'a> {
+    pub l1i: Vec<SharedHierarchyCache<'a>>,
+    pub l1d: Vec<SharedHierarchyCache<'a>>,
+    pub l2: SharedHierarchyCache<'a>,
+    pub l3: Option<SharedHierarchyCache<'a>>,
+}
+
+impl<'a> CacheHierarchy<'a> {
+    /// Every cache, in the order the `Simulator` should step them:
+    /// the L1s first, then each shared level.
+    pub fn caches(&self) -> Vec<SharedMemory<'a>> {
+        let mut caches: Vec<SharedMemory<'a>> = Vec::new();
+        for (icache, dcache) in self.l1i.iter().zip(self.l1d.iter()) {
+            caches.push(icache.clone());
+            caches.push(dcache.clone());
+        }
+        caches.push(self.l2.clone());
+        if let Some(ref l3) = self.l3 {
+            caches.push(l3.clone());
+        }
+        caches
+    }
+
+    /// The statistics of every cache, as a table.
+    pub fn stats_table(&self) -> String {
+        let mut names = Vec::new();
+        let mut stats = Vec::new();
+        for (id, (icache, dcache)) in self.l1i.iter().zip(self.l1d.iter()).enumerate() {
+            names.push(format!("L1I{}", id));
+            stats.push(icache.borrow().cache_stats());
+            names.push(format!("L1D{}", id));
+            stats.push(dcache.borrow().cache_stats());
+        }
+        names.push("L2".to_owned());
+        stats.push(self.l2.borrow().cache_stats());
+        if let Some(ref l3) = self.l3 {
+            names.push("L3".to_owned());
+            stats.push(l3.borrow().cache_stats());
+        }
+
+        let rows: Vec<(&str, _)> = names.iter()
+            .map(|name| name.as_str())
+            .zip(stats.into_iter())
+            .collect();
+        stats_table(&rows)
+    }
+
+    /// Create a core that fetches through its L1I and accesses data
+    /// through its L1D.
+    pub fn core(&self, id: usize, entry: isa::Address, sp: isa::Address,
+                mmu: Box<Mmu + 'a>) -> Core<'a> {
+        let dcache: SharedCache<'a> = self.l1d[id].clone();
+        let icache: SharedCache<'a> = self.l1i[id].clone();
+        let mut core = Core::new(id, entry, sp, dcache, mmu);
+        core.set_instruction_cache(icache);
+        core
+    }
+}
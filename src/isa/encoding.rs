@@ -0,0 +1,363 @@
+// Copyright 2016 David Li
+// This file is part of rustv.
+
+// rustv is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// rustv is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with rustv.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Constructors that encode instructions, the inverse of the field
+//! accessors on `Instruction`. Immediates are given exactly as the
+//! corresponding accessor (`i_imm`, `s_imm`, `sb_imm`, `uj_imm`,
+//! `u_imm`) would return them.
+
+use std::fmt;
+
+use super::{Instruction, Register, Word};
+use super::{funct3, funct7, opcodes};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum EncodingError {
+    ImmediateOutOfRange {
+        value: i32,
+        min: i32,
+        max: i32,
+    },
+    /// The immediate has low bits set that the format cannot encode
+    MisalignedImmediate {
+        value: i32,
+        alignment: i32,
+    },
+}
+
+impl fmt::Display for EncodingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EncodingError::ImmediateOutOfRange { value, min, max } =>
+                write!(f, "immediate {} out of range [{}, {}]", value, min, max),
+            EncodingError::MisalignedImmediate { value, alignment } =>
+                write!(f, "immediate {} is not a multiple of {}", value, alignment),
+        }
+    }
+}
+
+pub type Result<T> = ::std::result::Result<T, EncodingError>;
+
+fn check_range(value: i32, min: i32, max: i32) -> Result<()> {
+    if value < min || value > max {
+        Err(EncodingError::ImmediateOutOfRange {
+            value: value,
+            min: min,
+            max: max,
+        })
+    }
+    else {
+        Ok(())
+    }
+}
+
+fn check_alignment(value: i32, alignment: i32) -> Result<()> {
+    if value % alignment != 0 {
+        Err(EncodingError::MisalignedImmediate {
+            value: value,
+            alignment: alignment,
+        })
+    }
+    else {
+        Ok(())
+    }
+}
+
+fn reg(register: Register) -> u32 {
+    register.as_num() as u32
+}
+
+fn encoded(word: u32) -> Instruction {
+    Instruction::new(Word(word))
+}
+
+impl Instruction {
+    pub fn r_type(opcode: u32, funct3: u32, funct7: u32,
+                  rd: Register, rs1: Register, rs2: Register) -> Instruction {
+        encoded((funct7 << 25) | (reg(rs2) << 20) | (reg(rs1) << 15) |
+                (funct3 << 12) | (reg(rd) << 7) | opcode)
+    }
+
+    pub fn i_type(opcode: u32, funct3: u32, rd: Register, rs1: Register,
+                  imm: i32) -> Result<Instruction> {
+        try!(check_range(imm, -2048, 2047));
+        Ok(encoded((((imm as u32) & 0xFFF) << 20) | (reg(rs1) << 15) |
+                   (funct3 << 12) | (reg(rd) << 7) | opcode))
+    }
+
+    pub fn s_type(opcode: u32, funct3: u32, rs1: Register, rs2: Register,
+                  imm: i32) -> Result<Instruction> {
+        try!(check_range(imm, -2048, 2047));
+        let imm = imm as u32;
+        Ok(encoded((((imm >> 5) & 0x7F) << 25) | (reg(rs2) << 20) |
+                   (reg(rs1) << 15) | (funct3 << 12) | ((imm & 0x1F) << 7) |
+                   opcode))
+    }
+
+    pub fn sb_type(opcode: u32, funct3: u32, rs1: Register, rs2: Register,
+                   imm: i32) -> Result<Instruction> {
+        try!(check_range(imm, -4096, 4094));
+        try!(check_alignment(imm, 2));
+        let imm = imm as u32;
+        Ok(encoded((((imm >> 12) & 0x1) << 31) | (((imm >> 5) & 0x3F) << 25) |
+                   (reg(rs2) << 20) | (reg(rs1) << 15) | (funct3 << 12) |
+                   (((imm >> 1) & 0xF) << 8) | (((imm >> 11) & 0x1) << 7) |
+                   opcode))
+    }
+
+    /// The immediate is the full value loaded, so its low 12 bits must
+    /// be clear.
+    pub fn u_type(opcode: u32, rd: Register, imm: i32) -> Result<Instruction> {
+        try!(check_alignment(imm, 1 << 12));
+        Ok(encoded(((imm as u32) & 0xFFFFF000) | (reg(rd) << 7) | opcode))
+    }
+
+    pub fn uj_type(opcode: u32, rd: Register, imm: i32) -> Result<Instruction> {
+        try!(check_range(imm, -(1 << 20), (1 << 20) - 2));
+        try!(check_alignment(imm, 2));
+        let imm = imm as u32;
+        Ok(encoded((((imm >> 20) & 0x1) << 31) | (((imm >> 1) & 0x3FF) << 21) |
+                   (((imm >> 11) & 0x1) << 20) | (((imm >> 12) & 0xFF) << 12) |
+                   (reg(rd) << 7) | opcode))
+    }
+
+    pub fn lui(rd: Register, imm: i32) -> Result<Instruction> {
+        Instruction::u_type(opcodes::LUI, rd, imm)
+    }
+
+    pub fn auipc(rd: Register, imm: i32) -> Result<Instruction> {
+        Instruction::u_type(opcodes::AUIPC, rd, imm)
+    }
+
+    pub fn jal(rd: Register, offset: i32) -> Result<Instruction> {
+        Instruction::uj_type(opcodes::JAL, rd, offset)
+    }
+
+    pub fn jalr(rd: Register, rs1: Register, imm: i32) -> Result<Instruction> {
+        Instruction::i_type(opcodes::JALR, 0, rd, rs1, imm)
+    }
+
+    pub fn beq(rs1: Register, rs2: Register, offset: i32) -> Result<Instruction> {
+        Instruction::sb_type(opcodes::BRANCH, funct3::BEQ, rs1, rs2, offset)
+    }
+
+    pub fn bne(rs1: Register, rs2: Register, offset: i32) -> Result<Instruction> {
+        Instruction::sb_type(opcodes::BRANCH, funct3::BNE, rs1, rs2, offset)
+    }
+
+    pub fn blt(rs1: Register, rs2: Register, offset: i32) -> Result<Instruction> {
+        Instruction::sb_type(opcodes::BRANCH, funct3::BLT, rs1, rs2, offset)
+    }
+
+    pub fn bge(rs1: Register, rs2: Register, offset: i32) -> Result<Instruction> {
+        Instruction::sb_type(opcodes::BRANCH, funct3::BGE, rs1, rs2, offset)
+    }
+
+    pub fn bltu(rs1: Register, rs2: Register, offset: i32) -> Result<Instruction> {
+        Instruction::sb_type(opcodes::BRANCH, funct3::BLTU, rs1, rs2, offset)
+    }
+
+    pub fn bgeu(rs1: Register, rs2: Register, offset: i32) -> Result<Instruction> {
+        Instruction::sb_type(opcodes::BRANCH, funct3::BGEU, rs1, rs2, offset)
+    }
+
+    pub fn lb(rd: Register, rs1: Register, imm: i32) -> Result<Instruction> {
+        Instruction::i_type(opcodes::LOAD, funct3::LB, rd, rs1, imm)
+    }
+
+    pub fn lh(rd: Register, rs1: Register, imm: i32) -> Result<Instruction> {
+        Instruction::i_type(opcodes::LOAD, funct3::LH, rd, rs1, imm)
+    }
+
+    pub fn lw(rd: Register, rs1: Register, imm: i32) -> Result<Instruction> {
+        Instruction::i_type(opcodes::LOAD, funct3::LW, rd, rs1, imm)
+    }
+
+    pub fn lbu(rd: Register, rs1: Register, imm: i32) -> Result<Instruction> {
+        Instruction::i_type(opcodes::LOAD, funct3::LBU, rd, rs1, imm)
+    }
+
+    pub fn lhu(rd: Register, rs1: Register, imm: i32) -> Result<Instruction> {
+        Instruction::i_type(opcodes::LOAD, funct3::LHU, rd, rs1, imm)
+    }
+
+    /// Store the low byte of `rs2` at `rs1 + imm`.
+    pub fn sb(rs1: Register, rs2: Register, imm: i32) -> Result<Instruction> {
+        Instruction::s_type(opcodes::STORE, funct3::SB, rs1, rs2, imm)
+    }
+
+    pub fn sh(rs1: Register, rs2: Register, imm: i32) -> Result<Instruction> {
+        Instruction::s_type(opcodes::STORE, funct3::SH, rs1, rs2, imm)
+    }
+
+    pub fn sw(rs1: Register, rs2: Register, imm: i32) -> Result<Instruction> {
+        Instruction::s_type(opcodes::STORE, funct3::SW, rs1, rs2, imm)
+    }
+
+    pub fn addi(rd: Register, rs1: Register, imm: i32) -> Result<Instruction> {
+        Instruction::i_type(opcodes::INTEGER_IMMEDIATE, funct3::ADDI, rd, rs1, imm)
+    }
+
+    pub fn slti(rd: Register, rs1: Register, imm: i32) -> Result<Instruction> {
+        Instruction::i_type(opcodes::INTEGER_IMMEDIATE, funct3::SLTI, rd, rs1, imm)
+    }
+
+    pub fn sltiu(rd: Register, rs1: Register, imm: i32) -> Result<Instruction> {
+        Instruction::i_type(opcodes::INTEGER_IMMEDIATE, funct3::SLTIU, rd, rs1, imm)
+    }
+
+    pub fn xori(rd: Register, rs1: Register, imm: i32) -> Result<Instruction> {
+        Instruction::i_type(opcodes::INTEGER_IMMEDIATE, funct3::XORI, rd, rs1, imm)
+    }
+
+    pub fn ori(rd: Register, rs1: Register, imm: i32) -> Result<Instruction> {
+        Instruction::i_type(opcodes::INTEGER_IMMEDIATE, funct3::ORI, rd, rs1, imm)
+    }
+
+    pub fn andi(rd: Register, rs1: Register, imm: i32) -> Result<Instruction> {
+        Instruction::i_type(opcodes::INTEGER_IMMEDIATE, funct3::ANDI, rd, rs1, imm)
+    }
+
+    fn shift_immediate(funct3: u32, funct7: u32, rd: Register, rs1: Register,
+                       shamt: i32) -> Result<Instruction> {
+        try!(check_range(shamt, 0, 31));
+        Ok(encoded((funct7 << 25) | ((shamt as u32) << 20) | (reg(rs1) << 15) |
+                   (funct3 << 12) | (reg(rd) << 7) | opcodes::INTEGER_IMMEDIATE))
+    }
+
+    pub fn slli(rd: Register, rs1: Register, shamt: i32) -> Result<Instruction> {
+        Instruction::shift_immediate(funct3::SLLI, 0, rd, rs1, shamt)
+    }
+
+    pub fn srli(rd: Register, rs1: Register, shamt: i32) -> Result<Instruction> {
+        Instruction::shift_immediate(funct3::SRLI_SRAI, funct7::SRLI, rd, rs1, shamt)
+    }
+
+    pub fn srai(rd: Register, rs1: Register, shamt: i32) -> Result<Instruction> {
+        Instruction::shift_immediate(funct3::SRLI_SRAI, funct7::SRAI, rd, rs1, shamt)
+    }
+
+    pub fn add(rd: Register, rs1: Register, rs2: Register) -> Instruction {
+        Instruction::r_type(opcodes::INTEGER_REGISTER, funct3::ADD_SUB,
+                            funct7::ADD_SRL, rd, rs1, rs2)
+    }
+
+    pub fn sub(rd: Register, rs1: Register, rs2: Register) -> Instruction {
+        Instruction::r_type(opcodes::INTEGER_REGISTER, funct3::ADD_SUB,
+                            funct7::SUB_SRA, rd, rs1, rs2)
+    }
+
+    pub fn sll(rd: Register, rs1: Register, rs2: Register) -> Instruction {
+        Instruction::r_type(opcodes::INTEGER_REGISTER, funct3::SLL, 0, rd, rs1, rs2)
+    }
+
+    pub fn slt(rd: Register, rs1: Register, rs2: Register) -> Instruction {
+        Instruction::r_type(opcodes::INTEGER_REGISTER, funct3::SLT, 0, rd, rs1, rs2)
+    }
+
+    pub fn sltu(rd: Register, rs1: Register, rs2: Register) -> Instruction {
+        Instruction::r_type(opcodes::INTEGER_REGISTER, funct3::SLTU, 0, rd, rs1, rs2)
+    }
+
+    pub fn xor(rd: Register, rs1: Register, rs2: Register) -> Instruction {
+        Instruction::r_type(opcodes::INTEGER_REGISTER, funct3::XOR, 0, rd, rs1, rs2)
+    }
+
+    pub fn srl(rd: Register, rs1: Register, rs2: Register) -> Instruction {
+        Instruction::r_type(opcodes::INTEGER_REGISTER, funct3::SRL_SRA,
+                            funct7::ADD_SRL, rd, rs1, rs2)
+    }
+
+    pub fn sra(rd: Register, rs1: Register, rs2: Register) -> Instruction {
+        Instruction::r_type(opcodes::INTEGER_REGISTER, funct3::SRL_SRA,
+                            funct7::SUB_SRA, rd, rs1, rs2)
+    }
+
+    pub fn or(rd: Register, rs1: Register, rs2: Register) -> Instruction {
+        Instruction::r_type(opcodes::INTEGER_REGISTER, funct3::OR, 0, rd, rs1, rs2)
+    }
+
+    pub fn and(rd: Register, rs1: Register, rs2: Register) -> Instruction {
+        Instruction::r_type(opcodes::INTEGER_REGISTER, funct3::AND, 0, rd, rs1, rs2)
+    }
+
+    pub fn mul(rd: Register, rs1: Register, rs2: Register) -> Instruction {
+        Instruction::r_type(opcodes::INTEGER_REGISTER, funct3::MUL,
+                            funct7::MULDIV, rd, rs1, rs2)
+    }
+
+    pub fn mulh(rd: Register, rs1: Register, rs2: Register) -> Instruction {
+        Instruction::r_type(opcodes::INTEGER_REGISTER, funct3::MULH,
+                            funct7::MULDIV, rd, rs1, rs2)
+    }
+
+    pub fn mulhsu(rd: Register, rs1: Register, rs2: Register) -> Instruction {
+        Instruction::r_type(opcodes::INTEGER_REGISTER, funct3::MULHSU,
+                            funct7::MULDIV, rd, rs1, rs2)
+    }
+
+    pub fn mulhu(rd: Register, rs1: Register, rs2: Register) -> Instruction {
+        Instruction::r_type(opcodes::INTEGER_REGISTER, funct3::MULHU,
+                            funct7::MULDIV, rd, rs1, rs2)
+    }
+
+    pub fn div(rd: Register, rs1: Register, rs2: Register) -> Instruction {
+        Instruction::r_type(opcodes::INTEGER_REGISTER, funct3::DIV,
+                            funct7::MULDIV, rd, rs1, rs2)
+    }
+
+    pub fn divu(rd: Register, rs1: Register, rs2: Register) -> Instruction {
+        Instruction::r_type(opcodes::INTEGER_REGISTER, funct3::DIVU,
+                            funct7::MULDIV, rd, rs1, rs2)
+    }
+
+    pub fn rem(rd: Register, rs1: Register, rs2: Register) -> Instruction {
+        Instruction::r_type(opcodes::INTEGER_REGISTER, funct3::REM,
+                            funct7::MULDIV, rd, rs1, rs2)
+    }
+
+    pub fn remu(rd: Register, rs1: Register, rs2: Register) -> Instruction {
+        Instruction::r_type(opcodes::INTEGER_REGISTER, funct3::REMU,
+                            funct7::MULDIV, rd, rs1, rs2)
+    }
+
+    /// `pred` and `succ` are the IORW bits, I being the most significant.
+    pub fn fence(pred: u32, succ: u32) -> Instruction {
+        encoded(((pred & 0xF) << 24) | ((succ & 0xF) << 20) |
+                (funct3::FENCE << 12) | opcodes::MISC_MEM)
+    }
+
+    pub fn fence_i() -> Instruction {
+        encoded((funct3::FENCE_I << 12) | opcodes::MISC_MEM)
+    }
+
+    pub fn ecall() -> Instruction {
+        encoded(opcodes::SYSTEM)
+    }
+
+    pub fn ebreak() -> Instruction {
+        encoded((1 << 20) | opcodes::SYSTEM)
+    }
+
+    /// Encode a CSR instruction. For the immediate variants, `rs1` holds
+    /// the 5-bit zero-extended immediate.
+    pub fn csr(funct3: u32, rd: Register, csr: u32, rs1: Register)
+               -> Result<Instruction> {
+        try!(check_range(csr as i32, 0, 0xFFF));
+        Ok(encoded((csr << 20) | (reg(rs1) << 15) | (funct3 << 12) |
+                   (reg(rd) << 7) | opcodes::SYSTEM))
+    }
+}
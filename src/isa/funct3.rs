@@ -48,3 +48,23 @@ pub const LHU: u32 = 0b101;
 pub const SB: u32 = 0b000;
 pub const SH: u32 = 0b001;
 pub const SW: u32 = 0b010;
+
+pub const FENCE: u32 = 0b000;
+pub const FENCE_I: u32 = 0b001;
+
+pub const PRIV: u32 = 0b000;
+pub const CSRRW: u32 = 0b001;
+pub const CSRRS: u32 = 0b010;
+pub const CSRRC: u32 = 0b011;
+pub const CSRRWI: u32 = 0b101;
+pub const CSRRSI: u32 = 0b110;
+pub const CSRRCI: u32 = 0b111;
+
+pub const MUL: u32 = 0b000;
+pub const MULH: u32 = 0b001;
+pub const MULHSU: u32 = 0b010;
+pub const MULHU: u32 = 0b011;
+pub const DIV: u32 = 0b100;
+pub const DIVU: u32 = 0b101;
+pub const REM: u32 = 0b110;
+pub const REMU: u32 = 0b111;
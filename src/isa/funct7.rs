@@ -15,7 +15,8 @@
 // along with rustv.  If not, see <http://www.gnu.org/licenses/>.
 
 pub const ADD_SRL: u32 = 0x0;
-pub const SUB_SRA: u32 = 0x1;
+pub const SUB_SRA: u32 = 0x20;
+pub const MULDIV: u32 = 0x01;
 
 pub const SRLI: u32 = 0x0;
-pub const SRAI: u32 = 0x1;
+pub const SRAI: u32 = 0x20;
@@ -17,6 +17,7 @@
 use std::fmt;
 use std::ops;
 
+pub mod encoding;
 pub mod opcodes;
 pub mod funct3;
 pub mod funct7;
@@ -218,7 +219,7 @@ isa_utype!(Byte, SignedByte, u8, i8);
 
 pub type Address = Word;
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub enum Register {
     X0 = 0,
     X1 = 1,
@@ -296,9 +297,20 @@ impl Register {
             _ => panic!("Invalid register number: {}", num),
         }
     }
+
+    /// The register's name in the standard calling convention.
+    pub fn abi_name(self) -> &'static str {
+        const NAMES: [&'static str; 32] = [
+            "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2",
+            "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5",
+            "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7",
+            "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6",
+        ];
+        NAMES[self.as_num()]
+    }
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub struct Instruction {
     // TODO: rename word to something correct - instructions are not always a
     // word
@@ -312,6 +324,10 @@ impl Instruction {
         }
     }
 
+    pub fn word(&self) -> Word {
+        self.word
+    }
+
     pub fn opcode(&self) -> u32 {
         (self.word & 0x7F).0
     }
@@ -356,7 +372,7 @@ impl Instruction {
         let low11 = (self.word >> 20) & 0x1;
         let low12 = (self.word >> 12) & 0xFF;
         // Want sign-extension
-        let low20 = ((self.word.as_signed_word()) >> 30).as_word();
+        let low20 = ((self.word.as_signed_word()) >> 31).as_word();
         ((low20 << 20) | (low12 << 12) | (low11 << 11) | (low1 << 1)).as_signed_word()
     }
 
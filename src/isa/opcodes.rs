@@ -22,5 +22,6 @@ pub const JAL: u32 = 0x6F;
 pub const INTEGER_IMMEDIATE: u32 = 0x13;
 pub const INTEGER_REGISTER: u32 = 0x33;
 pub const LOAD: u32 = 0x3;
+pub const MISC_MEM: u32 = 0xF;
 pub const STORE: u32 = 0x23;
 pub const SYSTEM: u32 = 0x73;
@@ -0,0 +1,217 @@
+// Copyright 2016 David Li
+// This file is part of rustv.
+
+// rustv is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// rustv is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with rustv.  If not, see <http://www.gnu.org/licenses/>.
+
+use isa;
+use isa::{funct3, funct7, opcodes};
+use pipeline::source_registers;
+use retirement::writes_register;
+
+/// The kinds of instructions that have their own latency.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InstructionClass {
+    Alu,
+    Shift,
+    Multiply,
+    Divide,
+    Load,
+    Store,
+    /// Branches and jumps
+    Branch,
+    /// System instructions and fences
+    Csr,
+}
+
+/// The number of instruction classes.
+pub const CLASSES: usize = 8;
+
+impl InstructionClass {
+    pub fn of(inst: isa::Instruction) -> InstructionClass {
+        match inst.opcode() {
+            opcodes::INTEGER_REGISTER if inst.funct7() == funct7::MULDIV => {
+                if inst.funct3() < funct3::DIV {
+                    InstructionClass::Multiply
+                }
+                else {
+                    InstructionClass::Divide
+                }
+            },
+            opcodes::INTEGER_REGISTER | opcodes::INTEGER_IMMEDIATE => {
+                match inst.funct3() {
+                    funct3::SLL | funct3::SRL_SRA => InstructionClass::Shift,
+                    _ => InstructionClass::Alu,
+                }
+            },
+            opcodes::LOAD => InstructionClass::Load,
+            opcodes::STORE => InstructionClass::Store,
+            opcodes::BRANCH | opcodes::JAL | opcodes::JALR => InstructionClass::Branch,
+            opcodes::SYSTEM | opcodes::MISC_MEM => InstructionClass::Csr,
+            _ => InstructionClass::Alu,
+        }
+    }
+
+    /// A dense index, for tables by class.
+    pub fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// The latency of the functional unit for one class of instructions.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct UnitLatency {
+    /// Cycles from issue until the result can be used
+    pub latency: u32,
+    /// Whether the unit can start an instruction every cycle. Otherwise
+    /// it is busy until its instruction completes.
+    pub pipelined: bool,
+}
+
+impl UnitLatency {
+    pub fn pipelined(latency: u32) -> UnitLatency {
+        assert!(latency > 0);
+        UnitLatency {
+            latency: latency,
+            pipelined: true,
+        }
+    }
+
+    pub fn unpipelined(latency: u32) -> UnitLatency {
+        assert!(latency > 0);
+        UnitLatency {
+            latency: latency,
+            pipelined: false,
+        }
+    }
+}
+
+/// Functional unit latencies by instruction class. Load and store
+/// latencies are for cache hits; misses stall on top of them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LatencyTable {
+    pub alu: UnitLatency,
+    pub shift: UnitLatency,
+    pub multiply: UnitLatency,
+    pub divide: UnitLatency,
+    pub load: UnitLatency,
+    pub store: UnitLatency,
+    pub branch: UnitLatency,
+    pub csr: UnitLatency,
+}
+
+impl LatencyTable {
+    /// Every instruction completes in one cycle.
+    pub fn new() -> LatencyTable {
+        let single = UnitLatency::pipelined(1);
+        LatencyTable {
+            alu: single,
+            shift: single,
+            multiply: single,
+            divide: single,
+            load: single,
+            store: single,
+            branch: single,
+            csr: single,
+        }
+    }
+
+    /// A pipelined three-cycle multiplier, an unpipelined 32-cycle
+    /// divider, and two-cycle loads.
+    pub fn typical() -> LatencyTable {
+        LatencyTable {
+            multiply: UnitLatency::pipelined(3),
+            divide: UnitLatency::unpipelined(32),
+            load: UnitLatency::pipelined(2),
+            ..LatencyTable::new()
+        }
+    }
+
+    pub fn get(&self, class: InstructionClass) -> UnitLatency {
+        match class {
+            InstructionClass::Alu => self.alu,
+            InstructionClass::Shift => self.shift,
+            InstructionClass::Multiply => self.multiply,
+            InstructionClass::Divide => self.divide,
+            InstructionClass::Load => self.load,
+            InstructionClass::Store => self.store,
+            InstructionClass::Branch => self.branch,
+            InstructionClass::Csr => self.csr,
+        }
+    }
+}
+
+/// Stalls charged by `FunctionalUnits`, in cycles.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LatencyStats {
+    /// Waiting for the result of a multi-cycle instruction
+    pub data_stalls: u32,
+    /// Waiting for a busy unpipelined unit
+    pub structural_stalls: u32,
+}
+
+/// A scoreboard for an in-order core, tracking when each register's
+/// value is ready and when each unpipelined unit is free.
+pub struct FunctionalUnits {
+    table: LatencyTable,
+    /// The cycle from which each register can be read
+    ready: Vec<u64>,
+    /// The cycle from which each class's unit can start an instruction
+    free: Vec<u64>,
+    stats: LatencyStats,
+}
+
+impl FunctionalUnits {
+    pub fn new(table: LatencyTable) -> FunctionalUnits {
+        FunctionalUnits {
+            table: table,
+            ready: vec![0; 32],
+            free: vec![0; CLASSES],
+            stats: LatencyStats::default(),
+        }
+    }
+
+    pub fn table(&self) -> LatencyTable {
+        self.table
+    }
+
+    pub fn stats(&self) -> LatencyStats {
+        self.stats
+    }
+
+    /// Account for an instruction that executed in `cycle`. Returns the
+    /// cycles it would have waited to issue.
+    pub fn issue(&mut self, inst: isa::Instruction, cycle: u64) -> u32 {
+        let class = InstructionClass::of(inst);
+        let unit = self.table.get(class);
+
+        let operands = source_registers(inst).iter()
+            .map(|register| self.ready[register.as_num()])
+            .max()
+            .unwrap_or(0);
+        let data = operands.saturating_sub(cycle);
+        let structural = self.free[class.index()].saturating_sub(cycle + data);
+        let start = cycle + data + structural;
+
+        if !unit.pipelined {
+            self.free[class.index()] = start + unit.latency as u64;
+        }
+        if writes_register(inst) && inst.rd() != isa::Register::X0 {
+            self.ready[inst.rd().as_num()] = start + unit.latency as u64;
+        }
+
+        self.stats.data_stalls += data as u32;
+        self.stats.structural_stalls += structural as u32;
+        (data + structural) as u32
+    }
+}
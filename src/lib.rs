@@ -18,12 +18,29 @@
            op_assign_traits, step_by)]
 extern crate elfloader32 as elfloader_lib;
 
+pub mod assembler;
+pub mod branch;
 pub mod cache;
+pub mod callgraph;
+pub mod disassembler;
+pub mod functional;
+pub mod generator;
+pub mod hierarchy;
 pub mod isa;
+pub mod latency;
+pub mod lockstep;
 pub mod memory;
+pub mod ooo;
+pub mod pipeline;
+pub mod profile;
 pub mod register_file;
+pub mod retirement;
+pub mod rng;
 pub mod simulator;
+pub mod superscalar;
+pub mod symbols;
 pub mod syscall;
+pub mod trace;
 pub mod trap;
 
 pub use elfloader_lib as elfloader;
@@ -151,4 +168,2176 @@ mod tests {
         assert_eq!(dm_cache.write_halfword(Word(0x12), HalfWord(0x4242)), Ok(()));
         assert_eq!(memory_ref.borrow_mut().read_word(Word(0x10)), Ok(Word(0x42424542)));
     }
+
+    #[test]
+    fn core_sub_and_arithmetic_shifts() {
+        use std::rc::Rc;
+        use std::cell::RefCell;
+
+        use cache::*;
+        use isa::*;
+        use memory::*;
+        use simulator::*;
+        use syscall::NoSyscalls;
+
+        // Encoded by hand, so that the funct7 fields are checked against
+        // the specification rather than an encoder
+        let program = [
+            0xff000513, // li a0,-16
+            0x00300593, // li a1,3
+            0x40b50633, // sub a2,a0,a1
+            0x40b556b3, // sra a3,a0,a1
+            0x40255713, // srai a4,a0,0x2
+            0x00b557b3, // srl a5,a0,a1
+            0x00255813, // srli a6,a0,0x2
+            0x00b508b3, // add a7,a0,a1
+            0x40c02023, // sw a2,1024(zero)
+            0x40d02223, // sw a3,1028(zero)
+            0x40e02423, // sw a4,1032(zero)
+            0x40f02623, // sw a5,1036(zero)
+            0x41002823, // sw a6,1040(zero)
+            0x41102a23, // sw a7,1044(zero)
+            0x00008067, // ret
+        ];
+        let mut memory = Memory::new(0x1000);
+        for (i, &word) in program.iter().enumerate() {
+            memory.write_word(Word(0x1000 + 4 * i as u32), Word(word)).unwrap();
+        }
+        let memory_ref = Rc::new(RefCell::new(memory));
+        let cache = Rc::new(RefCell::new(DirectMappedCache::new(
+            4, 4, memory_ref.clone(), EmptyEventHandler {})));
+        let core = Core::new(0, Word(0x1000), Word(0x3FF0),
+                             cache.clone(), Box::new(IdentityMmu::new()));
+        let mut simulator = Simulator::new(
+            vec![core], memory_ref.clone(), vec![cache.clone()], NoSyscalls {});
+        simulator.run_max(2000);
+
+        // The write-through cache leaves the results in memory
+        let results = [-19i32 as u32, -2i32 as u32, -4i32 as u32,
+                       0xFFFFFFF0 >> 3, 0xFFFFFFF0 >> 2, -13i32 as u32];
+        for (i, &result) in results.iter().enumerate() {
+            assert_eq!(memory_ref.borrow_mut().read_word(Word(0x400 + 4 * i as u32)),
+                       Ok(Word(result)));
+        }
+    }
+
+    #[test]
+    fn disassemble_objdump_format() {
+        use disassembler::*;
+        use isa::*;
+        use symbols::*;
+
+        let mut symbols = SymbolTable::new();
+        symbols.insert(Word(0x10074), "main");
+        let disassembler = Disassembler::with_symbols(&symbols);
+        let pc = Word(0x10074);
+        let disassemble = |word| {
+            disassembler.disassemble(pc, Instruction::new(Word(word)))
+        };
+
+        assert_eq!(disassemble(0xfe010113), "addi\tsp,sp,-32");
+        assert_eq!(disassemble(0x00000513), "li\ta0,0");
+        assert_eq!(disassemble(0x00050793), "mv\ta5,a0");
+        assert_eq!(disassemble(0x00000013), "nop");
+        assert_eq!(disassemble(0x00008067), "ret");
+        assert_eq!(disassemble(0x00112e23), "sw\tra,28(sp)");
+        assert_eq!(disassemble(0x01c12083), "lw\tra,28(sp)");
+        assert_eq!(disassemble(0x000127b7), "lui\ta5,0x12");
+        assert_eq!(disassemble(0x00279793), "slli\ta5,a5,0x2");
+        assert_eq!(disassemble(0x40f50533), "sub\ta0,a0,a5");
+        assert_eq!(disassemble(0x008000ef), "jal\tra,1007c <main+0x8>");
+        assert_eq!(disassemble(0x0000006f), "j\t10074 <main>");
+        assert_eq!(disassemble(0x00000073), "ecall");
+        assert_eq!(disassembler.format_line(pc, Instruction::new(Word(0x00050463))),
+                   "   10074:\t00050463          \tbeqz\ta0,1007c <main+0x8>");
+    }
+
+    #[test]
+    fn assembler_program_on_core() {
+        use std::rc::Rc;
+        use std::cell::RefCell;
+
+        use assembler::*;
+        use cache::*;
+        use isa::*;
+        use memory::*;
+        use simulator::*;
+        use syscall::NoSyscalls;
+
+        let source = "
+            .data
+        array:
+            .word 1, 2, 3, 4, 5
+        result:
+            .word 0
+        message:
+            .string \"hi\\n\"
+
+            .text
+            .globl _start
+        _start:
+            la a0, array
+            li a1, 5
+            li a2, 0
+        1:  lw t0, 0(a0)      # sum the array
+            add a2, a2, t0
+            addi a0, a0, 4
+            addi a1, a1, -1
+            bnez a1, 1b
+            li t1, 0x12345678
+            lui t2, %hi(result)
+            sw t1, %lo(result)(t2)
+            lw t4, result
+        2:  auipc t5, %pcrel_hi(message)
+            addi t5, t5, %pcrel_lo(2b)
+            lbu t6, 2(t5)
+            ret
+        ";
+
+        let program = Assembler::new(Word(0x1000), Word(0x2000))
+            .assemble(source).unwrap();
+        assert_eq!(program.entry(), Word(0x1000));
+        assert_eq!(program.symbol("result"), Some(Word(0x2014)));
+        assert_eq!(&program.data[0x18..], b"hi\n\0");
+
+        let mut memory = Memory::new(0x1000);
+        program.load(&mut memory);
+        let memory_ref = Rc::new(RefCell::new(memory));
+        let cache = Rc::new(RefCell::new(DirectMappedCache::new(
+            4, 4, memory_ref.clone(), EmptyEventHandler {})));
+        let core = Core::new(0, program.entry(), Word(0x3FF0),
+                             cache.clone(), Box::new(IdentityMmu::new()));
+        let mut simulator = Simulator::new(
+            vec![core], memory_ref.clone(), vec![cache.clone()], NoSyscalls {});
+        simulator.run_max(10000);
+
+        let registers = simulator.cores()[0].registers();
+        assert_eq!(registers.read_word(Register::X12), Word(15));
+        assert_eq!(registers.read_word(Register::X29), Word(0x12345678));
+        assert_eq!(registers.read_word(Register::X30), Word(0x2018));
+        assert_eq!(registers.read_word(Register::X31), Word(b'\n' as u32));
+
+        let error = Assembler::new(Word(0x1000), Word(0x2000))
+            .assemble("addi a0, a0, 4096").err().unwrap();
+        assert_eq!(error.line, 1);
+        for source in [".zero -1", ".space 0x100000000", ".align 32", ".align -1",
+                       ".balign -2", "li a0, é"].iter() {
+            assert!(Assembler::new(Word(0x1000), Word(0x2000))
+                    .assemble(source).is_err());
+        }
+    }
+
+    #[test]
+    fn encoder_round_trip() {
+        use isa::*;
+        use isa::encoding::EncodingError;
+
+        let addi = Instruction::addi(Register::X10, Register::X2, -32).unwrap();
+        assert_eq!(addi.word(), Word(0xfe010513));
+        assert_eq!(addi.i_imm(), SignedWord(-32));
+
+        let sw = Instruction::sw(Register::X2, Register::X1, 28).unwrap();
+        assert_eq!(sw.word(), Word(0x00112e23));
+        assert_eq!(sw.s_imm(), SignedWord(28));
+
+        for &offset in [-4096, -2, 0, 2, 2048, 4094].iter() {
+            let branch = Instruction::bne(Register::X5, Register::X6, offset).unwrap();
+            assert_eq!(branch.sb_imm(), SignedWord(offset));
+        }
+        for &offset in [-(1 << 20), -4, 0, 2048, (1 << 20) - 2].iter() {
+            let jump = Instruction::jal(Register::X1, offset).unwrap();
+            assert_eq!(jump.uj_imm(), SignedWord(offset));
+            assert_eq!(jump.rd(), Register::X1);
+        }
+        let lui = Instruction::lui(Register::X15, 0x12000).unwrap();
+        assert_eq!(lui.u_imm(), SignedWord(0x12000));
+        assert_eq!(Instruction::sub(Register::X10, Register::X10, Register::X15).word(),
+                   Word(0x40f50533));
+
+        assert_eq!(Instruction::addi(Register::X1, Register::X1, 2048).err(),
+                   Some(EncodingError::ImmediateOutOfRange {
+                       value: 2048,
+                       min: -2048,
+                       max: 2047,
+                   }));
+        assert_eq!(Instruction::beq(Register::X1, Register::X1, 3).err(),
+                   Some(EncodingError::MisalignedImmediate {
+                       value: 3,
+                       alignment: 2,
+                   }));
+        assert!(Instruction::slli(Register::X1, Register::X1, 32).is_err());
+        assert!(Instruction::lui(Register::X1, 0x123).is_err());
+    }
+
+    #[test]
+    fn random_programs_match_reference() {
+        use generator::*;
+
+        for seed in 1..21 {
+            let mut generator = ProgramGenerator::new(seed, seed % 2 == 0);
+            let program = generator.generate(300);
+            assert_eq!(check_against_reference(&program, 100000), Ok(()),
+                       "seed {}", seed);
+        }
+    }
+
+    #[test]
+    fn lockstep_reports_first_divergence() {
+        use std::rc::Rc;
+        use std::cell::RefCell;
+
+        use assembler::*;
+        use cache::*;
+        use functional::FunctionalCore;
+        use isa::*;
+        use lockstep::*;
+        use memory::*;
+        use simulator::*;
+        use syscall::NoSyscalls;
+
+        let source = "
+            .data
+        value:
+            .word 7
+            .text
+            la a0, value
+            lw a1, 0(a0)
+            addi a1, a1, 1
+            sw a1, 4(a0)
+            ret
+        ";
+        let program = Assembler::new(Word(0x1000), Word(0x2000))
+            .assemble(source).unwrap();
+
+        let check = |patch: Option<Word>| {
+            let mut memory = Memory::new(0x1000);
+            program.load(&mut memory);
+            let memory_ref = Rc::new(RefCell::new(memory));
+            let cache = Rc::new(RefCell::new(DirectMappedCache::new(
+                4, 4, memory_ref.clone(), EmptyEventHandler {})));
+            let core = Core::new(0, program.entry(), Word(0), cache.clone(),
+                                 Box::new(IdentityMmu::new()));
+            let simulator = Simulator::new(vec![core], memory_ref.clone(),
+                                           vec![cache], NoSyscalls {});
+
+            let mut reference_memory = Memory::new(0x1000);
+            program.load(&mut reference_memory);
+            if let Some(value) = patch {
+                reference_memory.write_word(Word(0x2000), value).unwrap();
+            }
+            let reference = FunctionalCore::new(0, program.entry(), Word(0),
+                                                Box::new(IdentityMmu::new()));
+            let mut checker = LockstepChecker::new(
+                simulator, vec![reference], Box::new(reference_memory),
+                NoSyscalls {});
+            let result = checker.run_max(1000).map(|_| ());
+            (result, checker.retired()[0])
+        };
+
+        assert_eq!(check(None), (Ok(()), 6));
+
+        let (result, retired) = check(Some(Word(8)));
+        let divergence = result.unwrap_err();
+        assert_eq!(retired, 2);
+        assert_eq!(divergence.actual.pc, Word(0x1008));
+        assert_eq!(divergence.kind, DivergenceKind::Register {
+            register: Register::X11,
+            expected: Word(8),
+            actual: Word(7),
+        });
+    }
+
+    #[test]
+    fn set_associative_replacement() {
+        use std::rc::Rc;
+        use std::cell::RefCell;
+
+        use cache::*;
+        use cache::replacement::*;
+        use isa::*;
+        use memory::*;
+
+        let mut lru = LruPolicy::new(1, 4);
+        let mut fifo = FifoPolicy::new(1, 4);
+        let mut plru = TreePlruPolicy::new(1, 4);
+        let mut nru = NruPolicy::new(1, 4);
+        for way in 0..4 {
+            lru.insert(0, way);
+            fifo.insert(0, way);
+            plru.insert(0, way);
+            nru.insert(0, way);
+        }
+        lru.touch(0, 0);
+        fifo.touch(0, 0);
+        plru.touch(0, 0);
+        assert_eq!(lru.victim(0), 1);
+        assert_eq!(fifo.victim(0), 0);
+        assert_eq!(plru.victim(0), 2);
+        assert_eq!(nru.victim(0), 0);
+        nru.touch(0, 0);
+        assert_eq!(nru.victim(0), 1);
+        for way in 1..4 {
+            nru.touch(0, way);
+        }
+        nru.invalidate(0, 2);
+        assert_eq!(nru.victim(0), 2);
+
+        let mut random = RandomPolicy::new(4, 42);
+        let mut same_seed = RandomPolicy::new(4, 42);
+        for _ in 0..16 {
+            let way = random.victim(0);
+            assert!(way < 4);
+            assert_eq!(way, same_seed.victim(0));
+        }
+
+        struct Recorder {
+            ways: RefCell<Vec<u32>>,
+        }
+
+        impl EventHandler for Recorder {
+            fn block_requested(&self, location: CacheLocation) {
+                self.ways.borrow_mut().push(location.way);
+            }
+
+            fn block_fetched(&self, _: CacheLocation) {}
+        }
+
+        let memory_ref = Rc::new(RefCell::new(Memory::new(0x40)));
+        let mut cache = SetAssociativeCache::new(
+            1, 2, 1, Replacement::Lru.build(1, 2), memory_ref.clone(),
+            Recorder { ways: RefCell::new(Vec::new()) });
+        {
+            let mut read = |address| {
+                while let Err(MemoryError::CacheMiss { .. }) = cache.read_word(Word(address)) {
+                    cache.step();
+                }
+                cache.cache_metadata().tags
+            };
+
+            read(0x10);
+            read(0x20);
+            read(0x10);
+            assert_eq!(read(0x30), vec![Some(Word(0x4)), Some(Word(0xC))]);
+            assert_eq!(read(0x20), vec![Some(Word(0x8)), Some(Word(0xC))]);
+        }
+        assert_eq!(*cache.events().ways.borrow(), vec![0, 1, 1, 0]);
+    }
+
+    #[test]
+    fn fully_associative_in_front_of_direct_mapped() {
+        use std::rc::Rc;
+        use std::cell::RefCell;
+
+        use cache::*;
+        use isa::*;
+        use memory::*;
+
+        let mut memory = Memory::new(0x1000);
+        for address in 1..0x1000 {
+            memory.write_word(Word(4 * address), Word(address)).unwrap();
+        }
+        let memory_ref = Rc::new(RefCell::new(memory));
+        let l1 = Rc::new(RefCell::new(DirectMappedCache::new(
+            4, 4, memory_ref.clone(), EmptyEventHandler {})));
+        let mut l0 = FullyAssociativeCache::new(
+            4, 2, Replacement::Lru, l1.clone(), EmptyEventHandler {});
+
+        let read = |l0: &mut FullyAssociativeCache<EmptyEventHandler>, address| {
+            loop {
+                match l0.read_word(Word(address)) {
+                    Ok(value) => return value,
+                    Err(MemoryError::CacheMiss { .. }) => {
+                        l0.step();
+                        l1.borrow_mut().step();
+                    },
+                    Err(e) => panic!("{:?}", e),
+                }
+            }
+        };
+
+        // Four lines that conflict in the L1 all fit in the L0
+        for &address in [0x100, 0x208, 0x310, 0x418].iter() {
+            assert_eq!(read(&mut l0, address), Word(address / 4));
+        }
+        for &address in [0x104, 0x20C, 0x314, 0x41C].iter() {
+            assert!(l0.is_address_accessible(Word(address)));
+        }
+        // The least recently used line is evicted
+        assert_eq!(read(&mut l0, 0x100), Word(0x40));
+        assert_eq!(read(&mut l0, 0x520), Word(0x148));
+        assert!(!l0.is_address_accessible(Word(0x208)));
+        assert!(l0.is_address_accessible(Word(0x100)));
+        assert_eq!(l0.cache_metadata().num_ways, 4);
+
+        let mut large = FullyAssociativeCache::new(
+            4096, 1, Replacement::Fifo, memory_ref.clone(), EmptyEventHandler {});
+        for address in 1..0x1000 {
+            while let Err(_) = large.read_word(Word(4 * address)) {
+                large.step();
+            }
+        }
+        for address in 1..0x1000 {
+            assert_eq!(large.read_word(Word(4 * address)), Ok(Word(address)));
+        }
+    }
+
+    #[test]
+    fn write_back_and_no_allocate() {
+        use std::rc::Rc;
+        use std::cell::RefCell;
+
+        use cache::*;
+        use isa::*;
+        use memory::*;
+
+        let memory_ref = Rc::new(RefCell::new(Memory::new(0x100)));
+        let mut cache = DirectMappedCache::new(
+            2, 1, memory_ref.clone(), EmptyEventHandler {});
+        cache.set_write_policy(WritePolicy::WriteBack);
+        let memory = |address| memory_ref.borrow_mut().read_word(Word(address));
+
+        while let Err(_) = cache.write_word(Word(0x10), Word(0x1234)) {
+            cache.step();
+            cache.set_retry(true);
+        }
+        cache.set_retry(false);
+        assert!(cache.is_dirty(Word(0x10)));
+        assert_eq!(memory(0x10), Ok(Word(0)));
+
+        // Evicting a dirty block costs an extra trip to memory
+        assert_eq!(cache.read_word(Word(0x18)), Err(MemoryError::CacheMiss {
+            stall_cycles: 200,
+            retry: true,
+        }));
+        assert!(!cache.is_address_accessible(Word(0x10)));
+        cache.step();
+        assert_eq!(memory(0x10), Ok(Word(0x1234)));
+        cache.set_retry(true);
+        while let Err(_) = cache.read_word(Word(0x18)) {
+            cache.step();
+        }
+        cache.set_retry(false);
+
+        assert_eq!(cache.write_byte(Word(0x19), Byte(0x56)), Ok(()));
+        assert_eq!(cache.flush(), Err(MemoryError::CacheMiss {
+            stall_cycles: 100,
+            retry: false,
+        }));
+        assert!(!cache.is_dirty(Word(0x18)));
+        cache.step();
+        assert_eq!(memory(0x18), Ok(Word(0x5600)));
+        assert_eq!(cache.flush(), Ok(()));
+
+        assert_eq!(cache.write_word(Word(0x18), Word(0x78)), Ok(()));
+        assert!(cache.invalidate(Word(0x18)).is_err());
+        assert!(!cache.is_address_accessible(Word(0x18)));
+        cache.step();
+        assert_eq!(memory(0x18), Ok(Word(0x78)));
+
+        cache.set_write_miss_policy(WriteMissPolicy::WriteNoAllocate);
+        assert_eq!(cache.write_word(Word(0x24), Word(0x9A)), Ok(()));
+        assert_eq!(cache.write_halfword(Word(0x26), HalfWord(0xBC)), Ok(()));
+        assert!(!cache.is_address_accessible(Word(0x24)));
+        assert_eq!(memory(0x24), Ok(Word(0xBC009A)));
+        // Stores that bypass the cache still count as misses
+        let stats = cache.cache_stats();
+        assert_eq!((stats.writes, stats.misses), (5, 4));
+    }
+
+    #[test]
+    fn mshrs_merge_and_hit_under_miss() {
+        use std::rc::Rc;
+        use std::cell::RefCell;
+
+        use cache::*;
+        use isa::*;
+        use memory::*;
+
+        let memory_ref = Rc::new(RefCell::new(Memory::new(0x100)));
+        let mut cache = SetAssociativeCache::new(
+            2, 1, 2, Replacement::Lru.build(2, 1), memory_ref.clone(),
+            EmptyEventHandler {});
+        cache.set_mshr_count(2);
+        let miss = |cycles| Err(MemoryError::CacheMiss {
+            stall_cycles: cycles,
+            retry: true,
+        });
+
+        while let Err(_) = cache.read_word(Word(0x40)) {
+            cache.step();
+        }
+
+        assert_eq!(cache.read_word(Word(0x10)), miss(100));
+        cache.step();
+        assert_eq!(cache.read_word(Word(0x28)), miss(100));
+        assert_eq!(cache.outstanding_misses(), 2);
+        // Secondary misses merge with the outstanding fetch
+        assert_eq!(cache.read_word(Word(0x14)), miss(99));
+        // All MSHRs are in use
+        assert_eq!(cache.read_word(Word(0x80)), miss(99));
+        assert_eq!(cache.outstanding_misses(), 2);
+        // Hits are served under the misses
+        assert_eq!(cache.read_word(Word(0x44)), Ok(Word(0)));
+
+        for _ in 0..99 {
+            cache.step();
+        }
+        assert_eq!(cache.outstanding_misses(), 1);
+        assert_eq!(cache.read_word(Word(0x10)), Ok(Word(0)));
+        cache.step();
+        assert_eq!(cache.outstanding_misses(), 0);
+        assert!(cache.is_address_accessible(Word(0x28)));
+
+        // A miss must wait when every way of its set is reserved
+        let mut cache = SetAssociativeCache::new(
+            1, 2, 1, Replacement::Lru.build(1, 2), memory_ref.clone(),
+            EmptyEventHandler {});
+        assert_eq!(cache.read_word(Word(0x10)), miss(100));
+        assert_eq!(cache.read_word(Word(0x20)), miss(100));
+        assert_eq!(cache.read_word(Word(0x30)), miss(100));
+        assert_eq!(cache.outstanding_misses(), 2);
+    }
+
+    #[test]
+    fn hierarchy_inclusion_and_fetch() {
+        use std::rc::Rc;
+        use std::cell::RefCell;
+
+        use assembler::*;
+        use cache::*;
+        use hierarchy::*;
+        use isa::*;
+        use memory::*;
+        use simulator::*;
+        use syscall::NoSyscalls;
+
+        let read = |hierarchy: &CacheHierarchy, address| {
+            loop {
+                if let Ok(value) = hierarchy.l1d[0].borrow_mut().read_word(Word(address)) {
+                    return value;
+                }
+                for cache in hierarchy.caches() {
+                    cache.borrow_mut().step();
+                }
+            }
+        };
+        let l1 = CacheConfig::new(4, 2, 2);
+        let l2 = CacheConfig::new(1, 1, 2);
+
+        // Evicting a block from an inclusive L2 removes it from the L1
+        let memory: SharedMemory = Rc::new(RefCell::new(Memory::new(0x100)));
+        let hierarchy = HierarchyBuilder::new(1, l1, l1, l2)
+            .inclusion(Inclusion::Inclusive)
+            .build(memory.clone());
+        read(&hierarchy, 0x10);
+        assert!(hierarchy.l2.borrow().is_address_accessible(Word(0x10)));
+        read(&hierarchy, 0x28);
+        for cache in hierarchy.caches() {
+            cache.borrow_mut().step();
+        }
+        assert!(!hierarchy.l1d[0].borrow().is_address_accessible(Word(0x10)));
+        assert!(hierarchy.l1d[0].borrow().is_address_accessible(Word(0x28)));
+
+        // An exclusive L2 gives blocks up, and takes back L1 victims
+        let memory: SharedMemory = Rc::new(RefCell::new(Memory::new(0x100)));
+        memory.borrow_mut().write_word(Word(0x10), Word(0x42)).unwrap();
+        let hierarchy = HierarchyBuilder::new(1, l1, l1, CacheConfig::new(1, 2, 2))
+            .inclusion(Inclusion::Exclusive)
+            .build(memory.clone());
+        assert_eq!(read(&hierarchy, 0x10), Word(0x42));
+        assert!(!hierarchy.l2.borrow().is_address_accessible(Word(0x10)));
+        read(&hierarchy, 0x30);
+        read(&hierarchy, 0x50);
+        assert!(!hierarchy.l1d[0].borrow().is_address_accessible(Word(0x10)));
+        for cache in hierarchy.caches() {
+            cache.borrow_mut().step();
+        }
+        assert!(hierarchy.l2.borrow().is_address_accessible(Word(0x10)));
+        assert_eq!(read(&hierarchy, 0x10), Word(0x42));
+
+        // Instruction fetch goes through the L1I
+        let source = "
+            li a0, 0
+            li a1, 10
+        1:  addi a0, a0, 3
+            addi a1, a1, -1
+            bnez a1, 1b
+            ret
+        ";
+        let program = Assembler::new(Word(0x1000), Word(0x2000))
+            .assemble(source).unwrap();
+        let mut memory = Memory::new(0x1000);
+        program.load(&mut memory);
+        let memory: SharedMemory = Rc::new(RefCell::new(memory));
+        let hierarchy = HierarchyBuilder::new(1, l1, l1, CacheConfig::new(16, 4, 2))
+            .l3(CacheConfig::new(64, 8, 2))
+            .build(memory.clone());
+        let core = hierarchy.core(0, program.entry(), Word(0),
+                                  Box::new(IdentityMmu::new()));
+        let mut simulator = Simulator::new(vec![core], memory.clone(),
+                                           hierarchy.caches(), NoSyscalls {});
+        simulator.run();
+        assert_eq!(simulator.cores()[0].registers().read_word(Register::X10),
+                   Word(30));
+        assert!(hierarchy.l1i[0].borrow().is_address_accessible(Word(0x1008)));
+        assert!(hierarchy.l3.as_ref().unwrap().borrow()
+                .is_address_accessible(Word(0x1008)));
+        // Each of the three instruction blocks missed all the way to memory
+        let stalls = simulator.report()[0].stalls;
+        assert!(stalls >= 3 * 99);
+    }
+
+    #[test]
+    fn snooping_mesi_and_moesi() {
+        use std::rc::Rc;
+        use std::cell::RefCell;
+
+        use cache::*;
+        use isa::*;
+        use memory::*;
+
+        struct Transitions {
+            seen: RefCell<Vec<(CoherenceState, CoherenceState)>>,
+        }
+
+        impl EventHandler for Transitions {
+            fn block_requested(&self, _: CacheLocation) {}
+            fn block_fetched(&self, _: CacheLocation) {}
+            fn state_changed(&self, _: Address, from: CoherenceState,
+                             to: CoherenceState) {
+                self.seen.borrow_mut().push((from, to));
+            }
+        }
+
+        type Caches<'a> = Vec<Rc<RefCell<CoherentCache<'a, Transitions>>>>;
+
+        fn build<'a>(memory: &SharedMemory<'a>, protocol: Protocol) -> Caches<'a> {
+            let bus = SnoopingBus::new(protocol, 2);
+            (0..2).map(|_| {
+                let cache = SetAssociativeCache::new(
+                    4, 2, 2, Replacement::Lru.build(4, 2), memory.clone(),
+                    Transitions { seen: RefCell::new(Vec::new()) });
+                CoherentCache::new(cache, &bus)
+            }).collect()
+        }
+
+        fn step(caches: &Caches) {
+            for cache in caches {
+                cache.borrow_mut().step();
+            }
+        }
+
+        fn read(caches: &Caches, id: usize, address: u32) -> Word {
+            loop {
+                let result = caches[id].borrow_mut().read_word(Word(address));
+                match result {
+                    Ok(value) => return value,
+                    Err(MemoryError::CacheMiss { .. }) => step(caches),
+                    Err(MemoryError::InvalidAddress) => panic!("invalid address"),
+                }
+            }
+        }
+
+        fn write(caches: &Caches, id: usize, address: u32, value: u32) {
+            loop {
+                let result = caches[id].borrow_mut()
+                    .write_word(Word(address), Word(value));
+                match result {
+                    Ok(()) | Err(MemoryError::CacheMiss { retry: false, .. }) => return,
+                    Err(MemoryError::CacheMiss { .. }) => step(caches),
+                    Err(MemoryError::InvalidAddress) => panic!("invalid address"),
+                }
+            }
+        }
+
+        use cache::CoherenceState::*;
+
+        let memory: SharedMemory = Rc::new(RefCell::new(Memory::new(0x100)));
+        memory.borrow_mut().write_word(Word(0x10), Word(7)).unwrap();
+        let caches = build(&memory, Protocol::Mesi);
+        // Every miss pays for the bus, even when no cache supplies it
+        assert_eq!(caches[0].borrow_mut().read_word(Word(0x10)),
+                   Err(MemoryError::CacheMiss { stall_cycles: 102, retry: true }));
+        assert_eq!(read(&caches, 0, 0x10), Word(7));
+        assert_eq!(caches[0].borrow().state(Word(0x10)), Exclusive);
+        // The second reader is supplied by the first
+        assert_eq!(read(&caches, 1, 0x14), Word(0));
+        assert_eq!(caches[0].borrow().state(Word(0x10)), Shared);
+        assert_eq!(caches[1].borrow().state(Word(0x10)), Shared);
+        assert_eq!(caches[0].borrow().coherence_stats().interventions, 1);
+
+        write(&caches, 1, 0x10, 9);
+        assert_eq!(caches[0].borrow().state(Word(0x10)), Invalid);
+        assert_eq!(caches[1].borrow().state(Word(0x10)), Modified);
+        assert_eq!(read(&caches, 0, 0x10), Word(9));
+        assert_eq!(caches[1].borrow().state(Word(0x10)), Shared);
+        let stats = caches[0].borrow().coherence_stats();
+        assert_eq!(stats.coherence_misses, 1);
+        assert_eq!(stats.invalidations, 1);
+        assert_eq!(caches[1].borrow().coherence_stats().upgrades, 1);
+        // Under MESI, sharing a modified block writes it back
+        step(&caches);
+        assert_eq!(memory.borrow_mut().read_word(Word(0x10)), Ok(Word(9)));
+        write(&caches, 0, 0x14, 1);
+        assert_eq!(caches[1].borrow().state(Word(0x10)), Invalid);
+        assert_eq!(*caches[0].borrow().events().seen.borrow(),
+                   vec![(Invalid, Exclusive), (Exclusive, Shared),
+                        (Shared, Invalid), (Invalid, Shared),
+                        (Shared, Modified)]);
+
+        // Under MOESI, the writer keeps the dirty block as its owner
+        let memory: SharedMemory = Rc::new(RefCell::new(Memory::new(0x100)));
+        memory.borrow_mut().write_word(Word(0x10), Word(7)).unwrap();
+        let caches = build(&memory, Protocol::Moesi);
+        assert_eq!(read(&caches, 0, 0x10), Word(7));
+        write(&caches, 0, 0x10, 9);
+        assert_eq!(caches[0].borrow().coherence_stats().upgrades, 0);
+        assert_eq!(read(&caches, 1, 0x10), Word(9));
+        assert_eq!(caches[0].borrow().state(Word(0x10)), Owned);
+        assert_eq!(caches[1].borrow().state(Word(0x10)), Shared);
+        write(&caches, 1, 0x10, 11);
+        assert_eq!(caches[0].borrow().state(Word(0x10)), Invalid);
+        assert_eq!(read(&caches, 0, 0x10), Word(11));
+        step(&caches);
+        assert_eq!(memory.borrow_mut().read_word(Word(0x10)), Ok(Word(7)));
+    }
+
+    #[test]
+    fn directory_coherence() {
+        use std::rc::Rc;
+        use std::cell::RefCell;
+
+        use cache::*;
+        use cache::CoherenceState::*;
+        use isa::*;
+        use memory::*;
+
+        type Caches<'a> = Vec<Rc<RefCell<CoherentCache<'a, EmptyEventHandler>>>>;
+
+        fn run(caches: &Caches, id: usize, address: u32, value: Option<u32>) -> Word {
+            loop {
+                let result = match value {
+                    Some(value) => caches[id].borrow_mut()
+                        .write_word(Word(address), Word(value))
+                        .map(|_| Word(value)),
+                    None => caches[id].borrow_mut().read_word(Word(address)),
+                };
+                match result {
+                    Ok(value) => return value,
+                    Err(MemoryError::CacheMiss { retry: false, .. }) => return Word(0),
+                    Err(MemoryError::CacheMiss { .. }) => {
+                        for cache in caches {
+                            cache.borrow_mut().step();
+                        }
+                    },
+                    Err(MemoryError::InvalidAddress) => panic!("invalid address"),
+                }
+            }
+        }
+
+        let latencies = DirectoryLatencies {
+            request: 2,
+            forward: 4,
+            invalidate: 4,
+            reply: 2,
+        };
+        for &tracking in &[SharerTracking::FullMap, SharerTracking::LimitedPointer(2)] {
+            let memory: SharedMemory = Rc::new(RefCell::new(Memory::new(0x100)));
+            memory.borrow_mut().write_word(Word(0x10), Word(7)).unwrap();
+            let directory = Directory::new(Protocol::Mesi, tracking, latencies);
+            let caches: Caches = (0..4).map(|_| {
+                let cache = SetAssociativeCache::new(
+                    4, 2, 2, Replacement::Lru.build(4, 2), memory.clone(),
+                    EmptyEventHandler {});
+                CoherentCache::new(cache, &directory)
+            }).collect();
+
+            assert_eq!(run(&caches, 0, 0x10, None), Word(7));
+            assert_eq!(caches[0].borrow().state(Word(0x10)), Exclusive);
+            run(&caches, 0, 0x10, Some(9));
+            // The owner supplies the block
+            assert_eq!(run(&caches, 1, 0x10, None), Word(9));
+            assert_eq!(directory.borrow().stats().forwards, 1);
+            assert_eq!(directory.borrow().sharers(Word(0x10)), Some(vec![0, 1]));
+            assert_eq!(run(&caches, 2, 0x14, None), Word(0));
+            assert_eq!(caches[2].borrow().state(Word(0x10)), Shared);
+
+            run(&caches, 3, 0x10, Some(5));
+            for id in 0..3 {
+                assert_eq!(caches[id].borrow().state(Word(0x10)), Invalid);
+            }
+            let stats = directory.borrow().stats();
+            assert_eq!(stats.invalidations, 3);
+            match tracking {
+                SharerTracking::FullMap => assert_eq!(stats.broadcasts, 0),
+                SharerTracking::LimitedPointer(_) => assert_eq!(stats.broadcasts, 1),
+            }
+            assert_eq!(directory.borrow().sharers(Word(0x10)), Some(vec![3]));
+
+            assert_eq!(run(&caches, 0, 0x10, None), Word(5));
+            assert_eq!(caches[0].borrow().coherence_stats().coherence_misses, 1);
+            assert_eq!(caches[3].borrow().state(Word(0x10)), Shared);
+        }
+    }
+
+    #[test]
+    fn prefetchers() {
+        use std::rc::Rc;
+        use std::cell::RefCell;
+
+        use cache::*;
+        use isa::*;
+        use memory::*;
+
+        type Cache<'a> = SetAssociativeCache<'a, EmptyEventHandler>;
+
+        fn build<'a>(prefetch: Prefetch) -> Cache<'a> {
+            let memory: SharedMemory = Rc::new(RefCell::new(Memory::new(0x1000)));
+            let mut cache = SetAssociativeCache::new(
+                16, 2, 2, Replacement::Lru.build(16, 2), memory,
+                EmptyEventHandler {});
+            cache.set_prefetcher(prefetch.build(2));
+            cache
+        }
+
+        fn read(cache: &mut Cache, pc: u32, address: u32) {
+            cache.set_pc(Word(pc));
+            cache.set_retry(false);
+            while let Err(_) = cache.read_word(Word(address)) {
+                cache.step();
+                cache.set_retry(true);
+            }
+        }
+
+        // A sequential sweep misses once, then stays ahead
+        let mut cache = build(Prefetch::NextLine { degree: 1 });
+        for address in (0..0x100).step_by(4) {
+            read(&mut cache, 0x1000, address);
+        }
+        let stats = cache.prefetch_stats();
+        assert_eq!(stats.misses, 1);
+        assert_eq!(stats.useful, 31);
+        assert!(stats.coverage() > 0.95);
+        // Evict the block prefetched past the end of the sweep
+        read(&mut cache, 0x1000, 0x200);
+        read(&mut cache, 0x1000, 0x300);
+        assert!(cache.prefetch_stats().unused >= 1);
+
+        // One load strides through memory, while another hits in place
+        let mut cache = build(Prefetch::Stride { entries: 16, degree: 1 });
+        for i in 0..16 {
+            read(&mut cache, 0x1000, 0x800);
+            read(&mut cache, 0x1004, 0x40 * i);
+        }
+        let stats = cache.prefetch_stats();
+        assert_eq!(stats.useful, 13);
+        assert_eq!(stats.misses, 4);
+        // Only the prefetch past the last access goes unused
+        assert_eq!(stats.issued, 14);
+
+        // A descending stream is detected and run ahead of
+        let mut cache = build(Prefetch::Stream { streams: 2, distance: 4, degree: 2 });
+        for i in 0..32 {
+            read(&mut cache, 0x1000, 0x3F8 - 8 * i);
+        }
+        let stats = cache.prefetch_stats();
+        // Three misses establish the stream's direction
+        assert_eq!(stats.misses, 3);
+        assert_eq!(stats.useful, 29);
+    }
+
+    #[test]
+    fn victim_and_miss_caches() {
+        use std::rc::Rc;
+        use std::cell::RefCell;
+
+        use cache::*;
+        use isa::*;
+        use memory::*;
+
+        type Cache<'a> = DirectMappedCache<'a, EmptyEventHandler>;
+
+        fn build<'a>(memory: &SharedMemory<'a>, buffer: VictimBuffer) -> Cache<'a> {
+            let mut cache = DirectMappedCache::new(
+                4, 1, memory.clone(), EmptyEventHandler {});
+            cache.set_write_policy(WritePolicy::WriteBack);
+            cache.attach_buffer(buffer);
+            cache
+        }
+
+        // Returns the value read and the stall reported by the first try
+        fn read(cache: &mut Cache, address: u32) -> (Word, u32) {
+            let mut stall = 0;
+            loop {
+                match cache.read_word(Word(address)) {
+                    Ok(value) => return (value, stall),
+                    Err(MemoryError::CacheMiss { stall_cycles, .. }) => {
+                        if stall == 0 {
+                            stall = stall_cycles;
+                        }
+                        cache.step();
+                    },
+                    Err(MemoryError::InvalidAddress) => panic!("invalid address"),
+                }
+            }
+        }
+
+        // 0x40 and 0x50 conflict in the direct-mapped cache
+        let memory: SharedMemory = Rc::new(RefCell::new(Memory::new(0x100)));
+        memory.borrow_mut().write_word(Word(0x50), Word(5)).unwrap();
+        let mut cache = build(&memory, VictimBuffer::victim_cache(1, 2));
+        while let Err(_) = cache.write_word(Word(0x40), Word(7)) {
+            cache.step();
+        }
+        let (_, stall) = read(&mut cache, 0x50);
+        assert!(stall >= 100);
+        for _ in 0..4 {
+            assert_eq!(read(&mut cache, 0x40), (Word(7), 2));
+            assert_eq!(read(&mut cache, 0x50), (Word(5), 2));
+        }
+        let stats = cache.buffer_stats().unwrap();
+        assert_eq!(stats.lookups, 10);
+        assert_eq!(stats.hits, 8);
+        // The dirty block moved between the cache and the buffer without
+        // being written back, until the flush
+        assert_eq!(memory.borrow_mut().read_word(Word(0x40)), Ok(Word(0)));
+        assert_eq!(cache.buffer().unwrap().blocks(), vec![Word(0x40)]);
+        let _ = cache.flush();
+        cache.step();
+        assert_eq!(memory.borrow_mut().read_word(Word(0x40)), Ok(Word(7)));
+
+        // Without write allocation, a store to a block in the buffer still
+        // goes to the buffered copy rather than around it
+        let next_level: SharedMemory = Rc::new(RefCell::new(Memory::new(0x100)));
+        let mut cache = build(&next_level, VictimBuffer::victim_cache(1, 2));
+        cache.set_write_miss_policy(WriteMissPolicy::WriteNoAllocate);
+        read(&mut cache, 0x40);
+        while let Err(_) = cache.write_word(Word(0x40), Word(8)) {
+            cache.step();
+        }
+        read(&mut cache, 0x50);
+        assert_eq!(cache.buffer().unwrap().blocks(), vec![Word(0x40)]);
+        while let Err(_) = cache.write_word(Word(0x40), Word(9)) {
+            cache.step();
+        }
+        assert_eq!(read(&mut cache, 0x40), (Word(9), 0));
+        let _ = cache.flush();
+        cache.step();
+        assert_eq!(next_level.borrow_mut().read_word(Word(0x40)), Ok(Word(9)));
+
+        // A miss cache keeps copies of both blocks
+        let mut cache = build(&memory, VictimBuffer::miss_cache(2, 1));
+        for _ in 0..5 {
+            read(&mut cache, 0x40);
+            read(&mut cache, 0x50);
+        }
+        let stats = cache.buffer_stats().unwrap();
+        assert_eq!(stats.lookups, 10);
+        assert_eq!(stats.hits, 8);
+        // A store makes the copy stale, so it is dropped
+        while let Err(_) = cache.write_word(Word(0x50), Word(6)) {
+            cache.step();
+        }
+        assert_eq!(cache.buffer().unwrap().blocks(), vec![Word(0x40)]);
+        assert_eq!(read(&mut cache, 0x40).0, Word(7));
+        assert_eq!(read(&mut cache, 0x50).0, Word(6));
+    }
+
+    #[test]
+    fn cache_stats_and_miss_classes() {
+        use std::rc::Rc;
+        use std::cell::RefCell;
+
+        use cache::*;
+        use isa::*;
+        use memory::*;
+
+        let memory: SharedMemory = Rc::new(RefCell::new(Memory::new(0x100)));
+        let mut cache = DirectMappedCache::new(4, 1, memory, EmptyEventHandler {});
+        cache.set_write_policy(WritePolicy::WriteBack);
+        cache.classify_misses();
+
+        while let Err(_) = cache.write_word(Word(0x40), Word(1)) {
+            cache.step();
+            cache.set_retry(true);
+        }
+        // 0x40 and 0x50 conflict, though a fully associative cache
+        // would hold both; then a sweep of six blocks overflows it
+        let addresses = [0x40, 0x50, 0x40, 0x50,
+                         0x100, 0x104, 0x108, 0x10C, 0x110, 0x114, 0x100];
+        for &address in addresses.iter() {
+            cache.set_retry(false);
+            while let Err(_) = cache.read_word(Word(address)) {
+                cache.step();
+                cache.set_retry(true);
+            }
+        }
+
+        let stats = cache.cache_stats();
+        assert_eq!(stats.reads, 11);
+        assert_eq!(stats.writes, 1);
+        assert_eq!(stats.hits, 1);
+        assert_eq!(stats.misses, 11);
+        assert_eq!(stats.evictions, 7);
+        assert_eq!(stats.writebacks, 1);
+        assert_eq!(stats.classes, Some(MissClasses {
+            compulsory: 8,
+            capacity: 1,
+            conflict: 2,
+        }));
+
+        let table = stats.to_string();
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("cache"));
+        assert!(lines[0].ends_with("conflict"));
+        assert!(lines[1].ends_with(" 2"));
+    }
+
+    /// Assemble the loop that most of the timing tests run, which sums
+    /// `array` into a2. `start` goes before the loop and `finish` after.
+    fn sum_program(start: &str, finish: &str) -> ::assembler::Program {
+        use assembler::*;
+        use isa::*;
+
+        let source = format!("
+            .data
+        array:
+            .word 1, 2, 3, 4
+        result:
+            .word 0
+
+            .text
+        {}
+            la a0, array
+            li a1, 4
+            li a2, 0
+        loop:
+            lw t0, 0(a0)
+            add a2, a2, t0
+            addi a0, a0, 4
+            addi a1, a1, -1
+            bnez a1, loop
+        {}
+        ", start, finish);
+        Assembler::new(Word(0x1000), Word(0x2000)).assemble(&source).unwrap()
+    }
+
+    /// Load a program into memory behind a direct-mapped data cache.
+    fn load(program: &::assembler::Program)
+            -> (::memory::SharedMemory<'static>, ::cache::SharedCache<'static>) {
+        use std::rc::Rc;
+        use std::cell::RefCell;
+
+        use cache::*;
+        use memory::*;
+
+        let mut memory = Memory::new(0x1000);
+        program.load(&mut memory);
+        let memory: SharedMemory = Rc::new(RefCell::new(memory));
+        let cache: SharedCache = Rc::new(RefCell::new(DirectMappedCache::new(
+            16, 4, memory.clone(), EmptyEventHandler {})));
+        (memory, cache)
+    }
+
+    /// Put a loaded program on an in-order core, set up by `configure`.
+    fn core_simulator<F>(program: &::assembler::Program, configure: F)
+                         -> ::simulator::Simulator<'static, ::syscall::NoSyscalls>
+        where F: FnOnce(&mut ::simulator::Core<'static>) {
+        use isa::*;
+        use memory::*;
+        use simulator::*;
+        use syscall::NoSyscalls;
+
+        let (memory, cache) = load(program);
+        let mut core = Core::new(0, program.entry(), Word(0x3FF0),
+                                 cache.clone(), Box::new(IdentityMmu::new()));
+        configure(&mut core);
+        Simulator::new(vec![core], memory, vec![cache], NoSyscalls {})
+    }
+
+    /// Run a program to completion on an in-order core set up by
+    /// `configure`.
+    fn run_core<F>(program: &::assembler::Program, configure: F)
+                   -> ::simulator::Simulator<'static, ::syscall::NoSyscalls>
+        where F: FnOnce(&mut ::simulator::Core<'static>) {
+        let mut simulator = core_simulator(program, configure);
+        simulator.run();
+        simulator
+    }
+
+    #[test]
+    fn five_stage_pipeline() {
+        use isa::*;
+        use pipeline::*;
+
+        let program = sum_program("", "ret");
+        let base_stalls = run_core(&program, |_| {}).report()[0].stalls;
+
+        // Each add waits a cycle for its load, and each taken branch
+        // flushes two instructions
+        let mut simulator = run_core(&program, |core| {
+            core.set_pipeline(PipelineConfig::new())
+        });
+        assert_eq!(simulator.cores()[0].registers().read_word(Register::X12), Word(10));
+        let stats = simulator.cores()[0].pipeline_stats().unwrap();
+        assert_eq!(stats.load_use_stalls, 4);
+        assert_eq!(stats.data_stalls, 0);
+        assert_eq!(stats.flushes, 3);
+        assert_eq!(stats.control_stalls, 6);
+        assert_eq!(simulator.report()[0].stalls, base_stalls + 10);
+
+        // Resolving branches in ID halves the flush, but each branch
+        // waits for the addi before it
+        let mut simulator = run_core(&program, |core| core.set_pipeline(PipelineConfig {
+            forwarding: Forwarding::full(),
+            branch_resolution: BranchResolution::Decode,
+        }));
+        assert_eq!(simulator.cores()[0].registers().read_word(Register::X12), Word(10));
+        let stats = simulator.cores()[0].pipeline_stats().unwrap();
+        assert_eq!(stats.load_use_stalls, 4);
+        assert_eq!(stats.data_stalls, 4);
+        assert_eq!(stats.control_stalls, 3);
+
+        // Without forwarding, dependent instructions wait for writeback
+        let mut simulator = run_core(&program, |core| core.set_pipeline(PipelineConfig {
+            forwarding: Forwarding::none(),
+            branch_resolution: BranchResolution::Execute,
+        }));
+        assert_eq!(simulator.cores()[0].registers().read_word(Register::X12), Word(10));
+        let stats = simulator.cores()[0].pipeline_stats().unwrap();
+        assert_eq!(stats.load_use_stalls, 4 * 2);
+        assert!(stats.data_stalls > 0);
+    }
+
+    #[test]
+    fn branch_prediction() {
+        use branch::*;
+        use isa::*;
+
+        // A loop branch taken seven times out of eight
+        let accuracy = |predictor: Predictor| {
+            let mut predictor = predictor.build();
+            let (pc, target) = (Word(0x1010), Word(0x1000));
+            let mut correct = 0;
+            for i in 0..800 {
+                let taken = i % 8 != 7;
+                if predictor.predict(pc, target) == taken {
+                    correct += 1;
+                }
+                predictor.update(pc, target, taken);
+            }
+            correct as f64 / 800.0
+        };
+        assert_eq!(accuracy(Predictor::AlwaysNotTaken), 0.125);
+        assert_eq!(accuracy(Predictor::Btfn), 0.875);
+        assert!(accuracy(Predictor::Bimodal { entries: 16 }) < 0.9);
+        assert!(accuracy(Predictor::Gshare { entries: 256, history: 8 }) > 0.95);
+        assert!(accuracy(Predictor::Tournament { entries: 256, history: 8 }) > 0.95);
+        assert!(accuracy(Predictor::Tage { tables: 4, entries: 256 }) > 0.95);
+
+        let program = sum_program("
+        _start:
+            jal sum
+            jal sum
+            li ra, 0
+            ret
+        sum:", "ret");
+        let base_stalls = run_core(&program, |_| {}).report()[0].stalls;
+
+        let unit = BranchUnit::new(Predictor::Bimodal { entries: 16 }.build(), 16, 4, 3);
+        let mut simulator = run_core(&program, |core| core.set_branch_unit(unit));
+        assert_eq!(simulator.cores()[0].registers().read_word(Register::X12), Word(10));
+        let stats = simulator.cores()[0].branch_stats().unwrap();
+        assert_eq!(stats.branches, 8);
+        // The first two iterations train the counter, and each loop exit
+        // is mispredicted
+        assert_eq!(stats.direction_mispredictions, 3);
+        assert_eq!(stats.accuracy(), 5.0 / 8.0);
+        assert_eq!(stats.jumps, 4);
+        // Both returns come from the stack, but each call misses in the
+        // BTB the only time it runs
+        assert_eq!(stats.ras_hits, 2);
+        assert_eq!(stats.btb_misses, 2);
+        assert_eq!(stats.mispredictions, 5);
+        assert_eq!(stats.penalty_cycles, 15);
+        assert_eq!(simulator.report()[0].stalls, base_stalls + 15);
+    }
+
+    #[test]
+    fn out_of_order_core() {
+        use isa::*;
+        use memory::*;
+        use ooo::*;
+        use simulator::*;
+        use syscall::NoSyscalls;
+
+        let program = sum_program("", "
+            la a3, result
+            sw a2, 0(a3)
+            lw a4, 0(a3)
+            ret");
+        let in_order_cycles = run_core(&program, |_| {}).report()[0].cycles;
+
+        let (memory, cache) = load(&program);
+        let core = OutOfOrderCore::new(0, program.entry(), Word(0x3FF0),
+                                       cache.clone(), Box::new(IdentityMmu::new()),
+                                       OutOfOrderConfig::new());
+        let mut simulator = Simulator::new(
+            vec![core], memory.clone(), vec![cache.clone()], NoSyscalls {});
+        simulator.run();
+        {
+            let core = &mut simulator.cores()[0];
+            assert_eq!(core.registers().read_word(Register::X12), Word(10));
+            assert_eq!(core.registers().read_word(Register::X14), Word(10));
+        }
+        assert_eq!(memory.borrow_mut().read_word(Word(0x2010)), Ok(Word(10)));
+
+        let stats = simulator.cores()[0].stats();
+        let cycles = simulator.report()[0].cycles;
+        assert_eq!(stats.cycles, cycles);
+        assert_eq!(stats.committed, 29);
+        assert_eq!(stats.forwarded_loads, 1);
+        assert!(stats.mispredictions > 0);
+        assert!(cycles < in_order_cycles);
+    }
+
+    #[test]
+    fn superscalar_issue() {
+        use functional::FunctionalCore;
+        use isa::*;
+        use lockstep::*;
+        use memory::*;
+        use superscalar::*;
+        use syscall::NoSyscalls;
+
+        let program = sum_program("", "ret");
+
+        // Runs the program checked against the reference, returning the
+        // issue statistics and the cycles taken
+        let run = |config: Option<SuperscalarConfig>| {
+            let simulator = core_simulator(&program, |core| {
+                if let Some(config) = config {
+                    core.set_superscalar(config);
+                }
+            });
+
+            let mut reference_memory = Memory::new(0x1000);
+            program.load(&mut reference_memory);
+            let reference = FunctionalCore::new(0, program.entry(), Word(0x3FF0),
+                                                Box::new(IdentityMmu::new()));
+            let mut checker = LockstepChecker::new(
+                simulator, vec![reference], Box::new(reference_memory),
+                NoSyscalls {});
+            assert!(checker.run_max(1000).is_ok());
+            assert_eq!(checker.retired()[0], 25);
+
+            let simulator = checker.simulator();
+            assert_eq!(simulator.cores()[0].registers().read_word(Register::X12),
+                       Word(10));
+            let cycles = simulator.report()[0].cycles;
+            (simulator.cores()[0].superscalar_stats(), cycles)
+        };
+
+        let (_, scalar_cycles) = run(None);
+
+        let (stats, cycles) = run(Some(SuperscalarConfig::new(2)));
+        let stats = stats.unwrap();
+        assert_eq!(stats.cycles, cycles);
+        assert_eq!(stats.issued, 25);
+        // Each iteration pairs the add with the next addi, and the final
+        // branch cannot pair with the ret for want of a branch unit
+        assert_eq!(stats.issue_histogram, vec![99, 15, 5]);
+        assert_eq!(stats.dependence_stops, 9);
+        assert_eq!(stats.structural_stops, 1);
+        assert_eq!(cycles, scalar_cycles - 6);
+
+        // Four slots let both addis join the add, but no more
+        let (wide, wide_cycles) = run(Some(SuperscalarConfig::new(4)));
+        let wide = wide.unwrap();
+        assert_eq!(wide.issue_histogram, vec![100, 10, 0, 5, 0]);
+        assert_eq!(wide_cycles, cycles - 4);
+        assert!(wide.utilisation() < stats.utilisation());
+    }
+
+    #[test]
+    fn functional_unit_latencies() {
+        use assembler::*;
+        use isa::*;
+        use latency::*;
+        use memory::*;
+        use ooo::*;
+        use register_file::RegisterFile;
+        use simulator::*;
+        use syscall::NoSyscalls;
+
+        let source = "
+            li a0, 100
+            li a1, 7
+            div a2, a0, a1
+            div a3, a0, a1
+            mul a4, a2, a1
+            add a5, a4, a4
+            ret
+        ";
+        let program = Assembler::new(Word(0x1000), Word(0x2000))
+            .assemble(source).unwrap();
+
+        let check = |registers: &mut RegisterFile| {
+            assert_eq!(registers.read_word(Register::X12), Word(14));
+            assert_eq!(registers.read_word(Register::X13), Word(14));
+            assert_eq!(registers.read_word(Register::X14), Word(98));
+            assert_eq!(registers.read_word(Register::X15), Word(196));
+        };
+
+        let mut simulator = run_core(&program, |core| core.set_latencies(LatencyTable::new()));
+        check(simulator.cores()[0].registers());
+        assert_eq!(simulator.cores()[0].latency_stats(), Some(LatencyStats::default()));
+        let base_stalls = simulator.report()[0].stalls;
+
+        // The second divide waits for the divider to finish the first,
+        // and the add waits for the multiply
+        let mut simulator = run_core(&program, |core| {
+            core.set_latencies(LatencyTable::typical())
+        });
+        check(simulator.cores()[0].registers());
+        let stats = simulator.cores()[0].latency_stats().unwrap();
+        assert_eq!(stats.structural_stalls, 31);
+        assert_eq!(stats.data_stalls, 2);
+        assert_eq!(simulator.report()[0].stalls, base_stalls + 33);
+
+        // A pipelined divider only delays the instructions that need it
+        let mut simulator = run_core(&program, |core| core.set_latencies(LatencyTable {
+            divide: UnitLatency::pipelined(32),
+            ..LatencyTable::typical()
+        }));
+        check(simulator.cores()[0].registers());
+        let stats = simulator.cores()[0].latency_stats().unwrap();
+        assert_eq!(stats.structural_stalls, 0);
+        assert_eq!(stats.data_stalls, 30 + 2);
+
+        // The out-of-order core waits for the divider too: the second
+        // divide finishes 64 cycles after the first issues, while the
+        // multiply and add overlap with it. With single-cycle units, the
+        // chain through them takes 3 cycles instead.
+        let run_ooo = |table: LatencyTable| {
+            let (memory, cache) = load(&program);
+            let core = OutOfOrderCore::new(0, program.entry(), Word(0x3FF0),
+                                           cache.clone(), Box::new(IdentityMmu::new()),
+                                           OutOfOrderConfig {
+                                               latencies: table,
+                                               ..OutOfOrderConfig::new()
+                                           });
+            let mut simulator = Simulator::new(
+                vec![core], memory.clone(), vec![cache.clone()], NoSyscalls {});
+            simulator.run();
+            check(simulator.cores()[0].registers());
+            simulator.cores()[0].stats().cycles
+        };
+        let fast = run_ooo(LatencyTable::new());
+        let slow = run_ooo(LatencyTable::typical());
+        assert_eq!(slow, fast + 64 - 3);
+    }
+
+    #[test]
+    fn cycle_stack_breakdown() {
+        use std::rc::Rc;
+        use std::cell::RefCell;
+
+        use branch::*;
+        use cache::*;
+        use isa::*;
+        use latency::*;
+        use memory::*;
+        use ooo::*;
+        use simulator::*;
+        use syscall::NoSyscalls;
+
+        let program = sum_program("", "
+            ecall
+            ret");
+
+        // Adds an instruction cache in front of the same memory
+        let build = || {
+            let (memory, dcache) = load(&program);
+            let icache: SharedCache = Rc::new(RefCell::new(DirectMappedCache::new(
+                16, 4, memory.clone(), EmptyEventHandler {})));
+            (memory, icache, dcache)
+        };
+
+        let (memory, icache, dcache) = build();
+        let mut core = Core::new(0, program.entry(), Word(0x3FF0), dcache.clone(),
+                                 Box::new(IdentityMmu::new()));
+        core.set_instruction_cache(icache.clone());
+        core.set_branch_unit(BranchUnit::new(
+            Predictor::Bimodal { entries: 16 }.build(), 16, 4, 3));
+        core.set_latencies(LatencyTable::typical());
+        let mut simulator = Simulator::new(
+            vec![core], memory.clone(), vec![icache.clone(), dcache.clone()],
+            NoSyscalls {});
+        simulator.run();
+
+        let report = simulator.report()[0];
+        let stack = report.cycle_stack;
+        assert_eq!(report.instructions_retired, 26);
+        assert_eq!(stack.total(), report.cycles);
+        // One instruction retires in each base cycle
+        assert_eq!(stack.base, 25);
+        assert_eq!(stack.syscall, 1);
+        // Each add waits a cycle for its two-cycle load
+        assert_eq!(stack.structural, 4);
+        let branches = simulator.cores()[0].branch_stats().unwrap();
+        assert_eq!(stack.branch_mispredict, branches.penalty_cycles);
+        assert!(stack.icache_miss > 0 && stack.dcache_miss > 0);
+        assert_eq!(report.cpi(), report.cycles as f64 / 26.0);
+        assert!(report.to_string().contains("CPI"));
+
+        let (memory, icache, dcache) = build();
+        let mut core = OutOfOrderCore::new(0, program.entry(), Word(0x3FF0),
+                                           dcache.clone(), Box::new(IdentityMmu::new()),
+                                           OutOfOrderConfig::new());
+        core.set_instruction_cache(icache.clone());
+        let mut simulator = Simulator::new(
+            vec![core], memory.clone(), vec![icache.clone(), dcache.clone()],
+            NoSyscalls {});
+        simulator.run();
+
+        let report = simulator.report()[0];
+        let stack = report.cycle_stack;
+        assert_eq!(report.instructions_retired, 26);
+        assert_eq!(stack.total(), report.cycles);
+        assert_eq!(stack.syscall, 1);
+        assert!(stack.base < 25);
+        assert!(stack.icache_miss > 0 && stack.dcache_miss > 0);
+    }
+
+    #[test]
+    fn instruction_profile() {
+        use disassembler::*;
+        use isa::*;
+        use latency::*;
+        use memory::*;
+        use profile::*;
+        use simulator::*;
+        use symbols::*;
+        use syscall::NoSyscalls;
+
+        let program = sum_program("", "
+            ecall
+            ret");
+        // The listing reads the loaded program back from memory
+        let (memory, dcache) = load(&program);
+
+        let mut core = Core::new(0, program.entry(), Word(0x3FF0), dcache.clone(),
+                                 Box::new(IdentityMmu::new()));
+        core.set_latencies(LatencyTable::typical());
+        core.set_profile();
+        let mut simulator = Simulator::new(
+            vec![core], memory.clone(), vec![dcache.clone()], NoSyscalls {});
+        simulator.run();
+        let cycles = simulator.report()[0].cycles as u64;
+
+        let core = &mut simulator.cores()[0];
+        let profile = core.profile().unwrap();
+        assert_eq!(profile.cycles(), cycles);
+        assert_eq!(profile.pcs().iter().map(|&(_, counts)| counts.cycles).sum::<u64>(),
+                   cycles);
+        assert_eq!(&profile.mnemonics()[..4],
+                   &[("addi", 11), ("add", 4), ("bne", 4), ("lw", 4)]);
+
+        let lw = program.symbol("loop").unwrap();
+        let load = profile.get(lw).unwrap();
+        assert_eq!(load.count, 4);
+        // The array fits in one line, so only the first load misses
+        assert_eq!(load.stall_cycles, 100);
+        assert_eq!(load.cycles, 104);
+        // Each add waits a cycle for its load
+        assert_eq!(profile.get(lw + 4).unwrap(),
+                   PcCounts { count: 4, cycles: 8, stall_cycles: 4 });
+
+        let mut symbols = SymbolTable::new();
+        symbols.insert(program.entry(), "_start");
+        symbols.insert(lw, "loop");
+        let listing = profile.annotate(&Disassembler::with_symbols(&symbols),
+                                       &mut *memory.borrow_mut());
+        assert!(listing.starts_with(" Percent    Count   Stalls\n\n00001000 <_start>:\n"));
+        assert!(listing.contains(
+            "\n00001010 <loop>:\n   80.00        4      100 :    1010:\t00052283          \tlw\tt0,0(a0)\n"));
+    }
+
+    #[test]
+    fn call_graph_profile() {
+        use std::rc::Rc;
+        use std::cell::RefCell;
+
+        use assembler::*;
+        use cache::*;
+        use callgraph::*;
+        use isa::*;
+        use memory::*;
+        use simulator::*;
+        use symbols::*;
+        use syscall::NoSyscalls;
+
+        let source = "
+            .text
+        _start:
+            addi sp, sp, -16
+            sw ra, 0(sp)
+            li a0, 3
+            call square
+            mv s0, a0
+            li a0, 4
+            call sumsq
+            add a0, a0, s0
+            lw ra, 0(sp)
+            addi sp, sp, 16
+            ret
+        square:
+            mul a0, a0, a0
+            ret
+        sumsq:
+            addi sp, sp, -16
+            sw ra, 0(sp)
+            call square
+            addi a0, a0, 1
+            lw ra, 0(sp)
+            addi sp, sp, 16
+            ret
+        ";
+        let program = Assembler::new(Word(0x1000), Word(0x2000))
+            .assemble(source).unwrap();
+        let mut memory = Memory::new(0x1000);
+        program.load(&mut memory);
+        let memory: SharedMemory = Rc::new(RefCell::new(memory));
+        let dcache: SharedCache = Rc::new(RefCell::new(DirectMappedCache::new(
+            16, 4, memory.clone(), EmptyEventHandler {})));
+
+        let mut core = Core::new(0, program.entry(), Word(0x3FF0), dcache.clone(),
+                                 Box::new(IdentityMmu::new()));
+        core.set_call_graph();
+        let mut simulator = Simulator::new(
+            vec![core], memory.clone(), vec![dcache.clone()], NoSyscalls {});
+        simulator.run();
+        let cycles = simulator.report()[0].cycles as u64;
+
+        let core = &mut simulator.cores()[0];
+        assert_eq!(core.registers().read_word(Register::X10), Word(26));
+        let calls = core.call_graph().unwrap();
+        assert_eq!(calls.stack(), vec![program.entry()]);
+
+        let mut symbols = SymbolTable::new();
+        for name in ["_start", "square", "sumsq"].iter() {
+            symbols.insert(program.symbol(name).unwrap(), name);
+        }
+        let functions = calls.functions();
+        assert_eq!(functions.iter().map(|&(_, stats)| stats.self_cycles).sum::<u64>(),
+                   cycles);
+        let (start, sumsq, square) = (functions[0], functions[1], functions[2]);
+        assert_eq!(start.0, program.entry());
+        assert_eq!(start.1.total_cycles, cycles);
+        assert_eq!((sumsq.0, square.0), (program.symbol("sumsq").unwrap(),
+                                         program.symbol("square").unwrap()));
+        assert_eq!(square.1, FunctionStats { calls: 2, self_cycles: 4, total_cycles: 4 });
+        assert_eq!(sumsq.1.calls, 1);
+        // sumsq includes one of the calls to square
+        assert_eq!(sumsq.1.total_cycles, sumsq.1.self_cycles + 2);
+
+        assert_eq!(calls.folded(&symbols),
+                   format!("_start {}\n_start;square 2\n_start;sumsq {}\n\
+                            _start;sumsq;square 2\n",
+                           start.1.self_cycles, sumsq.1.self_cycles));
+        let report = calls.gprof(&symbols);
+        assert!(report.starts_with("Flat profile:\n"));
+        assert!(report.contains(
+            "\n[3]       1.8        4         0         2  square [3]\n"));
+        assert!(report.contains(
+            "\n                                       1/2      sumsq [2]\n[3]"));
+
+        // A call retired before any cycle was charged still has a caller
+        let mut calls = CallGraph::new(Word(0x1000));
+        calls.retire(Instruction::new(Word(0x000000ef)), Word(0x1008));
+        assert_eq!(calls.functions(),
+                   vec![(Word(0x1000), FunctionStats::default()),
+                        (Word(0x1008), FunctionStats { calls: 1, ..FunctionStats::default() })]);
+        assert!(calls.gprof(&SymbolTable::new()).contains("1/1      0x1000 [1]\n"));
+    }
+
+    #[test]
+    fn spike_commit_log() {
+        use std::rc::Rc;
+        use std::cell::RefCell;
+
+        use assembler::*;
+        use cache::*;
+        use isa::*;
+        use memory::*;
+        use simulator::*;
+        use syscall::NoSyscalls;
+        use trace::*;
+
+        let source = "
+            .data
+        buffer:
+            .word 0, 0
+
+            .text
+        _start:
+            la a0, buffer
+            li a1, 42
+            sb a1, 1(a0)
+            sw a1, 4(a0)
+            lw a2, 0(a0)
+            ret
+        ";
+        let program = Assembler::new(Word(0x1000), Word(0x2000))
+            .assemble(source).unwrap();
+        let mut memory = Memory::new(0x1000);
+        program.load(&mut memory);
+        let memory: SharedMemory = Rc::new(RefCell::new(memory));
+        let dcache: SharedCache = Rc::new(RefCell::new(DirectMappedCache::new(
+            16, 4, memory.clone(), EmptyEventHandler {})));
+
+        let log = Rc::new(RefCell::new(CommitLog::new(Vec::new())));
+        let mut core = Core::new(0, program.entry(), Word(0x3FF0), dcache.clone(),
+                                 Box::new(IdentityMmu::new()));
+        core.set_trace(log.clone());
+        let mut simulator = Simulator::new(
+            vec![core], memory.clone(), vec![dcache.clone()], NoSyscalls {});
+        simulator.run();
+
+        let log = log.borrow();
+        let log = String::from_utf8_lossy(log.get_ref());
+        let expected = "\
+core   0: 0x00001000 (0x00001517) auipc   a0, 0x1
+core   0: 3 0x00001000 (0x00001517) x10 0x00002000
+core   0: 0x00001004 (0x00050513) mv      a0, a0
+core   0: 3 0x00001004 (0x00050513) x10 0x00002000
+core   0: 0x00001008 (0x02a00593) li      a1, 42
+core   0: 3 0x00001008 (0x02a00593) x11 0x0000002a
+core   0: 0x0000100c (0x00b500a3) sb      a1, 1(a0)
+core   0: 3 0x0000100c (0x00b500a3) mem 0x00002001 0x2a
+core   0: 0x00001010 (0x00b52223) sw      a1, 4(a0)
+core   0: 3 0x00001010 (0x00b52223) mem 0x00002004 0x0000002a
+core   0: 0x00001014 (0x00052603) lw      a2, 0(a0)
+core   0: 3 0x00001014 (0x00052603) x12 0x00002a00 mem 0x00002000
+core   0: 0x00001018 (0x00008067) ret
+core   0: 3 0x00001018 (0x00008067)
+";
+        // Accesses that missed in the cache are only logged once they retire
+        assert_eq!(log, expected);
+    }
+
+    #[test]
+    fn dinero_address_trace() {
+        use std::rc::Rc;
+        use std::cell::RefCell;
+
+        use assembler::*;
+        use cache::*;
+        use isa::*;
+        use memory::*;
+        use simulator::*;
+        use syscall::NoSyscalls;
+        use trace::*;
+
+        let source = "
+            .data
+        buffer:
+            .word 0, 0
+
+            .text
+        _start:
+            la a0, buffer
+            li a1, 42
+            sb a1, 1(a0)
+            sw a1, 4(a0)
+            lw a2, 0(a0)
+            ret
+        ";
+        let program = Assembler::new(Word(0x1000), Word(0x2000))
+            .assemble(source).unwrap();
+
+        let run = |format| {
+            let mut memory = Memory::new(0x1000);
+            program.load(&mut memory);
+            let memory: SharedMemory = Rc::new(RefCell::new(memory));
+            let icache: SharedCache = Rc::new(RefCell::new(DirectMappedCache::new(
+                16, 4, memory.clone(), EmptyEventHandler {})));
+            let dcache: SharedCache = Rc::new(RefCell::new(DirectMappedCache::new(
+                16, 4, memory.clone(), EmptyEventHandler {})));
+
+            let trace = Rc::new(RefCell::new(AddressTrace::new(Vec::new(), format)));
+            let mut core = Core::new(0, program.entry(), Word(0x3FF0), dcache.clone(),
+                                     Box::new(IdentityMmu::new()));
+            core.set_instruction_cache(icache.clone());
+            core.set_address_trace(trace.clone());
+            let mut simulator = Simulator::new(
+                vec![core], memory.clone(), vec![icache.clone(), dcache.clone()],
+                NoSyscalls {});
+            simulator.run();
+
+            let trace = trace.borrow();
+            trace.get_ref().clone()
+        };
+
+        // Fetches and accesses that missed appear once
+        assert_eq!(String::from_utf8(run(AddressTraceFormat::DinWithSizes)).unwrap(), "\
+2 1000 4
+2 1004 4
+2 1008 4
+2 100c 4
+1 2001 1
+2 1010 4
+1 2004 4
+2 1014 4
+0 2000 4
+2 1018 4
+");
+        let din = String::from_utf8(run(AddressTraceFormat::Din)).unwrap();
+        assert_eq!(din.lines().nth(4), Some("1 2001"));
+        assert_eq!(din.lines().count(), 10);
+
+        let binary = run(AddressTraceFormat::Binary);
+        assert_eq!(binary.len(), 10 * 8);
+        assert_eq!(&binary[..8], &[0x00, 0x10, 0, 0, 4, 0, 2, 0]);
+        assert_eq!(&binary[32..40], &[0x01, 0x20, 0, 0, 1, 0, 1, 0]);
+    }
+
+    #[test]
+    fn core_multiply_divide_edge_cases() {
+        use std::rc::Rc;
+        use std::cell::RefCell;
+
+        use assembler::*;
+        use cache::*;
+        use isa::*;
+        use memory::*;
+        use simulator::*;
+        use syscall::NoSyscalls;
+
+        let source = "
+            li a0, 7
+            li a1, 0
+            div s0, a0, a1
+            divu s1, a0, a1
+            rem s2, a0, a1
+            remu s3, a0, a1
+            lui t0, 0x80000
+            li t1, -1
+            div s4, t0, t1
+            rem s5, t0, t1
+            li t2, 2
+            mulhsu s6, t1, t1
+            mulhsu s7, t2, t1
+            mulhsu s8, t0, t2
+            mulh s9, t1, t1
+            mulhu s10, t1, t1
+            li a2, -7
+            div s11, a2, t2
+            rem a3, a2, t2
+            mul a4, t0, t1
+            ret
+        ";
+        let program = Assembler::new(Word(0x1000), Word(0x2000))
+            .assemble(source).unwrap();
+        let mut memory = Memory::new(0x1000);
+        program.load(&mut memory);
+        let memory_ref = Rc::new(RefCell::new(memory));
+        let cache = Rc::new(RefCell::new(DirectMappedCache::new(
+            4, 4, memory_ref.clone(), EmptyEventHandler {})));
+        let core = Core::new(0, program.entry(), Word(0x3FF0),
+                             cache.clone(), Box::new(IdentityMmu::new()));
+        let mut simulator = Simulator::new(
+            vec![core], memory_ref.clone(), vec![cache.clone()], NoSyscalls {});
+        simulator.run_max(1000);
+
+        let registers = simulator.cores()[0].registers();
+        // Division by zero gives all ones, and the remainder is the dividend
+        assert_eq!(registers.read_word(Register::X8), Word(0xFFFFFFFF));
+        assert_eq!(registers.read_word(Register::X9), Word(0xFFFFFFFF));
+        assert_eq!(registers.read_word(Register::X18), Word(7));
+        assert_eq!(registers.read_word(Register::X19), Word(7));
+        // Overflow gives the dividend and a zero remainder
+        assert_eq!(registers.read_word(Register::X20), Word(0x80000000));
+        assert_eq!(registers.read_word(Register::X21), Word(0));
+        // mulhsu treats only the first operand as signed
+        assert_eq!(registers.read_word(Register::X22), Word(0xFFFFFFFF));
+        assert_eq!(registers.read_word(Register::X23), Word(1));
+        assert_eq!(registers.read_word(Register::X24), Word(0xFFFFFFFF));
+        assert_eq!(registers.read_word(Register::X25), Word(0));
+        assert_eq!(registers.read_word(Register::X26), Word(0xFFFFFFFE));
+        // Signed division rounds towards zero
+        assert_eq!(registers.read_word(Register::X27), Word(-3i32 as u32));
+        assert_eq!(registers.read_word(Register::X13), Word(-1i32 as u32));
+        assert_eq!(registers.read_word(Register::X14), Word(0x80000000));
+    }
+
+    #[test]
+    fn core_backward_jal() {
+        use std::rc::Rc;
+        use std::cell::RefCell;
+
+        use assembler::*;
+        use cache::*;
+        use isa::*;
+        use memory::*;
+        use simulator::*;
+        use syscall::NoSyscalls;
+
+        // The backward jump's offset is -2048, where bit 10 of the
+        // immediate differs from its sign
+        let source = "
+        _start:
+            li a0, 0
+            j forward
+        back:
+            addi a0, a0, 1
+            ret
+            .space 2036
+        forward:
+            addi a0, a0, 10
+            j back
+        ";
+        let program = Assembler::new(Word(0x1000), Word(0x2000))
+            .assemble(source).unwrap();
+        let jump = Instruction::new(Word(0x801ff06f));
+        assert_eq!(jump.uj_imm(), SignedWord(-2048));
+
+        let mut memory = Memory::new(0x1000);
+        program.load(&mut memory);
+        assert_eq!(memory.read_instruction(Word(0x1808)), Some(jump));
+        let memory_ref = Rc::new(RefCell::new(memory));
+        let cache = Rc::new(RefCell::new(DirectMappedCache::new(
+            4, 4, memory_ref.clone(), EmptyEventHandler {})));
+        let core = Core::new(0, program.entry(), Word(0x3FF0),
+                             cache.clone(), Box::new(IdentityMmu::new()));
+        let mut simulator = Simulator::new(
+            vec![core], memory_ref.clone(), vec![cache.clone()], NoSyscalls {});
+        simulator.run_max(1000);
+
+        let core = &mut simulator.cores()[0];
+        assert!(!core.is_running());
+        assert_eq!(core.registers().read_word(Register::X10), Word(11));
+    }
+
+    #[test]
+    fn lockstep_through_fences() {
+        use std::rc::Rc;
+        use std::cell::RefCell;
+
+        use assembler::*;
+        use cache::*;
+        use functional::FunctionalCore;
+        use isa::*;
+        use lockstep::*;
+        use memory::*;
+        use simulator::*;
+        use syscall::NoSyscalls;
+
+        let source = "
+            .data
+        value:
+            .word 7
+            .text
+            la a0, value
+            sw zero, 0(a0)
+            fence
+            lw a1, 0(a0)
+            fence rw, w
+            addi a1, a1, 1
+            fence.i
+            sw a1, 4(a0)
+            ret
+        ";
+        let program = Assembler::new(Word(0x1000), Word(0x2000))
+            .assemble(source).unwrap();
+
+        let mut memory = Memory::new(0x1000);
+        program.load(&mut memory);
+        let memory_ref = Rc::new(RefCell::new(memory));
+        let cache = Rc::new(RefCell::new(DirectMappedCache::new(
+            4, 4, memory_ref.clone(), EmptyEventHandler {})));
+        let core = Core::new(0, program.entry(), Word(0), cache.clone(),
+                             Box::new(IdentityMmu::new()));
+        let simulator = Simulator::new(vec![core], memory_ref.clone(),
+                                       vec![cache], NoSyscalls {});
+
+        let mut reference_memory = Memory::new(0x1000);
+        program.load(&mut reference_memory);
+        let reference = FunctionalCore::new(0, program.entry(), Word(0),
+                                            Box::new(IdentityMmu::new()));
+        let mut checker = LockstepChecker::new(
+            simulator, vec![reference], Box::new(reference_memory),
+            NoSyscalls {});
+        assert!(checker.run_max(1000).is_ok());
+        assert_eq!(checker.retired()[0], 10);
+        assert_eq!(checker.simulator().cores()[0].registers().read_word(Register::X11),
+                   Word(1));
+    }
+
+    #[test]
+    fn store_to_victim_under_miss_is_written_back() {
+        use std::rc::Rc;
+        use std::cell::RefCell;
+
+        use cache::*;
+        use isa::*;
+        use memory::*;
+
+        let memory_ref = Rc::new(RefCell::new(Memory::new(0x100)));
+        let mut cache = SetAssociativeCache::new(
+            1, 1, 1, Replacement::Lru.build(1, 1), memory_ref.clone(),
+            EmptyEventHandler {});
+        cache.set_write_policy(WritePolicy::WriteBack);
+
+        while let Err(_) = cache.read_word(Word(0x10)) {
+            cache.step();
+        }
+        // The clean block is chosen as the victim, but stays valid until
+        // the fill, so the store hits it
+        assert!(cache.read_word(Word(0x20)).is_err());
+        assert_eq!(cache.write_word(Word(0x10), Word(0x1234)), Ok(()));
+        assert!(cache.is_dirty(Word(0x10)));
+
+        while let Err(_) = cache.read_word(Word(0x20)) {
+            cache.step();
+        }
+        assert!(!cache.is_address_accessible(Word(0x10)));
+        assert_eq!(cache.block_contents(Word(0x10)), Some(vec![Word(0x1234)]));
+        cache.step();
+        assert_eq!(memory_ref.borrow_mut().read_word(Word(0x10)), Ok(Word(0x1234)));
+        assert_eq!(cache.cache_stats().writebacks, 1);
+    }
+
+    #[test]
+    fn repeated_accesses_are_counted() {
+        use std::rc::Rc;
+        use std::cell::RefCell;
+
+        use assembler::*;
+        use cache::*;
+        use isa::*;
+        use memory::*;
+        use simulator::*;
+        use syscall::NoSyscalls;
+
+        // The same store misses four times without being retried
+        let source = "
+            li a1, 4
+        1:
+            sw a1, 0x100(zero)
+            addi a1, a1, -1
+            bnez a1, 1b
+            ret
+        ";
+        let program = Assembler::new(Word(0x1000), Word(0x2000))
+            .assemble(source).unwrap();
+        let mut memory = Memory::new(0x1000);
+        program.load(&mut memory);
+        let memory_ref = Rc::new(RefCell::new(memory));
+        let mut cache = DirectMappedCache::new(
+            4, 4, memory_ref.clone(), EmptyEventHandler {});
+        cache.set_write_miss_policy(WriteMissPolicy::WriteNoAllocate);
+        let cache = Rc::new(RefCell::new(cache));
+        let core = Core::new(0, program.entry(), Word(0x3FF0),
+                             cache.clone(), Box::new(IdentityMmu::new()));
+        let mut simulator = Simulator::new(
+            vec![core], memory_ref.clone(), vec![cache.clone()], NoSyscalls {});
+        simulator.run_max(1000);
+
+        assert_eq!(memory_ref.borrow_mut().read_word(Word(0x100)), Ok(Word(1)));
+        let stats = cache.borrow().cache_stats();
+        assert_eq!((stats.writes, stats.misses), (4, 4));
+    }
+
+    #[test]
+    fn superscalar_fetches_each_instruction_once() {
+        use std::rc::Rc;
+        use std::cell::RefCell;
+
+        use assembler::*;
+        use cache::*;
+        use isa::*;
+        use memory::*;
+        use simulator::*;
+        use superscalar::*;
+        use syscall::NoSyscalls;
+
+        // Most instructions wait a cycle to issue, and each load misses
+        let source = "
+            li a0, 0x100
+            li a1, 4
+        1:  lw t0, 0(a0)
+            add a2, a2, t0
+            addi a0, a0, 16
+            addi a1, a1, -1
+            bnez a1, 1b
+            ret
+        ";
+        let program = Assembler::new(Word(0x1000), Word(0x2000))
+            .assemble(source).unwrap();
+        let mut memory = Memory::new(0x1000);
+        program.load(&mut memory);
+        let memory: SharedMemory = Rc::new(RefCell::new(memory));
+        let icache: SharedCache = Rc::new(RefCell::new(DirectMappedCache::new(
+            16, 4, memory.clone(), EmptyEventHandler {})));
+        let dcache: SharedCache = Rc::new(RefCell::new(DirectMappedCache::new(
+            16, 4, memory.clone(), EmptyEventHandler {})));
+        let mut core = Core::new(0, program.entry(), Word(0x3FF0), dcache.clone(),
+                                 Box::new(IdentityMmu::new()));
+        core.set_instruction_cache(icache.clone());
+        core.set_superscalar(SuperscalarConfig::new(2));
+        let mut simulator = Simulator::new(
+            vec![core], memory.clone(), vec![icache.clone(), dcache.clone()],
+            NoSyscalls {});
+        simulator.run_max(2000);
+
+        let retired = simulator.report()[0].instructions_retired;
+        assert_eq!(retired, 23);
+        let stats = simulator.cores()[0].superscalar_stats().unwrap();
+        assert!(stats.dependence_stops > 0);
+        assert_eq!(icache.borrow().cache_stats().reads as u64, retired);
+        assert_eq!(dcache.borrow().cache_stats().reads, 4);
+    }
+
+    #[test]
+    fn fence_i_refetches_modified_instructions() {
+        use std::rc::Rc;
+        use std::cell::RefCell;
+
+        use assembler::*;
+        use cache::*;
+        use isa::*;
+        use memory::*;
+        use simulator::*;
+        use syscall::NoSyscalls;
+
+        // The second pass through the loop runs the patched instruction
+        let source = "
+            li a0, 0
+            li a1, 2
+        patch:
+            addi a0, a0, 1
+            addi a1, a1, -1
+            beqz a1, 1f
+            la t0, patch
+            la t1, replacement
+            lw t2, 0(t1)
+            sw t2, 0(t0)
+            fence.i
+            j patch
+        1:
+            ret
+        replacement:
+            addi a0, a0, 10
+        ";
+        let program = Assembler::new(Word(0x1000), Word(0x2000))
+            .assemble(source).unwrap();
+
+        for &with_icache in &[false, true] {
+            let mut memory = Memory::new(0x1000);
+            program.load(&mut memory);
+            let memory_ref: SharedMemory = Rc::new(RefCell::new(memory));
+            let icache = Rc::new(RefCell::new(DirectMappedCache::new(
+                4, 4, memory_ref.clone(), EmptyEventHandler {})));
+            let mut dcache = DirectMappedCache::new(
+                4, 4, memory_ref.clone(), EmptyEventHandler {});
+            dcache.set_write_policy(WritePolicy::WriteBack);
+            let dcache = Rc::new(RefCell::new(dcache));
+
+            let mut core = Core::new(0, program.entry(), Word(0), dcache.clone(),
+                                     Box::new(IdentityMmu::new()));
+            let mut caches: Vec<SharedMemory> = vec![dcache.clone()];
+            if with_icache {
+                core.set_instruction_cache(icache.clone());
+                caches.push(icache.clone());
+            }
+            let mut simulator = Simulator::new(vec![core], memory_ref.clone(),
+                                               caches, NoSyscalls {});
+            simulator.run_max(10000);
+
+            let core = &mut simulator.cores()[0];
+            assert!(!core.is_running());
+            assert_eq!(core.registers().read_word(Register::X10), Word(11));
+        }
+    }
+
+    #[test]
+    fn prefetched_blocks_in_coherent_caches() {
+        use std::rc::Rc;
+        use std::cell::RefCell;
+
+        use cache::*;
+        use isa::*;
+        use memory::*;
+
+        type Caches<'a> = Vec<Rc<RefCell<CoherentCache<'a, EmptyEventHandler>>>>;
+
+        fn run(caches: &Caches, id: usize, address: u32, value: Option<u32>) -> Word {
+            loop {
+                let result = match value {
+                    Some(value) => caches[id].borrow_mut()
+                        .write_word(Word(address), Word(value))
+                        .map(|_| Word(value)),
+                    None => caches[id].borrow_mut().read_word(Word(address)),
+                };
+                match result {
+                    Ok(value) => return value,
+                    Err(MemoryError::CacheMiss { retry: false, .. }) => return Word(0),
+                    Err(MemoryError::CacheMiss { .. }) => {
+                        for cache in caches {
+                            cache.borrow_mut().step();
+                        }
+                    },
+                    Err(MemoryError::InvalidAddress) => panic!("invalid address"),
+                }
+            }
+        }
+
+        let memory: SharedMemory = Rc::new(RefCell::new(Memory::new(0x100)));
+        memory.borrow_mut().write_word(Word(0x10), Word(7)).unwrap();
+        let bus = SnoopingBus::new(Protocol::Mesi, 2);
+        let caches: Caches = (0..2).map(|_| {
+            let cache = SetAssociativeCache::new(
+                4, 2, 2, Replacement::Lru.build(4, 2), memory.clone(),
+                EmptyEventHandler {});
+            CoherentCache::new(cache, &bus)
+        }).collect();
+        run(&caches, 1, 0x10, Some(9));
+
+        // A prefetcher attached after wrapping fetches the stale block
+        // straight from memory, outside the coherence protocol
+        caches[0].borrow_mut().set_prefetcher(Prefetch::NextLine { degree: 1 }.build(2));
+        run(&caches, 0, 0x08, None);
+        for _ in 0..200 {
+            for cache in &caches {
+                cache.borrow_mut().step();
+            }
+        }
+        assert!(caches[0].borrow().is_address_accessible(Word(0x10)));
+        assert_eq!(caches[0].borrow().state(Word(0x10)), CoherenceState::Invalid);
+        // The coherent read replaces the stale copy instead of adding one
+        assert_eq!(run(&caches, 0, 0x10, None), Word(9));
+        assert_eq!(caches[0].borrow().state(Word(0x10)), CoherenceState::Shared);
+        let _ = caches[0].borrow_mut().invalidate(Word(0x10));
+        assert!(!caches[0].borrow().is_address_accessible(Word(0x10)));
+    }
+
+    #[test]
+    #[should_panic(expected = "a coherent cache cannot prefetch")]
+    fn coherent_caches_reject_prefetchers() {
+        use std::rc::Rc;
+        use std::cell::RefCell;
+
+        use cache::*;
+        use memory::*;
+
+        let memory: SharedMemory = Rc::new(RefCell::new(Memory::new(0x100)));
+        let bus = SnoopingBus::new(Protocol::Mesi, 2);
+        let mut cache = SetAssociativeCache::new(
+            4, 2, 2, Replacement::Lru.build(4, 2), memory, EmptyEventHandler {});
+        cache.set_prefetcher(Prefetch::NextLine { degree: 1 }.build(2));
+        CoherentCache::new(cache, &bus);
+    }
 }
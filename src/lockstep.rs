@@ -0,0 +1,198 @@
+// Copyright 2016 David Li
+// This file is part of rustv.
+
+// rustv is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// rustv is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with rustv.  If not, see <http://www.gnu.org/licenses/>.
+
+use functional::FunctionalCore;
+use isa;
+use memory::MemoryInterface;
+use retirement::{MemoryAccess, Retirement};
+use simulator::{HaltReason, Simulator};
+use syscall::SyscallHandler;
+
+/// What differed between the timing core and the reference.
+#[derive(Debug, PartialEq)]
+pub enum DivergenceKind {
+    /// The instructions retired at different addresses.
+    Pc {
+        expected: isa::Address,
+        actual: isa::Address,
+    },
+    /// A register held a different value after retirement.
+    Register {
+        register: isa::Register,
+        expected: isa::Word,
+        actual: isa::Word,
+    },
+    /// The instructions wrote different registers or values. This is
+    /// checked instead of the whole register file for instructions
+    /// retired before the last one in a cycle.
+    Writeback {
+        expected: Option<(isa::Register, isa::Word)>,
+        actual: Option<(isa::Register, isa::Word)>,
+    },
+    /// The instructions wrote different data, or only one wrote.
+    MemoryWrite {
+        expected: Option<MemoryAccess>,
+        actual: Option<MemoryAccess>,
+    },
+    /// The timing core retired an instruction after the reference halted.
+    ReferenceHalted,
+}
+
+/// The first point at which a timing core disagreed with the reference.
+#[derive(Debug, PartialEq)]
+pub struct Divergence {
+    pub core: usize,
+    /// How many instructions the core had retired before this one.
+    pub retired: u64,
+    /// The instruction retired by the timing core.
+    pub actual: Retirement,
+    pub kind: DivergenceKind,
+}
+
+fn memory_write(retirement: &Retirement) -> Option<MemoryAccess> {
+    match retirement.access {
+        Some(access @ MemoryAccess::Write { .. }) => Some(access),
+        _ => None,
+    }
+}
+
+/// Runs a `Simulator` alongside one `FunctionalCore` per timing core.
+/// Whenever a timing core retires an instruction, the matching reference
+/// core executes one instruction and the two are compared.
+///
+/// The reference cores share a separate flat memory, which should be
+/// loaded with the same program as the simulator's memory, and a
+/// separate syscall handler.
+pub struct LockstepChecker<'a, T: SyscallHandler, R: SyscallHandler> {
+    simulator: Simulator<'a, T>,
+    references: Vec<FunctionalCore<'a>>,
+    memory: Box<MemoryInterface + 'a>,
+    system: R,
+    retired: Vec<u64>,
+}
+
+impl<'a, T: SyscallHandler, R: SyscallHandler> LockstepChecker<'a, T, R> {
+    pub fn new(simulator: Simulator<'a, T>, references: Vec<FunctionalCore<'a>>,
+               memory: Box<MemoryInterface + 'a>, system: R)
+               -> LockstepChecker<'a, T, R> {
+        let retired = vec![0; references.len()];
+        LockstepChecker {
+            simulator: simulator,
+            references: references,
+            memory: memory,
+            system: system,
+            retired: retired,
+        }
+    }
+
+    pub fn simulator(&mut self) -> &mut Simulator<'a, T> {
+        &mut self.simulator
+    }
+
+    /// The number of instructions each core has retired and checked.
+    pub fn retired(&self) -> &[u64] {
+        &self.retired
+    }
+
+    /// Check an instruction retired by a core. The register files are
+    /// compared only if it is the last the core retired in the cycle.
+    fn check(&mut self, index: usize, actual: Retirement, last: bool)
+             -> Result<(), Divergence> {
+        let retired = self.retired[index];
+        let divergence = |kind| Divergence {
+            core: index,
+            retired: retired,
+            actual: actual,
+            kind: kind,
+        };
+
+        let reference = &mut self.references[index];
+        reference.step(&mut *self.memory, &mut self.system);
+        let expected = match reference.take_retired() {
+            Some(expected) => expected,
+            None => return Err(divergence(DivergenceKind::ReferenceHalted)),
+        };
+
+        if expected.pc != actual.pc {
+            return Err(divergence(DivergenceKind::Pc {
+                expected: expected.pc,
+                actual: actual.pc,
+            }));
+        }
+
+        if memory_write(&expected) != memory_write(&actual) {
+            return Err(divergence(DivergenceKind::MemoryWrite {
+                expected: memory_write(&expected),
+                actual: memory_write(&actual),
+            }));
+        }
+
+        if !last {
+            if expected.writeback != actual.writeback {
+                return Err(divergence(DivergenceKind::Writeback {
+                    expected: expected.writeback,
+                    actual: actual.writeback,
+                }));
+            }
+            self.retired[index] += 1;
+            return Ok(());
+        }
+
+        let core = &mut self.simulator.cores()[index];
+        for num in 1..32 {
+            let register = isa::Register::from_num(num);
+            let expected = reference.registers().read_word(register);
+            let actual = core.registers().read_word(register);
+            if expected != actual {
+                return Err(divergence(DivergenceKind::Register {
+                    register: register,
+                    expected: expected,
+                    actual: actual,
+                }));
+            }
+        }
+
+        self.retired[index] += 1;
+        Ok(())
+    }
+
+    /// Advance the simulator by one cycle and check every instruction
+    /// retired in that cycle. Returns whether any core is still running.
+    pub fn step(&mut self) -> Result<bool, Divergence> {
+        let running = self.simulator.step();
+        for index in 0..self.references.len() {
+            let mut retired = Vec::new();
+            while let Some(actual) = self.simulator.cores()[index].take_retired() {
+                retired.push(actual);
+            }
+            let count = retired.len();
+            for (n, actual) in retired.into_iter().enumerate() {
+                try!(self.check(index, actual, n + 1 == count));
+            }
+        }
+        Ok(running)
+    }
+
+    /// Run until the cores halt or `cycles` cycles have elapsed.
+    pub fn run_max(&mut self, cycles: usize) -> Result<HaltReason, Divergence> {
+        for _ in 0..cycles {
+            if !try!(self.step()) {
+                return Ok(HaltReason::CoresHalted);
+            }
+        }
+        Ok(HaltReason::OutOfCycles)
+    }
+}
@@ -37,8 +37,32 @@ pub trait MemoryInterface {
 
     fn step(&mut self);
 
-    // fn prefetch(&mut self, address: isa::Address);
-    // fn invalidate(&mut self, address: isa::Address);
+    /// Start fetching the block containing an address, if this level
+    /// is a cache with room to do so. The request completes on its own.
+    fn prefetch(&mut self, _address: isa::Address) {}
+
+    /// Note whether the next accesses retry ones that missed, so that a
+    /// cache does not count them again. Requesters set this before each
+    /// access.
+    fn set_retry(&mut self, _retry: bool) {}
+
+    /// Remove the block containing an address, writing it back first
+    /// if it is dirty. Returns a non-retry `CacheMiss` if the writeback
+    /// costs cycles.
+    fn invalidate(&mut self, _address: isa::Address) -> Result<()> {
+        Ok(())
+    }
+
+    /// Write back every dirty block, keeping them in the cache.
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Remove every block, writing back the dirty ones first, so that
+    /// later reads see what other requesters wrote to the next level.
+    fn invalidate_all(&mut self) -> Result<()> {
+        Ok(())
+    }
 
     fn is_address_accessible(&self, address: isa::Address) -> bool;
 
@@ -0,0 +1,924 @@
+// Copyright 2016 David Li
+// This file is part of rustv.
+
+// rustv is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// rustv is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with rustv.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::VecDeque;
+
+use branch::{BranchPredictor, Predictor, ReturnAddressStack};
+use cache::SharedCache;
+use functional::{branch_taken, integer_result};
+use isa::{self, IsaType};
+use isa::{funct3, opcodes};
+use latency::{self, InstructionClass, LatencyTable};
+use memory::{MemoryError, Mmu, SharedMemory};
+use register_file::RegisterFile;
+use retirement::{access_width, writes_register};
+use simulator::{CoreInterface, CycleCategory, CycleStack};
+use syscall::SyscallHandler;
+use trap::Trap;
+
+/// The widths and queue sizes of an `OutOfOrderCore`.
+#[derive(Clone, Copy, Debug)]
+pub struct OutOfOrderConfig {
+    /// Instructions fetched per cycle
+    pub fetch_width: usize,
+    /// Instructions renamed and dispatched per cycle
+    pub dispatch_width: usize,
+    /// Instructions that begin executing per cycle
+    pub issue_width: usize,
+    /// Instructions committed per cycle
+    pub commit_width: usize,
+    pub rob_entries: usize,
+    pub reservation_stations: usize,
+    pub lsq_entries: usize,
+    pub latencies: LatencyTable,
+    /// Predicts conditional branches at fetch
+    pub predictor: Predictor,
+    pub ras_depth: usize,
+}
+
+impl OutOfOrderConfig {
+    /// A four-wide core with a 32-entry reorder buffer.
+    pub fn new() -> OutOfOrderConfig {
+        OutOfOrderConfig {
+            fetch_width: 4,
+            dispatch_width: 4,
+            issue_width: 4,
+            commit_width: 4,
+            rob_entries: 32,
+            reservation_stations: 16,
+            lsq_entries: 16,
+            latencies: LatencyTable::new(),
+            predictor: Predictor::Bimodal { entries: 256 },
+            ras_depth: 8,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct OutOfOrderStats {
+    pub cycles: u32,
+    pub committed: u32,
+    /// Committed branches and jumps whose next PC was mispredicted
+    pub mispredictions: u32,
+    /// Instructions discarded after mispredictions
+    pub squashed: u32,
+    /// Loads that took their value from an older store
+    pub forwarded_loads: u32,
+    /// Cycles in which dispatch stopped because the structure was full
+    pub rob_full: u32,
+    pub stations_full: u32,
+    pub lsq_full: u32,
+}
+
+impl OutOfOrderStats {
+    /// Instructions committed per cycle.
+    pub fn ipc(&self) -> f64 {
+        if self.cycles == 0 {
+            0.0
+        }
+        else {
+            self.committed as f64 / self.cycles as f64
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Operand {
+    Ready(isa::Word),
+    /// Waiting for the result of the ROB entry with this tag
+    Waiting(u64),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    /// In a reservation station, waiting for operands
+    Waiting,
+    /// A load with its address, which may access memory from this cycle
+    Memory(u32),
+    /// Executing, with the result broadcast in this cycle
+    Executing(u32),
+    Done,
+}
+
+struct Fetched {
+    pc: isa::Address,
+    inst: isa::Instruction,
+    /// Where fetch went next
+    predicted: isa::Address,
+    /// The fetch itself failed
+    illegal: bool,
+}
+
+/// A reorder buffer entry.
+struct Entry {
+    tag: u64,
+    pc: isa::Address,
+    inst: isa::Instruction,
+    predicted: isa::Address,
+    state: State,
+    operands: [Operand; 2],
+    /// The value for `rd`, or the data of a store
+    value: Option<isa::Word>,
+    /// The translated address of a load or store, once computed
+    address: Option<isa::Address>,
+    next_pc: isa::Address,
+    trap: Option<Trap>,
+    /// A jump to address 0, which halts the core when committed
+    halt: bool,
+    /// A load or store that missed, so that its next access retries
+    missed: bool,
+}
+
+/// How a load depends on the stores before it.
+enum StoreDependence {
+    None,
+    Forward(isa::Word),
+    /// An older store has an unknown or partially overlapping address
+    Blocked,
+}
+
+fn is_ecall(inst: isa::Instruction) -> bool {
+    inst.word() == isa::Word(0x00000073)
+}
+
+/// Sign or zero extend a loaded value to a word.
+fn extend(inst: isa::Instruction, raw: isa::Word) -> isa::Word {
+    isa::Word(match inst.funct3() {
+        funct3::LB => raw.0 as u8 as i8 as i32 as u32,
+        funct3::LH => raw.0 as u16 as i16 as i32 as u32,
+        funct3::LBU => raw.0 & 0xFF,
+        funct3::LHU => raw.0 & 0xFFFF,
+        _ => raw.0,
+    })
+}
+
+/// A core that executes the same ISA as `Core` out of order, with
+/// Tomasulo scheduling and a reorder buffer.
+///
+/// Each cycle, the core commits, writes back, accesses memory, issues,
+/// dispatches and fetches, in that order, so that a result written back
+/// in one cycle can be used by an instruction issuing in the same cycle.
+///
+/// - Fetch predicts conditional branches with a `BranchPredictor`, and
+///   returns with a return address stack. Other indirect jumps are
+///   predicted not taken. A full fetch group ends at a predicted-taken
+///   control transfer.
+/// - Dispatch renames registers to ROB entries. Each instruction that
+///   needs an operation takes a reservation station until it issues,
+///   and each load and store takes a load/store queue entry until it
+///   commits.
+/// - Issue picks the oldest instructions whose operands are ready and
+///   whose functional unit is free. Operations take the latency of their
+///   class; loads and stores compute their address, and the latency of
+///   a load counts from when it accesses memory.
+/// - Loads access the data cache, one per cycle, once every older store
+///   has an address. A load to the same address as an older store of at
+///   least its width takes the store's data instead.
+/// - A mispredicted control transfer squashes every younger instruction
+///   when it writes back, and fetch restarts at the right address.
+/// - Commit is in order. Stores write the data cache when they commit,
+///   one per cycle, and `ecall` runs there; instructions after an
+///   `ecall` are not dispatched until it commits.
+///
+/// Traps, including those of illegal fetches, are taken at commit, so
+/// the wrong path cannot trap.
+pub struct OutOfOrderCore<'a> {
+    id: usize,
+    config: OutOfOrderConfig,
+    /// The address of the next instruction to commit
+    pc: isa::Address,
+    /// The committed registers
+    registers: RegisterFile,
+    running: bool,
+    cache: SharedCache<'a>,
+    /// Instructions are fetched from memory if there is no cache
+    icache: Option<SharedCache<'a>>,
+    mmu: Box<Mmu + 'a>,
+    predictor: Box<BranchPredictor + 'a>,
+    ras: ReturnAddressStack,
+    fetch_pc: isa::Address,
+    /// Cycles until fetch retries after an instruction cache miss
+    fetch_stall: u32,
+    /// The last fetch missed, so that the next one retries it
+    fetch_missed: bool,
+    /// Fetch failed, and waits to be redirected
+    fetch_blocked: bool,
+    /// Nothing has committed since the last squash
+    refilling: bool,
+    /// Holds two cycles' worth of fetched instructions
+    fetch_queue: VecDeque<Fetched>,
+    rob: VecDeque<Entry>,
+    /// The ROB entry that will write each register, if any
+    rename: Vec<Option<u64>>,
+    next_tag: u64,
+    /// The cycle in which the store at the head of the ROB may retry
+    store_retry: u32,
+    /// The cycle from which each unpipelined unit is free, by class
+    unit_free: Vec<u32>,
+    /// Cycles in which nothing committed
+    stall_count: u32,
+    cycle_stack: CycleStack,
+    stats: OutOfOrderStats,
+}
+
+impl<'a> OutOfOrderCore<'a> {
+    pub fn new(id: usize, entry: isa::Address, sp: isa::Address,
+               cache: SharedCache<'a>, mmu: Box<Mmu + 'a>,
+               config: OutOfOrderConfig) -> OutOfOrderCore<'a> {
+        let mut registers = RegisterFile::new();
+        registers.write_word(isa::Register::X2, sp);
+        OutOfOrderCore {
+            id: id,
+            config: config,
+            pc: entry,
+            registers: registers,
+            running: true,
+            cache: cache,
+            icache: None,
+            mmu: mmu,
+            predictor: config.predictor.build(),
+            ras: ReturnAddressStack::new(config.ras_depth),
+            fetch_pc: entry,
+            fetch_stall: 0,
+            fetch_missed: false,
+            fetch_blocked: false,
+            refilling: false,
+            fetch_queue: VecDeque::new(),
+            rob: VecDeque::new(),
+            rename: vec![None; 32],
+            next_tag: 0,
+            store_retry: 0,
+            unit_free: vec![0; latency::CLASSES],
+            stall_count: 0,
+            cycle_stack: CycleStack::default(),
+            stats: OutOfOrderStats::default(),
+        }
+    }
+
+    pub fn pc(&self) -> isa::Address {
+        self.pc
+    }
+
+    pub fn config(&self) -> OutOfOrderConfig {
+        self.config
+    }
+
+    pub fn stats(&self) -> OutOfOrderStats {
+        self.stats
+    }
+
+    /// Fetch instructions through a cache, so that fetch misses stall
+    /// the front end.
+    pub fn set_instruction_cache(&mut self, icache: SharedCache<'a>) {
+        self.icache = Some(icache);
+    }
+
+    fn step_cycle(&mut self, memory: &SharedMemory<'a>, system: &mut SyscallHandler) {
+        self.stats.cycles += 1;
+        let now = self.stats.cycles;
+
+        let category = match self.commit(now, system) {
+            (_, true) => CycleCategory::Syscall,
+            (0, false) => {
+                self.stall_count += 1;
+                self.stall_category()
+            },
+            _ => CycleCategory::Base,
+        };
+        self.cycle_stack.add(category);
+        if !self.running {
+            return;
+        }
+        self.writeback(now);
+        self.access_memory(now);
+        self.issue(now);
+        self.dispatch();
+        self.fetch(memory);
+    }
+
+    /// Attribute a cycle in which nothing committed to the instruction at
+    /// the head of the ROB, or if it is empty, to the front end.
+    fn stall_category(&self) -> CycleCategory {
+        match self.rob.front() {
+            None if self.fetch_stall > 0 => CycleCategory::InstructionCache,
+            None if self.refilling => CycleCategory::BranchMispredict,
+            None => CycleCategory::Base,
+            Some(entry) => match entry.state {
+                // A load waiting for the cache, or a store retrying
+                State::Memory(_) | State::Done => CycleCategory::DataCache,
+                _ => CycleCategory::Structural,
+            },
+        }
+    }
+
+    /// Commit finished instructions in order. Returns how many committed,
+    /// and whether one was a system call.
+    fn commit(&mut self, now: u32, system: &mut SyscallHandler) -> (usize, bool) {
+        let mut committed = 0;
+        let mut syscall = false;
+        while committed < self.config.commit_width {
+            match self.rob.front() {
+                Some(entry) if entry.state == State::Done => {},
+                _ => break,
+            }
+            if let Some(trap) = self.rob[0].trap.take() {
+                self.trap(trap);
+                break;
+            }
+
+            let (pc, inst) = (self.rob[0].pc, self.rob[0].inst);
+            let mut last = false;
+            match inst.opcode() {
+                opcodes::STORE => {
+                    if now < self.store_retry {
+                        break;
+                    }
+                    let address = self.rob[0].address.unwrap();
+                    let value = self.rob[0].value.unwrap();
+                    let result = {
+                        let mut cache = self.cache.borrow_mut();
+                        cache.set_pc(pc);
+                        cache.set_retry(self.rob[0].missed);
+                        match inst.funct3() {
+                            funct3::SB => cache.write_byte(address, value.as_byte()),
+                            funct3::SH => cache.write_halfword(address, value.as_half_word()),
+                            _ => cache.write_word(address, value),
+                        }
+                    };
+                    match result {
+                        Ok(()) | Err(MemoryError::CacheMiss { retry: false, .. }) => {},
+                        Err(MemoryError::CacheMiss { stall_cycles, .. }) => {
+                            self.store_retry = now + stall_cycles;
+                            self.rob[0].missed = true;
+                            break;
+                        },
+                        Err(MemoryError::InvalidAddress) => {
+                            self.trap(Trap::IllegalWrite {
+                                address: pc,
+                                instruction: inst,
+                                memory_address: address,
+                                memory_value: value,
+                            });
+                            break;
+                        },
+                    }
+                    // The cache takes one store per cycle
+                    last = true;
+                },
+                opcodes::SYSTEM if is_ecall(inst) => {
+                    syscall = true;
+                    let result = system.syscall(self.id, &mut self.registers,
+                                                &*self.mmu);
+                    if let Some(trap) = result {
+                        self.trap(trap);
+                        break;
+                    }
+                },
+                opcodes::BRANCH => {
+                    let target = pc.wrapping_add(inst.sb_imm().as_word());
+                    let taken = self.rob[0].next_pc != pc + 4;
+                    self.predictor.update(pc, target, taken);
+                },
+                _ => {},
+            }
+
+            let entry = self.rob.pop_front().unwrap();
+            if entry.next_pc != entry.predicted {
+                self.stats.mispredictions += 1;
+            }
+            if entry.halt {
+                self.running = false;
+            }
+            else if let Some(value) = entry.value {
+                if writes_register(inst) {
+                    self.registers.write_word(inst.rd(), value);
+                }
+            }
+            if self.rename[inst.rd().as_num()] == Some(entry.tag) {
+                self.rename[inst.rd().as_num()] = None;
+            }
+            self.pc = entry.next_pc;
+            self.stats.committed += 1;
+            self.refilling = false;
+            committed += 1;
+            if last || !self.running {
+                break;
+            }
+        }
+        (committed, syscall)
+    }
+
+    /// Broadcast the results of instructions finishing this cycle, and
+    /// recover from the oldest mispredicted one.
+    fn writeback(&mut self, now: u32) {
+        for index in 0..self.rob.len() {
+            let finished = match self.rob[index].state {
+                State::Executing(cycle) => cycle <= now,
+                _ => false,
+            };
+            if !finished {
+                continue;
+            }
+
+            self.rob[index].state = State::Done;
+            let (tag, inst) = (self.rob[index].tag, self.rob[index].inst);
+            if let Some(value) = self.rob[index].value {
+                if writes_register(inst) {
+                    self.broadcast(tag, value);
+                }
+            }
+            let next_pc = self.rob[index].next_pc;
+            if next_pc != self.rob[index].predicted {
+                self.squash(index, next_pc);
+                return;
+            }
+        }
+    }
+
+    fn broadcast(&mut self, tag: u64, value: isa::Word) {
+        for entry in self.rob.iter_mut() {
+            if entry.state != State::Waiting {
+                continue;
+            }
+            for operand in entry.operands.iter_mut() {
+                if *operand == Operand::Waiting(tag) {
+                    *operand = Operand::Ready(value);
+                }
+            }
+        }
+    }
+
+    /// Discard every instruction after the ROB entry at `index`, and
+    /// restart fetch at `next_pc`.
+    fn squash(&mut self, index: usize, next_pc: isa::Address) {
+        self.stats.squashed += (self.rob.len() - index - 1 +
+                                self.fetch_queue.len()) as u32;
+        while self.rob.len() > index + 1 {
+            self.rob.pop_back();
+        }
+        self.fetch_queue.clear();
+
+        self.rename = vec![None; 32];
+        for entry in self.rob.iter() {
+            if writes_register(entry.inst) && entry.inst.rd() != isa::Register::X0 {
+                self.rename[entry.inst.rd().as_num()] = Some(entry.tag);
+            }
+        }
+
+        self.fetch_pc = next_pc;
+        self.fetch_stall = 0;
+        self.fetch_missed = false;
+        self.fetch_blocked = false;
+        self.refilling = true;
+    }
+
+    fn store_dependence(&self, index: usize, address: isa::Address, width: u32)
+                        -> StoreDependence {
+        // The youngest older store decides
+        for entry in self.rob.iter().take(index).rev() {
+            if entry.inst.opcode() != opcodes::STORE || entry.trap.is_some() {
+                continue;
+            }
+            let store = match entry.address {
+                Some(store) => store,
+                None => return StoreDependence::Blocked,
+            };
+            let store_width = access_width(entry.inst);
+            if store == address && store_width >= width {
+                return StoreDependence::Forward(entry.value.unwrap());
+            }
+            if store.0 < address.0 + width && address.0 < store.0 + store_width {
+                return StoreDependence::Blocked;
+            }
+        }
+        StoreDependence::None
+    }
+
+    /// Perform loads whose addresses are known, through the one cache port.
+    fn access_memory(&mut self, now: u32) {
+        let mut port_free = true;
+        for index in 0..self.rob.len() {
+            let ready = match self.rob[index].state {
+                State::Memory(cycle) => cycle <= now,
+                _ => false,
+            };
+            if !ready {
+                continue;
+            }
+
+            let (pc, inst) = (self.rob[index].pc, self.rob[index].inst);
+            let address = self.rob[index].address.unwrap();
+            match self.store_dependence(index, address, access_width(inst)) {
+                StoreDependence::Blocked => continue,
+                StoreDependence::Forward(value) => {
+                    let entry = &mut self.rob[index];
+                    entry.value = Some(extend(inst, value));
+                    entry.state = State::Executing(now + self.config.latencies.load.latency);
+                    self.stats.forwarded_loads += 1;
+                    continue;
+                },
+                StoreDependence::None if !port_free => continue,
+                StoreDependence::None => {},
+            }
+
+            port_free = false;
+            let result = {
+                let mut cache = self.cache.borrow_mut();
+                cache.set_pc(pc);
+                cache.set_retry(self.rob[index].missed);
+                match inst.funct3() {
+                    funct3::LB | funct3::LBU =>
+                        cache.read_byte(address).map(|b| b.as_word()),
+                    funct3::LH | funct3::LHU =>
+                        cache.read_halfword(address).map(|h| h.as_word()),
+                    _ => cache.read_word(address),
+                }
+            };
+
+            let entry = &mut self.rob[index];
+            match result {
+                Ok(value) => {
+                    entry.value = Some(extend(inst, value));
+                    entry.state = State::Executing(now + self.config.latencies.load.latency);
+                },
+                Err(MemoryError::CacheMiss { stall_cycles, .. }) => {
+                    entry.state = State::Memory(now + stall_cycles);
+                    entry.missed = true;
+                },
+                Err(MemoryError::InvalidAddress) => {
+                    entry.trap = Some(Trap::IllegalRead {
+                        address: pc,
+                        instruction: inst,
+                        memory_address: address,
+                    });
+                    entry.state = State::Done;
+                },
+            }
+        }
+    }
+
+    /// Start executing the oldest instructions with ready operands.
+    fn issue(&mut self, now: u32) {
+        let mut issued = 0;
+        for index in 0..self.rob.len() {
+            if issued == self.config.issue_width {
+                break;
+            }
+            if self.rob[index].state != State::Waiting {
+                continue;
+            }
+            let operands = self.rob[index].operands;
+            let (src1, src2) = match (operands[0], operands[1]) {
+                (Operand::Ready(src1), Operand::Ready(src2)) => (src1, src2),
+                _ => continue,
+            };
+            let class = InstructionClass::of(self.rob[index].inst);
+            let unit = self.config.latencies.get(class);
+            if self.unit_free[class.index()] > now {
+                continue;
+            }
+            if !unit.pipelined {
+                self.unit_free[class.index()] = now + unit.latency;
+            }
+            // A load's latency counts from its memory access
+            let done = if class == InstructionClass::Load {
+                now + 1
+            }
+            else {
+                now + unit.latency
+            };
+            self.execute(index, src1, src2, done);
+            issued += 1;
+        }
+    }
+
+    /// Execute an instruction, with its result broadcast in cycle `done`,
+    /// or for a load, with its memory access from then.
+    fn execute(&mut self, index: usize, src1: isa::Word, src2: isa::Word, done: u32) {
+        let mmu = &self.mmu;
+        let entry = &mut self.rob[index];
+        let (pc, inst) = (entry.pc, entry.inst);
+        let illegal = || Trap::IllegalInstruction {
+            address: pc,
+            instruction: inst,
+        };
+
+        entry.state = State::Executing(done);
+        match inst.opcode() {
+            opcodes::INTEGER_IMMEDIATE | opcodes::INTEGER_REGISTER => {
+                let src2 = if inst.opcode() == opcodes::INTEGER_IMMEDIATE {
+                    inst.i_imm().as_word()
+                }
+                else {
+                    src2
+                };
+                match integer_result(inst, src1, src2) {
+                    Some(value) => entry.value = Some(value),
+                    None => entry.trap = Some(illegal()),
+                }
+            },
+            opcodes::BRANCH => match branch_taken(inst, src1, src2) {
+                Some(true) => entry.next_pc = pc.wrapping_add(inst.sb_imm().as_word()),
+                Some(false) => {},
+                None => entry.trap = Some(illegal()),
+            },
+            opcodes::JALR => {
+                let target = src1.wrapping_add(inst.i_imm().as_word());
+                if target == isa::Word(0) {
+                    entry.halt = true;
+                }
+                else {
+                    entry.value = Some(pc + 4);
+                    entry.next_pc = target;
+                }
+            },
+            opcodes::LOAD => match inst.funct3() {
+                funct3::LB | funct3::LH | funct3::LW | funct3::LBU | funct3::LHU => {
+                    let address = src1.wrapping_add(inst.i_imm().as_word());
+                    entry.address = Some(mmu.translate(address));
+                    entry.state = State::Memory(done);
+                },
+                _ => entry.trap = Some(illegal()),
+            },
+            opcodes::STORE => match inst.funct3() {
+                funct3::SB | funct3::SH | funct3::SW => {
+                    let address = src1.wrapping_add(inst.s_imm().as_word());
+                    entry.address = Some(mmu.translate(address));
+                    entry.value = Some(src2);
+                },
+                _ => entry.trap = Some(illegal()),
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    /// The operand for a source register: its committed value, the value
+    /// of the ROB entry that wrote it, or that entry's tag.
+    fn operand(&mut self, register: isa::Register) -> Operand {
+        match self.rename[register.as_num()] {
+            Some(tag) => match self.rob.iter().find(|entry| entry.tag == tag) {
+                Some(&Entry { state: State::Done, value: Some(value), .. }) =>
+                    Operand::Ready(value),
+                _ => Operand::Waiting(tag),
+            },
+            None => Operand::Ready(self.registers.read_word(register)),
+        }
+    }
+
+    /// Rename fetched instructions and place them in the ROB, reservation
+    /// stations and load/store queue.
+    fn dispatch(&mut self) {
+        for _ in 0..self.config.dispatch_width {
+            if self.rob.iter().any(|entry| is_ecall(entry.inst)) {
+                break;
+            }
+            let (pc, inst, predicted, illegal) = match self.fetch_queue.front() {
+                Some(fetched) => (fetched.pc, fetched.inst, fetched.predicted,
+                                  fetched.illegal),
+                None => break,
+            };
+
+            let opcode = inst.opcode();
+            let operation = !illegal && match opcode {
+                opcodes::INTEGER_IMMEDIATE | opcodes::INTEGER_REGISTER |
+                opcodes::BRANCH | opcodes::JALR | opcodes::LOAD |
+                opcodes::STORE => true,
+                _ => false,
+            };
+            let memory = operation &&
+                (opcode == opcodes::LOAD || opcode == opcodes::STORE);
+            if self.rob.len() == self.config.rob_entries {
+                self.stats.rob_full += 1;
+                break;
+            }
+            let stations = self.rob.iter()
+                .filter(|entry| entry.state == State::Waiting)
+                .count();
+            if operation && stations == self.config.reservation_stations {
+                self.stats.stations_full += 1;
+                break;
+            }
+            let queued = self.rob.iter()
+                .filter(|entry| entry.inst.opcode() == opcodes::LOAD ||
+                        entry.inst.opcode() == opcodes::STORE)
+                .count();
+            if memory && queued == self.config.lsq_entries {
+                self.stats.lsq_full += 1;
+                break;
+            }
+            self.fetch_queue.pop_front();
+
+            let uses_rs2 = match opcode {
+                opcodes::BRANCH | opcodes::STORE | opcodes::INTEGER_REGISTER => true,
+                _ => false,
+            };
+            let operands = if operation {
+                let src2 = if uses_rs2 {
+                    self.operand(inst.rs2())
+                }
+                else {
+                    Operand::Ready(isa::Word(0))
+                };
+                [self.operand(inst.rs1()), src2]
+            }
+            else {
+                [Operand::Ready(isa::Word(0)); 2]
+            };
+
+            let tag = self.next_tag;
+            self.next_tag += 1;
+            let mut entry = Entry {
+                tag: tag,
+                pc: pc,
+                inst: inst,
+                predicted: predicted,
+                state: if operation { State::Waiting } else { State::Done },
+                operands: operands,
+                value: None,
+                address: None,
+                next_pc: pc + 4,
+                trap: None,
+                halt: false,
+                missed: false,
+            };
+            if illegal {
+                entry.trap = Some(Trap::IllegalFetch {
+                    address: pc,
+                });
+            }
+            else {
+                match opcode {
+                    opcodes::LUI => entry.value = Some(inst.u_imm().as_word()),
+                    opcodes::AUIPC => {
+                        entry.value = Some(pc.wrapping_add(inst.u_imm().as_word()));
+                    },
+                    opcodes::JAL => {
+                        entry.value = Some(pc + 4);
+                        entry.next_pc = pc.wrapping_add(inst.uj_imm().as_word());
+                    },
+                    // ecall runs at commit, and the rest are no-ops
+                    opcodes::SYSTEM | opcodes::MISC_MEM => {},
+                    _ if operation => {},
+                    _ => {
+                        entry.trap = Some(Trap::IllegalInstruction {
+                            address: pc,
+                            instruction: inst,
+                        });
+                    },
+                }
+            }
+
+            if entry.trap.is_none() && writes_register(inst) &&
+                inst.rd() != isa::Register::X0 {
+                self.rename[inst.rd().as_num()] = Some(tag);
+            }
+            self.rob.push_back(entry);
+        }
+    }
+
+    /// Predict where fetch goes after an instruction.
+    fn predict(&mut self, pc: isa::Address, inst: isa::Instruction) -> isa::Address {
+        let fallthrough = pc + 4;
+        let ra = isa::Register::X1;
+        match inst.opcode() {
+            opcodes::JAL => {
+                if inst.rd() == ra {
+                    self.ras.push(fallthrough);
+                }
+                pc.wrapping_add(inst.uj_imm().as_word())
+            },
+            opcodes::BRANCH => {
+                let target = pc.wrapping_add(inst.sb_imm().as_word());
+                if self.predictor.predict(pc, target) {
+                    target
+                }
+                else {
+                    fallthrough
+                }
+            },
+            opcodes::JALR => {
+                let is_return = inst.rs1() == ra && inst.rd() == isa::Register::X0;
+                let predicted = if is_return {
+                    self.ras.pop().unwrap_or(fallthrough)
+                }
+                else {
+                    fallthrough
+                };
+                if inst.rd() == ra {
+                    self.ras.push(fallthrough);
+                }
+                predicted
+            },
+            _ => fallthrough,
+        }
+    }
+
+    fn fetch(&mut self, memory: &SharedMemory<'a>) {
+        if self.fetch_stall > 0 {
+            self.fetch_stall -= 1;
+            return;
+        }
+
+        for _ in 0..self.config.fetch_width {
+            if self.fetch_blocked ||
+                self.fetch_queue.len() >= 2 * self.config.fetch_width {
+                break;
+            }
+
+            let pc = self.fetch_pc;
+            let address = self.mmu.translate(pc);
+            let result = match self.icache {
+                Some(ref icache) => {
+                    let mut icache = icache.borrow_mut();
+                    icache.set_retry(self.fetch_missed);
+                    icache.read_word(address).map(isa::Instruction::new)
+                },
+                None => memory.borrow_mut().read_instruction(address)
+                    .ok_or(MemoryError::InvalidAddress),
+            };
+            self.fetch_missed = false;
+            let inst = match result {
+                Ok(inst) => inst,
+                Err(MemoryError::CacheMiss { stall_cycles, .. }) => {
+                    self.fetch_stall = stall_cycles - 1;
+                    self.fetch_missed = true;
+                    break;
+                },
+                Err(MemoryError::InvalidAddress) => {
+                    self.fetch_queue.push_back(Fetched {
+                        pc: pc,
+                        inst: isa::Instruction::new(isa::Word(0)),
+                        predicted: pc + 4,
+                        illegal: true,
+                    });
+                    self.fetch_blocked = true;
+                    break;
+                },
+            };
+
+            let predicted = self.predict(pc, inst);
+            self.fetch_queue.push_back(Fetched {
+                pc: pc,
+                inst: inst,
+                predicted: predicted,
+                illegal: false,
+            });
+            self.fetch_pc = predicted;
+            if predicted != pc + 4 {
+                break;
+            }
+        }
+    }
+
+    fn trap(&mut self, trap: Trap) {
+        println!("Trap: {:?}", trap);
+        self.running = false;
+    }
+}
+
+impl<'a> CoreInterface<'a> for OutOfOrderCore<'a> {
+    fn id(&self) -> usize {
+        self.id
+    }
+
+    fn is_running(&self) -> bool {
+        self.running
+    }
+
+    fn step(&mut self, memory: &SharedMemory<'a>, system: &mut SyscallHandler) {
+        self.step_cycle(memory, system);
+    }
+
+    fn registers(&mut self) -> &mut RegisterFile {
+        &mut self.registers
+    }
+
+    fn cycle_count(&self) -> u32 {
+        self.stats.cycles
+    }
+
+    fn stall_count(&self) -> u32 {
+        self.stall_count
+    }
+
+    fn instructions_retired(&self) -> u64 {
+        self.stats.committed as u64
+    }
+
+    fn cycle_stack(&self) -> CycleStack {
+        self.cycle_stack
+    }
+}
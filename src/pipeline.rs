@@ -0,0 +1,236 @@
+// Copyright 2016 David Li
+// This file is part of rustv.
+
+// rustv is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// rustv is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with rustv.  If not, see <http://www.gnu.org/licenses/>.
+
+use isa;
+use isa::opcodes;
+use retirement::writes_register;
+
+/// The forwarding paths of a pipeline. Forwarded values go to the EX
+/// stage, or to the ID stage for branches resolved there.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Forwarding {
+    /// From the EX/MEM latch, so that an ALU result can be used by the
+    /// next instruction.
+    pub ex_mem: bool,
+    /// From the MEM/WB latch, so that an ALU result can be used by the
+    /// instruction after next, and a loaded value by the next
+    /// instruction after one stall.
+    pub mem_wb: bool,
+    /// The register file is written in the first half of a cycle and
+    /// read in the second half, so that an instruction in ID can read a
+    /// value being written back.
+    pub split_register_file: bool,
+}
+
+impl Forwarding {
+    pub fn full() -> Forwarding {
+        Forwarding {
+            ex_mem: true,
+            mem_wb: true,
+            split_register_file: true,
+        }
+    }
+
+    /// No forwarding: dependent instructions wait for the register file.
+    pub fn none() -> Forwarding {
+        Forwarding {
+            ex_mem: false,
+            mem_wb: false,
+            split_register_file: true,
+        }
+    }
+}
+
+/// The stage in which conditional branches and indirect jumps are
+/// resolved. Direct jumps are always resolved in ID.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BranchResolution {
+    /// A taken branch flushes one instruction, but its operands are
+    /// needed a cycle earlier.
+    Decode,
+    /// A taken branch flushes two instructions.
+    Execute,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct PipelineConfig {
+    pub forwarding: Forwarding,
+    pub branch_resolution: BranchResolution,
+}
+
+impl PipelineConfig {
+    /// Full forwarding, with branches resolved in EX.
+    pub fn new() -> PipelineConfig {
+        PipelineConfig {
+            forwarding: Forwarding::full(),
+            branch_resolution: BranchResolution::Execute,
+        }
+    }
+}
+
+/// Bubbles inserted by a pipeline, in cycles.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PipelineStats {
+    pub instructions: u32,
+    /// Stalls waiting for the result of an ALU instruction
+    pub data_stalls: u32,
+    /// Stalls waiting for the result of a load
+    pub load_use_stalls: u32,
+    /// Instructions flushed after taken branches and jumps
+    pub control_stalls: u32,
+    /// Taken branches and jumps
+    pub flushes: u32,
+}
+
+#[derive(Clone, Copy)]
+struct Producer {
+    /// The cycle in which the instruction is in MEM
+    mem: u64,
+    load: bool,
+}
+
+/// A timing model of the classic in-order pipeline, with IF, ID, EX, MEM
+/// and WB stages. The core still executes each instruction at once; the
+/// pipeline is told about each instruction as it completes, and works
+/// out how many bubbles it would have needed.
+///
+/// Operands are read in ID, or forwarded to the stage that needs them.
+/// Fetch is predicted not taken, so taken branches and jumps flush the
+/// instructions fetched behind them. Store data is needed in EX, like
+/// any other operand. Memory stalls freeze the whole pipeline, so they
+/// do not affect hazards, and the pipeline's own clock ignores them.
+pub struct Pipeline {
+    config: PipelineConfig,
+    /// The earliest cycle in which the next instruction can be in ID
+    next_decode: u64,
+    /// The last instruction to write each register
+    producers: Vec<Option<Producer>>,
+    stats: PipelineStats,
+}
+
+impl Pipeline {
+    pub fn new(config: PipelineConfig) -> Pipeline {
+        Pipeline {
+            config: config,
+            next_decode: 0,
+            producers: vec![None; 32],
+            stats: PipelineStats::default(),
+        }
+    }
+
+    pub fn config(&self) -> PipelineConfig {
+        self.config
+    }
+
+    pub fn stats(&self) -> PipelineStats {
+        self.stats
+    }
+
+    /// Account for an instruction that has completed. `redirected` is
+    /// true if it changed the PC to anything but the next instruction.
+    /// Returns the bubbles it caused, in cycles.
+    pub fn issue(&mut self, inst: isa::Instruction, redirected: bool) -> u32 {
+        let opcode = inst.opcode();
+        let control = opcode == opcodes::BRANCH || opcode == opcodes::JALR;
+        let in_decode = control &&
+            self.config.branch_resolution == BranchResolution::Decode;
+
+        let mut decode = self.next_decode;
+        let mut load_use = false;
+        loop {
+            let needed = if in_decode { decode } else { decode + 1 };
+            let blocking = source_registers(inst).into_iter()
+                .filter_map(|register| self.producers[register.as_num()])
+                .find(|&producer| !self.available(producer, needed, in_decode));
+            match blocking {
+                Some(producer) => {
+                    load_use = load_use || producer.load;
+                    decode += 1;
+                },
+                None => break,
+            }
+        }
+
+        let stalls = (decode - self.next_decode) as u32;
+        if load_use {
+            self.stats.load_use_stalls += stalls;
+        }
+        else {
+            self.stats.data_stalls += stalls;
+        }
+
+        if writes_register(inst) && inst.rd() != isa::Register::X0 {
+            self.producers[inst.rd().as_num()] = Some(Producer {
+                mem: decode + 2,
+                load: opcode == opcodes::LOAD,
+            });
+        }
+
+        let flushed = if !redirected {
+            0
+        }
+        else if opcode == opcodes::JAL || in_decode {
+            1
+        }
+        else {
+            2
+        };
+        if flushed > 0 {
+            self.stats.flushes += 1;
+            self.stats.control_stalls += flushed;
+        }
+
+        self.stats.instructions += 1;
+        self.next_decode = decode + 1 + flushed as u64;
+        stalls + flushed
+    }
+
+    /// Delay the next instruction by stalls from outside the pipeline,
+    /// such as mispredictions charged by a `BranchUnit`.
+    pub fn delay(&mut self, cycles: u32) {
+        self.next_decode += cycles as u64;
+    }
+
+    /// Whether an operand can be had in `cycle` by a stage consuming it,
+    /// which is ID if `in_decode`, and otherwise EX.
+    fn available(&self, producer: Producer, cycle: u64, in_decode: bool) -> bool {
+        let forwarding = self.config.forwarding;
+        let writeback = producer.mem + 1;
+        // The register file is read in ID
+        let read = if in_decode { cycle } else { cycle - 1 };
+
+        (forwarding.ex_mem && !producer.load && cycle == producer.mem) ||
+            (forwarding.mem_wb && cycle == writeback) ||
+            read > writeback ||
+            (forwarding.split_register_file && read == writeback)
+    }
+}
+
+/// The registers an instruction reads, other than x0.
+pub fn source_registers(inst: isa::Instruction) -> Vec<isa::Register> {
+    let sources = match inst.opcode() {
+        opcodes::JALR | opcodes::LOAD | opcodes::INTEGER_IMMEDIATE => {
+            vec![inst.rs1()]
+        },
+        opcodes::BRANCH | opcodes::STORE | opcodes::INTEGER_REGISTER => {
+            vec![inst.rs1(), inst.rs2()]
+        },
+        _ => Vec::new(),
+    };
+    sources.into_iter()
+        .filter(|&register| register != isa::Register::X0)
+        .collect()
+}
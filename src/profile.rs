@@ -0,0 +1,135 @@
+// Copyright 2016 David Li
+// This file is part of rustv.
+
+// rustv is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// rustv is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with rustv.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::BTreeMap;
+
+use disassembler::{mnemonic, Disassembler};
+use isa;
+use memory::MemoryInterface;
+use simulator::CycleCategory;
+
+/// What a profile has seen of one instruction.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PcCounts {
+    /// Times the instruction retired
+    pub count: u64,
+    /// Cycles charged to the instruction, including its stalls
+    pub cycles: u64,
+    /// Cycles in which the instruction was stalled
+    pub stall_cycles: u64,
+}
+
+/// Instruction counts by mnemonic and by PC, with the cycles spent at
+/// each PC.
+pub struct Profile {
+    pcs: BTreeMap<u32, PcCounts>,
+    mnemonics: BTreeMap<&'static str, u64>,
+    cycles: u64,
+}
+
+impl Profile {
+    pub fn new() -> Profile {
+        Profile {
+            pcs: BTreeMap::new(),
+            mnemonics: BTreeMap::new(),
+            cycles: 0,
+        }
+    }
+
+    /// Count an instruction that retired.
+    pub fn retire(&mut self, pc: isa::Address, inst: isa::Instruction) {
+        self.pcs.entry(pc.0).or_insert_with(PcCounts::default).count += 1;
+        let name = mnemonic(inst).unwrap_or("unknown");
+        *self.mnemonics.entry(name).or_insert(0) += 1;
+    }
+
+    /// Charge a cycle to the instruction at a PC. Cycles spent on
+    /// anything but retiring instructions or system calls are stalls.
+    pub fn charge(&mut self, pc: isa::Address, category: CycleCategory) {
+        let counts = self.pcs.entry(pc.0).or_insert_with(PcCounts::default);
+        counts.cycles += 1;
+        match category {
+            CycleCategory::Base | CycleCategory::Syscall => {},
+            _ => counts.stall_cycles += 1,
+        }
+        self.cycles += 1;
+    }
+
+    /// The cycles charged to all instructions.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    pub fn get(&self, pc: isa::Address) -> Option<PcCounts> {
+        self.pcs.get(&pc.0).cloned()
+    }
+
+    /// Every PC seen, in address order.
+    pub fn pcs(&self) -> Vec<(isa::Address, PcCounts)> {
+        self.pcs.iter()
+            .map(|(&pc, &counts)| (isa::Word(pc), counts))
+            .collect()
+    }
+
+    /// Retired instruction counts by mnemonic, most frequent first.
+    pub fn mnemonics(&self) -> Vec<(&'static str, u64)> {
+        let mut mnemonics = self.mnemonics.iter()
+            .map(|(&name, &count)| (name, count))
+            .collect::<Vec<_>>();
+        mnemonics.sort_by(|a, b| (b.1, a.0).cmp(&(a.1, b.0)));
+        mnemonics
+    }
+
+    /// A listing of every instruction seen, in the manner of `perf
+    /// annotate`: the share of cycles, retire count and stall cycles of
+    /// each instruction beside its disassembly. Runs of addresses are
+    /// separated, with a header where a symbol begins. The memory
+    /// should be the backing store, not a cache.
+    pub fn annotate(&self, disassembler: &Disassembler,
+                    memory: &mut MemoryInterface) -> String {
+        let mut listing = String::new();
+        listing.push_str(" Percent    Count   Stalls\n");
+        let mut last = None;
+
+        for (&pc, counts) in self.pcs.iter() {
+            let address = isa::Word(pc);
+            let symbol = disassembler.symbols().and_then(|s| s.get(address));
+            if let Some(name) = symbol {
+                listing.push_str(&format!("\n{:08x} <{}>:\n", pc, name));
+            }
+            else if last.is_some() && last != Some(pc.wrapping_sub(4)) {
+                listing.push_str("\n");
+            }
+            last = Some(pc);
+
+            let percent = if self.cycles == 0 {
+                0.0
+            }
+            else {
+                100.0 * counts.cycles as f64 / self.cycles as f64
+            };
+            let line = match memory.read_instruction(address) {
+                Some(inst) => disassembler.format_line(address, inst),
+                None => format!("{:8x}:", pc),
+            };
+            listing.push_str(&format!("{:>8.2} {:>8} {:>8} :{}\n",
+                                      percent, counts.count, counts.stall_cycles,
+                                      line));
+        }
+
+        listing
+    }
+}
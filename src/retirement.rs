@@ -0,0 +1,110 @@
+// Copyright 2016 David Li
+// This file is part of rustv.
+
+// rustv is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// rustv is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with rustv.  If not, see <http://www.gnu.org/licenses/>.
+
+use isa::{self, IsaType};
+use isa::{funct3, opcodes};
+use register_file::RegisterFile;
+
+/// A data memory access made by a retired instruction. Addresses are
+/// virtual, as computed by the instruction.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MemoryAccess {
+    Read {
+        address: isa::Address,
+        width: u32,
+    },
+    Write {
+        address: isa::Address,
+        width: u32,
+        value: isa::Word,
+    },
+}
+
+/// The architectural effects of one retired instruction.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Retirement {
+    pub pc: isa::Address,
+    pub instruction: isa::Instruction,
+    /// The destination register and the value written to it.
+    pub writeback: Option<(isa::Register, isa::Word)>,
+    pub access: Option<MemoryAccess>,
+}
+
+/// The width in bytes of a load or store.
+pub fn access_width(inst: isa::Instruction) -> u32 {
+    match inst.funct3() & 0x3 {
+        0 => 1,
+        1 => 2,
+        _ => 4,
+    }
+}
+
+/// Whether the instruction writes its `rd` field.
+pub fn writes_register(inst: isa::Instruction) -> bool {
+    match inst.opcode() {
+        opcodes::LUI | opcodes::AUIPC | opcodes::JAL | opcodes::JALR |
+        opcodes::LOAD | opcodes::INTEGER_IMMEDIATE |
+        opcodes::INTEGER_REGISTER => true,
+        _ => false,
+    }
+}
+
+impl Retirement {
+    /// Start recording an instruction. This must be called before the
+    /// instruction executes, as memory accesses are computed from the
+    /// source registers.
+    pub fn new(pc: isa::Address, inst: isa::Instruction,
+               registers: &mut RegisterFile) -> Retirement {
+        let base = registers.read_word(inst.rs1());
+        let access = match inst.opcode() {
+            opcodes::LOAD => Some(MemoryAccess::Read {
+                address: base.wrapping_add(inst.i_imm().as_word()),
+                width: access_width(inst),
+            }),
+            opcodes::STORE => {
+                let width = access_width(inst);
+                let value = registers.read_word(inst.rs2());
+                Some(MemoryAccess::Write {
+                    address: base.wrapping_add(inst.s_imm().as_word()),
+                    width: width,
+                    value: match inst.funct3() {
+                        funct3::SB => value & 0xFF,
+                        funct3::SH => value & 0xFFFF,
+                        _ => value,
+                    },
+                })
+            },
+            _ => None,
+        };
+
+        Retirement {
+            pc: pc,
+            instruction: inst,
+            writeback: None,
+            access: access,
+        }
+    }
+
+    /// Finish recording once the instruction has executed. `completed` is
+    /// false if the instruction halted the core or trapped instead of
+    /// writing its result.
+    pub fn complete(&mut self, registers: &mut RegisterFile, completed: bool) {
+        let rd = self.instruction.rd();
+        if completed && writes_register(self.instruction) && rd != isa::Register::X0 {
+            self.writeback = Some((rd, registers.read_word(rd)));
+        }
+    }
+}
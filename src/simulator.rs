@@ -14,24 +14,67 @@
 // You should have received a copy of the GNU General Public License
 // along with rustv.  If not, see <http://www.gnu.org/licenses/>.
 
+use std::collections::VecDeque;
+use std::fmt;
+
+use branch::{BranchStats, BranchUnit};
 use cache::SharedCache;
+use callgraph::CallGraph;
 use isa;
 use isa::IsaType;
+use latency::{FunctionalUnits, LatencyStats, LatencyTable};
 use memory::{MemoryInterface, MemoryError, Mmu, SharedMemory};
+use pipeline::{Pipeline, PipelineConfig, PipelineStats};
+use profile::Profile;
 use register_file::RegisterFile;
+use retirement::{access_width, Retirement};
+use superscalar::{Superscalar, SuperscalarConfig, SuperscalarStats};
 use syscall::SyscallHandler;
+use trace::{AccessKind, SharedAccessSink, SharedTraceSink};
 use trap::Trap;
 
 pub struct Core<'a> {
     id: usize,
     pc: isa::Address,
     registers: RegisterFile,
-    stall: u32,
+    /// Stall cycles still to come, by cause and the PC of the stalled
+    /// instruction, in the order they are spent
+    stalls: VecDeque<(CycleCategory, isa::Address, u32)>,
+    /// What missed in this cycle, if anything
+    missed: Option<CycleCategory>,
     running: bool,
     cache: SharedCache<'a>,
+    /// Instructions are fetched from memory if there is no cache
+    icache: Option<SharedCache<'a>>,
     mmu: Box<Mmu + 'a>,
     cycle_count: u32,
     stall_count: u32,
+    instructions_retired: u64,
+    cycle_stack: CycleStack,
+    retired: VecDeque<Retirement>,
+    /// Charges pipeline hazards as stalls, if enabled
+    pipeline: Option<Pipeline>,
+    /// Predicts control transfers, charging for mispredictions, if enabled
+    branches: Option<BranchUnit<'a>>,
+    /// Issues several instructions per cycle, if enabled
+    superscalar: Option<Superscalar>,
+    /// Charges multi-cycle latencies as stalls, if enabled
+    units: Option<FunctionalUnits>,
+    /// Counts instructions and cycles by PC, if enabled
+    profile: Option<Profile>,
+    /// Attributes cycles to guest functions, if enabled
+    calls: Option<CallGraph>,
+    /// Receives retired instructions, if enabled
+    trace: Option<SharedTraceSink<'a>>,
+    /// Receives fetch, load and store addresses, if enabled
+    accesses: Option<SharedAccessSink<'a>>,
+    /// Whether the instruction at the PC is being retried after a miss,
+    /// and so has already been fetched
+    retrying: bool,
+    /// Whether the last fetch missed, so that the next one retries it
+    fetch_missed: bool,
+    /// The instruction at the PC, if it was fetched but did not issue
+    fetched: Option<isa::Instruction>,
 }
 
 /// Why the simulator has halted execution.
@@ -44,8 +87,132 @@ pub enum HaltReason {
     SystemHalt,
 }
 
-pub struct Simulator<'a, T: SyscallHandler> {
-    cores: Vec<Core<'a>>,
+/// What a core spent a cycle on.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CycleCategory {
+    /// Retiring instructions
+    Base,
+    InstructionCache,
+    DataCache,
+    BranchMispredict,
+    /// Waiting for operands or functional units
+    Structural,
+    /// Running a system call
+    Syscall,
+}
+
+/// A core's cycles, by what they were spent on.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CycleStack {
+    pub base: u32,
+    pub icache_miss: u32,
+    pub dcache_miss: u32,
+    pub branch_mispredict: u32,
+    pub structural: u32,
+    pub syscall: u32,
+}
+
+impl CycleStack {
+    pub fn add(&mut self, category: CycleCategory) {
+        match category {
+            CycleCategory::Base => self.base += 1,
+            CycleCategory::InstructionCache => self.icache_miss += 1,
+            CycleCategory::DataCache => self.dcache_miss += 1,
+            CycleCategory::BranchMispredict => self.branch_mispredict += 1,
+            CycleCategory::Structural => self.structural += 1,
+            CycleCategory::Syscall => self.syscall += 1,
+        }
+    }
+
+    pub fn total(&self) -> u32 {
+        self.base + self.icache_miss + self.dcache_miss +
+            self.branch_mispredict + self.structural + self.syscall
+    }
+}
+
+/// The performance of one core, from `Simulator::report`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CoreReport {
+    pub id: usize,
+    pub cycles: u32,
+    /// Cycles in which the core was stalled
+    pub stalls: u32,
+    pub instructions_retired: u64,
+    pub cycle_stack: CycleStack,
+}
+
+impl CoreReport {
+    /// Cycles per retired instruction.
+    pub fn cpi(&self) -> f64 {
+        if self.instructions_retired == 0 {
+            0.0
+        }
+        else {
+            self.cycles as f64 / self.instructions_retired as f64
+        }
+    }
+}
+
+impl fmt::Display for CoreReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(writeln!(f, "core {}: {} instructions in {} cycles, CPI {:.2}",
+                      self.id, self.instructions_retired, self.cycles, self.cpi()));
+        let stack = self.cycle_stack;
+        let categories = [
+            ("base", stack.base),
+            ("icache miss", stack.icache_miss),
+            ("dcache miss", stack.dcache_miss),
+            ("branch mispredict", stack.branch_mispredict),
+            ("structural", stack.structural),
+            ("syscall", stack.syscall),
+        ];
+        for &(name, cycles) in categories.iter() {
+            let cpi = if self.instructions_retired == 0 {
+                0.0
+            }
+            else {
+                cycles as f64 / self.instructions_retired as f64
+            };
+            try!(writeln!(f, "  {:<17} {:>10} {:>8.2}", name, cycles, cpi));
+        }
+        Ok(())
+    }
+}
+
+/// A timing model of a core that a `Simulator` can drive.
+pub trait CoreInterface<'a> {
+    fn id(&self) -> usize;
+
+    fn is_running(&self) -> bool;
+
+    /// Advance the core by one cycle.
+    fn step(&mut self, memory: &SharedMemory<'a>, system: &mut SyscallHandler);
+
+    /// The architectural registers, as of the last retired instruction.
+    fn registers(&mut self) -> &mut RegisterFile;
+
+    fn cycle_count(&self) -> u32;
+
+    fn stall_count(&self) -> u32;
+
+    fn instructions_retired(&self) -> u64;
+
+    /// Where the core's cycles have gone. Every cycle is counted once.
+    fn cycle_stack(&self) -> CycleStack;
+
+    fn report(&self) -> CoreReport {
+        CoreReport {
+            id: self.id(),
+            cycles: self.cycle_count(),
+            stalls: self.stall_count(),
+            instructions_retired: self.instructions_retired(),
+            cycle_stack: self.cycle_stack(),
+        }
+    }
+}
+
+pub struct Simulator<'a, T: SyscallHandler, C: CoreInterface<'a> = Core<'a>> {
+    cores: Vec<C>,
     memory: SharedMemory<'a>,
     caches: Vec<SharedMemory<'a>>,
     syscall: T,
@@ -61,12 +228,28 @@ impl<'a> Core<'a> {
             id: id,
             pc: entry,
             registers: registers,
-            stall: 0,
+            stalls: VecDeque::new(),
+            missed: None,
             running: true,
             cache: cache,
+            icache: None,
             mmu: mmu,
             cycle_count: 0,
             stall_count: 0,
+            instructions_retired: 0,
+            cycle_stack: CycleStack::default(),
+            retired: VecDeque::new(),
+            pipeline: None,
+            branches: None,
+            superscalar: None,
+            units: None,
+            profile: None,
+            calls: None,
+            trace: None,
+            accesses: None,
+            retrying: false,
+            fetch_missed: false,
+            fetched: None,
         }
     }
 
@@ -74,16 +257,285 @@ impl<'a> Core<'a> {
         &mut self.registers
     }
 
-    fn step(&mut self, inst: isa::Instruction, system: &mut SyscallHandler) {
+    pub fn pc(&self) -> isa::Address {
+        self.pc
+    }
+
+    /// Fetch instructions through a cache, so that fetch misses stall
+    /// the core.
+    pub fn set_instruction_cache(&mut self, icache: SharedCache<'a>) {
+        self.icache = Some(icache);
+    }
+
+    /// Model a five-stage pipeline, so that hazards stall the core.
+    pub fn set_pipeline(&mut self, config: PipelineConfig) {
+        self.pipeline = Some(Pipeline::new(config));
+    }
+
+    pub fn pipeline_stats(&self) -> Option<PipelineStats> {
+        self.pipeline.as_ref().map(|pipeline| pipeline.stats())
+    }
+
+    /// Predict branches and jumps, stalling on mispredictions. With a
+    /// pipeline, the misprediction penalty replaces its flushes.
+    pub fn set_branch_unit(&mut self, branches: BranchUnit<'a>) {
+        self.branches = Some(branches);
+    }
+
+    pub fn branch_stats(&self) -> Option<BranchStats> {
+        self.branches.as_ref().map(|branches| branches.stats())
+    }
+
+    /// Issue up to `config.width` independent instructions per cycle.
+    pub fn set_superscalar(&mut self, config: SuperscalarConfig) {
+        self.superscalar = Some(Superscalar::new(config));
+    }
+
+    pub fn superscalar_stats(&self) -> Option<SuperscalarStats> {
+        self.superscalar.as_ref().map(|superscalar| superscalar.stats())
+    }
+
+    /// Give each class of instructions a latency, so that dependent
+    /// instructions and busy unpipelined units stall the core.
+    pub fn set_latencies(&mut self, table: LatencyTable) {
+        self.units = Some(FunctionalUnits::new(table));
+    }
+
+    pub fn latency_stats(&self) -> Option<LatencyStats> {
+        self.units.as_ref().map(|units| units.stats())
+    }
+
+    /// Count retired instructions and the cycles spent on each PC.
+    pub fn set_profile(&mut self) {
+        self.profile = Some(Profile::new());
+    }
+
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_ref()
+    }
+
+    /// Follow calls and returns from here on, attributing cycles to the
+    /// functions they are spent in.
+    pub fn set_call_graph(&mut self) {
+        self.calls = Some(CallGraph::new(self.pc));
+    }
+
+    pub fn call_graph(&self) -> Option<&CallGraph> {
+        self.calls.as_ref()
+    }
+
+    /// Send every instruction retired from here on to a trace sink.
+    pub fn set_trace(&mut self, trace: SharedTraceSink<'a>) {
+        self.trace = Some(trace);
+    }
+
+    /// Send the physical address and size of every fetch, load and store
+    /// from here on to an address trace. References that miss are sent
+    /// once, when they complete, and an instruction retried after a
+    /// miss is only fetched once.
+    pub fn set_address_trace(&mut self, accesses: SharedAccessSink<'a>) {
+        self.accesses = Some(accesses);
+    }
+
+    /// Take the record of an instruction retired in the last cycle, oldest
+    /// first, if any. Stalled cycles and retried accesses retire nothing.
+    pub fn take_retired(&mut self) -> Option<Retirement> {
+        self.retired.pop_front()
+    }
+
+    /// Fetch the instruction at the PC. Returns `None` if the fetch
+    /// missed, in which case the core stalls, or trapped.
+    fn fetch(&mut self, memory: &SharedMemory<'a>) -> Option<isa::Instruction> {
+        let pc = self.mmu.translate(self.pc);
+        let result = match self.icache {
+            Some(ref icache) => {
+                let mut icache = icache.borrow_mut();
+                icache.set_retry(self.fetch_missed);
+                icache.read_word(pc).map(isa::Instruction::new)
+            },
+            None => memory.borrow_mut().read_instruction(pc)
+                .ok_or(MemoryError::InvalidAddress),
+        };
+
+        self.fetch_missed = false;
+        match result {
+            Ok(inst) => Some(inst),
+            Err(MemoryError::CacheMiss { stall_cycles, .. }) => {
+                self.fetch_missed = true;
+                self.miss(CycleCategory::InstructionCache, stall_cycles);
+                None
+            },
+            Err(MemoryError::InvalidAddress) => {
+                let address = self.pc;
+                self.trap(Trap::IllegalFetch {
+                    address: address,
+                });
+                None
+            },
+        }
+    }
+
+    fn record_access(&self, kind: AccessKind, address: isa::Address, size: u32) {
+        if let Some(ref accesses) = self.accesses {
+            accesses.borrow_mut().access(self.id, kind, address, size);
+        }
+    }
+
+    /// Stall the instruction at a PC for some cycles after this one.
+    fn stall(&mut self, category: CycleCategory, pc: isa::Address, cycles: u32) {
+        if cycles > 0 {
+            self.stalls.push_back((category, pc, cycles));
+        }
+    }
+
+    /// Stall for a cache miss by the instruction at the PC, which takes
+    /// this cycle and those after.
+    fn miss(&mut self, category: CycleCategory, stall_cycles: u32) {
         let pc = self.pc;
+        self.missed = Some(category);
+        self.stall(category, pc, stall_cycles - 1);
+    }
 
+    fn step_cycle(&mut self, memory: &SharedMemory<'a>, system: &mut SyscallHandler) {
         self.cycle_count += 1;
+        self.retired.clear();
+        self.missed = None;
+
+        let (category, pc) = match self.stalls.pop_front() {
+            Some((category, pc, cycles)) => {
+                if cycles > 1 {
+                    self.stalls.push_front((category, pc, cycles - 1));
+                }
+                self.stall_count += 1;
+                (category, pc)
+            },
+            None => {
+                let pc = self.pc;
+                self.issue(memory, system);
+                let syscall = self.retired.iter().any(|retirement| {
+                    let inst = retirement.instruction;
+                    inst.opcode() == isa::opcodes::SYSTEM &&
+                        inst.i_imm() == isa::SignedWord(0x0)
+                });
+                // The cycle belongs to the oldest instruction issued
+                let pc = self.retired.front().map_or(pc, |retirement| retirement.pc);
+                if syscall {
+                    (CycleCategory::Syscall, pc)
+                }
+                else if !self.retired.is_empty() {
+                    (CycleCategory::Base, pc)
+                }
+                else {
+                    (self.missed.unwrap_or(CycleCategory::Base), pc)
+                }
+            },
+        };
+        self.cycle_stack.add(category);
+        if let Some(ref mut profile) = self.profile {
+            profile.charge(pc, category);
+        }
+        if let Some(ref mut calls) = self.calls {
+            calls.charge();
+        }
+
+        if let Some(ref mut superscalar) = self.superscalar {
+            superscalar.end_cycle();
+        }
+    }
+
+    /// Fetch and execute the instructions issued this cycle: one, or with
+    /// superscalar issue, a group ending at a stall or a taken branch.
+    fn issue(&mut self, memory: &SharedMemory<'a>, system: &mut SyscallHandler) {
+        loop {
+            let inst = match self.fetched.take().or_else(|| self.fetch(memory)) {
+                Some(inst) => inst,
+                None => return,
+            };
+            let admitted = match self.superscalar {
+                Some(ref mut superscalar) => superscalar.admits(inst),
+                None => true,
+            };
+            if !admitted {
+                // Issue it next cycle without fetching it again
+                self.fetched = Some(inst);
+                return;
+            }
+
+            let pc = self.pc;
+            if !self.retrying {
+                let address = self.mmu.translate(pc);
+                self.record_access(AccessKind::Fetch, address, 4);
+            }
+            let mut retirement = Retirement::new(pc, inst, &mut self.registers);
+            self.retrying = !self.execute(inst, system);
+            if self.retrying {
+                self.fetched = Some(inst);
+                return;
+            }
+            retirement.complete(&mut self.registers, self.running);
+            if let Some(ref trace) = self.trace {
+                trace.borrow_mut().retire(self.id, &retirement);
+            }
+            self.retired.push_back(retirement);
+            self.instructions_retired += 1;
+            if let Some(ref mut profile) = self.profile {
+                profile.retire(pc, inst);
+            }
+            if let Some(ref mut calls) = self.calls {
+                calls.retire(inst, self.pc);
+            }
+            if self.running {
+                self.charge_hazards(pc, inst);
+            }
 
-        if self.stall > 0 {
-            self.stall -= 1;
-            self.stall_count += 1;
-            return;
+            let more = match self.superscalar {
+                Some(ref mut superscalar) => {
+                    superscalar.issue(inst);
+                    !superscalar.is_full()
+                },
+                None => false,
+            };
+            if !more || !self.running || !self.stalls.is_empty() || self.pc != pc + 4 {
+                return;
+            }
         }
+    }
+
+    /// Charge the stalls that the functional units, pipeline and branch
+    /// unit attribute to an instruction that has completed.
+    fn charge_hazards(&mut self, pc: isa::Address, inst: isa::Instruction) {
+        let latency = match self.units {
+            Some(ref mut units) => units.issue(inst, self.cycle_count as u64),
+            None => 0,
+        };
+        let penalty = match self.branches {
+            Some(ref mut branches) => Some(branches.resolve(pc, inst, self.pc)),
+            None => None,
+        };
+        // Pipeline flushes are mispredictions of a not-taken predictor
+        let (hazards, flushes) = match self.pipeline {
+            Some(ref mut pipeline) => {
+                let flushed = pipeline.stats().control_stalls;
+                let bubbles = match penalty {
+                    Some(penalty) => {
+                        let bubbles = pipeline.issue(inst, false);
+                        pipeline.delay(penalty);
+                        bubbles
+                    },
+                    None => pipeline.issue(inst, self.pc != pc + 4),
+                };
+                let flushes = pipeline.stats().control_stalls - flushed;
+                (bubbles - flushes, flushes)
+            },
+            None => (0, 0),
+        };
+        self.stall(CycleCategory::Structural, pc, latency + hazards);
+        self.stall(CycleCategory::BranchMispredict, pc, flushes + penalty.unwrap_or(0));
+    }
+
+    /// Execute an instruction, returning false if it must be retried.
+    fn execute(&mut self, inst: isa::Instruction, system: &mut SyscallHandler) -> bool {
+        let pc = self.pc;
 
         match inst.opcode() {
             isa::opcodes::LUI => {
@@ -106,15 +558,14 @@ impl<'a> Core<'a> {
                 else {
                     self.registers.write_word(inst.rd(), retval);
                     self.pc = target;
-                    return;
+                    return true;
                 }
             },
             isa::opcodes::JAL => {
                 let target = ((pc.as_signed_word()) + inst.uj_imm()).as_address();
                 self.registers.write_word(inst.rd(), (pc + 4).as_word());
                 self.pc = target;
-                // panic!("JAL to {:X} 0x{:X}", pc, target);
-                return;
+                return true;
             }
             isa::opcodes::BRANCH => {
                 let target = ((pc.as_signed_word()) + inst.sb_imm()).as_address();
@@ -136,7 +587,7 @@ impl<'a> Core<'a> {
                     }
                 } {
                     self.pc = target;
-                    return;
+                    return true;
                 }
             },
             isa::opcodes::INTEGER_IMMEDIATE => {
@@ -198,6 +649,46 @@ impl<'a> Core<'a> {
                     self.registers.write_word(inst.rd(), value);
                 }
             },
+            isa::opcodes::INTEGER_REGISTER if inst.funct7() == isa::funct7::MULDIV => {
+                let src1 = self.registers.read_word(inst.rs1());
+                let src2 = self.registers.read_word(inst.rs2());
+                let signed1 = src1.as_signed_word().0;
+                let signed2 = src2.as_signed_word().0;
+                let value = match inst.funct3() {
+                    isa::funct3::MUL => src1.0.wrapping_mul(src2.0),
+                    isa::funct3::MULH =>
+                        (((signed1 as i64) * (signed2 as i64)) >> 32) as u32,
+                    isa::funct3::MULHSU =>
+                        (((signed1 as i64) * (src2.0 as i64)) >> 32) as u32,
+                    isa::funct3::MULHU =>
+                        (((src1.0 as u64) * (src2.0 as u64)) >> 32) as u32,
+                    // Division by zero and overflow do not trap
+                    isa::funct3::DIV => {
+                        if signed2 == 0 {
+                            0xFFFFFFFF
+                        }
+                        else {
+                            signed1.wrapping_div(signed2) as u32
+                        }
+                    },
+                    isa::funct3::DIVU => {
+                        if src2.0 == 0 { 0xFFFFFFFF } else { src1.0 / src2.0 }
+                    },
+                    isa::funct3::REM => {
+                        if signed2 == 0 {
+                            src1.0
+                        }
+                        else {
+                            signed1.wrapping_rem(signed2) as u32
+                        }
+                    },
+                    isa::funct3::REMU => {
+                        if src2.0 == 0 { src1.0 } else { src1.0 % src2.0 }
+                    },
+                    _ => unreachable!(),
+                };
+                self.registers.write_word(inst.rd(), isa::Word(value));
+            },
             isa::opcodes::INTEGER_REGISTER => {
                 let src1 = self.registers.read_word(inst.rs1());
                 let src2 = self.registers.read_word(inst.rs2());
@@ -275,6 +766,8 @@ impl<'a> Core<'a> {
                 let base = self.registers.read_word(inst.rs1());
                 let address = ((base.as_signed_word()) + imm).as_address();
                 let address = self.mmu.translate(address);
+                self.cache.borrow_mut().set_pc(pc);
+                self.cache.borrow_mut().set_retry(self.retrying);
 
                 let result = match inst.funct3() {
                     isa::funct3::LB =>
@@ -300,12 +793,16 @@ impl<'a> Core<'a> {
                 };
 
                 match result {
-                    Ok(value) => self.registers.write_word(inst.rd(), value),
+                    Ok(value) => {
+                        self.record_access(AccessKind::Read, address, access_width(inst));
+                        self.registers.write_word(inst.rd(), value);
+                    },
                     Err(MemoryError::CacheMiss { stall_cycles, retry }) => {
-                        self.stall = stall_cycles - 1;
+                        self.miss(CycleCategory::DataCache, stall_cycles);
                         if retry {
-                            return;  // don't increment PC
+                            return false;  // don't increment PC
                         }
+                        self.record_access(AccessKind::Read, address, access_width(inst));
                     },
                     Err(MemoryError::InvalidAddress) => {
                         self.trap(Trap::IllegalRead {
@@ -322,6 +819,8 @@ impl<'a> Core<'a> {
                 let val = self.registers.read_word(inst.rs2());
                 let address = ((base.as_signed_word()) + imm).as_address();
                 let address = self.mmu.translate(address);
+                self.cache.borrow_mut().set_pc(pc);
+                self.cache.borrow_mut().set_retry(self.retrying);
 
                 let result = match inst.funct3() {
                     isa::funct3::SB =>
@@ -337,12 +836,15 @@ impl<'a> Core<'a> {
                 };
 
                 match result {
-                    Ok(()) => (),
+                    Ok(()) => {
+                        self.record_access(AccessKind::Write, address, access_width(inst));
+                    },
                     Err(MemoryError::CacheMiss { stall_cycles, retry }) => {
-                        self.stall = stall_cycles - 1;
+                        self.miss(CycleCategory::DataCache, stall_cycles);
                         if retry {
-                            return;  // don't increment PC
+                            return false;  // don't increment PC
                         }
+                        self.record_access(AccessKind::Write, address, access_width(inst));
                     },
                     Err(MemoryError::InvalidAddress) => {
                         self.trap(Trap::IllegalWrite {
@@ -366,12 +868,33 @@ impl<'a> Core<'a> {
 
                 }
             },
+            // Memory is always coherent, so only fence.i has work to do:
+            // stores may have changed instructions that the instruction
+            // cache holds stale, or that fetches from memory cannot see
+            // yet because they are in a write-back data cache
+            isa::opcodes::MISC_MEM => if inst.funct3() == isa::funct3::FENCE_I {
+                let mut stall_cycles = 0;
+                if let Err(MemoryError::CacheMiss { stall_cycles: stall, .. }) =
+                    self.cache.borrow_mut().flush() {
+                    stall_cycles += stall;
+                }
+                if let Some(ref icache) = self.icache {
+                    if let Err(MemoryError::CacheMiss { stall_cycles: stall, .. }) =
+                        icache.borrow_mut().invalidate_all() {
+                        stall_cycles += stall;
+                    }
+                }
+                if stall_cycles > 0 {
+                    self.miss(CycleCategory::DataCache, stall_cycles);
+                }
+            },
             _ => {
                 panic!("Invalid opcode: 0x{:02X} at PC 0x{:X} in instruction {:?}",
                        inst.opcode(), pc, inst);
             }
         }
         self.pc += 4;
+        true
     }
 
     fn trap(&mut self, trap: Trap) {
@@ -380,10 +903,44 @@ impl<'a> Core<'a> {
     }
 }
 
-impl<'a, T: SyscallHandler> Simulator<'a, T> {
-    pub fn new(cores: Vec<Core<'a>>, memory: SharedMemory<'a>,
+impl<'a> CoreInterface<'a> for Core<'a> {
+    fn id(&self) -> usize {
+        self.id
+    }
+
+    fn is_running(&self) -> bool {
+        self.running
+    }
+
+    fn step(&mut self, memory: &SharedMemory<'a>, system: &mut SyscallHandler) {
+        self.step_cycle(memory, system);
+    }
+
+    fn registers(&mut self) -> &mut RegisterFile {
+        &mut self.registers
+    }
+
+    fn cycle_count(&self) -> u32 {
+        self.cycle_count
+    }
+
+    fn stall_count(&self) -> u32 {
+        self.stall_count
+    }
+
+    fn instructions_retired(&self) -> u64 {
+        self.instructions_retired
+    }
+
+    fn cycle_stack(&self) -> CycleStack {
+        self.cycle_stack
+    }
+}
+
+impl<'a, T: SyscallHandler, C: CoreInterface<'a>> Simulator<'a, T, C> {
+    pub fn new(cores: Vec<C>, memory: SharedMemory<'a>,
                caches: Vec<SharedMemory<'a>>, syscall: T)
-               -> Simulator<'a, T> {
+               -> Simulator<'a, T, C> {
         // TODO: initialize GP, registers (GP is in headers)
         Simulator {
             cores: cores,
@@ -393,24 +950,16 @@ impl<'a, T: SyscallHandler> Simulator<'a, T> {
         }
     }
 
-    fn step(&mut self) -> bool {
+    /// Advance every running core and cache by one cycle. Returns false
+    /// once all cores have halted.
+    pub fn step(&mut self) -> bool {
         let mut ran = false;
         for core in self.cores.iter_mut() {
-            if !core.running {
+            if !core.is_running() {
                 continue;
             }
 
-            let pc = core.pc;
-            let pc = core.mmu.translate(pc);
-            let inst = self.memory.borrow_mut().read_instruction(pc);
-
-            if let Some(inst) = inst {
-                core.step(inst, &mut self.syscall);
-            }
-            else {
-                // TODO: trap
-            }
-
+            core.step(&self.memory, &mut self.syscall);
             ran = true;
         }
 
@@ -421,10 +970,12 @@ impl<'a, T: SyscallHandler> Simulator<'a, T> {
         ran
     }
 
-    pub fn report(&self) -> Vec<(usize, u32, u32)> {
-        self.cores.iter()
-            .map(|core| (core.id, core.stall_count, core.cycle_count))
-            .collect()
+    pub fn cores(&mut self) -> &mut [C] {
+        &mut self.cores
+    }
+
+    pub fn report(&self) -> Vec<CoreReport> {
+        self.cores.iter().map(|core| core.report()).collect()
     }
 
     pub fn run(&mut self) -> HaltReason {
@@ -0,0 +1,178 @@
+// Copyright 2016 David Li
+// This file is part of rustv.
+
+// rustv is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// rustv is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with rustv.  If not, see <http://www.gnu.org/licenses/>.
+
+use isa;
+use isa::opcodes;
+use pipeline::source_registers;
+use retirement::writes_register;
+
+/// The issue width and functional units of a superscalar core.
+#[derive(Clone, Copy, Debug)]
+pub struct SuperscalarConfig {
+    /// Instructions issued per cycle
+    pub width: usize,
+    pub alus: usize,
+    /// Loads and stores issued per cycle
+    pub memory_ports: usize,
+    /// Branches and jumps issued per cycle
+    pub branch_units: usize,
+}
+
+impl SuperscalarConfig {
+    /// An ALU per issue slot, with one memory port and one branch unit.
+    pub fn new(width: usize) -> SuperscalarConfig {
+        SuperscalarConfig {
+            width: width,
+            alus: width,
+            memory_ports: 1,
+            branch_units: 1,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SuperscalarStats {
+    pub cycles: u32,
+    pub issued: u32,
+    /// Cycles by the number of instructions issued in them, from zero
+    /// to the issue width
+    pub issue_histogram: Vec<u32>,
+    /// Groups ended by an instruction depending on one in the group
+    pub dependence_stops: u32,
+    /// Groups ended by an instruction finding no free functional unit
+    pub structural_stops: u32,
+}
+
+impl SuperscalarStats {
+    /// The fraction of issue slots filled.
+    pub fn utilisation(&self) -> f64 {
+        let slots = self.cycles as usize * (self.issue_histogram.len() - 1);
+        if slots == 0 {
+            0.0
+        }
+        else {
+            self.issued as f64 / slots as f64
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Unit {
+    Alu,
+    Memory,
+    Branch,
+    /// System instructions and fences, which issue alone
+    Serial,
+}
+
+fn unit(inst: isa::Instruction) -> Unit {
+    match inst.opcode() {
+        opcodes::LOAD | opcodes::STORE => Unit::Memory,
+        opcodes::BRANCH | opcodes::JAL | opcodes::JALR => Unit::Branch,
+        opcodes::SYSTEM | opcodes::MISC_MEM => Unit::Serial,
+        _ => Unit::Alu,
+    }
+}
+
+/// Forms the group of instructions a core issues each cycle. Issue is in
+/// order: the group ends at the first instruction that reads a register
+/// written earlier in the group, or that finds its functional units all
+/// taken. Every operation completes within the cycle, so its result is
+/// available to the next group.
+///
+/// The core ends the group itself after a taken branch or jump, and
+/// whenever it stalls.
+pub struct Superscalar {
+    config: SuperscalarConfig,
+    /// The registers written by the current group
+    written: Vec<isa::Register>,
+    /// The instructions in the current group, by unit
+    units: Vec<Unit>,
+    stats: SuperscalarStats,
+}
+
+impl Superscalar {
+    pub fn new(config: SuperscalarConfig) -> Superscalar {
+        assert!(config.width > 0 && config.alus > 0 &&
+                config.memory_ports > 0 && config.branch_units > 0);
+        Superscalar {
+            config: config,
+            written: Vec::new(),
+            units: Vec::new(),
+            stats: SuperscalarStats {
+                issue_histogram: vec![0; config.width + 1],
+                ..SuperscalarStats::default()
+            },
+        }
+    }
+
+    pub fn config(&self) -> SuperscalarConfig {
+        self.config
+    }
+
+    pub fn stats(&self) -> SuperscalarStats {
+        self.stats.clone()
+    }
+
+    /// Whether the group has filled every issue slot.
+    pub fn is_full(&self) -> bool {
+        self.units.len() == self.config.width
+    }
+
+    /// Whether an instruction can join the current group.
+    pub fn admits(&mut self, inst: isa::Instruction) -> bool {
+        if self.units.is_empty() {
+            return true;
+        }
+        let unit = unit(inst);
+        if unit == Unit::Serial || self.units.contains(&Unit::Serial) {
+            return false;
+        }
+
+        if source_registers(inst).iter().any(|register| self.written.contains(register)) {
+            self.stats.dependence_stops += 1;
+            return false;
+        }
+
+        let limit = match unit {
+            Unit::Alu => self.config.alus,
+            Unit::Memory => self.config.memory_ports,
+            _ => self.config.branch_units,
+        };
+        if self.units.iter().filter(|&&other| other == unit).count() == limit {
+            self.stats.structural_stops += 1;
+            return false;
+        }
+        true
+    }
+
+    /// Add an instruction that has executed to the current group.
+    pub fn issue(&mut self, inst: isa::Instruction) {
+        if writes_register(inst) && inst.rd() != isa::Register::X0 {
+            self.written.push(inst.rd());
+        }
+        self.units.push(unit(inst));
+    }
+
+    /// End the current cycle's group.
+    pub fn end_cycle(&mut self) {
+        self.stats.cycles += 1;
+        self.stats.issued += self.units.len() as u32;
+        self.stats.issue_histogram[self.units.len()] += 1;
+        self.written.clear();
+        self.units.clear();
+    }
+}
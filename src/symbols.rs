@@ -0,0 +1,91 @@
+// Copyright 2016 David Li
+// This file is part of rustv.
+
+// rustv is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// rustv is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with rustv.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::BTreeMap;
+
+use elfloader::{self, ElfBinary};
+use isa;
+
+/// Maps addresses to the names of the symbols defined there.
+pub struct SymbolTable {
+    symbols: BTreeMap<u32, String>,
+}
+
+impl SymbolTable {
+    pub fn new() -> SymbolTable {
+        SymbolTable {
+            symbols: BTreeMap::new(),
+        }
+    }
+
+    /// Collect the function and label symbols of an ELF binary.
+    pub fn from_elf(elf: &ElfBinary) -> SymbolTable {
+        let mut table = SymbolTable::new();
+        let mut functions = Vec::new();
+
+        elf.for_each_symbol(|symbol| {
+            let ty = symbol.sym_type().0;
+            let name = elf.symbol_name(symbol);
+            if name.len() == 0 || name.starts_with("$") || symbol.value == 0 {
+                return;
+            }
+
+            if ty == elfloader::elf::STT_FUNC.0 {
+                functions.push((symbol.value, name.to_owned()));
+            }
+            else if ty == elfloader::elf::STT_NOTYPE.0 ||
+                ty == elfloader::elf::STT_OBJECT.0 {
+                table.insert(isa::Word(symbol.value), name);
+            }
+        });
+
+        // Function names take precedence over labels at the same address
+        for (address, name) in functions {
+            table.symbols.insert(address, name);
+        }
+
+        table
+    }
+
+    /// Add a symbol, unless one is already defined at that address.
+    pub fn insert(&mut self, address: isa::Address, name: &str) {
+        self.symbols.entry(address.0).or_insert_with(|| name.to_owned());
+    }
+
+    /// Find the symbol defined exactly at an address.
+    pub fn get(&self, address: isa::Address) -> Option<&str> {
+        self.symbols.get(&address.0).map(|name| &name[..])
+    }
+
+    /// Find the closest symbol at or before an address, along with the
+    /// offset of the address from that symbol.
+    pub fn lookup(&self, address: isa::Address) -> Option<(&str, u32)> {
+        self.symbols.range(..address.0.saturating_add(1)).next_back()
+            .map(|(base, name)| (&name[..], address.0 - base))
+    }
+
+    /// Find the address of a symbol by name.
+    pub fn address_of(&self, name: &str) -> Option<isa::Address> {
+        self.symbols.iter()
+            .find(|&(_, symbol)| symbol == name)
+            .map(|(address, _)| isa::Word(*address))
+    }
+
+    pub fn iter<'s>(&'s self) -> Box<Iterator<Item=(isa::Address, &'s str)> + 's> {
+        Box::new(self.symbols.iter()
+                 .map(|(address, name)| (isa::Word(*address), &name[..])))
+    }
+}
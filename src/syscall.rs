@@ -27,3 +27,16 @@ pub trait SyscallHandler {
     /// Determine, after each cycle, whether the processor should halt.
     fn should_halt(&self) -> bool;
 }
+
+/// Ignores every system call and never halts.
+pub struct NoSyscalls {}
+
+impl SyscallHandler for NoSyscalls {
+    fn syscall(&mut self, _: usize, _: &mut RegisterFile, _: &Mmu) -> Option<trap::Trap> {
+        None
+    }
+
+    fn should_halt(&self) -> bool {
+        false
+    }
+}
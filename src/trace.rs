@@ -0,0 +1,213 @@
+// Copyright 2016 David Li
+// This file is part of rustv.
+
+// rustv is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// rustv is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with rustv.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use disassembler::Disassembler;
+use isa;
+use retirement::{MemoryAccess, Retirement};
+
+/// Receives each instruction a core retires.
+pub trait TraceSink {
+    fn retire(&mut self, core: usize, retirement: &Retirement);
+}
+
+pub type SharedTraceSink<'a> = Rc<RefCell<TraceSink + 'a>>;
+
+/// Rewrite `objdump`-style disassembly the way Spike prints it, with
+/// the mnemonic padded and a space after each comma.
+fn spike_disassembly(disassembly: &str) -> String {
+    let mut parts = disassembly.splitn(2, '\t');
+    let name = parts.next().unwrap();
+    match parts.next() {
+        Some(operands) => format!("{:<7} {}", name, operands.replace(",", ", ")),
+        None => name.to_owned(),
+    }
+}
+
+/// Logs retired instructions in the format of `spike -l --log-commits`:
+/// a line with the disassembly, then a line with the register written
+/// and the memory accessed, if any. Every instruction is logged as if
+/// it ran in machine mode.
+///
+/// Writing stops at the first I/O error, which `finish` returns.
+pub struct CommitLog<W: Write> {
+    out: W,
+    error: Option<io::Error>,
+}
+
+impl<W: Write> CommitLog<W> {
+    pub fn new(out: W) -> CommitLog<W> {
+        CommitLog {
+            out: out,
+            error: None,
+        }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.out
+    }
+
+    /// Stop logging, returning the writer.
+    pub fn finish(self) -> io::Result<W> {
+        match self.error {
+            Some(error) => Err(error),
+            None => Ok(self.out),
+        }
+    }
+
+    fn write(&mut self, core: usize, retirement: &Retirement) -> io::Result<()> {
+        let pc = retirement.pc.0;
+        let inst = retirement.instruction;
+        let disassembly = Disassembler::new().disassemble(retirement.pc, inst);
+        try!(writeln!(self.out, "core {:3}: 0x{:08x} (0x{:08x}) {}",
+                      core, pc, inst.word().0, spike_disassembly(&disassembly)));
+
+        try!(write!(self.out, "core {:3}: 3 0x{:08x} (0x{:08x})",
+                    core, pc, inst.word().0));
+        if let Some((rd, value)) = retirement.writeback {
+            try!(write!(self.out, " x{:<2} 0x{:08x}", rd.as_num(), value.0));
+        }
+        match retirement.access {
+            Some(MemoryAccess::Read { address, .. }) => {
+                try!(write!(self.out, " mem 0x{:08x}", address.0));
+            },
+            Some(MemoryAccess::Write { address, width, value }) => {
+                try!(write!(self.out, " mem 0x{:08x} 0x{:02$x}",
+                            address.0, value.0, width as usize * 2));
+            },
+            None => {},
+        }
+        writeln!(self.out, "")
+    }
+}
+
+impl<W: Write> TraceSink for CommitLog<W> {
+    fn retire(&mut self, core: usize, retirement: &Retirement) {
+        if self.error.is_some() {
+            return;
+        }
+        if let Err(error) = self.write(core, retirement) {
+            self.error = Some(error);
+        }
+    }
+}
+
+/// The kinds of memory reference in an address trace.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AccessKind {
+    Read,
+    Write,
+    Fetch,
+}
+
+impl AccessKind {
+    /// The Dinero IV label for the reference.
+    pub fn label(self) -> u8 {
+        match self {
+            AccessKind::Read => 0,
+            AccessKind::Write => 1,
+            AccessKind::Fetch => 2,
+        }
+    }
+}
+
+/// Receives each memory reference a core makes.
+pub trait AccessSink {
+    fn access(&mut self, core: usize, kind: AccessKind,
+              address: isa::Address, size: u32);
+}
+
+pub type SharedAccessSink<'a> = Rc<RefCell<AccessSink + 'a>>;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AddressTraceFormat {
+    /// Dinero IV's din format: a `label address` line per reference,
+    /// with the address in hex
+    Din,
+    /// The din format with the size in bytes after the address
+    DinWithSizes,
+    /// Eight bytes per reference: the address as a little-endian word,
+    /// the size as a little-endian halfword, the label, and a zero
+    Binary,
+}
+
+/// Writes the references of every core to one address trace, for cache
+/// simulators such as Dinero IV.
+///
+/// Writing stops at the first I/O error, which `finish` returns.
+pub struct AddressTrace<W: Write> {
+    out: W,
+    format: AddressTraceFormat,
+    error: Option<io::Error>,
+}
+
+impl<W: Write> AddressTrace<W> {
+    pub fn new(out: W, format: AddressTraceFormat) -> AddressTrace<W> {
+        AddressTrace {
+            out: out,
+            format: format,
+            error: None,
+        }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.out
+    }
+
+    /// Stop tracing, returning the writer.
+    pub fn finish(self) -> io::Result<W> {
+        match self.error {
+            Some(error) => Err(error),
+            None => Ok(self.out),
+        }
+    }
+
+    fn write(&mut self, kind: AccessKind, address: isa::Address, size: u32)
+             -> io::Result<()> {
+        let address = address.0;
+        match self.format {
+            AddressTraceFormat::Din => {
+                writeln!(self.out, "{} {:x}", kind.label(), address)
+            },
+            AddressTraceFormat::DinWithSizes => {
+                writeln!(self.out, "{} {:x} {}", kind.label(), address, size)
+            },
+            AddressTraceFormat::Binary => {
+                self.out.write_all(&[
+                    address as u8, (address >> 8) as u8,
+                    (address >> 16) as u8, (address >> 24) as u8,
+                    size as u8, (size >> 8) as u8,
+                    kind.label(), 0,
+                ])
+            },
+        }
+    }
+}
+
+impl<W: Write> AccessSink for AddressTrace<W> {
+    fn access(&mut self, _: usize, kind: AccessKind,
+              address: isa::Address, size: u32) {
+        if self.error.is_some() {
+            return;
+        }
+        if let Err(error) = self.write(kind, address, size) {
+            self.error = Some(error);
+        }
+    }
+}
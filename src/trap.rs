@@ -18,6 +18,9 @@ use isa;
 
 #[derive(Debug)]
 pub enum Trap {
+    IllegalFetch {
+        address: isa::Address,
+    },
     IllegalInstruction {
         address: isa::Address,
         instruction: isa::Instruction,